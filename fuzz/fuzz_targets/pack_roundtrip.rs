@@ -0,0 +1,261 @@
+//! Writes an arbitrary sequence of objects with `stream_to_pack_file`, reads the packfile back with
+//! `PackReader`, and checks that every object comes back unchanged (with the same delta, if it's
+//! deltified) and that the index lists all of them at the right offsets, both when read in full and when
+//! looked up one at a time. Along the way, it checks that `shorthash_hex` gives the start of each OID's
+//! full hex.
+//!
+//! The first byte of the input picks the offset past which the index uses its table of 8-byte offsets:
+//! the default, 0 (so every object is in the table), or an arbitrary offset. The second byte picks the
+//! object count that the writer is told in advance: none, the right one, or a wrong one that it has to
+//! recover from. The rest of the input is read as a sequence of commands, each of which adds one object:
+//!
+//! - a raw object of any type, with its data taken from the input
+//! - a large, incompressible blob, which pushes the offsets of later objects past the lengths where the
+//!   delta base offset encoding needs another byte
+//! - a delta against any earlier object (which can itself be deltified), made of copy instructions of
+//!   arbitrary sizes and offsets and "add new data" instructions of 1 to 127 bytes
+//!
+//! Run with `cargo fuzz run pack_roundtrip` from the repository root.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use std::{collections::HashSet, fs, process};
+
+#[allow(dead_code)]
+#[path = "../../src/direct_io.rs"]
+mod direct_io;
+#[allow(dead_code)]
+#[path = "../../src/git_utils.rs"]
+mod git_utils;
+#[allow(dead_code)]
+#[path = "../../src/pack_reader.rs"]
+mod pack_reader;
+
+use git_utils::{
+    apply_delta, encode_delta, shorthash_hex, stream_to_pack_file, GitObject, GitObjectType,
+    ObjectSize, PackFileDeltaInstruction, PackFileObject, PackWriteOptions, Sha1Oid,
+};
+use pack_reader::{lookup_index_entry, read_index_entries, verify_pack_trailer, PackReader};
+
+// Keeps each run fast; longer sequences mostly repeat what shorter ones already cover.
+const MAX_OBJECT_COUNT: usize = 64;
+
+/// Parses the fuzzer input. Once the input runs out, every read returns zeros.
+struct Input<'a>(&'a [u8]);
+
+impl Input<'_> {
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn byte(&mut self) -> u8 {
+        match self.0.split_first() {
+            Some((&byte, rest)) => {
+                self.0 = rest;
+                byte
+            }
+            None => 0,
+        }
+    }
+
+    fn u16(&mut self) -> usize {
+        usize::from(u16::from_le_bytes([self.byte(), self.byte()]))
+    }
+
+    fn u24(&mut self) -> usize {
+        self.u16() | usize::from(self.byte()) << 16
+    }
+
+    fn bytes(&mut self, max_len: usize) -> Vec<u8> {
+        let (bytes, rest) = self.0.split_at(max_len.min(self.0.len()));
+        self.0 = rest;
+        bytes.to_vec()
+    }
+}
+
+/// Returns `len` bytes that zlib can't compress, so that the blob takes up about `len` bytes in the pack.
+fn incompressible_data(seed: u8, len: usize) -> Vec<u8> {
+    let mut state = u64::from(seed) | 1;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+/// Builds a delta against `base` from the input.
+fn delta_against(input: &mut Input, base: &[u8]) -> Vec<PackFileDeltaInstruction> {
+    let instruction_count = 1 + usize::from(input.byte() % 16);
+    (0..instruction_count)
+        .filter_map(|_| {
+            if input.byte() & 1 == 0 && !base.is_empty() {
+                let offset = input.u24() % base.len();
+                // Copy sizes of zero aren't representable, since the encoding uses zero for 0x10000.
+                let size = 1 + input.u24() % (base.len() - offset);
+                Some(PackFileDeltaInstruction::CopyFromBaseObject { offset, size })
+            } else {
+                let len = 1 + usize::from(input.byte() % 127);
+                let new_data = input.bytes(len);
+                if new_data.is_empty() {
+                    None
+                } else {
+                    Some(PackFileDeltaInstruction::AddNewData(new_data))
+                }
+            }
+        })
+        .collect()
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut input = Input(data);
+    let big_offset_threshold = match input.byte() % 3 {
+        0 => None,
+        1 => Some(0),
+        _ => Some(input.u24()),
+    };
+    let object_count_kind = input.byte() % 3;
+    let mut objects = Vec::new();
+    // The expected contents of each object, and its encoded delta if it's deltified, in pack order
+    let mut expected: Vec<(Sha1Oid, GitObject, Option<Vec<u8>>)> = Vec::new();
+    let mut oids = HashSet::new();
+
+    while !input.is_empty() && objects.len() < MAX_OBJECT_COUNT {
+        let (object, packed, encoded_delta) = match input.byte() % 3 {
+            0 => {
+                let object_type = match input.byte() % 4 {
+                    0 => GitObjectType::Commit,
+                    1 => GitObjectType::Tree,
+                    2 => GitObjectType::Blob,
+                    _ => GitObjectType::Tag,
+                };
+                let len = input.u16();
+                let object = GitObject {
+                    object_type,
+                    data: input.bytes(len),
+                };
+                (object.clone(), PackFileObject::Raw(object), None)
+            }
+            1 => {
+                let seed = input.byte();
+                let object =
+                    GitObject::blob(incompressible_data(seed, 512 * usize::from(input.byte())));
+                (object.clone(), PackFileObject::Raw(object), None)
+            }
+            _ => {
+                if expected.is_empty() {
+                    continue;
+                }
+                let (base_oid, base, _) = &expected[input.u16() % expected.len()];
+                let delta = delta_against(&mut input, &base.data);
+                if delta.is_empty() {
+                    continue;
+                }
+                let object = GitObject {
+                    object_type: base.object_type.clone(),
+                    data: apply_delta(&base.data, &delta).unwrap(),
+                };
+                let base_size = ObjectSize(base.data.len());
+                let new_size = ObjectSize(object.data.len());
+                let encoded_delta = encode_delta(base_size, new_size, &delta).unwrap();
+                let packed = PackFileObject::Deltified {
+                    base_oid: *base_oid,
+                    base_size,
+                    delta,
+                    new_oid: object.oid(),
+                    new_size,
+                };
+                (object, packed, Some(encoded_delta))
+            }
+        };
+        // The writer counts distinct OIDs in the pack header, so repeating an object would make the
+        // header disagree with the number of entries. The generator never repeats objects.
+        if oids.insert(object.oid()) {
+            expected.push((object.oid(), object, encoded_delta));
+            objects.push(packed);
+        }
+    }
+
+    let pack_path = std::env::temp_dir().join(format!("pack_roundtrip-{}.pack", process::id()));
+    let index_path = pack_path.with_extension("idx");
+    // The OID, offset and entry length of each object, as reported while the pack is being written
+    let mut written = Vec::new();
+    let mut record_written =
+        |oid: &Sha1Oid, offset, entry_length| written.push((*oid, offset, entry_length));
+    let write_options = PackWriteOptions {
+        on_object_written: Some(&mut record_written),
+        big_offset_threshold,
+        object_count: match object_count_kind {
+            0 => None,
+            1 => Some(objects.len() as u32),
+            _ => Some(objects.len() as u32 + 1),
+        },
+        ..PackWriteOptions::default()
+    };
+    stream_to_pack_file(&pack_path, objects, write_options).unwrap();
+
+    assert!(verify_pack_trailer(&mut fs::File::open(&pack_path).unwrap()).unwrap());
+    let mut reader = PackReader::open(&pack_path).unwrap();
+    assert_eq!(reader.object_count() as usize, expected.len());
+    // The offset and CRC32 of each object, as read from the packfile
+    let mut entries = Vec::new();
+    for (oid, object, encoded_delta) in &expected {
+        let packed = reader.next().unwrap().unwrap();
+        entries.push((packed.oid, packed.offset, packed.crc32));
+        assert_eq!(packed.oid, *oid);
+        assert_eq!(packed.object.data, object.data);
+        let full_hex = oid.iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+        for &nibbles in &[0, 1, 7, 40] {
+            assert_eq!(shorthash_hex(oid, nibbles), full_hex[..nibbles]);
+        }
+        let decoded_delta = packed.delta.as_ref().map(|delta| {
+            encode_delta(
+                delta.base_size,
+                ObjectSize(packed.object.data.len()),
+                &delta.instructions,
+            )
+            .unwrap()
+        });
+        assert_eq!(decoded_delta, *encoded_delta);
+    }
+    assert!(reader.next().is_none());
+    assert!(written
+        .iter()
+        .map(|(oid, offset, _)| (*oid, *offset))
+        .eq(entries.iter().map(|(oid, offset, _)| (*oid, *offset))));
+    // Each entry ends where the next one starts, and the last one ends at the trailing checksum.
+    let next_offsets = written
+        .iter()
+        .skip(1)
+        .map(|(_, offset, _)| offset.0)
+        .chain(Some(fs::metadata(&pack_path).unwrap().len() - 20));
+    for ((_, offset, entry_length), next_offset) in written.iter().zip(next_offsets) {
+        assert_eq!(offset.0 + *entry_length as u64, next_offset);
+    }
+    // An "offset delta" can only refer back, so a delta base at or after the delta's own offset has to
+    // be rejected rather than wrapping around into a huge distance.
+    for pair in written.windows(2) {
+        let (earlier, later) = (pair[0].1, pair[1].1);
+        assert_eq!(later.distance_back_to(earlier), Some(later.0 - earlier.0));
+        assert_eq!(earlier.distance_back_to(later), None);
+        assert_eq!(later.distance_back_to(later), None);
+    }
+
+    entries.sort_unstable();
+    let index_entries = read_index_entries(&index_path)
+        .unwrap()
+        .into_iter()
+        .map(|metadata| (metadata.oid, metadata.offset, metadata.crc32))
+        .collect::<Vec<_>>();
+    assert_eq!(index_entries, entries);
+    for (oid, offset, crc32) in &entries {
+        let entry = lookup_index_entry(&index_path, oid).unwrap().unwrap();
+        assert_eq!((entry.metadata.offset, entry.metadata.crc32), (*offset, *crc32));
+    }
+
+    fs::remove_file(pack_path).unwrap();
+    fs::remove_file(index_path).unwrap();
+});
@@ -0,0 +1,45 @@
+use crate::{git_utils::Sha1Oid, pack_reader::for_each_index_oid};
+use std::{io, path::Path};
+
+/// Counts how many of the objects in a pack index need each abbreviation length to be unambiguous within
+/// the index. Entry `n` of the result is the number of objects whose shortest unique prefix is `n` hex
+/// characters. This ignores git's own minimum abbreviation length (4, or more by default), so it shows
+/// how long abbreviations in the repository really have to be: once every 7-character shorthash is
+/// taken, very few objects can be identified by 7 characters.
+///
+/// An object's shortest unique prefix is one character longer than the longest prefix it shares with
+/// any other object, and in the sorted order of the index that's always one of its neighbors, so this
+/// only has to stream through the OIDs once.
+pub fn abbreviation_lengths(index_path: &Path) -> io::Result<[usize; 41]> {
+    let mut counts = [0; 41];
+    // The previous OID, and the number of hex characters it shares with the OID before it
+    let mut previous: Option<(Sha1Oid, usize)> = None;
+    for_each_index_oid(index_path, |oid| {
+        let shared_with_previous = match previous {
+            Some((previous_oid, previous_shared)) => {
+                let shared = common_hex_prefix_length(&previous_oid, oid);
+                counts[previous_shared.max(shared) + 1] += 1;
+                shared
+            }
+            None => 0,
+        };
+        previous = Some((*oid, shared_with_previous));
+    })?;
+    if let Some((_, previous_shared)) = previous {
+        counts[previous_shared + 1] += 1;
+    }
+    Ok(counts)
+}
+
+/// Returns the number of leading hex characters that two OIDs have in common. The OIDs in an index are
+/// distinct, so this is at most 39.
+fn common_hex_prefix_length(a: &Sha1Oid, b: &Sha1Oid) -> usize {
+    match a
+        .iter()
+        .zip(b)
+        .position(|(a_byte, b_byte)| a_byte != b_byte)
+    {
+        Some(index) => 2 * index + usize::from((a[index] ^ b[index]) < 0x10),
+        None => 40,
+    }
+}
@@ -0,0 +1,70 @@
+//! Reads the manifest of `--base-objects`, which lists objects to write to the pack ahead of the
+//! generated commits, e.g. a tree hierarchy and the blobs in it for a `--base-commit-file` to point to.
+//! Each non-empty line of the manifest that doesn't start with `#` is one object:
+//!
+//! - `loose <path>`: a loose object file, as found under `.git/objects`
+//! - `<type> <path>`: an object of type `commit`, `tree`, `blob` or `tag`, whose contents are the file
+//!
+//! Relative paths are relative to the directory that the manifest is in.
+
+use crate::git_utils::{GitObject, GitObjectType, Sha1Oid};
+use flate2::read::ZlibDecoder;
+use std::{collections::HashSet, fs, io, io::Read, path::Path};
+
+/// Reads the objects listed in the manifest at `manifest_path`, in order. An object that's listed more
+/// than once, or that's already in `existing_oids`, is only returned the first time, since a pack can
+/// only contain each object once. The OIDs of the returned objects are added to `existing_oids`.
+pub fn read_manifest(
+    manifest_path: &Path,
+    existing_oids: &mut HashSet<Sha1Oid>,
+) -> io::Result<Vec<GitObject>> {
+    let manifest = fs::read_to_string(manifest_path)?;
+    let manifest_dir = manifest_path.parent().unwrap_or_else(|| Path::new(""));
+    let mut objects = Vec::new();
+    for (line_index, line) in manifest.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid_line = |message: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} line {}: {}",
+                    manifest_path.display(),
+                    line_index + 1,
+                    message
+                ),
+            )
+        };
+        let (kind, path) = line.split_once(' ').ok_or_else(|| {
+            invalid_line("expected `<type> <path>` or `loose <path>`".to_string())
+        })?;
+        let path = manifest_dir.join(path.trim_start());
+        let object_type = match kind {
+            "loose" => None,
+            "commit" => Some(GitObjectType::Commit),
+            "tree" => Some(GitObjectType::Tree),
+            "blob" => Some(GitObjectType::Blob),
+            "tag" => Some(GitObjectType::Tag),
+            _ => return Err(invalid_line(format!("unknown object type {}", kind))),
+        };
+        let object = match object_type {
+            Some(object_type) => GitObject {
+                data: fs::read(&path)?,
+                object_type,
+            },
+            None => {
+                let mut loose = Vec::new();
+                ZlibDecoder::new(fs::File::open(&path)?)
+                    .read_to_end(&mut loose)
+                    .and_then(|_| GitObject::from_loose(&loose))
+                    .map_err(|err| invalid_line(format!("{}: {}", path.display(), err)))?
+            }
+        };
+        if existing_oids.insert(object.oid()) {
+            objects.push(object);
+        }
+    }
+    Ok(objects)
+}
@@ -0,0 +1,116 @@
+//! A compact record of the progress of a run: which shorthashes have been found, and how many attempts
+//! it took. Resuming from a checkpoint is much faster than reading the OIDs out of a multi-gigabyte index
+//! file, and also lets the entropy sequence continue where it left off instead of retrying the entropy
+//! values of the previous run.
+//!
+//! The format is, with integers in network byte order:
+//!
+//! - the 4-byte magic `ESCP`
+//! - a 4-byte version number, currently 1
+//! - the number of shorthash bits, as 1 byte
+//! - the number of bits in the OID prefix, as 1 byte, followed by the prefix itself as 8 bytes
+//! - the number of attempts made so far, as 8 bytes
+//! - the number of found shorthashes, as 8 bytes
+//! - the found-shorthash bitmap
+//! - a SHA1 checksum of everything above
+//!
+//! Checkpoints are written to a temporary file and renamed into place, so a crash while saving one
+//! leaves the previous checkpoint intact, and the checksum catches any other corruption.
+
+use crate::{
+    git_utils::Sha1Oid,
+    shorthash::{OidPrefix, PackedBoolArray, SHORTHASH_BITS},
+};
+use sha1::{Digest, Sha1};
+use std::{convert::TryInto, ffi::OsString, fs, fs::File, io, io::Write, path::Path};
+
+const MAGIC: &[u8; 4] = b"ESCP";
+const VERSION: u32 = 1;
+const HEADER_LENGTH: usize = 34;
+
+pub struct Checkpoint {
+    pub prefix: OidPrefix,
+    pub attempt_count: u64,
+    pub found_shorthashes: PackedBoolArray,
+}
+
+impl Checkpoint {
+    /// Atomically replaces the checkpoint at `path` with one for the given state. (This takes the fields
+    /// separately, since the bitmap is too large to copy into a `Checkpoint` casually.)
+    pub fn write(
+        path: &Path,
+        prefix: OidPrefix,
+        attempt_count: u64,
+        found_shorthashes: &PackedBoolArray,
+    ) -> io::Result<()> {
+        let bitmap = found_shorthashes.as_bytes();
+        let mut contents = Vec::with_capacity(HEADER_LENGTH + bitmap.len() + 20);
+        contents.extend_from_slice(MAGIC);
+        contents.extend_from_slice(&VERSION.to_be_bytes());
+        contents.push(SHORTHASH_BITS as u8);
+        contents.push(prefix.bit_count as u8);
+        contents.extend_from_slice(&prefix.value.to_be_bytes());
+        contents.extend_from_slice(&attempt_count.to_be_bytes());
+        contents.extend_from_slice(&(found_shorthashes.count_set() as u64).to_be_bytes());
+        contents.extend_from_slice(bitmap);
+        let checksum: Sha1Oid = Sha1::digest(&contents).into();
+        contents.extend_from_slice(&checksum);
+
+        let mut temp_file_name = OsString::from(path.file_name().unwrap_or_default());
+        temp_file_name.push(".tmp");
+        let temp_path = path.with_file_name(temp_file_name);
+        let mut temp_file = File::create(&temp_path)?;
+        temp_file.write_all(&contents)?;
+        temp_file.sync_all()?;
+        fs::rename(temp_path, path)
+    }
+
+    /// Reads a checkpoint, rejecting it if it's corrupt or was written by an incompatible version.
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let contents = fs::read(path)?;
+        let invalid = |message: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not a valid checkpoint: {}", path.display(), message),
+            )
+        };
+
+        if contents.len() < HEADER_LENGTH + 20 || &contents[..4] != MAGIC {
+            return Err(invalid("it doesn't start with a checkpoint header"));
+        }
+        let (body, checksum) = contents.split_at(contents.len() - 20);
+        if Sha1::digest(body).as_slice() != checksum {
+            return Err(invalid("it doesn't match its checksum"));
+        }
+        let version = u32::from_be_bytes(body[4..8].try_into().unwrap());
+        if version != VERSION {
+            return Err(invalid(&format!(
+                "it has version {}, but only version {} is supported",
+                version, VERSION
+            )));
+        }
+        if u32::from(body[8]) != SHORTHASH_BITS {
+            return Err(invalid(&format!(
+                "it's for {}-bit shorthashes, but this build uses {}-bit shorthashes",
+                body[8], SHORTHASH_BITS
+            )));
+        }
+        let prefix = OidPrefix {
+            bit_count: u32::from(body[9]),
+            value: u64::from_be_bytes(body[10..18].try_into().unwrap()),
+        };
+        let attempt_count = u64::from_be_bytes(body[18..26].try_into().unwrap());
+        let found_count = u64::from_be_bytes(body[26..34].try_into().unwrap());
+        let found_shorthashes = PackedBoolArray::from_bytes(body[HEADER_LENGTH..].to_vec())
+            .ok_or_else(|| invalid("its bitmap has the wrong length"))?;
+        if found_shorthashes.count_set() as u64 != found_count {
+            return Err(invalid("its bitmap doesn't match its found count"));
+        }
+
+        Ok(Self {
+            prefix,
+            attempt_count,
+            found_shorthashes,
+        })
+    }
+}
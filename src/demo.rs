@@ -0,0 +1,107 @@
+//! Runs a small generation end to end, for `demo`: creates a repository in a temporary directory,
+//! generates a pack into it with a second copy of the tool, points `master` at the top-level merge
+//! commit, and checks with git that the repository is valid and that `master` has every commit in its
+//! history. The repository is left in place so that it can be looked at afterwards.
+//!
+//! The search is stopped with `--max-attempts` rather than `--commit-count-cap`, since a capped run
+//! leaves out the merge commits. Early in the search almost every attempt finds a new shorthash, so
+//! `2**bits` attempts generate nearly `2**bits` commits.
+
+use std::{
+    env, fs, io,
+    path::Path,
+    process::{self, Command, Output},
+};
+
+/// Runs the demo with `2**bits` attempts, returning an error if any step fails.
+pub fn run_demo(bits: u32) -> io::Result<()> {
+    let repo = env::temp_dir().join(format!("every-commit-shorthash-demo-{}", process::id()));
+    fs::create_dir_all(&repo)?;
+    git(&repo, &["init", "--quiet"])?;
+    git(&repo, &["symbolic-ref", "HEAD", "refs/heads/master"])?;
+
+    println!("Generating a pack in {}", repo.display());
+    let output = run(
+        Command::new(env::current_exe()?)
+            .args(["--max-attempts", &(1u64 << bits).to_string()])
+            // Stopping early saves a checkpoint, which would otherwise end up in the work tree.
+            .args(["--write-checkpoint", ".git/every-shorthash.checkpoint"])
+            .current_dir(&repo),
+        "generating the pack",
+    )?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    print!("{}", output);
+    // These lines are printed by every run that finishes its merge commits.
+    let printed_value = |prefix: &str| {
+        output
+            .lines()
+            .find_map(|line| line.strip_prefix(prefix))
+            .ok_or_else(|| demo_failed(format!("the run didn't print \"{}\"", prefix.trim_end())))
+    };
+    let top_level_merge_commit = printed_value("Top-level merge commit: ")?;
+    let printed_count = |prefix: &str| -> io::Result<usize> {
+        printed_value(prefix)?.parse().map_err(|_| {
+            demo_failed(format!(
+                "the run printed an invalid \"{}\"",
+                prefix.trim_end()
+            ))
+        })
+    };
+    // Every commit in the pack should be in the history. The delta base commits cover their own
+    // shorthashes, so they're merged like the generated commits.
+    let expected_count = printed_count("Generated commits: ")?
+        + printed_count("Delta base commits: ")?
+        + printed_count("First-level merge commits: ")?
+        + printed_count("Top-level merge commits: ")?;
+    git(
+        &repo,
+        &["update-ref", "refs/heads/master", top_level_merge_commit],
+    )?;
+
+    let count = git(&repo, &["rev-list", "--count", "master"])?;
+    if count.trim() != expected_count.to_string() {
+        return Err(demo_failed(format!(
+            "master has {} commits in its history, but should have {}",
+            count.trim(),
+            expected_count
+        )));
+    }
+    git(&repo, &["fsck", "--no-dangling"])?;
+    println!(
+        "{} passed git fsck, and master has all {} commits. Delete it when you're done with it.",
+        repo.display(),
+        expected_count
+    );
+    Ok(())
+}
+
+/// Runs git in `repo` and returns its output.
+fn git(repo: &Path, args: &[&str]) -> io::Result<String> {
+    let output = run(
+        Command::new("git").args(args).current_dir(repo),
+        &format!("git {}", args[0]),
+    )?;
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Runs `command` and returns its output, or an error with its stderr if it fails. The repository is
+/// found from the working directory, so variables that point git at another repository are cleared.
+fn run(command: &mut Command, description: &str) -> io::Result<Output> {
+    let output = command
+        .env_remove("GIT_DIR")
+        .env_remove("GIT_COMMON_DIR")
+        .env_remove("GIT_OBJECT_DIRECTORY")
+        .output()?;
+    if !output.status.success() {
+        return Err(demo_failed(format!(
+            "{} failed: {}",
+            description,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        )));
+    }
+    Ok(output)
+}
+
+fn demo_failed(message: String) -> io::Error {
+    io::Error::other(message)
+}
@@ -0,0 +1,204 @@
+//! Writes the packfile and index file of `--direct-io` runs without going through the page cache. A full
+//! run writes tens of GiB exactly once, so caching it only evicts everything else that's cached. On Linux,
+//! the files are written with `O_DIRECT`, which needs the memory, offset and length of every write to be
+//! aligned to the device's block size, so the data is collected in an aligned buffer and written a
+//! buffer at a time. The unaligned bits at the end (the last partial block, and the header and checksums
+//! that are filled in afterwards) are written normally, and dropped from the page cache once the file is
+//! synced.
+
+use std::{fs::File, io, io::Write};
+
+/// A file that's written sequentially from its current position, which must be 0 for `Direct`.
+pub enum OutputFile {
+    Cached(File),
+    #[cfg(target_os = "linux")]
+    Direct(DirectWriter),
+}
+
+impl OutputFile {
+    /// Wraps `file`, which is positioned at its start, for writing with `O_DIRECT` if `direct_io`.
+    pub fn new(file: File, direct_io: bool) -> io::Result<Self> {
+        if !direct_io {
+            return Ok(Self::Cached(file));
+        }
+        #[cfg(target_os = "linux")]
+        return DirectWriter::new(file).map(Self::Direct);
+        #[cfg(not(target_os = "linux"))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "--direct-io is only supported on Linux",
+        ));
+    }
+
+    /// Writes whatever is still buffered and returns the file, positioned right after the written data.
+    /// The file can then be read and written as usual.
+    pub fn into_file(self) -> io::Result<File> {
+        match self {
+            Self::Cached(file) => Ok(file),
+            #[cfg(target_os = "linux")]
+            Self::Direct(writer) => writer.into_file(),
+        }
+    }
+
+    /// Syncs what has been written so far to disk, without its metadata. Flush first. A `Direct` file
+    /// can't be synced partway, since the last partial block is only written by `into_file`.
+    pub fn sync_data(&self) -> io::Result<()> {
+        match self {
+            Self::Cached(file) => file.sync_data(),
+            #[cfg(target_os = "linux")]
+            Self::Direct(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "a file written with --direct-io can't be synced until it's finished",
+            )),
+        }
+    }
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Cached(file) => file.write(buf),
+            #[cfg(target_os = "linux")]
+            Self::Direct(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Cached(file) => file.flush(),
+            #[cfg(target_os = "linux")]
+            Self::Direct(writer) => writer.flush(),
+        }
+    }
+}
+
+/// Drops the pages of `file` from the page cache. The file has to be synced first, since only clean pages
+/// are dropped.
+#[cfg(target_os = "linux")]
+pub fn drop_from_page_cache(file: &File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    // Unlike most calls, this returns the error number rather than setting `errno`.
+    match unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) } {
+        0 => Ok(()),
+        errno => Err(io::Error::from_raw_os_error(errno)),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn drop_from_page_cache(_file: &File) -> io::Result<()> {
+    Ok(())
+}
+
+// Writes are aligned to this many bytes. This is the largest logical block size of common devices; a
+// device with smaller blocks accepts writes aligned to bigger ones.
+#[cfg(target_os = "linux")]
+const BLOCK_SIZE: usize = 4096;
+
+// The number of bytes written by each system call
+#[cfg(target_os = "linux")]
+const BUFFER_SIZE: usize = 8 << 20;
+
+/// Writes a file with `O_DIRECT`, a whole buffer at a time.
+#[cfg(target_os = "linux")]
+pub struct DirectWriter {
+    file: File,
+    // Has `BLOCK_SIZE` bytes more than the buffer needs, so that the buffer can start at an aligned address
+    // within it.
+    allocation: Vec<u8>,
+    buffer_start: usize,
+    buffered_length: usize,
+}
+
+#[cfg(target_os = "linux")]
+impl DirectWriter {
+    fn new(file: File) -> io::Result<Self> {
+        set_direct(&file, true).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!(
+                    "can't use --direct-io, probably because the filesystem doesn't support it: {}",
+                    err
+                ),
+            )
+        })?;
+        let allocation = vec![0; BUFFER_SIZE + BLOCK_SIZE];
+        let buffer_start = allocation.as_ptr().align_offset(BLOCK_SIZE);
+        Ok(Self {
+            file,
+            allocation,
+            buffer_start,
+            buffered_length: 0,
+        })
+    }
+
+    fn buffer(&mut self) -> &mut [u8] {
+        &mut self.allocation[self.buffer_start..self.buffer_start + BUFFER_SIZE]
+    }
+
+    fn into_file(mut self) -> io::Result<File> {
+        use std::io::{Seek, SeekFrom};
+        // The last block is padded with zeros to write it with `O_DIRECT`, and the file is then positioned
+        // back at the end of the real data. Whatever is written next overwrites the padding, and the
+        // callers truncate the file to its real length at the end.
+        let length = self.buffered_length;
+        let padded_length = length.next_multiple_of(BLOCK_SIZE);
+        self.buffer()[length..padded_length].fill(0);
+        let Self {
+            mut file,
+            allocation,
+            buffer_start,
+            ..
+        } = self;
+        file.write_all(&allocation[buffer_start..buffer_start + padded_length])?;
+        set_direct(&file, false)?;
+        file.seek(SeekFrom::Current(length as i64 - padded_length as i64))?;
+        Ok(file)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Write for DirectWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let start = self.buffered_length;
+        let length = buf.len().min(BUFFER_SIZE - start);
+        self.buffer()[start..start + length].copy_from_slice(&buf[..length]);
+        self.buffered_length += length;
+        if self.buffered_length == BUFFER_SIZE {
+            let Self {
+                file,
+                allocation,
+                buffer_start,
+                ..
+            } = self;
+            file.write_all(&allocation[*buffer_start..*buffer_start + BUFFER_SIZE])?;
+            self.buffered_length = 0;
+        }
+        Ok(length)
+    }
+
+    /// Does nothing, since only whole blocks can be written with `O_DIRECT`. The rest is written by
+    /// `into_file`.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Turns `O_DIRECT` on or off for an open file.
+#[cfg(target_os = "linux")]
+fn set_direct(file: &File, direct: bool) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let fd = file.as_raw_fd();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let flags = if direct {
+        flags | libc::O_DIRECT
+    } else {
+        flags & !libc::O_DIRECT
+    };
+    if unsafe { libc::fcntl(fd, libc::F_SETFL, flags) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
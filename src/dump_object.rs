@@ -0,0 +1,98 @@
+use crate::{
+    git_utils::{oid_to_hex, GitObjectType, Sha1Oid},
+    pack_reader::{lookup_index_entry, PackReader},
+};
+use std::{convert::TryInto, io, io::Write, path::Path};
+
+/// Writes the contents of one object in a packfile to stdout, for debugging a generated pack without
+/// `git`. The object is found with the pack's index, and deltified objects are written with their delta
+/// applied. A description of the object goes to stderr, so that stdout only has its contents. With
+/// `pretty`, trees are written as `git cat-file -p` would, rather than in their binary form.
+pub fn dump_object(
+    pack_path: &Path,
+    index_path: &Path,
+    oid: &Sha1Oid,
+    pretty: bool,
+) -> io::Result<()> {
+    let entry = lookup_index_entry(index_path, oid)?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} isn't in {}", oid_to_hex(oid), index_path.display()),
+        )
+    })?;
+    let packed = PackReader::open(pack_path)?.read_object_at(entry.metadata.offset)?;
+    // A mismatch means that the index doesn't belong to the pack, or that one of them is corrupt.
+    if packed.oid != *oid {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} says {} is at offset {}, but the object there is {}",
+                index_path.display(),
+                oid_to_hex(oid),
+                entry.metadata.offset,
+                oid_to_hex(&packed.oid)
+            ),
+        ));
+    }
+
+    eprintln!(
+        "{} {} ({} bytes) at offset {} of {}",
+        packed.object.object_type.type_name(),
+        oid_to_hex(oid),
+        packed.object.data.len(),
+        packed.offset,
+        pack_path.display()
+    );
+    if let Some(delta) = &packed.delta {
+        eprintln!(
+            "deltified against {} with {} instructions",
+            oid_to_hex(&delta.base_oid),
+            delta.instructions.len()
+        );
+    }
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    match packed.object.object_type {
+        GitObjectType::Tree if pretty => write_tree_entries(&mut out, &packed.object.data)?,
+        _ => out.write_all(&packed.object.data)?,
+    }
+    out.flush()
+}
+
+/// Writes each entry of a tree as `<mode> <type> <OID>\t<name>`, the format of `git cat-file -p`.
+fn write_tree_entries<W: Write>(out: &mut W, mut data: &[u8]) -> io::Result<()> {
+    let malformed = || io::Error::new(io::ErrorKind::InvalidData, "malformed tree entry");
+    while !data.is_empty() {
+        // Each entry is `<mode> <name>\0` followed by the 20-byte OID.
+        let space = data
+            .iter()
+            .position(|&byte| byte == b' ')
+            .ok_or_else(malformed)?;
+        let nul = data
+            .iter()
+            .position(|&byte| byte == 0)
+            .ok_or_else(malformed)?;
+        if nul < space || data.len() < nul + 21 {
+            return Err(malformed());
+        }
+        let mode = &data[..space];
+        let name = &data[space + 1..nul];
+        let oid: Sha1Oid = data[nul + 1..nul + 21].try_into().unwrap();
+        let object_type = match mode {
+            b"40000" => "tree",
+            b"160000" => "commit",
+            _ => "blob",
+        };
+        // Git leaves out the leading zero of a tree's mode in the tree itself, but not when printing it.
+        for _ in mode.len()..6 {
+            out.write_all(b"0")?;
+        }
+        out.write_all(mode)?;
+        write!(out, " {} {}\t", object_type, oid_to_hex(&oid))?;
+        out.write_all(name)?;
+        out.write_all(b"\n")?;
+        data = &data[nul + 21..];
+    }
+    Ok(())
+}
@@ -0,0 +1,105 @@
+//! Sources of the entropy values that get appended to the delta base commit to form each attempted
+//! commit. Any sequence of values works, since the generator skips commits whose shorthashes have
+//! already been found; the sequence only affects which OIDs end up in the pack.
+
+use std::{
+    fs::File,
+    io::Read,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+pub trait EntropySource: Send {
+    /// Returns the entropy value for the next attempted commit. While the generator is using fewer than 16
+    /// characters of entropy, only the low 4 bits per character of the value are used.
+    fn next_entropy(&mut self) -> u64;
+
+    /// Skips the next `count` entropy values, as if `next_entropy` had been called `count` times. This is
+    /// used to continue the sequence of a previous run when resuming from a checkpoint.
+    fn skip(&mut self, count: u64);
+
+    /// Returns a copy of the source at the same point in its sequence, for the hashing threads to continue
+    /// from.
+    fn boxed_clone(&self) -> Box<dyn EntropySource>;
+}
+
+/// Returns the hex characters that an attempted commit with `entropy` ends with (before any footer), when
+/// the generator is using `length` characters of entropy.
+pub fn entropy_hex(entropy: u64, length: usize) -> String {
+    match length {
+        8 => format!("{:08x}", entropy as u32),
+        16 => format!("{:016x}", entropy),
+        length => format!("{:01$x}", entropy & ((1 << (4 * length)) - 1), length),
+    }
+}
+
+/// Counts up from 0, so that every attempt uses a different entropy value. This is the default, and
+/// makes the generated commits a dense, predictable sequence.
+#[derive(Clone, Debug, Default)]
+pub struct CounterEntropySource {
+    next: u64,
+}
+
+impl EntropySource for CounterEntropySource {
+    #[inline(always)]
+    fn next_entropy(&mut self) -> u64 {
+        let entropy = self.next;
+        self.next += 1;
+        entropy
+    }
+
+    fn skip(&mut self, count: u64) {
+        self.next += count;
+    }
+
+    fn boxed_clone(&self) -> Box<dyn EntropySource> {
+        Box::new(self.clone())
+    }
+}
+
+/// Produces pseudorandom entropy values with [SplitMix64](https://prng.di.unimi.it/splitmix64.c), which
+/// is fast and passes the usual statistical tests. Runs with the same seed generate the same commits.
+/// Unlike the counter, this occasionally repeats an entropy value, which just wastes an attempt.
+#[derive(Clone, Debug)]
+pub struct RandomEntropySource {
+    state: u64,
+}
+
+impl RandomEntropySource {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// Picks a seed from `/dev/urandom`, or from the current time if that isn't available.
+    pub fn random_seed() -> u64 {
+        let mut seed = [0; 8];
+        match File::open("/dev/urandom").and_then(|mut urandom| urandom.read_exact(&mut seed)) {
+            Ok(()) => u64::from_le_bytes(seed),
+            Err(_) => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos() as u64,
+        }
+    }
+}
+
+impl EntropySource for RandomEntropySource {
+    #[inline(always)]
+    fn next_entropy(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn skip(&mut self, count: u64) {
+        // The state just counts up in steps of a fixed constant, so skipping ahead is cheap.
+        self.state = self
+            .state
+            .wrapping_add(0x9e37_79b9_7f4a_7c15u64.wrapping_mul(count));
+    }
+
+    fn boxed_clone(&self) -> Box<dyn EntropySource> {
+        Box::new(self.clone())
+    }
+}
@@ -0,0 +1,123 @@
+//! Estimates of how much work it takes to find shorthashes, based on the
+//! [coupon collector's problem](https://en.wikipedia.org/wiki/Coupon_collector%27s_problem). Each
+//! attempted commit has an effectively-random shorthash, so finding every one of `n` shorthashes takes
+//! `n * H(n)` attempts on average, where `H(n)` is the `n`th harmonic number.
+//!
+//! This also estimates how large the output files get, for checking that they'll fit on the disk.
+
+const EULER_MASCHERONI: f64 = 0.577_215_664_901_532_9;
+
+/// Approximates the `k`th harmonic number, `1 + 1/2 + ... + 1/k`.
+fn harmonic_number(k: f64) -> f64 {
+    if k < 1.0 {
+        0.0
+    } else {
+        k.ln() + EULER_MASCHERONI + 1.0 / (2.0 * k) - 1.0 / (12.0 * k * k)
+    }
+}
+
+/// Returns the expected number of attempts needed to find every shorthash, when shorthashes have
+/// `shorthash_bits` bits. For 28-bit (7-character) shorthashes, this is about 2**32.3.
+pub fn expected_attempts(shorthash_bits: u32) -> f64 {
+    expected_attempts_for_coverage(shorthash_bits, 1.0)
+}
+
+/// Returns the expected number of attempts needed to find the given fraction (between 0 and 1) of all
+/// shorthashes. Finding `m` of `n` shorthashes takes `n * (H(n) - H(n - m))` attempts on average, so
+/// the last few shorthashes are by far the most expensive.
+pub fn expected_attempts_for_coverage(shorthash_bits: u32, fraction: f64) -> f64 {
+    let shorthash_count = 2f64.powi(shorthash_bits as i32);
+    let remaining = (shorthash_count * (1.0 - fraction.clamp(0.0, 1.0))).round();
+    shorthash_count * (harmonic_number(shorthash_count) - harmonic_number(remaining))
+}
+
+/// Returns the expected number of attempts needed to find every one of a subset of `subset_size`
+/// shorthashes, when attempts outside of the subset are wasted. Only one in `n / subset_size` attempts
+/// lands in the subset, so this is `n * H(subset_size)`: not much less than finding every shorthash,
+/// since the coupon collector's problem makes the last few shorthashes of any subset the expensive ones.
+pub fn expected_attempts_for_subset(shorthash_bits: u32, subset_size: usize) -> f64 {
+    2f64.powi(shorthash_bits as i32) * harmonic_number(subset_size as f64)
+}
+
+// The average size of a generated commit's packfile entry, not counting the offset of its delta base.
+// Measured from capped runs with the default delta base commit.
+const DELTIFIED_COMMIT_ENTRY_SIZE: f64 = 24.9;
+
+// The average number of bytes that each parent adds to a merge commit's packfile entry, after zlib
+// compression. Measured the same way.
+const MERGE_COMMIT_SIZE_PER_PARENT: f64 = 24.4;
+
+// The same sizes with `--compression none`, where zlib stores the data as-is. A merge commit's entry
+// has the whole `parent <hex OID>\n` line for each parent.
+const UNCOMPRESSED_DELTIFIED_COMMIT_ENTRY_SIZE: f64 = 27.2;
+const UNCOMPRESSED_MERGE_COMMIT_SIZE_PER_PARENT: f64 = 48.0;
+
+// The packfile header, the trailing checksum, and the entries of the empty tree and the delta base
+// commit, roughly.
+const FIXED_PACK_SIZE: u64 = 12 + 20 + 200;
+
+/// Estimates the size of the packfile and index file for a run that generates `commit_count` commits with
+/// the default delta base commit and merge commits with `merge_commit_parent_count` parents, and that
+/// compresses the generated commits and the merge commits unless `leaves_compressed` and
+/// `merges_compressed` respectively are false. The index size is exact given the number of
+/// objects, except for the number of objects that need large offsets, which depends on the size of the
+/// packfile.
+pub fn estimate_output_sizes(
+    commit_count: u64,
+    merge_commit_parent_count: u64,
+    leaves_compressed: bool,
+    merges_compressed: bool,
+) -> (u64, u64) {
+    let merge_commit_count = if commit_count == 0 {
+        0
+    } else if commit_count <= merge_commit_parent_count {
+        // Just the top-level merge commit
+        1
+    } else {
+        // The first-level merge commits, plus the top-level merge commit
+        commit_count.div_ceil(merge_commit_parent_count) + 1
+    };
+    // The commits, the merge commits, the delta base commit and the empty tree
+    let object_count = commit_count + merge_commit_count + 2;
+
+    // Every generated commit is a delta against the delta base commit at the start of the packfile, so
+    // its entry includes its own offset, which takes more bytes as the packfile grows.
+    let deltified_commit_size = if leaves_compressed {
+        DELTIFIED_COMMIT_ENTRY_SIZE
+    } else {
+        UNCOMPRESSED_DELTIFIED_COMMIT_ENTRY_SIZE
+    };
+    let merge_commit_size_per_parent = if merges_compressed {
+        MERGE_COMMIT_SIZE_PER_PARENT
+    } else {
+        UNCOMPRESSED_MERGE_COMMIT_SIZE_PER_PARENT
+    };
+    let mut pack_size = FIXED_PACK_SIZE as f64;
+    let mut remaining_commits = commit_count as f64;
+    let mut offset_length = 1.0;
+    let mut offset_limit = 128.0;
+    while remaining_commits > 0.0 {
+        let commit_size = deltified_commit_size + offset_length + merge_commit_size_per_parent;
+        let commits = ((offset_limit - pack_size) / commit_size)
+            .ceil()
+            .clamp(0.0, remaining_commits);
+        pack_size += commits * commit_size;
+        remaining_commits -= commits;
+        offset_length += 1.0;
+        // Each extra byte of an offset holds 7 more bits, plus one due to the encoding (see
+        // `append_variable_length_size_with_continuation_increment`).
+        offset_limit = (offset_limit + 1.0) * 128.0;
+    }
+    let pack_size = pack_size as u64;
+
+    // Objects that start more than 2**31 bytes into the packfile need an extra 8-byte offset. The objects
+    // are all roughly the same size, so they're assumed to be spread evenly through the packfile.
+    let large_offset_count = if pack_size > 1 << 31 {
+        (object_count as f64 * ((pack_size - (1 << 31)) as f64 / pack_size as f64)) as u64
+    } else {
+        0
+    };
+    let index_size = 8 + 1024 + object_count * (20 + 4 + 4) + large_offset_count * 8 + 40;
+
+    (pack_size, index_size)
+}
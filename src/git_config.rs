@@ -0,0 +1,260 @@
+//! Reads the identity that the generated commits are authored by from git's config files, so that they're
+//! attributed to whoever runs the tool, like the commits they make with git. Only the `[user]` section is
+//! used, but the files are parsed with git's syntax, including quoted values, escape sequences, comments
+//! and line continuations. Includes (`[include]` and `[includeIf]`) aren't followed.
+//!
+//! As with git, the repository's config takes precedence over the global config
+//! (`$XDG_CONFIG_HOME/git/config`, then `~/.gitconfig`), one setting at a time.
+
+use crate::git_utils::resolve_common_dir;
+use std::{
+    env, fmt, fs, io,
+    iter::Peekable,
+    path::{Path, PathBuf},
+    str::Chars,
+};
+
+/// The identity of the commits when git's config doesn't have one
+pub const FALLBACK_IDENTITY: Identity<'static> = Identity {
+    name: "Teddy Katz",
+    email: "teddy.katz@gmail.com",
+};
+
+/// The name and email of an `author` or `committer` line
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Identity<'a> {
+    pub name: &'a str,
+    pub email: &'a str,
+}
+
+impl fmt::Display for Identity<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} <{}>", self.name, self.email)
+    }
+}
+
+/// The `user.name` and `user.email` settings from git's config files
+#[derive(Debug, Default)]
+pub struct UserConfig {
+    pub name: Option<String>,
+    pub email: Option<String>,
+}
+
+impl UserConfig {
+    /// Reads the settings of the current repository, if there is one, and the global config files that
+    /// exist.
+    pub fn read() -> io::Result<Self> {
+        let mut config = Self::default();
+        for path in global_config_paths()
+            .into_iter()
+            .chain(resolve_common_dir().ok().map(|dir| dir.join("config")))
+        {
+            match fs::read_to_string(&path) {
+                Ok(contents) => config.parse(&contents, &path)?,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(config)
+    }
+
+    /// Returns the configured identity, or `None` without a `user.email`. Without a `user.name`, the
+    /// part of the email before the `@` stands in for it.
+    pub fn identity(&self) -> Option<Identity<'_>> {
+        let email = self.email.as_deref()?;
+        let name = match self.name.as_deref() {
+            Some(name) if !name.is_empty() => name,
+            _ => email.split('@').next().unwrap_or(email),
+        };
+        Some(Identity { name, email })
+    }
+
+    /// Applies the `[user]` settings in `contents`, which was read from `path`, over the current ones.
+    fn parse(&mut self, contents: &str, path: &Path) -> io::Result<()> {
+        let mut parser = Parser {
+            chars: contents.chars().peekable(),
+            line: 1,
+        };
+        let bad_line = |line| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("bad config line {} in file {}", line, path.display()),
+            )
+        };
+        let mut in_user_section = false;
+        loop {
+            parser.skip_while(char::is_whitespace);
+            match parser.chars.peek() {
+                None => return Ok(()),
+                Some('#') | Some(';') => parser.skip_comment(),
+                Some('[') => {
+                    in_user_section = parser
+                        .section_header()
+                        .ok_or_else(|| bad_line(parser.line))?
+                        .eq_ignore_ascii_case("user");
+                }
+                Some(c) if c.is_ascii_alphabetic() => {
+                    let key = parser.key();
+                    parser.skip_while(|c| c == ' ' || c == '\t');
+                    // A key without a value is a boolean, which `name` and `email` aren't.
+                    if matches!(parser.chars.peek(), None | Some('\n' | '#' | ';')) {
+                        continue;
+                    }
+                    let value = parser.value().ok_or_else(|| bad_line(parser.line))?;
+                    if in_user_section {
+                        if key.eq_ignore_ascii_case("name") {
+                            self.name = Some(without_crud(&value));
+                        } else if key.eq_ignore_ascii_case("email") {
+                            self.email = Some(without_crud(&value));
+                        }
+                    }
+                }
+                Some(_) => return Err(bad_line(parser.line)),
+            }
+        }
+    }
+}
+
+/// The global config files in the order that git reads them, so that later ones take precedence
+fn global_config_paths() -> Vec<PathBuf> {
+    let home = env::var_os("HOME").map(PathBuf::from);
+    let xdg_config_home = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| home.as_ref().map(|home| home.join(".config")));
+    xdg_config_home
+        .map(|dir| dir.join("git").join("config"))
+        .into_iter()
+        .chain(home.map(|home| home.join(".gitconfig")))
+        .collect()
+}
+
+/// Cleans up a name or email the way git does for an `author` line: the characters that would make the
+/// line ambiguous are dropped, along with whitespace and some punctuation at either end.
+fn without_crud(value: &str) -> String {
+    let is_crud =
+        |c: char| c <= ' ' || matches!(c, ',' | ':' | ';' | '<' | '>' | '"' | '\\' | '\'');
+    value
+        .trim_matches(is_crud)
+        .chars()
+        .filter(|c| !matches!(c, '<' | '>' | '\n'))
+        .collect()
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    // The line that the next character is on, for errors
+    line: usize,
+}
+
+impl Parser<'_> {
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if c == Some('\n') {
+            self.line += 1;
+        }
+        c
+    }
+
+    fn skip_while(&mut self, predicate: impl Fn(char) -> bool) {
+        while self.chars.peek().is_some_and(|&c| predicate(c)) {
+            self.next();
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        self.skip_while(|c| c != '\n');
+    }
+
+    /// Parses a `[section]` or `[section "subsection"]` header, and returns the section's name, or an
+    /// empty name for a subsection, which is never `[user]`. Returns `None` if the header is malformed.
+    fn section_header(&mut self) -> Option<String> {
+        self.next();
+        let mut name = String::new();
+        while let Some(c) = self.next() {
+            match c {
+                ']' => return Some(name),
+                c if c.is_ascii_alphanumeric() || c == '-' || c == '.' => name.push(c),
+                ' ' | '\t' => {
+                    self.skip_while(|c| c == ' ' || c == '\t');
+                    self.quoted_subsection()?;
+                    return Some(String::new());
+                }
+                _ => return None,
+            }
+        }
+        None
+    }
+
+    /// Skips the quoted subsection of a section header, along with the closing `]`.
+    fn quoted_subsection(&mut self) -> Option<()> {
+        if self.next()? != '"' {
+            return None;
+        }
+        loop {
+            match self.next()? {
+                '"' => break,
+                '\\' => {
+                    self.next()?;
+                }
+                '\n' => return None,
+                _ => {}
+            }
+        }
+        (self.next()? == ']').then_some(())
+    }
+
+    fn key(&mut self) -> String {
+        let mut key = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if !(c.is_ascii_alphanumeric() || c == '-') {
+                break;
+            }
+            key.push(c);
+            self.next();
+        }
+        key
+    }
+
+    /// Parses the `= value` after a key through the end of its line, or returns `None` if it's malformed.
+    /// Whitespace around the value is removed unless it's quoted.
+    fn value(&mut self) -> Option<String> {
+        if self.next()? != '=' {
+            return None;
+        }
+        self.skip_while(|c| c == ' ' || c == '\t');
+        let mut value = String::new();
+        // The length of the value without its unquoted trailing whitespace
+        let mut trimmed_length = 0;
+        let mut in_quotes = false;
+        loop {
+            match self.next() {
+                None | Some('\n') if !in_quotes => break,
+                None | Some('\n') => return None,
+                Some('#') | Some(';') if !in_quotes => {
+                    self.skip_comment();
+                    break;
+                }
+                Some('"') => {
+                    in_quotes = !in_quotes;
+                    continue;
+                }
+                Some('\\') => match self.next()? {
+                    '\n' => continue,
+                    'n' => value.push('\n'),
+                    't' => value.push('\t'),
+                    'b' => value.push('\u{8}'),
+                    c @ ('"' | '\\') => value.push(c),
+                    _ => return None,
+                },
+                Some(c) if c.is_whitespace() && !in_quotes => {
+                    value.push(c);
+                    continue;
+                }
+                Some(c) => value.push(c),
+            }
+            trimmed_length = value.len();
+        }
+        value.truncate(trimmed_length);
+        Some(value)
+    }
+}
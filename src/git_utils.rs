@@ -1,25 +1,122 @@
+use crate::direct_io::{drop_from_page_cache, OutputFile};
 use crc::crc32;
-use flate2::{write::ZlibEncoder, Compression};
+use flate2::{write::ZlibEncoder, Compress, Compression, FlushCompress, Status};
 use sha1::{Digest, Sha1};
 use std::{
-    collections::BTreeMap,
-    io,
-    io::{copy, Seek, SeekFrom, Write},
+    cell::Cell,
+    collections::{BTreeMap, HashMap},
+    fmt, io,
+    io::{copy, Read, Seek, SeekFrom, Write},
+    mem,
     num::NonZeroU8,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
 };
 use std::{
-    fs::OpenOptions,
+    env, fs,
+    fs::{File, OpenOptions},
     io::BufWriter,
+    path::{Path, PathBuf},
 };
 
 pub type Sha1Oid = [u8; 20];
 
+/// A position in a packfile, counted in bytes from its start, such as where an object's entry begins.
+/// Packfiles can be larger than 4 GiB, so this is 64 bits wide on every platform.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PackOffset(pub u64);
+
+impl PackOffset {
+    /// Returns the offset `length` bytes further into the packfile, or `None` on overflow.
+    pub fn checked_add(self, length: usize) -> Option<Self> {
+        self.0.checked_add(length as u64).map(Self)
+    }
+
+    /// Returns how far back `earlier` is from this offset, which is what an "offset delta" records about
+    /// its delta base, or `None` if `earlier` isn't strictly before this offset. The distance is encoded
+    /// as an unsigned number, so an "offset delta" can only refer back.
+    pub fn distance_back_to(self, earlier: Self) -> Option<u64> {
+        if earlier < self {
+            Some(self.0 - earlier.0)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for PackOffset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// The length of an object's data, not counting its header, as recorded at the start of a delta for
+/// both the delta base and the resulting object. This is a separate type from `PackOffset` so that the
+/// two can't be mixed up, e.g. when a delta is encoded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ObjectSize(pub usize);
+
+impl fmt::Display for ObjectSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// The OID of the tree with no entries. Unless `--tree-from-dir` is used, every generated commit points
+/// to this tree.
+pub const EMPTY_TREE_OID: Sha1Oid = parse_hex_oid("4b825dc642cb6eb9a060e54bf8d69288fbee4904");
+
+/// Parses a 40-character hex OID. This is a `const fn` so that invalid OID constants fail to compile.
+pub const fn parse_hex_oid(hex: &str) -> Sha1Oid {
+    const fn nibble(character: u8) -> u8 {
+        match character {
+            b'0'..=b'9' => character - b'0',
+            b'a'..=b'f' => character - b'a' + 10,
+            _ => panic!("invalid hex character in OID"),
+        }
+    }
+
+    let hex = hex.as_bytes();
+    assert!(hex.len() == 40, "OIDs must be 40 hex characters long");
+    let mut oid = [0; 20];
+    let mut i = 0;
+    while i < 20 {
+        oid[i] = nibble(hex[2 * i]) << 4 | nibble(hex[2 * i + 1]);
+        i += 1;
+    }
+    oid
+}
+
+/// Formats an OID as 40 lowercase hex characters, as git displays it.
+pub fn oid_to_hex(oid: &Sha1Oid) -> String {
+    shorthash_hex(oid, 2 * oid.len())
+}
+
+/// Formats the first `nibbles` hex characters of an OID (up to 40), e.g. 7 for its shorthash. The
+/// characters are written directly, so this doesn't format the rest of the OID only to truncate it.
+pub fn shorthash_hex(oid: &Sha1Oid, nibbles: usize) -> String {
+    const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+    (0..nibbles)
+        .map(|index| {
+            let byte = oid[index / 2];
+            let nibble = if index % 2 == 0 {
+                byte >> 4
+            } else {
+                byte & 0xf
+            };
+            HEX_DIGITS[nibble as usize] as char
+        })
+        .collect()
+}
+
 #[derive(Clone, Debug)]
 pub enum GitObjectType {
     Commit,
     Tree,
     #[allow(dead_code)]
     Blob,
+    #[allow(dead_code)]
+    Tag,
 }
 
 #[derive(Clone, Debug)]
@@ -29,40 +126,276 @@ pub struct GitObject {
 }
 
 impl GitObjectType {
-    fn type_name(&self) -> &'static str {
+    pub fn type_name(&self) -> &'static str {
         match self {
-            GitObjectType::Commit { .. } => "commit",
-            GitObjectType::Tree { .. } => "tree",
-            GitObjectType::Blob { .. } => "blob",
+            GitObjectType::Commit => "commit",
+            GitObjectType::Tree => "tree",
+            GitObjectType::Blob => "blob",
+            GitObjectType::Tag => "tag",
         }
     }
 }
 
 impl GitObject {
+    pub fn commit(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            object_type: GitObjectType::Commit,
+        }
+    }
+
+    pub fn tree(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            object_type: GitObjectType::Tree,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn blob(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            object_type: GitObjectType::Blob,
+        }
+    }
+
+    /// Parses the uncompressed contents of a loose object (`<type> <length>\0<data>`), checking that the
+    /// declared length matches the data. This is the inverse of `to_loose`.
+    pub fn from_loose(loose: &[u8]) -> io::Result<Self> {
+        let invalid =
+            |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+        let header_length = loose
+            .iter()
+            .position(|&byte| byte == 0)
+            .ok_or_else(|| invalid("loose object has no header"))?;
+        let header = std::str::from_utf8(&loose[..header_length])
+            .map_err(|_| invalid("loose object header isn't valid UTF-8"))?;
+        let (type_name, length) = header
+            .split_once(' ')
+            .ok_or_else(|| invalid("loose object header has no length"))?;
+        let object_type = match type_name {
+            "commit" => GitObjectType::Commit,
+            "tree" => GitObjectType::Tree,
+            "blob" => GitObjectType::Blob,
+            "tag" => GitObjectType::Tag,
+            _ => return Err(invalid("unsupported loose object type")),
+        };
+        let data = &loose[header_length + 1..];
+        if !length.bytes().all(|byte| byte.is_ascii_digit()) || length.parse() != Ok(data.len()) {
+            return Err(invalid("loose object length doesn't match its data"));
+        }
+        Ok(Self {
+            data: data.to_vec(),
+            object_type,
+        })
+    }
+
+    /// Returns the uncompressed contents of the object's loose representation.
+    pub fn to_loose(&self) -> Vec<u8> {
+        let mut loose = self.header().into_bytes();
+        loose.extend_from_slice(&self.data);
+        loose
+    }
+
     pub fn oid(&self) -> Sha1Oid {
         Sha1::new()
-            .chain(format!("{} {}\0", self.object_type.type_name(), self.data.len()).as_bytes())
+            .chain(self.header().as_bytes())
             .chain(&self.data)
             .finalize()
             .into()
     }
+
+    /// The `<type> <length>\0` header that precedes the data when hashing the object or storing it as a
+    /// loose object.
+    fn header(&self) -> String {
+        format!("{} {}\0", self.object_type.type_name(), self.data.len())
+    }
 }
 
-#[derive(Debug)]
+/// Finds the object directory of the current repository the same way git does: `$GIT_DIR` if it's
+/// set, otherwise the nearest `.git` in the current directory or one of its ancestors. A `.git` file
+/// (as used by worktrees and submodules) is followed to the gitdir it names, and a gitdir with a
+/// `commondir` file (as used by worktrees) shares the object directory of the main repository.
+pub fn resolve_objects_dir() -> io::Result<PathBuf> {
+    Ok(resolve_common_dir()?.join("objects"))
+}
+
+/// Finds the directory of the current repository that's shared between its worktrees, which has the
+/// object directory and the repository's `config` file. See `resolve_objects_dir`.
+pub fn resolve_common_dir() -> io::Result<PathBuf> {
+    let git_dir = match env::var_os("GIT_DIR") {
+        Some(git_dir) => PathBuf::from(git_dir),
+        None => find_git_dir(&env::current_dir()?)?,
+    };
+    match env::var_os("GIT_COMMON_DIR") {
+        Some(common_dir) => Ok(PathBuf::from(common_dir)),
+        None => common_dir_for_git_dir(git_dir),
+    }
+}
+
+/// Finds the object directory of the repository at `repo_path`, which can be a work tree (or a
+/// directory inside one) or a bare repository. Unlike `resolve_objects_dir`, this ignores `$GIT_DIR`.
+pub fn repo_objects_dir(repo_path: &Path) -> io::Result<PathBuf> {
+    let git_dir = if repo_path.join("objects").is_dir() && repo_path.join("HEAD").is_file() {
+        repo_path.to_path_buf()
+    } else {
+        find_git_dir(repo_path)?
+    };
+    Ok(common_dir_for_git_dir(git_dir)?.join("objects"))
+}
+
+fn common_dir_for_git_dir(git_dir: PathBuf) -> io::Result<PathBuf> {
+    match fs::read_to_string(git_dir.join("commondir")) {
+        Ok(common_dir) => Ok(git_dir.join(common_dir.trim_end())),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(git_dir),
+        Err(err) => Err(err),
+    }
+}
+
+/// Registers `alternate_objects_dir` as an [alternate object
+/// directory](https://git-scm.com/docs/gitrepository-layout#Documentation/gitrepository-layout.txt-objectsinfoalternates)
+/// of the repository whose object directory is `objects_dir`, so that the repository can use its objects
+/// without copying them. Returns `false` if it was already registered.
+pub fn add_alternate(objects_dir: &Path, alternate_objects_dir: &Path) -> io::Result<bool> {
+    // Relative paths in the alternates file are relative to the object directory rather than the current
+    // directory, so always write an absolute path.
+    let alternate_objects_dir = alternate_objects_dir.canonicalize()?;
+    let alternate_line = alternate_objects_dir.to_str().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "alternate object directory paths must be valid UTF-8",
+        )
+    })?;
+    let info_dir = objects_dir.join("info");
+    let alternates_path = info_dir.join("alternates");
+    let mut contents = match fs::read_to_string(&alternates_path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err),
+    };
+    if contents.lines().any(|line| line == alternate_line) {
+        return Ok(false);
+    }
+    if !contents.is_empty() && !contents.ends_with('\n') {
+        contents.push('\n');
+    }
+    contents.push_str(alternate_line);
+    contents.push('\n');
+    fs::create_dir_all(&info_dir)?;
+    fs::write(alternates_path, contents)?;
+    Ok(true)
+}
+
+/// Lists a pack in the `objects/info/packs` file of the object directory `objects_dir`, which is how
+/// clients of the [dumb HTTP protocol](https://git-scm.com/docs/http-protocol#_discovering_references)
+/// find out which packs there are. Entries for packs that no longer exist are dropped, as `git
+/// update-server-info` would. Returns `false` if the pack was already listed.
+pub fn add_info_pack(objects_dir: &Path, pack_path: &Path) -> io::Result<bool> {
+    let pack_name = pack_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "pack file names must be valid UTF-8",
+            )
+        })?;
+    let info_dir = objects_dir.join("info");
+    let info_packs_path = info_dir.join("packs");
+    let contents = match fs::read_to_string(&info_packs_path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err),
+    };
+    let mut pack_names = contents
+        .lines()
+        .filter_map(|line| line.strip_prefix("P "))
+        .filter(|name| objects_dir.join("pack").join(name).is_file())
+        .collect::<Vec<_>>();
+    let was_listed = pack_names.contains(&pack_name);
+    if !was_listed {
+        pack_names.push(pack_name);
+    }
+    // Git ends the list with a blank line.
+    let mut new_contents = String::new();
+    for name in pack_names {
+        new_contents.push_str(&format!("P {}\n", name));
+    }
+    new_contents.push('\n');
+    fs::create_dir_all(&info_dir)?;
+    fs::write(info_packs_path, new_contents)?;
+    Ok(!was_listed)
+}
+
+fn find_git_dir(start: &Path) -> io::Result<PathBuf> {
+    for dir in start.ancestors() {
+        let dot_git = dir.join(".git");
+        if dot_git.is_dir() {
+            return Ok(dot_git);
+        }
+        if dot_git.is_file() {
+            let contents = fs::read_to_string(&dot_git)?;
+            return match contents.strip_prefix("gitdir: ") {
+                Some(git_dir) => Ok(dir.join(git_dir.trim_end())),
+                None => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid gitfile format: {}", dot_git.display()),
+                )),
+            };
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "not a git repository (or any of the parent directories)",
+    ))
+}
+
+/// An instruction of a delta. The packfile encoding limits the offsets of copies to 4 bytes, the sizes of
+/// copies to 1 to 2**24 - 1 bytes, and the new data added by one instruction to 1 to 127 bytes, so writing
+/// a delta that doesn't fit in those limits fails rather than truncating the values.
+#[derive(Clone, Debug)]
 pub enum PackFileDeltaInstruction {
     CopyFromBaseObject { offset: usize, size: usize },
     AddNewData(Vec<u8>),
 }
 
-#[derive(Debug)]
+/// Reconstructs an object's data by applying delta instructions to the data of its delta base.
+pub fn apply_delta(base: &[u8], delta: &[PackFileDeltaInstruction]) -> io::Result<Vec<u8>> {
+    let mut result = Vec::new();
+    for delta_instruction in delta {
+        match delta_instruction {
+            PackFileDeltaInstruction::CopyFromBaseObject { offset, size } => {
+                let copied = offset
+                    .checked_add(*size)
+                    .and_then(|end| base.get(*offset..end))
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!(
+                                "delta copies {} bytes at offset {} from a {}-byte base object",
+                                size,
+                                offset,
+                                base.len()
+                            ),
+                        )
+                    })?;
+                result.extend_from_slice(copied);
+            }
+            PackFileDeltaInstruction::AddNewData(new_data) => result.extend_from_slice(new_data),
+        }
+    }
+    Ok(result)
+}
+
+#[derive(Clone, Debug)]
 pub enum PackFileObject {
     Raw(GitObject),
     Deltified {
         base_oid: Sha1Oid,
-        base_size: usize,
+        base_size: ObjectSize,
         delta: Vec<PackFileDeltaInstruction>,
         new_oid: Sha1Oid,
-        new_size: usize,
+        new_size: ObjectSize,
     },
 }
 
@@ -77,7 +410,210 @@ impl PackFileObject {
 
 #[derive(Debug)]
 pub struct PackFile {
-    object_positions: BTreeMap<Sha1Oid, (usize, u32)>,
+    object_positions: BTreeMap<Sha1Oid, (PackOffset, u32)>,
+    fanout: [u32; 256],
+    checksum: Sha1Oid,
+    was_cancelled: bool,
+}
+
+/// The location and checksum of one object in a packfile, as recorded in its index file.
+#[derive(Clone, Copy, Debug)]
+pub struct PackObjectMetadata {
+    pub oid: Sha1Oid,
+    pub offset: PackOffset,
+    pub crc32: u32,
+}
+
+impl PackFile {
+    /// Returns the metadata of every object in the packfile, in OID order. This is empty unless the
+    /// packfile was written with `PackWriteOptions::keep_metadata`.
+    pub fn metadata(&self) -> impl Iterator<Item = PackObjectMetadata> + '_ {
+        self.object_positions
+            .iter()
+            .map(|(&oid, &(offset, crc32))| PackObjectMetadata { oid, offset, crc32 })
+    }
+
+    /// Returns the fanout table of the index file: the number of objects whose first OID byte is at most
+    /// `i`, for each `i` from 0 to 255.
+    pub fn fanout(&self) -> &[u32; 256] {
+        &self.fanout
+    }
+
+    /// Returns the SHA1 checksum at the end of the packfile. Git names packfiles after this checksum.
+    pub fn checksum(&self) -> &Sha1Oid {
+        &self.checksum
+    }
+
+    /// Returns whether writing was cancelled with a `CancelToken`, in which case the packfile only
+    /// contains the objects that were written before the cancellation.
+    pub fn was_cancelled(&self) -> bool {
+        self.was_cancelled
+    }
+}
+
+/// A flag that can be set from another thread to stop `stream_to_pack_file` early.
+#[derive(Debug, Default)]
+pub struct CancelToken(AtomicBool);
+
+impl CancelToken {
+    pub const fn new() -> Self {
+        Self(AtomicBool::new(false))
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// How far a packfile has been written: its length so far, and the number of objects in it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PackPosition {
+    pub length: PackOffset,
+    pub object_count: u32,
+}
+
+/// Lets the iterator that produces a packfile's objects have the packfile synced to disk, for a journal
+/// of a long write that can be resumed after a crash. `write_to_sink` only takes the next object from the
+/// iterator once the previous one has been written, so while the iterator is producing an object,
+/// `written` is the position right after everything it produced before. A sync that it requests then
+/// happens before the next object is written, and `synced` says how far it got.
+#[derive(Debug, Default)]
+pub struct PackSync {
+    written: Cell<PackPosition>,
+    synced: Cell<PackPosition>,
+    is_requested: Cell<bool>,
+}
+
+impl PackSync {
+    pub fn written(&self) -> PackPosition {
+        self.written.get()
+    }
+
+    pub fn synced(&self) -> PackPosition {
+        self.synced.get()
+    }
+
+    /// Asks for the packfile to be synced up to `written` before the next object is written.
+    pub fn request(&self) {
+        self.is_requested.set(true);
+    }
+}
+
+/// The type of `PackWriteOptions::on_object_written`.
+pub type ObjectWrittenCallback<'a> = dyn FnMut(&Sha1Oid, PackOffset, usize) + 'a;
+
+/// Optional settings for `stream_to_pack_file`.
+#[derive(Default)]
+pub struct PackWriteOptions<'a> {
+    /// Checked before each object is written. Once the token is cancelled, no more objects are taken
+    /// from the iterator, and the packfile and index file are finished with the objects written so far.
+    pub cancel_token: Option<&'a CancelToken>,
+
+    /// Called with the total number of bytes written to the packfile so far, after each object and once
+    /// more after the trailing checksum. This tracks the I/O side of the work separately from how quickly
+    /// the iterator produces objects.
+    pub on_bytes_written: Option<&'a mut dyn FnMut(u64)>,
+
+    /// Called with the OID, offset and entry length of each object once its entry has been written, in the
+    /// order the objects are written. These are the offsets that the index lists, so e.g. a lookup table
+    /// from shorthashes to offsets can be built during the write instead of by reading the index back.
+    /// The length is that of the whole entry as it was encoded (the header, the delta base's offset or
+    /// OID, and the compressed data), so the sizes of the objects can be tallied without a second pass.
+    pub on_object_written: Option<&'a mut ObjectWrittenCallback<'a>>,
+
+    /// Accumulates the time spent in each phase of writing the pack, if given. This costs a few clock
+    /// reads per object, so it's only done when asked for.
+    pub profile: Option<&'a mut WriteProfile>,
+
+    /// Objects at or past this offset are listed in the index's table of 8-byte offsets, rather than
+    /// directly in its table of 4-byte offsets. This defaults to (and can't be more than)
+    /// `BIG_OFFSET_THRESHOLD`, the first offset that doesn't fit in 4 bytes; a lower threshold exercises
+    /// the 8-byte table without writing a 2 GiB packfile. (Git only accepts indexes where at least the
+    /// first object, at offset 12, has a 4-byte offset.)
+    pub big_offset_threshold: Option<usize>,
+
+    /// The number of objects that the iterator is going to produce, if that's known in advance. The
+    /// header then has the right count from the start, so the trailing checksum is computed as the
+    /// objects are written, and the index is started right after the last object instead of after
+    /// reading the whole packfile back. If the count turns out to be wrong (e.g. because the write was
+    /// cancelled), the header is fixed and the packfile is read back as usual.
+    pub object_count: Option<u32>,
+
+    /// Writes the packfile and index file with `O_DIRECT` (see `direct_io`), so that they don't fill up
+    /// the page cache. This is only supported on Linux, and only by some filesystems.
+    pub direct_io: bool,
+
+    /// The zlib compression level of the objects' data, which defaults to `Compression::best()`. Git
+    /// requires the data to be in zlib format, but `Compression::none()` stores it in uncompressed
+    /// blocks, which is the fastest to write but makes the packfile about 50% larger.
+    pub compression: Option<Compression>,
+
+    /// The zlib compression level of deltified objects, which defaults to `compression`. A generated
+    /// commit's delta is mostly a few bytes of entropy that zlib can't shrink, whereas a merge commit is
+    /// hundreds of KB of hex that it can, so the two are worth compressing differently.
+    pub delta_compression: Option<Compression>,
+
+    /// Syncs the packfile to disk between objects when asked to (see `PackSync`). Only packfiles written
+    /// to a file, without `direct_io`, can be synced partway.
+    pub sync: Option<&'a PackSync>,
+
+    /// Keeps the offset and CRC32 of every object in the returned `PackFile`, for `PackFile::metadata`.
+    /// These take several GiB for a full pack and hours to free, so by default they're leaked as soon as
+    /// the index is written, and `metadata` returns nothing. A caller that keeps them should leak the
+    /// `PackFile` rather than dropping it.
+    pub keep_metadata: bool,
+}
+
+/// The first object offset that doesn't fit in a 4-byte entry of an index file, whose top bit instead
+/// marks an index into the table of 8-byte offsets
+pub const BIG_OFFSET_THRESHOLD: usize = 0x80_00_00_00;
+
+/// Wall-clock time spent in each phase of `write_pack_and_index`.
+#[derive(Debug, Default)]
+pub struct WriteProfile {
+    /// Waiting for the iterator to produce the next object. For generated commits, this is almost all
+    /// SHA1 hashing.
+    pub generating: Duration,
+    /// Encoding and zlib-compressing objects, and computing their CRC32s
+    pub compressing: Duration,
+    /// Recording the offset of each object in the B-tree that the index is built from
+    pub recording_offsets: Duration,
+    /// Writing the encoded objects to the packfile
+    pub writing_pack: Duration,
+    /// Filling in the header and hashing the packfile for its trailing checksum (unless the object count
+    /// was known in advance, in which case it was hashed as it was written), and syncing it
+    pub finishing_pack: Duration,
+    /// Writing the index file
+    pub writing_index: Duration,
+}
+
+/// Charges the time between successive calls of `lap` to phases of a `WriteProfile`. Without a profile,
+/// it never reads the clock.
+struct PhaseTimer<'a> {
+    profile: Option<&'a mut WriteProfile>,
+    last_lap: Instant,
+}
+
+impl<'a> PhaseTimer<'a> {
+    fn new(profile: Option<&'a mut WriteProfile>) -> Self {
+        Self {
+            profile,
+            last_lap: Instant::now(),
+        }
+    }
+
+    #[inline(always)]
+    fn lap(&mut self, phase: fn(&mut WriteProfile) -> &mut Duration) {
+        if let Some(profile) = &mut self.profile {
+            let now = Instant::now();
+            *phase(profile) += now - self.last_lap;
+            self.last_lap = now;
+        }
+    }
 }
 
 /// Generates a git packfile and index file containing the given git objects.
@@ -85,187 +621,779 @@ pub struct PackFile {
 /// noted below, the format documentation is underspecified; this generator is implemented based on a combination
 /// of the documented behavior, testing with git itself, and reading the git source code to figure out what it
 /// actually accepts.
+/// The packfile is written to `pack_path`, and the index file is written next to it with an `.idx` extension.
 pub fn stream_to_pack_file<T: IntoIterator<Item = PackFileObject>>(
+    pack_path: &Path,
     iter: T,
+    options: PackWriteOptions,
 ) -> io::Result<PackFile> {
-    let mut pack = BufWriter::new(
-        OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(".git/objects/pack/pack-every-shorthash.pack")?,
-    );
+    write_pack_and_index(
+        create_output_file(pack_path)?,
+        create_output_file(&pack_path.with_extension("idx"))?,
+        iter,
+        options,
+    )
+}
+
+/// Creates (or truncates) a file for `write_pack_and_index` to write to.
+pub fn create_output_file(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+}
 
-    // --- Start of packfile header ---
+/// Allocates disk space for the first `length` bytes of a file up front, so that a file which is later
+/// written incrementally ends up less fragmented. On Linux this uses `fallocate`, and silently does nothing
+/// on filesystems that don't support it.
+#[cfg(target_os = "linux")]
+pub fn preallocate(file: &File, length: u64) -> io::Result<()> {
+    use std::{convert::TryFrom, os::unix::io::AsRawFd};
+    let length = libc::off_t::try_from(length)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "preallocation is too large"))?;
+    // Unlike `posix_fallocate`, this fails instead of falling back to writing zeros (which would take as
+    // long as writing the file itself) when the filesystem doesn't support preallocation.
+    if unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, length) } == 0 {
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::EOPNOTSUPP) => Ok(()),
+        _ => Err(err),
+    }
+}
+
+/// Elsewhere, the file is only extended with `set_len`. This usually creates a sparse file rather than
+/// allocating anything, but keeps the option usable on every platform.
+#[cfg(not(target_os = "linux"))]
+pub fn preallocate(file: &File, length: u64) -> io::Result<()> {
+    file.set_len(length)
+}
+
+/// Limits on the size of the files that can be written to a directory, as far as they could be detected.
+#[derive(Debug, Default)]
+pub struct FilesystemLimits {
+    /// The size of the largest file that can be written, and a description of what imposes the limit.
+    pub max_file_size: Option<(u64, &'static str)>,
+    /// The number of bytes available to this user, shared between all of the files written.
+    pub available_space: Option<u64>,
+}
+
+/// Detects the limits on writing files to `dir`. This is best-effort: it covers the free space, the
+/// process's file size resource limit, and filesystems with a well-known file size limit (FAT32), but not
+/// e.g. disk quotas.
+#[cfg(unix)]
+// The field types of `statvfs` and `rlimit` vary between platforms, so some of the casts to `u64` are
+// only unnecessary on some of them.
+#[allow(clippy::unnecessary_cast)]
+pub fn filesystem_limits(dir: &Path) -> io::Result<FilesystemLimits> {
+    use std::{ffi::CString, mem::MaybeUninit, os::unix::ffi::OsStrExt};
+    let path = CString::new(dir.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a NUL byte"))?;
+    let mut limits = FilesystemLimits::default();
+
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let stat = unsafe { stat.assume_init() };
+    limits.available_space = Some((stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64));
+
+    let mut rlimit = MaybeUninit::<libc::rlimit>::uninit();
+    if unsafe { libc::getrlimit(libc::RLIMIT_FSIZE, rlimit.as_mut_ptr()) } == 0 {
+        let rlimit = unsafe { rlimit.assume_init() };
+        if rlimit.rlim_cur != libc::RLIM_INFINITY {
+            limits.max_file_size = Some((
+                rlimit.rlim_cur as u64,
+                "the file size resource limit (ulimit -f)",
+            ));
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        // From `linux/magic.h`. FAT32 stores file sizes in 32 bits.
+        const MSDOS_SUPER_MAGIC: i64 = 0x4d44;
+        const FAT32_MAX_FILE_SIZE: u64 = (1 << 32) - 1;
+        let mut stat = MaybeUninit::<libc::statfs>::uninit();
+        if unsafe { libc::statfs(path.as_ptr(), stat.as_mut_ptr()) } == 0 {
+            let stat = unsafe { stat.assume_init() };
+            let is_smaller_limit = match limits.max_file_size {
+                Some((max_file_size, _)) => FAT32_MAX_FILE_SIZE < max_file_size,
+                None => true,
+            };
+            if stat.f_type as i64 == MSDOS_SUPER_MAGIC && is_smaller_limit {
+                limits.max_file_size = Some((
+                    FAT32_MAX_FILE_SIZE,
+                    "the file size limit of FAT32 filesystems",
+                ));
+            }
+        }
+    }
+
+    Ok(limits)
+}
+
+/// Elsewhere, no limits are detected.
+#[cfg(not(unix))]
+pub fn filesystem_limits(_dir: &Path) -> io::Result<FilesystemLimits> {
+    Ok(FilesystemLimits::default())
+}
+
+/// A destination for a sequence of objects, such as a packfile or a directory of loose objects. The
+/// objects are handed over one at a time by `write_to_sink`, so the code producing them doesn't depend on
+/// the output format, and supporting a new format only takes a new implementation of this trait.
+pub trait ObjectSink {
+    /// What `finish` returns once every object has been written
+    type Summary;
+
+    /// Checked after each object is produced and before it's written. Once this returns `false`, no more
+    /// objects are taken from the iterator, and the sink is finished with the objects written so far.
+    fn accepts_more(&mut self) -> bool {
+        true
+    }
+
+    /// Writes one object. A deltified object always comes after its delta base.
+    fn write_object(&mut self, object: &PackFileObject) -> io::Result<()>;
+
+    /// Finishes the output after the last object has been written.
+    fn finish(self) -> io::Result<Self::Summary>;
+}
+
+/// Writes each object from `iter` to `sink`, and then finishes the sink. The next object is only taken
+/// from the iterator once the previous one has been written, so the iterator can rely on everything it
+/// produced before having been written (and an error stops it from producing anything more).
+pub fn write_to_sink<S: ObjectSink, T: IntoIterator<Item = PackFileObject>>(
+    mut sink: S,
+    iter: T,
+) -> io::Result<S::Summary> {
+    for object in iter {
+        if !sink.accepts_more() {
+            break;
+        }
+        sink.write_object(&object)?;
+    }
+    sink.finish()
+}
+
+/// Like `stream_to_pack_file`, but writes to a packfile and index file that the caller has already opened
+/// for both reading and writing. Each file is written from the start and then truncated to the length of
+/// what was written, so the files can be preallocated (e.g. with `fallocate`) before being passed in.
+///
+/// If the disk fills up, the error says how far the write got.
+pub fn write_pack_and_index<T: IntoIterator<Item = PackFileObject>>(
+    pack_file: File,
+    index_file: File,
+    iter: T,
+    options: PackWriteOptions,
+) -> io::Result<PackFile> {
+    write_to_sink(PackFileSink::new(pack_file, index_file, options)?, iter)
+}
+
+/// Like `write_pack_and_index`, but continues a packfile that an earlier write didn't finish, from
+/// `position`. `objects` has the metadata of every object before that position, which is what the
+/// index needs to list them. Whatever the packfile has past that position is overwritten.
+pub fn resume_pack_and_index<T: IntoIterator<Item = PackFileObject>>(
+    pack_file: File,
+    index_file: File,
+    position: PackPosition,
+    objects: Vec<PackObjectMetadata>,
+    iter: T,
+    options: PackWriteOptions,
+) -> io::Result<PackFile> {
+    write_to_sink(
+        PackFileSink::resume(pack_file, index_file, position, objects, options)?,
+        iter,
+    )
+}
+
+/// Writes a packfile of exactly `object_count` objects to a stream that can't seek, such as a pipe or a
+/// FIFO that `git index-pack --stdin` is reading from. Since the header comes first, the object count has
+/// to be known up front, and the trailing checksum is computed as the packfile is written rather than by
+/// reading it back. Writes block while the reader is busy, so a slow reader just slows down the
+/// generation. No index file is written, since the reader can build one. Returns the trailing checksum.
+///
+/// If the iterator yields a different number of objects than `object_count` (or the write is cancelled),
+/// the packfile is left without its trailing checksum so that the reader rejects it, and an error is
+/// returned.
+pub fn stream_pack<W: Write, T: IntoIterator<Item = PackFileObject>>(
+    output: W,
+    object_count: u32,
+    iter: T,
+    options: PackWriteOptions,
+) -> io::Result<Sha1Oid> {
+    write_to_sink(PackStreamSink::new(output, object_count, options)?, iter)
+}
+
+/// Passes writes through to `inner`, hashing everything that's written if there's a hasher.
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Option<Sha1>,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&buf[..written]);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Returns whether an error means that there's no space left for writing files, either because the
+/// disk is full or because of a quota.
+pub fn is_storage_full(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded
+    )
+}
+
+/// How far a packfile sink has gotten, for reporting errors.
+#[derive(Debug, Default)]
+struct WriteProgress {
+    object_count: usize,
+    bytes_written: u64,
+    is_writing_index: bool,
+}
+
+impl WriteProgress {
+    /// Says how far the write got if the disk filled up.
+    fn describe_storage_full(&self, err: io::Error) -> io::Error {
+        if !is_storage_full(&err) {
+            return err;
+        }
+        io::Error::new(
+            err.kind(),
+            if self.is_writing_index {
+                format!(
+                    "ran out of disk space while writing the index, after writing a complete \
+                    packfile of {} objects ({} bytes): {}",
+                    self.object_count, self.bytes_written, err
+                )
+            } else {
+                format!(
+                    "ran out of disk space after writing {} objects ({} bytes) to the packfile: {}",
+                    self.object_count, self.bytes_written, err
+                )
+            },
+        )
+    }
+
+    /// Says how far the write got if the reader of a streamed packfile went away.
+    fn describe_broken_pipe(&self, err: io::Error) -> io::Error {
+        if err.kind() != io::ErrorKind::BrokenPipe {
+            return err;
+        }
+        io::Error::new(
+            err.kind(),
+            format!(
+                "the reader of the packfile went away after {} objects ({} bytes) were written: {}",
+                self.object_count, self.bytes_written, err
+            ),
+        )
+    }
+}
+
+/// Writes the 12-byte packfile header. If the object count isn't known yet, it's written as 0 and filled
+/// in afterwards.
+fn write_pack_header<W: Write>(pack: &mut W, object_count: u32) -> io::Result<()> {
     // 4-byte signature
-    pack.write_all("PACK".as_bytes())?;
+    pack.write_all(b"PACK")?;
 
     // 4-byte version number
     pack.write_all(&2u32.to_be_bytes())?;
 
-    // 4-byte number of objects (currently initialized to 0; will be filled in afterwards)
-    pack.write_all(&[0, 0, 0, 0])?;
+    // 4-byte number of objects
+    pack.write_all(&object_count.to_be_bytes())
+}
 
-    // --- End of packfile header ---
+/// Returns whether `options` has a cancel token that has been cancelled.
+fn is_cancelled(options: &PackWriteOptions) -> bool {
+    options
+        .cancel_token
+        .is_some_and(|cancel_token| cancel_token.is_cancelled())
+}
 
-    let mut current_position = 12;
-    let mut object_positions = BTreeMap::new();
-    let mut object_counts_by_first_byte = [0u32; 256];
-    let mut current_object = Vec::new();
+/// Writes objects to a packfile and, once finished, its index file. This is what `write_pack_and_index`
+/// uses; see there for how the files are written.
+pub struct PackFileSink<'a> {
+    pack: BufWriter<HashingWriter<OutputFile>>,
+    index_file: File,
+    options: PackWriteOptions<'a>,
+    timer: PhaseTimer<'a>,
+    progress: WriteProgress,
+    entries: PackEntryWriter,
+    was_cancelled: bool,
+}
 
-    for object in iter {
-        let oid = object.oid();
-        let current_object_position = current_position;
-
-        // Object type, using the ID values [here](https://git-scm.com/docs/pack-format#:~:text=Object%20types)
-        let object_type: u8 = match &object {
-            PackFileObject::Raw(GitObject { object_type, .. }) => match object_type {
-                GitObjectType::Commit => 1,
-                GitObjectType::Tree => 2,
-                GitObjectType::Blob => 3,
+impl<'a> PackFileSink<'a> {
+    /// Starts a packfile in `pack_file` by writing its header.
+    pub fn new(
+        mut pack_file: File,
+        index_file: File,
+        mut options: PackWriteOptions<'a>,
+    ) -> io::Result<Self> {
+        pack_file.seek(SeekFrom::Start(0))?;
+        let mut pack = BufWriter::new(HashingWriter {
+            inner: OutputFile::new(pack_file, options.direct_io)?,
+            hasher: options.object_count.map(|_| Sha1::new()),
+        });
+        write_pack_header(&mut pack, options.object_count.unwrap_or(0))?;
+        let compression = options.compression.unwrap_or_else(Compression::best);
+        let delta_compression = options.delta_compression.unwrap_or(compression);
+        Ok(Self {
+            pack,
+            index_file,
+            timer: PhaseTimer::new(options.profile.take()),
+            options,
+            progress: WriteProgress::default(),
+            entries: PackEntryWriter::new(compression, delta_compression),
+            was_cancelled: false,
+        })
+    }
+
+    /// Continues the packfile in `pack_file` from `position`, after the objects in `objects`. The header's
+    /// object count and the trailing checksum are filled in once the packfile is finished, as for a
+    /// packfile whose object count wasn't known in advance, so `options.object_count` is ignored.
+    pub fn resume(
+        mut pack_file: File,
+        index_file: File,
+        position: PackPosition,
+        objects: Vec<PackObjectMetadata>,
+        mut options: PackWriteOptions<'a>,
+    ) -> io::Result<Self> {
+        if options.direct_io {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "a packfile can't be resumed with direct I/O",
+            ));
+        }
+        options.object_count = None;
+        pack_file.set_len(position.length.0)?;
+        pack_file.seek(SeekFrom::Start(position.length.0))?;
+        let pack = BufWriter::new(HashingWriter {
+            inner: OutputFile::new(pack_file, false)?,
+            hasher: None,
+        });
+        let compression = options.compression.unwrap_or_else(Compression::best);
+        let delta_compression = options.delta_compression.unwrap_or(compression);
+        let mut entries = PackEntryWriter::new(compression, delta_compression);
+        for object in &objects {
+            entries
+                .object_positions
+                .insert(object.oid, (object.offset, object.crc32));
+            entries.object_counts_by_first_byte[object.oid[0] as usize] += 1;
+        }
+        entries.end_position = position.length;
+        if let Some(sync) = options.sync {
+            sync.written.set(position);
+            sync.synced.set(position);
+        }
+        Ok(Self {
+            pack,
+            index_file,
+            timer: PhaseTimer::new(options.profile.take()),
+            options,
+            progress: WriteProgress {
+                object_count: position.object_count as usize,
+                bytes_written: position.length.0,
+                is_writing_index: false,
             },
-            PackFileObject::Deltified { base_oid, .. } => {
-                if object_positions.contains_key(base_oid) {
-                    6
-                } else {
-                    7
-                }
+            entries,
+            was_cancelled: false,
+        })
+    }
+
+    /// Flushes and syncs the packfile if the producer of the objects asked for it.
+    fn sync_if_requested(&mut self) -> io::Result<()> {
+        if let Some(sync) = self.options.sync {
+            if sync.is_requested.take() {
+                self.pack.flush()?;
+                self.pack.get_ref().inner.sync_data()?;
+                sync.synced.set(sync.written.get());
+            }
+        }
+        Ok(())
+    }
+
+    fn finish_with_progress(self, progress: &mut WriteProgress) -> io::Result<PackFile> {
+        let Self {
+            pack,
+            index_file,
+            mut options,
+            mut timer,
+            entries:
+                PackEntryWriter {
+                    object_positions,
+                    object_counts_by_first_byte,
+                    end_position: current_position,
+                    ..
+                },
+            was_cancelled,
+            ..
+        } = self;
+        let HashingWriter {
+            inner: pack_output,
+            hasher,
+        } = pack.into_inner()?;
+        let mut pack_file = pack_output.into_file()?;
+        timer.lap(|profile| &mut profile.writing_pack);
+
+        let object_count = object_positions.len() as u32;
+        let pack_checksum = match hasher {
+            // The header was right all along, so everything written so far has already been hashed.
+            Some(hasher) if options.object_count == Some(object_count) => hasher.finalize(),
+            _ => {
+                // Now that all the objects have been added to the packfile, insert the correct object
+                // count into the header
+                pack_file.seek(SeekFrom::Start(8))?;
+                pack_file.write_all(&object_count.to_be_bytes())?;
+
+                // Only the written data is hashed, since a preallocated file might extend past it.
+                pack_file.seek(SeekFrom::Start(0))?;
+                let mut pack_hasher = Sha1::new();
+                copy(
+                    &mut (&mut pack_file).take(current_position.0),
+                    &mut pack_hasher,
+                )?;
+                pack_hasher.finalize()
             }
         };
+        // Add the sha1 pack checksum to the end of the packfile
+        pack_file.write_all(&pack_checksum)?;
+        pack_file.set_len(current_position.0 + pack_checksum.len() as u64)?;
+        if let Some(on_bytes_written) = &mut options.on_bytes_written {
+            on_bytes_written(current_position.0 + pack_checksum.len() as u64);
+        }
 
-        let encoded_object = match &object {
-            // Non-deltified objects have no packfile-specific encoding.
-            PackFileObject::Raw(git_object) => git_object.data.clone(),
-            PackFileObject::Deltified {
-                base_size,
-                delta,
-                new_size,
-                ..
-            } => {
-                // Deltified objects use the packfile-specific encoding described
-                // [here](https://git-scm.com/docs/pack-format#_deltified_representation).
-                let mut deltified_representation = Vec::new();
-                append_variable_length_size(&mut deltified_representation, *base_size)?;
-                append_variable_length_size(&mut deltified_representation, *new_size)?;
-                for delta_instruction in delta {
-                    match delta_instruction {
-                        PackFileDeltaInstruction::CopyFromBaseObject { offset, size } => {
-                            // The "copy from base object" instruction encoding, documented
-                            // [here](https://git-scm.com/docs/pack-format#_instruction_to_copy_from_base_object)
-                            let offset1 = NonZeroU8::new(*offset as u8);
-                            let offset2 = NonZeroU8::new((*offset >> 8) as u8);
-                            let offset3 = NonZeroU8::new((*offset >> 16) as u8);
-                            let offset4 = NonZeroU8::new((*offset >> 24) as u8);
-                            let size1 = NonZeroU8::new(*size as u8);
-                            let size2 = NonZeroU8::new((*size >> 8) as u8);
-                            let size3 = NonZeroU8::new((*size >> 16) as u8);
-                            deltified_representation.push(
-                                0b1000_0000
-                                    | if size3.is_some() { 0b0100_0000 } else { 0 }
-                                    | if size2.is_some() { 0b0010_0000 } else { 0 }
-                                    | if size1.is_some() { 0b0001_0000 } else { 0 }
-                                    | if offset4.is_some() { 0b0000_1000 } else { 0 }
-                                    | if offset3.is_some() { 0b0000_0100 } else { 0 }
-                                    | if offset2.is_some() { 0b0000_0010 } else { 0 }
-                                    | if offset1.is_some() { 0b0000_0001 } else { 0 },
-                            );
-                            deltified_representation.extend(
-                                vec![offset1, offset2, offset3, offset4, size1, size2, size3]
-                                    .into_iter()
-                                    .filter_map(|v| v)
-                                    .map(NonZeroU8::get),
-                            );
-                        }
-                        PackFileDeltaInstruction::AddNewData(new_data) => {
-                            // The "add new data" instruction encoding, documented
-                            // [here](https://git-scm.com/docs/pack-format#_instruction_to_add_new_data).
-                            // FIXME: is the length limit for this instruction actually 127?
-                            // It seems like it would be impossible to encode a length more than 127 with
-                            // the documented format, but that seems surprising. Maybe it's supposed to use the
-                            // variable-length encoding described in other places?
-                            // In any case, this tool only uses the instruction with sizes less than 127 anyway.
-                            debug_assert!((1..=127).contains(&new_data.len()));
-                            deltified_representation.push(new_data.len() as u8);
-                            deltified_representation.extend(new_data);
-                        }
+        pack_file.sync_all()?;
+        if options.direct_io {
+            drop_from_page_cache(&pack_file)?;
+        }
+        drop(pack_file);
+        timer.lap(|profile| &mut profile.finishing_pack);
+
+        // At this point, the packfile is complete and we're finished processing commits, but we still need
+        // to generate an index file.
+        progress.is_writing_index = true;
+        let fanout = write_index(
+            index_file,
+            &object_positions,
+            &object_counts_by_first_byte,
+            &pack_checksum,
+            options
+                .big_offset_threshold
+                .map_or(BIG_OFFSET_THRESHOLD, |threshold| {
+                    threshold.min(BIG_OFFSET_THRESHOLD)
+                }),
+            options.direct_io,
+        )?;
+        timer.lap(|profile| &mut profile.writing_index);
+
+        // Deallocating the B-tree of object positions is very, very slow. It's a really big B-tree that has
+        // lots of individual allocations. Deallocating the B-tree is also completely unnecessary if the
+        // process is about to exit, serving only to add hours to the runtime for no reason. So it's leaked
+        // here unless the caller asked to keep it, in which case the caller can explicitly leak the
+        // returned struct rather than dropping it if needed.
+        let object_positions = if options.keep_metadata {
+            object_positions
+        } else {
+            mem::forget(object_positions);
+            BTreeMap::new()
+        };
+        Ok(PackFile {
+            object_positions,
+            fanout,
+            checksum: pack_checksum.into(),
+            was_cancelled,
+        })
+    }
+}
+
+impl ObjectSink for PackFileSink<'_> {
+    type Summary = PackFile;
+
+    fn accepts_more(&mut self) -> bool {
+        self.was_cancelled = is_cancelled(&self.options);
+        !self.was_cancelled
+    }
+
+    fn write_object(&mut self, object: &PackFileObject) -> io::Result<()> {
+        self.sync_if_requested()
+            .and_then(|()| {
+                self.entries.write(
+                    &mut self.pack,
+                    object,
+                    &mut self.options,
+                    &mut self.timer,
+                    &mut self.progress,
+                )
+            })
+            .map_err(|err| self.progress.describe_storage_full(err))?;
+        if let Some(sync) = self.options.sync {
+            sync.written.set(PackPosition {
+                length: self.entries.end_position,
+                object_count: self.progress.object_count as u32,
+            });
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<PackFile> {
+        let mut progress = std::mem::take(&mut self.progress);
+        self.finish_with_progress(&mut progress)
+            .map_err(|err| progress.describe_storage_full(err))
+    }
+}
+
+/// Writes objects to a packfile in a stream that can't seek. This is what `stream_pack` uses; see there
+/// for how the packfile is written. Finishing returns the trailing checksum.
+pub struct PackStreamSink<'a, W: Write> {
+    pack: BufWriter<HashingWriter<W>>,
+    object_count: u32,
+    options: PackWriteOptions<'a>,
+    timer: PhaseTimer<'a>,
+    progress: WriteProgress,
+    entries: PackEntryWriter,
+    was_cancelled: bool,
+}
+
+impl<'a, W: Write> PackStreamSink<'a, W> {
+    /// Starts a packfile of `object_count` objects in `output` by writing its header.
+    pub fn new(
+        output: W,
+        object_count: u32,
+        mut options: PackWriteOptions<'a>,
+    ) -> io::Result<Self> {
+        let mut pack = BufWriter::new(HashingWriter {
+            inner: output,
+            hasher: Some(Sha1::new()),
+        });
+        let progress = WriteProgress::default();
+        write_pack_header(&mut pack, object_count)
+            .map_err(|err| progress.describe_broken_pipe(err))?;
+        let compression = options.compression.unwrap_or_else(Compression::best);
+        let delta_compression = options.delta_compression.unwrap_or(compression);
+        Ok(Self {
+            pack,
+            object_count,
+            timer: PhaseTimer::new(options.profile.take()),
+            options,
+            progress,
+            entries: PackEntryWriter::new(compression, delta_compression),
+            was_cancelled: false,
+        })
+    }
+
+    fn finish_with_progress(self, progress: &WriteProgress) -> io::Result<Sha1Oid> {
+        let Self {
+            pack,
+            object_count,
+            mut options,
+            mut timer,
+            entries,
+            was_cancelled,
+            ..
+        } = self;
+        if progress.object_count != object_count as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "the packfile header promised {} objects, but {} were written{}",
+                    object_count,
+                    progress.object_count,
+                    if was_cancelled {
+                        " before the write was cancelled"
+                    } else {
+                        ""
                     }
+                ),
+            ));
+        }
+        let HashingWriter { mut inner, hasher } = pack.into_inner()?;
+        let checksum: Sha1Oid = hasher
+            .expect("streamed packfiles are always hashed")
+            .finalize()
+            .into();
+        inner.write_all(&checksum)?;
+        inner.flush()?;
+        timer.lap(|profile| &mut profile.finishing_pack);
+        if let Some(on_bytes_written) = &mut options.on_bytes_written {
+            on_bytes_written(entries.end_position.0 + checksum.len() as u64);
+        }
+        // Nothing needs the object positions without an index, and dropping them is as slow as it is for
+        // `PackFile`.
+        mem::forget(entries.object_positions);
+        Ok(checksum)
+    }
+}
+
+impl<W: Write> ObjectSink for PackStreamSink<'_, W> {
+    type Summary = Sha1Oid;
+
+    fn accepts_more(&mut self) -> bool {
+        self.was_cancelled = is_cancelled(&self.options);
+        !self.was_cancelled
+    }
+
+    fn write_object(&mut self, object: &PackFileObject) -> io::Result<()> {
+        self.entries
+            .write(
+                &mut self.pack,
+                object,
+                &mut self.options,
+                &mut self.timer,
+                &mut self.progress,
+            )
+            .map_err(|err| self.progress.describe_broken_pipe(err))
+    }
+
+    fn finish(mut self) -> io::Result<Sha1Oid> {
+        let progress = std::mem::take(&mut self.progress);
+        self.finish_with_progress(&progress)
+            .map_err(|err| progress.describe_broken_pipe(err))
+    }
+}
+
+/// Encodes objects as packfile entries, and records what the index needs to know about each entry.
+struct PackEntryWriter {
+    object_positions: BTreeMap<Sha1Oid, (PackOffset, u32)>,
+    object_counts_by_first_byte: [u32; 256],
+    // The offset just past the last entry
+    end_position: PackOffset,
+    current_object: Vec<u8>,
+    entry_encoder: PackEntryEncoder,
+}
+
+impl PackEntryWriter {
+    fn new(compression: Compression, delta_compression: Compression) -> Self {
+        Self {
+            object_positions: BTreeMap::new(),
+            object_counts_by_first_byte: [0; 256],
+            // Just past the 12-byte packfile header
+            end_position: PackOffset(12),
+            current_object: Vec::new(),
+            entry_encoder: PackEntryEncoder::new(compression, delta_compression),
+        }
+    }
+
+    /// Encodes `object` and writes its entry to `pack`, which must be positioned at `end_position`.
+    fn write<W: Write>(
+        &mut self,
+        pack: &mut W,
+        object: &PackFileObject,
+        options: &mut PackWriteOptions,
+        timer: &mut PhaseTimer,
+        progress: &mut WriteProgress,
+    ) -> io::Result<()> {
+        timer.lap(|profile| &mut profile.generating);
+        // The header and the index's fanout table count objects in 4 bytes.
+        if progress.object_count == u32::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a packfile can't have more than 2**32 - 1 objects",
+            ));
+        }
+        let oid = object.oid();
+        let current_object_position = self.end_position;
+
+        let base_offset = match object {
+            PackFileObject::Deltified { base_oid, .. } => {
+                match self.object_positions.get(base_oid) {
+                    // Every recorded object should have been written before this one, but a base at or
+                    // after this offset would otherwise wrap around into a corrupt distance.
+                    Some(&(previous_position, _)) => Some(
+                        current_object_position
+                            .distance_back_to(previous_position)
+                            .ok_or_else(|| {
+                                io::Error::new(
+                                    io::ErrorKind::InvalidInput,
+                                    format!(
+                                        "the delta base {} of {} is at offset {}, which isn't before \
+                                        the delta's offset {}",
+                                        oid_to_hex(base_oid),
+                                        oid_to_hex(&oid),
+                                        previous_position,
+                                        current_object_position
+                                    ),
+                                )
+                            })?,
+                    ),
+                    None => None,
                 }
-                deltified_representation
             }
+            PackFileObject::Raw(_) => None,
         };
+        self.entry_encoder
+            .encode(object, base_offset, &mut self.current_object)?;
+        // The git pack format documentation specifies that the index file needs to include the CRC32
+        // of each object, but doesn't specify which CRC32 table to use. Emperically, it seems like git
+        // uses the IEEE CRC32 table.
+        let checksum = crc32::checksum_ieee(&self.current_object);
+        timer.lap(|profile| &mut profile.compressing);
 
-        // Append the object type and object size. The git pack format documentation specifies that this should
-        // be "3-bit type, (n-1)*7+4-bit length", but it underspecifies how exactly these bits need to be arranged.
-        // From viewing the git source code: the first byte always has a 1 as the most significant bit, followed
-        // by the 3 bits of the object type, followed by the four least significant bits of the encoded object size
-        // (measured before any compression is applied). Then the remaining bits of the encoded object size are appended
-        // in the documented format for variable-length sizes.
-        current_object.push(0x80 | (object_type << 4) | (encoded_object.len() & 0xf) as u8);
-        append_variable_length_size(&mut current_object, encoded_object.len() >> 4)?;
-
-        if let PackFileObject::Deltified { base_oid, .. } = object {
-            if let Some((previous_position, _)) = object_positions.get(&base_oid) {
-                // For "offset delta" objects, append the relative offset of the delta base.
-                let offset = current_object_position - *previous_position;
-                append_variable_length_size_with_continuation_increment(
-                    &mut current_object,
-                    offset,
-                );
-            } else {
-                // For "ref delta" objects, append the OID of the delta base.
-                current_object.extend(&base_oid);
-            }
+        self.object_positions
+            .insert(oid, (current_object_position, checksum));
+        timer.lap(|profile| &mut profile.recording_offsets);
+        // Offsets are 64 bits even on 32-bit platforms, so this can't overflow in practice, but a wrapped
+        // offset would make the index wrong.
+        self.end_position = self
+            .end_position
+            .checked_add(self.current_object.len())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "the packfile is too large for 64-bit offsets",
+                )
+            })?;
+        pack.write_all(&self.current_object)?;
+        if let Some(on_object_written) = &mut options.on_object_written {
+            on_object_written(&oid, current_object_position, self.current_object.len());
         }
-        // Append the encoded object data, with maximum compression.
-        let mut encoder = ZlibEncoder::new(&mut current_object, Compression::best());
-        encoder.write_all(&encoded_object)?;
-        encoder.finish()?;
-
-        object_positions.insert(
-            oid,
-            (
-                current_object_position,
-                // The git pack format documentation specifies that the index file needs to include the CRC32
-                // of each object, but doesn't specify which CRC32 table to use. Emperically, it seems like git
-                // uses the IEEE CRC32 table.
-                crc32::checksum_ieee(&current_object),
-            ),
-        );
-        current_position += current_object.len();
-        pack.write_all(&current_object)?;
-        current_object.clear();
-
-        object_counts_by_first_byte[oid[0] as usize] += 1;
-    }
-
-    let mut pack_file = pack.into_inner()?;
-
-    // Now that all the objects have been added to the packfile, insert the correct object count into the header
-    pack_file.seek(SeekFrom::Start(8))?;
-    pack_file.write_all(&(object_positions.len() as u32).to_be_bytes())?;
-
-    // Add the sha1 pack checksum to the end of the packfile
-    pack_file.seek(SeekFrom::Start(0))?;
-    let mut pack_hasher = Sha1::new();
-    copy(&mut pack_file, &mut pack_hasher)?;
-    let pack_checksum = pack_hasher.finalize();
-    pack_file.write_all(&pack_checksum)?;
-
-    pack_file.sync_all()?;
-    drop(pack_file);
-
-    // At this point, the packfile is complete and we're finished processing commits, but we still need to
-    // generate an index file. Version-2 index files are needed because the packfile is generally bigger than
-    // 2**32 bytes.
-    let mut index = BufWriter::new(
-        OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(".git/objects/pack/pack-every-shorthash.idx")?,
-    );
+        self.current_object.clear();
+        // With buffering, this can be slightly ahead of what has actually reached the disk.
+        progress.object_count += 1;
+        progress.bytes_written = self.end_position.0;
+        if let Some(on_bytes_written) = &mut options.on_bytes_written {
+            on_bytes_written(self.end_position.0);
+        }
+
+        self.object_counts_by_first_byte[oid[0] as usize] += 1;
+        timer.lap(|profile| &mut profile.writing_pack);
+        Ok(())
+    }
+}
+
+/// Writes a version-2 index file for a packfile, given the offset and CRC32 of every object by OID, the
+/// number of objects that start with each byte, and the packfile's trailing checksum. Version-2 index
+/// files are needed because the packfile is generally bigger than 2**32 bytes. As with
+/// `write_pack_and_index`, the file is written from the start and truncated afterwards. Objects at or
+/// past `big_offset_threshold`, which must be at most `BIG_OFFSET_THRESHOLD`, get 8-byte offsets. With
+/// `direct_io`, the file is written as in `PackWriteOptions::direct_io`. Returns the fanout table.
+pub fn write_index(
+    mut index_file: File,
+    object_positions: &BTreeMap<Sha1Oid, (PackOffset, u32)>,
+    object_counts_by_first_byte: &[u32; 256],
+    pack_checksum: &[u8],
+    big_offset_threshold: usize,
+    direct_io: bool,
+) -> io::Result<[u32; 256]> {
+    debug_assert!(big_offset_threshold <= BIG_OFFSET_THRESHOLD);
+    index_file.seek(SeekFrom::Start(0))?;
+    // The index is hashed as it's written, rather than read back afterwards like a packfile whose
+    // header had to be fixed.
+    let mut index = BufWriter::new(HashingWriter {
+        inner: OutputFile::new(index_file, direct_io)?,
+        hasher: Some(Sha1::new()),
+    });
 
     // --- Start of index file header ---
 
@@ -279,11 +1407,14 @@ pub fn stream_to_pack_file<T: IntoIterator<Item = PackFileObject>>(
 
     // 256-entry "fanout table", encoding the number of objects in the packfile that start with each of
     // 0, 1, 2, ..., 255.
+    let mut fanout = [0u32; 256];
     let mut num_objects: u32 = 0;
-    for &count_with_first_byte_equal in object_counts_by_first_byte.iter() {
+    for (first_byte, &count_with_first_byte_equal) in object_counts_by_first_byte.iter().enumerate()
+    {
         num_objects += count_with_first_byte_equal;
-        index.write_all(&num_objects.to_be_bytes()[..])?;
+        fanout[first_byte] = num_objects;
     }
+    write_table(&mut index, fanout.iter().map(|count| count.to_be_bytes()))?;
 
     // At this point, we need to iterate over the objects, in order of their OID, several times. Using a B-tree
     // is asymtotically optimal for this, but it results in pretty severe cache thrashing, which greatly slows down
@@ -299,73 +1430,437 @@ pub fn stream_to_pack_file<T: IntoIterator<Item = PackFileObject>>(
     //
     // Another way to avoid the issue would be to only iterate over the B-tree once, and write to several different
     // parts of the file simultaneously using multiple file descriptors.
+    //
+    // The `index_structures` benchmark in the tests compares this with sorting a `Vec` of the entries.
+    //
+    // Each table is written in large blocks rather than with one `write_all` per entry, since there are
+    // hundreds of millions of entries and the per-call overhead adds up even with a `BufWriter`.
 
     // All of the OIDs, in lexicographic order
-    for oid in object_positions.keys() {
-        index.write_all(oid)?;
-    }
+    write_table(&mut index, object_positions.keys().copied())?;
 
     // CRC32 checksums of the packed object data
-    for (_, checksum) in object_positions.values() {
-        index.write_all(&checksum.to_be_bytes())?;
-    }
+    write_table(
+        &mut index,
+        object_positions
+            .values()
+            .map(|(_, checksum)| checksum.to_be_bytes()),
+    )?;
 
     let mut num_big_offsets = 0u32;
-    // Table of 4-byte object offsets
-    for (position, _) in object_positions.values() {
-        if *position < 0x80_00_00_00 {
-            index.write_all(&(*position as u32).to_be_bytes())?;
-        } else {
-            index.write_all(&(0x80_00_00_00 | num_big_offsets).to_be_bytes())?;
-            num_big_offsets += 1;
-        }
+    // Table of 4-byte object offsets. An entry can only refer to the first 2**31 8-byte offsets, since
+    // its top bit is taken.
+    write_table(
+        &mut index,
+        object_positions.values().map(|(position, _)| {
+            if position.0 < big_offset_threshold as u64 {
+                (position.0 as u32).to_be_bytes()
+            } else {
+                num_big_offsets = num_big_offsets.saturating_add(1);
+                (BIG_OFFSET_THRESHOLD as u32 | (num_big_offsets - 1)).to_be_bytes()
+            }
+        }),
+    )?;
+    if num_big_offsets as usize > BIG_OFFSET_THRESHOLD {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "an index can't have more than 2**31 8-byte offsets, but {} objects need one",
+                num_big_offsets
+            ),
+        ));
     }
 
     // Table of 8-byte object offsets
-    for (position, _) in object_positions.values() {
-        // FIXME: might faster to have two separate cursors writing to the file rather than iterating over
-        // the B-tree twice
-        if *position >= 0x80_00_00_00 {
-            index.write_all(&(*position as u64).to_be_bytes())?;
-        }
-    }
+    // FIXME: might faster to have two separate cursors writing to the file rather than iterating over
+    // the B-tree twice
+    write_table(
+        &mut index,
+        object_positions
+            .values()
+            .filter(|(position, _)| position.0 >= big_offset_threshold as u64)
+            .map(|(position, _)| position.0.to_be_bytes()),
+    )?;
 
     // Add a copy of the pack file checksum
-    index.write_all(&pack_checksum)?;
+    index.write_all(pack_checksum)?;
 
-    let mut index_file = index.into_inner()?;
+    let HashingWriter {
+        inner: index_output,
+        hasher,
+    } = index.into_inner()?;
+    let mut index_file = index_output.into_file()?;
+    let index_length = index_file.stream_position()?;
 
     // Add the sha1 index checksum to the index of the index file
-    index_file.seek(SeekFrom::Start(0))?;
-    let mut index_hasher = Sha1::new();
-    copy(&mut index_file, &mut index_hasher)?;
-    index_file.write_all(&index_hasher.finalize())?;
+    let index_checksum = hasher.expect("the index is hashed").finalize();
+    index_file.write_all(&index_checksum)?;
+    index_file.set_len(index_length + index_checksum.len() as u64)?;
 
     index_file.sync_all()?;
+    if direct_io {
+        drop_from_page_cache(&index_file)?;
+    }
+
+    Ok(fanout)
+}
+
+/// Writes each object as a loose object in `objects_dir`, rather than as part of a packfile. Returns the
+/// number of distinct objects. Deltified objects are written in full, so their delta bases must also
+/// appear in `iter`, before them. Every object is kept in memory to resolve deltas, so this is only
+/// practical for small numbers of objects (e.g. when testing with a commit count cap).
+pub fn write_loose_objects<T: IntoIterator<Item = PackFileObject>>(
+    objects_dir: &Path,
+    iter: T,
+) -> io::Result<usize> {
+    write_to_sink(LooseObjectSink::new(objects_dir), iter)
+}
+
+/// Writes objects as loose objects. This is what `write_loose_objects` uses; see there for how deltified
+/// objects are handled. Finishing returns the number of distinct objects.
+pub struct LooseObjectSink<'a> {
+    objects_dir: &'a Path,
+    objects_by_oid: HashMap<Sha1Oid, GitObject>,
+}
+
+impl<'a> LooseObjectSink<'a> {
+    pub fn new(objects_dir: &'a Path) -> Self {
+        Self {
+            objects_dir,
+            objects_by_oid: HashMap::new(),
+        }
+    }
+}
+
+impl ObjectSink for LooseObjectSink<'_> {
+    type Summary = usize;
+
+    fn write_object(&mut self, object: &PackFileObject) -> io::Result<()> {
+        let git_object = match object {
+            PackFileObject::Raw(git_object) => git_object.clone(),
+            PackFileObject::Deltified {
+                base_oid,
+                delta,
+                new_oid,
+                ..
+            } => {
+                let base = self.objects_by_oid.get(base_oid).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "delta base {} of {} hasn't been written",
+                            oid_to_hex(base_oid),
+                            oid_to_hex(new_oid)
+                        ),
+                    )
+                })?;
+                GitObject {
+                    object_type: base.object_type.clone(),
+                    data: apply_delta(&base.data, delta)?,
+                }
+            }
+        };
+        let oid = git_object.oid();
+        write_loose_object(self.objects_dir, &oid, &git_object)?;
+        self.objects_by_oid.insert(oid, git_object);
+        Ok(())
+    }
+
+    fn finish(self) -> io::Result<usize> {
+        Ok(self.objects_by_oid.len())
+    }
+}
+
+/// Keeps the objects in memory, in the order they were written, e.g. for inspecting what a generator
+/// produces without writing any files.
+#[allow(dead_code)]
+#[derive(Debug, Default)]
+pub struct InMemorySink {
+    objects: Vec<PackFileObject>,
+}
+
+impl ObjectSink for InMemorySink {
+    type Summary = Vec<PackFileObject>;
+
+    fn write_object(&mut self, object: &PackFileObject) -> io::Result<()> {
+        self.objects.push(object.clone());
+        Ok(())
+    }
+
+    fn finish(self) -> io::Result<Vec<PackFileObject>> {
+        Ok(self.objects)
+    }
+}
+
+/// Writes a single zlib-compressed loose object to `objects_dir/xx/<remaining 38 hex characters>`.
+fn write_loose_object(objects_dir: &Path, oid: &Sha1Oid, object: &GitObject) -> io::Result<()> {
+    let hex = oid_to_hex(oid);
+    let object_dir = objects_dir.join(&hex[..2]);
+    let object_path = object_dir.join(&hex[2..]);
+    // Like git, leave existing objects alone; they have the same contents by definition.
+    if object_path.exists() {
+        return Ok(());
+    }
+    fs::create_dir_all(&object_dir)?;
+
+    // Write to a temporary file first, so that an interrupted run can't leave a truncated object
+    // behind.
+    let temp_path = object_path.with_extension("tmp");
+    let mut encoder = ZlibEncoder::new(
+        BufWriter::new(File::create(&temp_path)?),
+        Compression::default(),
+    );
+    encoder.write_all(&object.to_loose())?;
+    encoder.finish()?.into_inner()?;
+    fs::rename(temp_path, object_path)
+}
+
+/// Returns the 3-bit type ID that a packfile entry header uses for an object, using the ID values
+/// [here](https://git-scm.com/docs/pack-format#:~:text=Object%20types). Deltified objects are "offset
+/// delta" objects if their delta base is earlier in the same packfile (`base_in_pack`), and "ref delta"
+/// objects otherwise. The value 5 is reserved and never used, and 0 is invalid.
+pub fn pack_object_type_byte(object: &PackFileObject, base_in_pack: bool) -> u8 {
+    match object {
+        PackFileObject::Raw(GitObject { object_type, .. }) => match object_type {
+            GitObjectType::Commit => 1,
+            GitObjectType::Tree => 2,
+            GitObjectType::Blob => 3,
+            GitObjectType::Tag => 4,
+        },
+        PackFileObject::Deltified { .. } => {
+            if base_in_pack {
+                6
+            } else {
+                7
+            }
+        }
+    }
+}
 
-    // Deallocating the B-tree of object positions is very, very slow. It's a really big B-tree that has lots of
-    // individual allocations. Deallocating the B-tree is also completely unnecessary if the process is about to
-    // exit, serving only to add hours to the runtime for no reason. So the B-tree is included as a private
-    // returned struct field, and the caller can explicitly leak the struct rather than dropping it if needed.
-    Ok(PackFile {
-        object_positions,
-    })
+/// Encodes packfile entries. A packfile has one entry per object, so this keeps a zlib compressor for
+/// each compression level (one for deltified objects and one for the rest) and resets it between
+/// entries, rather than allocating and initializing the compressor's large internal state for each of
+/// the 2^28 objects.
+struct PackEntryEncoder {
+    compressor: Compress,
+    delta_compressor: Compress,
+    /// Scratch space for the deltified representation of the current entry, kept so that its capacity
+    /// can be reused by the next entry.
+    deltified_representation: Vec<u8>,
+}
+
+impl PackEntryEncoder {
+    fn new(compression: Compression, delta_compression: Compression) -> Self {
+        Self {
+            // Objects are compressed in zlib format.
+            compressor: Compress::new(compression, true),
+            delta_compressor: Compress::new(delta_compression, true),
+            deltified_representation: Vec::new(),
+        }
+    }
+
+    /// Appends the packfile representation of an object (its header, the location of its delta base if
+    /// it's deltified, and its compressed data) to `current_object`. `base_offset` is the distance back to
+    /// the delta base of a deltified object if the base is earlier in the packfile, or `None` if the base
+    /// should instead be referenced by OID.
+    fn encode(
+        &mut self,
+        object: &PackFileObject,
+        base_offset: Option<u64>,
+        current_object: &mut Vec<u8>,
+    ) -> io::Result<()> {
+        let Self {
+            compressor,
+            delta_compressor,
+            deltified_representation,
+        } = self;
+        let object_type = pack_object_type_byte(object, base_offset.is_some());
+
+        let (encoded_object, compressor): (&[u8], _) = match object {
+            // Non-deltified objects have no packfile-specific encoding, so their data is compressed
+            // directly. (Merge commits with 2**14 parents are almost 800 KB each, so copying them adds up.)
+            PackFileObject::Raw(git_object) => (&git_object.data, compressor),
+            PackFileObject::Deltified {
+                base_size,
+                delta,
+                new_size,
+                ..
+            } => {
+                deltified_representation.clear();
+                append_delta(deltified_representation, *base_size, *new_size, delta)?;
+                (deltified_representation, delta_compressor)
+            }
+        };
+
+        append_object_header(current_object, object_type, encoded_object.len());
+
+        if let PackFileObject::Deltified { base_oid, .. } = object {
+            if let Some(offset) = base_offset {
+                // For "offset delta" objects, append the relative offset of the delta base.
+                append_variable_length_size_with_continuation_increment(current_object, offset);
+            } else {
+                // For "ref delta" objects, append the OID of the delta base.
+                current_object.extend(base_oid);
+            }
+        }
+        // Append the compressed object data.
+        compress(compressor, encoded_object, current_object)
+    }
+}
+
+/// Encodes a delta in the packfile-specific format described
+/// [here](https://git-scm.com/docs/pack-format#_deltified_representation): the size of the delta base and
+/// the size of the resulting object, followed by the instructions. This is the data of a deltified
+/// packfile entry before compression. Fails if an instruction can't be encoded.
+#[allow(dead_code)]
+pub fn encode_delta(
+    base_size: ObjectSize,
+    new_size: ObjectSize,
+    instructions: &[PackFileDeltaInstruction],
+) -> io::Result<Vec<u8>> {
+    let mut delta = Vec::new();
+    append_delta(&mut delta, base_size, new_size, instructions)?;
+    Ok(delta)
+}
+
+/// Like `encode_delta`, but appends the delta to `out`, so that the buffer can be reused.
+fn append_delta(
+    out: &mut Vec<u8>,
+    base_size: ObjectSize,
+    new_size: ObjectSize,
+    instructions: &[PackFileDeltaInstruction],
+) -> io::Result<()> {
+    append_variable_length_size(out, base_size.0);
+    append_variable_length_size(out, new_size.0);
+    for delta_instruction in instructions {
+        match delta_instruction {
+            PackFileDeltaInstruction::CopyFromBaseObject { offset, size } => {
+                // The "copy from base object" instruction encoding, documented
+                // [here](https://git-scm.com/docs/pack-format#_instruction_to_copy_from_base_object)
+                // Git reads a size with no bytes present as 0x10000, so 0 can't be represented, but
+                // 0x10000 can.
+                if *offset > u32::MAX as usize || *size == 0 || *size > 0xff_ff_ff {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "can't encode a delta instruction that copies {} bytes at offset {}",
+                            size, offset
+                        ),
+                    ));
+                }
+                let offset1 = NonZeroU8::new(*offset as u8);
+                let offset2 = NonZeroU8::new((*offset >> 8) as u8);
+                let offset3 = NonZeroU8::new((*offset >> 16) as u8);
+                let offset4 = NonZeroU8::new((*offset >> 24) as u8);
+                let size1 = NonZeroU8::new(*size as u8);
+                let size2 = NonZeroU8::new((*size >> 8) as u8);
+                let size3 = NonZeroU8::new((*size >> 16) as u8);
+                out.push(
+                    0b1000_0000
+                        | if size3.is_some() { 0b0100_0000 } else { 0 }
+                        | if size2.is_some() { 0b0010_0000 } else { 0 }
+                        | if size1.is_some() { 0b0001_0000 } else { 0 }
+                        | if offset4.is_some() { 0b0000_1000 } else { 0 }
+                        | if offset3.is_some() { 0b0000_0100 } else { 0 }
+                        | if offset2.is_some() { 0b0000_0010 } else { 0 }
+                        | if offset1.is_some() { 0b0000_0001 } else { 0 },
+                );
+                // Only the nonzero bytes are present, in the same order as their flag bits.
+                out.extend(
+                    [offset1, offset2, offset3, offset4, size1, size2, size3]
+                        .iter()
+                        .flatten()
+                        .map(|byte| byte.get()),
+                );
+            }
+            PackFileDeltaInstruction::AddNewData(new_data) => {
+                // The "add new data" instruction encoding, documented
+                // [here](https://git-scm.com/docs/pack-format#_instruction_to_add_new_data).
+                // FIXME: is the length limit for this instruction actually 127?
+                // It seems like it would be impossible to encode a length more than 127 with
+                // the documented format, but that seems surprising. Maybe it's supposed to use the
+                // variable-length encoding described in other places?
+                // In any case, this tool only uses the instruction with sizes less than 127 anyway.
+                // A longer length would set the top bit, which would make git read the instruction as a
+                // copy instead, and a length of 0 is the opcode that git reserves and rejects.
+                if new_data.is_empty() || new_data.len() > 127 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "can't add {} bytes of new data in one delta instruction",
+                            new_data.len()
+                        ),
+                    ));
+                }
+                out.push(new_data.len() as u8);
+                out.extend(new_data);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Appends the zlib-compressed form of `data` to `out`, then resets the compressor for the next entry.
+fn compress(compressor: &mut Compress, data: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+    loop {
+        // The compressor only writes to the spare capacity of `out`. The objects are small and
+        // generally incompressible, so this is almost always enough for the whole stream at once.
+        out.reserve(data.len() + 64);
+        let consumed = compressor.total_in() as usize;
+        let status = compressor.compress_vec(&data[consumed..], out, FlushCompress::Finish)?;
+        if status == Status::StreamEnd {
+            compressor.reset();
+            return Ok(());
+        }
+    }
+}
+
+/// Writes a table of fixed-size index entries, batching them into large blocks so that the writer
+/// is called once per block rather than once per entry.
+fn write_table<W: Write, const N: usize>(
+    mut out: W,
+    entries: impl Iterator<Item = [u8; N]>,
+) -> io::Result<()> {
+    const BLOCK_SIZE: usize = 1 << 20;
+    let mut block = Vec::with_capacity(BLOCK_SIZE);
+    for entry in entries {
+        block.extend_from_slice(&entry);
+        if block.len() >= BLOCK_SIZE {
+            out.write_all(&block)?;
+            block.clear();
+        }
+    }
+    out.write_all(&block)
+}
+
+/// Appends the header of a packfile entry, containing the object type and the size of the encoded object
+/// (measured before any compression is applied). The git pack format documentation specifies that this
+/// should be "3-bit type, (n-1)*7+4-bit length", but it underspecifies how exactly these bits need to be
+/// arranged. From viewing the git source code: the first byte has a 1 as the most significant bit, followed
+/// by the 3 bits of the object type, followed by the four least significant bits of the size. Then the
+/// remaining bits of the size are appended in the documented format for variable-length sizes.
+///
+/// Unlike git, this always sets the continuation bit of the first byte, so the remaining bits take up at
+/// least one byte even if they're all zero (e.g. a 5-byte commit has the header `95 00`). Git accepts this,
+/// and changing it would change the generated packfiles.
+fn append_object_header(current_object: &mut Vec<u8>, object_type: u8, size: usize) {
+    current_object.push(0x80 | (object_type << 4) | (size & 0xf) as u8);
+    append_variable_length_size(current_object, size >> 4)
 }
 
 /// Appends a "size-encoded" non-negative integer to packfile data, using the
 /// encoding format specified [here](https://git-scm.com/docs/pack-format#:~:text=Size%20encoding).
-fn append_variable_length_size<T: Write>(mut data: T, mut size: usize) -> io::Result<()> {
+fn append_variable_length_size(data: &mut Vec<u8>, mut size: usize) {
     loop {
         let next_seven_bits = (size & 0x7f) as u8;
         size >>= 7;
         if size == 0 {
-            data.write_all(&[next_seven_bits])?;
+            data.push(next_seven_bits);
             break;
         } else {
-            data.write_all(&[next_seven_bits | 0x80])?;
+            data.push(next_seven_bits | 0x80);
         }
     }
-    Ok(())
 }
 
 /// Packfiles use a slightly different variable-length size encoding for delta offsets
@@ -373,7 +1868,7 @@ fn append_variable_length_size<T: Write>(mut data: T, mut size: usize) -> io::Re
 /// to generate a packfile that git will understand.
 /// [This blogpost](https://medium.com/@concertdaw/sneaky-git-number-encoding-ddcc5db5329f) contains
 /// some more information.
-fn append_variable_length_size_with_continuation_increment(data: &mut Vec<u8>, mut size: usize) {
+fn append_variable_length_size_with_continuation_increment(data: &mut Vec<u8>, mut size: u64) {
     let initial_index = data.len();
     data.push((size & 0x7f) as u8);
     size >>= 7;
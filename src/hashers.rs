@@ -0,0 +1,259 @@
+//! Hashes attempted commits on several threads, for `--threads`. Hashing is almost all of the work of a
+//! run, and each attempt only depends on its entropy value and delta base, so the attempts can be hashed
+//! in any order. Which attempts find a new shorthash depends on every attempt before them, though, so the
+//! generator still goes through the hashed attempts one at a time, in order, on the thread that writes
+//! the pack. The pack is the same as with a single thread.
+//!
+//! The attempts are split into chunks, and thread `i` of `n` hashes chunks `i`, `i + n`, `i + 2n` and so
+//! on, sending the attempts whose OIDs match the prefix over a channel of its own. The generator reads
+//! the chunks from the threads in turn, so they arrive in order without any reordering, and a thread
+//! that gets `--channel-depth` chunks ahead of the generator waits for it to catch up. That's what keeps
+//! the memory bounded when the hashers outrun the writer, which they do on a machine with enough cores.
+
+use crate::{
+    entropy::{entropy_hex, EntropySource},
+    git_utils::Sha1Oid,
+    shorthash::OidPrefix,
+};
+use sha1::{Digest, Sha1};
+use std::{
+    mem,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{sync_channel, Receiver, SyncSender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    vec,
+};
+
+// The number of attempts in a chunk. This is small enough that a chunk of attempts that all match (as
+// they do without a prefix) is a few hundred KB, and large enough that passing chunks between threads
+// costs nothing next to hashing them.
+const CHUNK_LENGTH: u64 = 1 << 14;
+
+/// How many hashed chunks each thread can have waiting for the generator, unless `--channel-depth` says
+/// otherwise
+pub const DEFAULT_CHANNEL_DEPTH: usize = 2;
+
+// The most memory that the matches of a chunk can take, when every attempt matches
+const MAX_CHUNK_SIZE: usize = CHUNK_LENGTH as usize * mem::size_of::<(u64, Sha1Oid)>();
+
+/// The attempts to hash, from `first_attempt` up to (but not including) `end_attempt`
+pub struct HashJob {
+    /// For each delta base, the SHA1 state after the part of its commit before the entropy, and the
+    /// footer that comes after the entropy. Attempt `n` uses delta base `n % delta_bases.len()`.
+    pub delta_bases: Vec<(Sha1, Vec<u8>)>,
+    /// The number of hex characters of entropy
+    pub entropy_length: usize,
+    pub prefix: OidPrefix,
+    pub first_attempt: u64,
+    pub first_entropy: u64,
+    /// The entropy source, positioned right after `first_entropy`
+    pub entropy_source: Box<dyn EntropySource>,
+    pub end_attempt: u64,
+}
+
+/// The hashed chunks that are waiting for the generator, and the memory that their matches take
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BufferUsage {
+    pub chunks: usize,
+    pub bytes: usize,
+}
+
+impl BufferUsage {
+    /// The most that `thread_count` threads can have waiting with a channel depth of `channel_depth`:
+    /// each thread's channel can be full, with one more chunk that the thread is waiting to send.
+    pub fn limit(thread_count: usize, channel_depth: usize) -> Self {
+        let chunks = thread_count * (channel_depth + 1);
+        Self {
+            chunks,
+            bytes: chunks * MAX_CHUNK_SIZE,
+        }
+    }
+}
+
+pub struct HasherPool {
+    receivers: Vec<Receiver<Vec<(u64, Sha1Oid)>>>,
+    threads: Vec<JoinHandle<()>>,
+    is_stopping: Arc<AtomicBool>,
+    buffered: Arc<BufferCounters>,
+    // The rest of the chunk that's being read, and the chunk after it
+    current_chunk: vec::IntoIter<(u64, Sha1Oid)>,
+    next_chunk: u64,
+    chunk_count: u64,
+    end_attempt: u64,
+}
+
+impl HasherPool {
+    /// Starts `thread_count` threads hashing the attempts of `job`, each of which can have up to
+    /// `channel_depth` hashed chunks waiting for `next_match`.
+    pub fn start(job: HashJob, thread_count: usize, channel_depth: usize) -> Self {
+        debug_assert!(thread_count > 0 && channel_depth > 0);
+        let attempt_count = job.end_attempt - job.first_attempt;
+        let chunk_count = attempt_count.div_ceil(CHUNK_LENGTH);
+        let is_stopping = Arc::new(AtomicBool::new(false));
+        let buffered = Arc::new(BufferCounters::default());
+        let delta_bases = Arc::new(job.delta_bases);
+        let mut receivers = Vec::with_capacity(thread_count);
+        let mut threads = Vec::with_capacity(thread_count);
+        for index in 0..thread_count {
+            let (sender, receiver) = sync_channel(channel_depth);
+            let thread = HashingThread {
+                delta_bases: Arc::clone(&delta_bases),
+                entropy_length: job.entropy_length,
+                prefix: job.prefix,
+                first_attempt: job.first_attempt,
+                first_entropy: job.first_entropy,
+                entropy_source: job.entropy_source.boxed_clone(),
+                end_attempt: job.end_attempt,
+                chunk_count,
+                is_stopping: Arc::clone(&is_stopping),
+                buffered: Arc::clone(&buffered),
+            };
+            receivers.push(receiver);
+            threads.push(
+                thread::Builder::new()
+                    .name(format!("hasher-{}", index))
+                    .spawn(move || thread.run(index as u64, thread_count as u64, sender))
+                    .expect("failed to spawn a hashing thread"),
+            );
+        }
+        Self {
+            receivers,
+            threads,
+            is_stopping,
+            buffered,
+            current_chunk: Vec::new().into_iter(),
+            next_chunk: 0,
+            chunk_count,
+            end_attempt: job.end_attempt,
+        }
+    }
+
+    /// Returns the next attempt whose OID matches the prefix, along with the OID, or `None` once every
+    /// attempt of the job has been hashed.
+    pub fn next_match(&mut self) -> Option<(u64, Sha1Oid)> {
+        loop {
+            if let Some(attempt) = self.current_chunk.next() {
+                return Some(attempt);
+            }
+            if self.next_chunk == self.chunk_count {
+                return None;
+            }
+            let receiver =
+                &self.receivers[(self.next_chunk % self.receivers.len() as u64) as usize];
+            let chunk = receiver.recv().expect("a hashing thread stopped early");
+            self.buffered.remove(&chunk);
+            self.current_chunk = chunk.into_iter();
+            self.next_chunk += 1;
+        }
+    }
+
+    /// The attempt that the job ends before
+    pub fn end_attempt(&self) -> u64 {
+        self.end_attempt
+    }
+
+    /// Returns how many hashed chunks are waiting for `next_match` right now, counting the ones that the
+    /// threads are waiting to send, and how much memory they take.
+    pub fn buffer_usage(&self) -> BufferUsage {
+        BufferUsage {
+            chunks: self.buffered.chunks.load(Ordering::Relaxed),
+            bytes: self.buffered.bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Counts the chunks that have been hashed but not received yet. These are only read for reporting, so
+/// they don't need to be exact at any given moment.
+#[derive(Default)]
+struct BufferCounters {
+    chunks: AtomicUsize,
+    bytes: AtomicUsize,
+}
+
+impl BufferCounters {
+    fn add(&self, chunk: &Vec<(u64, Sha1Oid)>) {
+        self.chunks.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(chunk_size(chunk), Ordering::Relaxed);
+    }
+
+    fn remove(&self, chunk: &Vec<(u64, Sha1Oid)>) {
+        self.chunks.fetch_sub(1, Ordering::Relaxed);
+        self.bytes.fetch_sub(chunk_size(chunk), Ordering::Relaxed);
+    }
+}
+
+fn chunk_size(chunk: &Vec<(u64, Sha1Oid)>) -> usize {
+    chunk.capacity() * mem::size_of::<(u64, Sha1Oid)>()
+}
+
+impl Drop for HasherPool {
+    fn drop(&mut self) {
+        self.is_stopping.store(true, Ordering::Relaxed);
+        // A thread waiting for room in its channel stops once the channel is closed.
+        drop(mem::take(&mut self.receivers));
+        for thread in self.threads.drain(..) {
+            // A panic in a thread has already been reported, and `next_match` panics too if it loses
+            // any chunks to it.
+            let _ = thread.join();
+        }
+    }
+}
+
+struct HashingThread {
+    delta_bases: Arc<Vec<(Sha1, Vec<u8>)>>,
+    entropy_length: usize,
+    prefix: OidPrefix,
+    first_attempt: u64,
+    first_entropy: u64,
+    entropy_source: Box<dyn EntropySource>,
+    end_attempt: u64,
+    chunk_count: u64,
+    is_stopping: Arc<AtomicBool>,
+    buffered: Arc<BufferCounters>,
+}
+
+impl HashingThread {
+    /// Hashes every `stride`-th chunk, starting with chunk `first_chunk`, and sends the matching attempts
+    /// of each chunk to `sender`.
+    fn run(mut self, first_chunk: u64, stride: u64, sender: SyncSender<Vec<(u64, Sha1Oid)>>) {
+        // The attempt that the entropy source's next value is for
+        let mut source_attempt = self.first_attempt + 1;
+        let mut chunk = first_chunk;
+        while chunk < self.chunk_count && !self.is_stopping.load(Ordering::Relaxed) {
+            let start = self.first_attempt + chunk * CHUNK_LENGTH;
+            let end = start.saturating_add(CHUNK_LENGTH).min(self.end_attempt);
+            let mut entropy = if start == self.first_attempt {
+                self.first_entropy
+            } else {
+                self.entropy_source.skip(start - source_attempt);
+                self.entropy_source.next_entropy()
+            };
+            let mut matches = Vec::new();
+            for attempt in start..end {
+                if attempt > start {
+                    entropy = self.entropy_source.next_entropy();
+                }
+                let (state, footer) =
+                    &self.delta_bases[(attempt % self.delta_bases.len() as u64) as usize];
+                let oid: Sha1Oid = state
+                    .clone()
+                    .chain(entropy_hex(entropy, self.entropy_length).as_bytes())
+                    .chain(footer)
+                    .finalize()
+                    .into();
+                if self.prefix.matches(&oid) {
+                    matches.push((attempt, oid));
+                }
+            }
+            source_attempt = end;
+            self.buffered.add(&matches);
+            if sender.send(matches).is_err() {
+                return;
+            }
+            chunk += stride;
+        }
+    }
+}
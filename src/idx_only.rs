@@ -0,0 +1,55 @@
+use crate::{
+    git_utils::{create_output_file, write_index, Sha1Oid, BIG_OFFSET_THRESHOLD},
+    pack_reader::{verify_pack_trailer, PackReader},
+};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io,
+    io::{BufReader, Read, Seek, SeekFrom},
+    mem::forget,
+    path::Path,
+};
+
+/// Regenerates the index file of an existing packfile, without regenerating the packfile itself. The
+/// index is written next to the packfile with an `.idx` extension. Returns the number of objects.
+pub fn rebuild_index(pack_path: &Path) -> io::Result<usize> {
+    // A corrupt packfile would make for a useless index, and checking the checksum is much faster than
+    // decompressing every object.
+    if !verify_pack_trailer(&mut BufReader::new(File::open(pack_path)?))? {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} doesn't match its checksum", pack_path.display()),
+        ));
+    }
+
+    let mut object_positions = BTreeMap::new();
+    let mut object_counts_by_first_byte = [0u32; 256];
+    for (i, packed) in PackReader::open(pack_path)?.enumerate() {
+        let packed = packed?;
+        object_positions.insert(packed.oid, (packed.offset, packed.crc32));
+        object_counts_by_first_byte[packed.oid[0] as usize] += 1;
+        if (i + 1) & 0xfffff == 0 {
+            println!("objects indexed so far: {}", i + 1);
+        }
+    }
+
+    // The index file contains a copy of the packfile's trailing checksum.
+    let mut pack_checksum = Sha1Oid::default();
+    let mut pack_file = File::open(pack_path)?;
+    pack_file.seek(SeekFrom::End(-(pack_checksum.len() as i64)))?;
+    pack_file.read_exact(&mut pack_checksum)?;
+
+    write_index(
+        create_output_file(&pack_path.with_extension("idx"))?,
+        &object_positions,
+        &object_counts_by_first_byte,
+        &pack_checksum,
+        BIG_OFFSET_THRESHOLD,
+        false,
+    )?;
+    let object_count = object_positions.len();
+    // As with generated packs, the B-tree takes a very long time to clean up.
+    forget(object_positions);
+    Ok(object_count)
+}
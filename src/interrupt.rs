@@ -0,0 +1,41 @@
+//! Turns the first Ctrl-C of a run into a request to finish early. The generator notices it the next time
+//! it checks the time limit, and then creates the merge commits and writes the pack and index as if the
+//! time limit had been reached, so that the run can be resumed afterwards. Writes that can't finish
+//! early, like merging packs, are cancelled instead. A second Ctrl-C kills the process as usual.
+
+use crate::git_utils::CancelToken;
+
+static INTERRUPTED: CancelToken = CancelToken::new();
+
+/// Returns whether Ctrl-C has been pressed since `install` was called.
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.is_cancelled()
+}
+
+/// Returns a token that's cancelled by Ctrl-C, for writing a pack that should stop right away.
+pub fn cancel_token() -> &'static CancelToken {
+    &INTERRUPTED
+}
+
+/// Starts handling Ctrl-C.
+#[cfg(unix)]
+pub fn install() {
+    extern "C" fn handle_sigint(_signal: libc::c_int) {
+        // Cancelling only stores to an atomic, so both of these are async-signal-safe.
+        INTERRUPTED.cancel();
+        unsafe {
+            libc::signal(libc::SIGINT, libc::SIG_DFL);
+        }
+    }
+
+    unsafe {
+        libc::signal(
+            libc::SIGINT,
+            handle_sigint as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+}
+
+/// Elsewhere, Ctrl-C keeps killing the process right away.
+#[cfg(not(unix))]
+pub fn install() {}
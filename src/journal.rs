@@ -0,0 +1,361 @@
+//! Keeps track of how far the pack of a `--journal` run has been written, so that a run that crashes or is
+//! killed partway through can finish the same pack instead of starting over. A pack can't be appended to
+//! once it's finished, since its header has the object count and its end has a checksum of everything
+//! before it, but an unfinished one can be continued from wherever its objects were last synced to disk.
+//!
+//! Every `--journal-interval`, between two batches of objects, the generator has the packfile synced, and
+//! then replaces the journal's entry with how long the packfile was and the generator's state at that
+//! point. A resumed run truncates the packfile back to that length and carries on from that state, so
+//! the finished pack is the same as if the run hadn't been interrupted. The found shorthashes aren't in
+//! the entry, since they take 32 MiB; they're read back from the commits in the packfile instead, along
+//! with the offsets and CRC32s that the index needs.
+//!
+//! The format is, with integers in network byte order:
+//!
+//! - the 4-byte magic `ESJN`
+//! - a 4-byte version number, currently 2
+//! - the number of bits in the OID prefix, as 1 byte, followed by the prefix itself as 8 bytes
+//! - the entropy source, as 1 byte (0 for the counter and 1 for random), followed by the seed of the
+//!   random entropy source (or 0) as 8 bytes
+//! - the SHA1 of the OIDs of the delta base commits, one after another
+//! - the initial number of characters of entropy, as 1 byte
+//! - the first and last byte of `--oid-prefix-range`, as 1 byte each
+//! - the number of prefixes from `--avoid-prefix`, as 4 bytes, followed by each of them in the same
+//!   format as the OID prefix
+//! - the batch size and the delta chain depth, as 8 bytes each, and whether the commits are merged, as 1
+//!   byte
+//! - whether there's an entry, as 1 byte, and if there is:
+//!   - the length of the packfile and the number of objects in it, as 8 and 4 bytes
+//!   - the number of attempts, the number of root commits, the number of generated commits, and the
+//!     number of first-level merge commits, as 8 bytes each
+//!   - whether the run was stopping, and whether it stopped early, as 1 byte each
+//!   - the number of delta bases, as 4 bytes, and for each one, whether a generated commit has been
+//!     chained to it, as 1 byte, followed by the OID, size, and delta chain depth of the last one, as 20,
+//!     8 and 8 bytes
+//!   - the number of levels of the merge tree, as 1 byte, and for each level, the number of commits on it
+//!     that haven't been merged yet, as 4 bytes, followed by their OIDs
+//! - a SHA1 checksum of everything above
+//!
+//! Like checkpoints, the journal is written to a temporary file and renamed into place, so it always has
+//! either the previous entry or the next one.
+
+use crate::{
+    git_utils::{PackOffset, PackPosition, Sha1Oid},
+    replay::ReplayParameters,
+    shorthash::{FirstByteRange, OidPrefix},
+};
+use sha1::{Digest, Sha1};
+use std::{
+    convert::TryInto,
+    ffi::OsString,
+    fs,
+    fs::File,
+    io,
+    io::Write,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+const MAGIC: &[u8; 4] = b"ESJN";
+const VERSION: u32 = 2;
+
+/// How often the journal is updated, unless `--journal-interval` says otherwise
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The options of a run that its journal has to agree with for the run to be resumed: the ones that
+/// decide which attempts are accepted, and the ones that decide how the accepted attempts are laid out in
+/// the pack.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JournalParameters {
+    pub replay: ReplayParameters,
+    pub batch_size: usize,
+    pub delta_chain_depth: usize,
+    pub merged: bool,
+}
+
+impl JournalParameters {
+    /// Describes the first difference between the parameters of two runs, if there is one.
+    pub fn difference(&self, other: &Self) -> Option<&'static str> {
+        if let Some(difference) = self.replay.difference(&other.replay) {
+            Some(difference)
+        } else if self.batch_size != other.batch_size {
+            Some("a different --batch-size")
+        } else if self.delta_chain_depth != other.delta_chain_depth {
+            Some("a different --delta-chain-depth")
+        } else if self.merged != other.merged {
+            Some("a different --leaves-only")
+        } else {
+            None
+        }
+    }
+}
+
+/// The generator's state at a point where everything it had generated was in the synced packfile
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JournalEntry {
+    pub position: PackPosition,
+    pub attempt_count: u64,
+    pub root_commit_count: u64,
+    pub commit_count: u64,
+    pub first_level_merge_commit_count: u64,
+    pub is_stopping: bool,
+    pub stopped_early: bool,
+    /// For each delta base, the OID, size, and delta chain depth of the last generated commit that was
+    /// chained to it
+    pub previous_chained_commits: Vec<Option<(Sha1Oid, u64, u64)>>,
+    /// The commits on each level of the merge tree that haven't been merged yet
+    pub merge_tree_levels: Vec<Vec<Sha1Oid>>,
+}
+
+pub struct Journal {
+    path: PathBuf,
+    parameters: JournalParameters,
+}
+
+impl Journal {
+    /// Starts a journal without an entry at `path`, replacing whatever is there.
+    pub fn create(path: &Path, parameters: JournalParameters) -> io::Result<Self> {
+        let journal = Self {
+            path: path.to_path_buf(),
+            parameters,
+        };
+        journal.write(None)?;
+        Ok(journal)
+    }
+
+    /// Reads the journal at `path`, returning it along with its entry, if it has one. A resumed run
+    /// writes its entries to the same journal.
+    pub fn open(path: &Path) -> io::Result<(Self, Option<JournalEntry>)> {
+        let contents = fs::read(path)?;
+        let invalid = |message: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not a valid journal: {}", path.display(), message),
+            )
+        };
+        if contents.len() < 8 + 20 || &contents[..4] != MAGIC {
+            return Err(invalid("it doesn't start with a journal header"));
+        }
+        let (body, checksum) = contents.split_at(contents.len() - 20);
+        if Sha1::digest(body)[..] != checksum[..] {
+            return Err(invalid("its checksum doesn't match"));
+        }
+        let version = u32::from_be_bytes(body[4..8].try_into().unwrap());
+        if version != VERSION {
+            return Err(invalid(&format!(
+                "it has version {}, but only version {} is supported",
+                version, VERSION
+            )));
+        }
+        let mut reader = Reader(&body[8..]);
+        let (parameters, entry) = read_contents(&mut reader)
+            .filter(|_| reader.0.is_empty())
+            .ok_or_else(|| invalid("it has the wrong length"))?;
+        Ok((
+            Self {
+                path: path.to_path_buf(),
+                parameters: parameters.map_err(invalid)?,
+            },
+            entry,
+        ))
+    }
+
+    pub fn parameters(&self) -> &JournalParameters {
+        &self.parameters
+    }
+
+    /// Atomically replaces the journal's entry with `entry`.
+    pub fn write_entry(&self, entry: &JournalEntry) -> io::Result<()> {
+        self.write(Some(entry))
+    }
+
+    /// Removes the journal once the pack is finished.
+    pub fn remove(self) -> io::Result<()> {
+        fs::remove_file(&self.path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn write(&self, entry: Option<&JournalEntry>) -> io::Result<()> {
+        let parameters = &self.parameters;
+        let mut contents = Vec::new();
+        contents.extend_from_slice(MAGIC);
+        contents.extend_from_slice(&VERSION.to_be_bytes());
+        contents.push(parameters.replay.prefix.bit_count as u8);
+        contents.extend_from_slice(&parameters.replay.prefix.value.to_be_bytes());
+        contents.push(parameters.replay.random_seed.is_some() as u8);
+        contents.extend_from_slice(&parameters.replay.random_seed.unwrap_or(0).to_be_bytes());
+        contents.extend_from_slice(&parameters.replay.delta_base_digest);
+        contents.push(parameters.replay.initial_entropy_length as u8);
+        contents.push(parameters.replay.first_byte_range.first);
+        contents.push(parameters.replay.first_byte_range.last);
+        contents
+            .extend_from_slice(&(parameters.replay.avoided_prefixes.len() as u32).to_be_bytes());
+        for avoided_prefix in &parameters.replay.avoided_prefixes {
+            contents.push(avoided_prefix.bit_count as u8);
+            contents.extend_from_slice(&avoided_prefix.value.to_be_bytes());
+        }
+        contents.extend_from_slice(&(parameters.batch_size as u64).to_be_bytes());
+        contents.extend_from_slice(&(parameters.delta_chain_depth as u64).to_be_bytes());
+        contents.push(parameters.merged as u8);
+        contents.push(entry.is_some() as u8);
+        if let Some(entry) = entry {
+            contents.extend_from_slice(&entry.position.length.0.to_be_bytes());
+            contents.extend_from_slice(&entry.position.object_count.to_be_bytes());
+            for count in [
+                entry.attempt_count,
+                entry.root_commit_count,
+                entry.commit_count,
+                entry.first_level_merge_commit_count,
+            ] {
+                contents.extend_from_slice(&count.to_be_bytes());
+            }
+            contents.push(entry.is_stopping as u8);
+            contents.push(entry.stopped_early as u8);
+            contents
+                .extend_from_slice(&(entry.previous_chained_commits.len() as u32).to_be_bytes());
+            for commit in &entry.previous_chained_commits {
+                let (oid, size, depth) = commit.unwrap_or_default();
+                contents.push(commit.is_some() as u8);
+                contents.extend_from_slice(&oid);
+                contents.extend_from_slice(&size.to_be_bytes());
+                contents.extend_from_slice(&depth.to_be_bytes());
+            }
+            contents.push(entry.merge_tree_levels.len() as u8);
+            for level in &entry.merge_tree_levels {
+                contents.extend_from_slice(&(level.len() as u32).to_be_bytes());
+                for oid in level {
+                    contents.extend_from_slice(oid);
+                }
+            }
+        }
+        let checksum: Sha1Oid = Sha1::digest(&contents).into();
+        contents.extend_from_slice(&checksum);
+
+        let mut temp_file_name = OsString::from(self.path.file_name().unwrap_or_default());
+        temp_file_name.push(".tmp");
+        let temp_path = self.path.with_file_name(temp_file_name);
+        let mut temp_file = File::create(&temp_path)?;
+        temp_file.write_all(&contents)?;
+        temp_file.sync_all()?;
+        fs::rename(temp_path, &self.path)
+    }
+}
+
+/// The parameters of a journal (or what's wrong with them) and its entry
+type Contents = (
+    Result<JournalParameters, &'static str>,
+    Option<JournalEntry>,
+);
+
+/// Reads the journal after its magic and version, returning `None` if it's too short.
+fn read_contents(reader: &mut Reader) -> Option<Contents> {
+    let prefix = OidPrefix {
+        bit_count: u32::from(reader.u8()?),
+        value: reader.u64()?,
+    };
+    let has_seed = reader.u8()?;
+    let seed = reader.u64()?;
+    let delta_base_digest = reader.oid()?;
+    let initial_entropy_length = usize::from(reader.u8()?);
+    let first_byte_range = FirstByteRange {
+        first: reader.u8()?,
+        last: reader.u8()?,
+    };
+    let avoided_prefixes = (0..reader.u32()?)
+        .map(|_| {
+            Some(OidPrefix {
+                bit_count: u32::from(reader.u8()?),
+                value: reader.u64()?,
+            })
+        })
+        .collect::<Option<Vec<_>>>()?;
+    let batch_size = reader.u64()? as usize;
+    let delta_chain_depth = reader.u64()? as usize;
+    let merged = reader.u8()? != 0;
+    let parameters = match has_seed {
+        0 | 1 => Ok(JournalParameters {
+            replay: ReplayParameters {
+                prefix,
+                random_seed: if has_seed == 1 { Some(seed) } else { None },
+                delta_base_digest,
+                initial_entropy_length,
+                first_byte_range,
+                avoided_prefixes,
+            },
+            batch_size,
+            delta_chain_depth,
+            merged,
+        }),
+        _ => Err("it has an unknown entropy source"),
+    };
+    if reader.u8()? == 0 {
+        return Some((parameters, None));
+    }
+    let position = PackPosition {
+        length: PackOffset(reader.u64()?),
+        object_count: reader.u32()?,
+    };
+    let attempt_count = reader.u64()?;
+    let root_commit_count = reader.u64()?;
+    let commit_count = reader.u64()?;
+    let first_level_merge_commit_count = reader.u64()?;
+    let is_stopping = reader.u8()? != 0;
+    let stopped_early = reader.u8()? != 0;
+    let previous_chained_commits = (0..reader.u32()?)
+        .map(|_| {
+            let is_some = reader.u8()? != 0;
+            let commit = (reader.oid()?, reader.u64()?, reader.u64()?);
+            Some(if is_some { Some(commit) } else { None })
+        })
+        .collect::<Option<_>>()?;
+    let merge_tree_levels = (0..reader.u8()?)
+        .map(|_| (0..reader.u32()?).map(|_| reader.oid()).collect())
+        .collect::<Option<_>>()?;
+    Some((
+        parameters,
+        Some(JournalEntry {
+            position,
+            attempt_count,
+            root_commit_count,
+            commit_count,
+            first_level_merge_commit_count,
+            is_stopping,
+            stopped_early,
+            previous_chained_commits,
+            merge_tree_levels,
+        }),
+    ))
+}
+
+/// Reads integers and OIDs from the start of a slice, returning `None` once it runs out.
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn bytes<const N: usize>(&mut self) -> Option<[u8; N]> {
+        if self.0.len() < N {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(N);
+        self.0 = rest;
+        Some(bytes.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes::<1>().map(|[byte]| byte)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes().map(u32::from_be_bytes)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.bytes().map(u64::from_be_bytes)
+    }
+
+    fn oid(&mut self) -> Option<Sha1Oid> {
+        self.bytes()
+    }
+}
@@ -1,268 +1,2742 @@
+mod abbrev;
+mod base_objects;
+mod checkpoint;
+mod demo;
+mod direct_io;
+mod dump_object;
+mod entropy;
+mod estimates;
+mod git_config;
 mod git_utils;
+mod hashers;
+mod idx_only;
+mod interrupt;
+mod journal;
+mod merge;
+mod merge_tree;
+mod merge_tree_only;
+mod options;
+mod pack_name;
+mod pack_reader;
+mod refs_map;
+mod replay;
+mod shorthash;
+mod snapshot;
+mod verify;
 
+use checkpoint::Checkpoint;
+use entropy::{entropy_hex, CounterEntropySource, EntropySource, RandomEntropySource};
+use flate2::Compression;
+use git_config::{UserConfig, FALLBACK_IDENTITY};
 use git_utils::{
-    stream_to_pack_file, GitObject, GitObjectType, PackFileDeltaInstruction, PackFileObject,
-    Sha1Oid,
+    add_alternate, add_info_pack, create_output_file, filesystem_limits, is_storage_full,
+    oid_to_hex, preallocate, repo_objects_dir, resolve_objects_dir, resume_pack_and_index,
+    stream_pack, write_loose_objects, write_pack_and_index, GitObject, GitObjectType, ObjectSize,
+    PackFile, PackFileDeltaInstruction, PackFileObject, PackObjectMetadata, PackPosition, PackSync,
+    PackWriteOptions, Sha1Oid, WriteProfile, EMPTY_TREE_OID,
 };
+use hashers::{BufferUsage, HashJob, HasherPool};
+use journal::{Journal, JournalEntry, JournalParameters};
+use merge_tree::{MergeTreeBuilder, MERGE_COMMIT_PARENT_COUNT};
+use options::{Command, EntropySourceKind, ExistingPack, Options, Preallocation};
+use pack_name::{utc_date_today, PackNameValues};
+use pack_reader::PackReader;
+use refs_map::RefsMapWriter;
+use replay::{AcceptedAttemptsReader, AcceptedAttemptsWriter, ReplayParameters};
 use sha1::{Digest, Sha1};
-use std::{convert::TryInto, iter::FusedIterator, mem::forget};
+use shorthash::{FirstByteRange, OidPrefix, PackedBoolArray, SHORTHASH_BITS, SHORTHASH_COUNT};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    env, fs,
+    fs::{File, OpenOptions},
+    io,
+    io::{BufWriter, Write},
+    iter,
+    iter::FusedIterator,
+    mem,
+    mem::forget,
+    num::NonZeroUsize,
+    ops::Range,
+    path::{Path, PathBuf},
+    rc::Rc,
+    thread,
+    time::{Duration, Instant},
+};
 
-#[repr(transparent)]
-struct PackedBoolArray {
-    data: Vec<u8>,
-}
+fn main() -> std::io::Result<()> {
+    let start = Instant::now();
+    let options = Options::from_args()?;
+    let prefix = OidPrefix::parse(options.prefix.as_deref().unwrap_or(""))?;
+    let oid_prefix_range = match &options.oid_prefix_range {
+        Some(range) => FirstByteRange::parse(range)?,
+        None => FirstByteRange::ALL,
+    };
+    let shorthash_range = oid_prefix_range.shorthash_indices();
+    let avoided_prefixes = options
+        .avoid_prefixes
+        .iter()
+        .map(|hex| OidPrefix::parse(hex))
+        .collect::<io::Result<Vec<_>>>()?;
+    let is_avoided = |oid: &Sha1Oid| {
+        prefix.matches(oid)
+            && avoided_prefixes.iter().any(|avoided_prefix| {
+                avoided_prefix
+                    .shorthash_indices()
+                    .contains(&prefix.shorthash_index(oid))
+            })
+    };
+    match &options.command {
+        Command::Generate => {}
+        Command::Merge {
+            input_paths,
+            output_path,
+        } => return merge::merge_packs(input_paths, output_path, prefix),
+        Command::IdxOnly { pack_path } => {
+            let object_count = idx_only::rebuild_index(pack_path)?;
+            println!(
+                "Wrote an index of {} objects to {}",
+                object_count,
+                pack_path.with_extension("idx").display()
+            );
+            return Ok(());
+        }
+        Command::AbbrevLengths { index_path } => {
+            return print_abbreviation_lengths(index_path);
+        }
+        Command::Lookup { oid, index_path } => {
+            return print_index_entry(index_path, oid);
+        }
+        Command::DumpObject {
+            oid,
+            pack_path,
+            index_path,
+            pretty,
+        } => {
+            return dump_object::dump_object(pack_path, index_path, oid, *pretty);
+        }
+        Command::MergeTreeOnly {
+            leaves_path,
+            parent_count,
+            output_path,
+        } => {
+            return merge_tree_only::rebuild_merge_tree(leaves_path, output_path, *parent_count);
+        }
+        Command::Demo { bits } => {
+            return demo::run_demo(*bits);
+        }
+        Command::Help => {
+            print!("{}", options::USAGE);
+            return Ok(());
+        }
+    }
+
+    let objects_dir = match &options.objects_dir {
+        // Nothing is written to the repository when only counting the objects or when streaming the
+        // pack, so there's no need for one (unless the count leaves out the objects that it has).
+        _ if (options.count_only && !options.reuse_existing) || options.stream_to.is_some() => {
+            PathBuf::new()
+        }
+        Some(objects_dir) => {
+            fs::create_dir_all(objects_dir.join("pack"))?;
+            objects_dir.clone()
+        }
+        None => resolve_objects_dir()?,
+    };
+    // Decide where the pack goes before spending days generating it. A name that depends on the
+    // finished pack has to wait, though, so the pack starts out with the usual name.
+    let mut pack_name_values = PackNameValues {
+        bits: prefix.bit_count + SHORTHASH_BITS,
+        fanout: if prefix.bit_count > 0 {
+            prefix.first_byte_range()
+        } else {
+            oid_prefix_range
+        },
+        date: utc_date_today(),
+        object_count: None,
+        checksum: None,
+    };
+    let pack_name = match &options.pack_name_template {
+        Some(template) if !template.needs_finished_pack() => template.expand(&pack_name_values),
+        _ => DEFAULT_PACK_NAME.to_string(),
+    };
+    // A `--journal` run that didn't finish leaves its pack behind along with the journal, and running it
+    // again finishes that pack rather than choosing a new one.
+    let journal_path = objects_dir
+        .join("pack")
+        .join(format!("{}.journal", pack_name));
+    let resumed_journal = if options.journal && !options.count_only && journal_path.exists() {
+        Some(Journal::open(&journal_path)?)
+    } else {
+        None
+    };
+    let (pack_path, replaces_existing_pack) =
+        if options.loose || options.count_only || options.stream_to.is_some() {
+            (PathBuf::new(), false)
+        } else if let Some((_, entry)) = &resumed_journal {
+            (journaled_pack_path(&journal_path, entry.is_some())?, false)
+        } else {
+            choose_pack_path(&objects_dir.join("pack"), &pack_name, options.existing_pack)?
+        };
+    let empty_tree = GitObject::tree(vec![]);
+    debug_assert_eq!(empty_tree.oid(), EMPTY_TREE_OID);
+    let generates_trees = matches!(options.object_type, GitObjectType::Tree);
+    // The commits are authored by the user from git's config, like the commits they make with git.
+    let user_config = UserConfig::read()?;
+    let identity = match user_config.identity() {
+        Some(identity) if !generates_trees => {
+            println!("Authoring commits as {}, from git config", identity);
+            identity
+        }
+        _ => FALLBACK_IDENTITY,
+    };
+    let entropy_length = options.entropy_prefix_len.unwrap_or(8);
+    // The generated commits have the footer after their entropy, and the delta base commit has it at its
+    // end, where their deltas copy it from. Everything after the entropy has to be hashed for every
+    // attempt, so it counts towards fitting the entropy in the final SHA1 block.
+    let footer = match &options.message_footer {
+        Some(path) => [&b"\n"[..], &fs::read(path)?].concat(),
+        None if generates_trees => [&b"\0"[..], &EMPTY_TREE_OID].concat(),
+        None => Vec::new(),
+    };
+    // In linear mode, every generated commit also has a `parent` line.
+    let parent_line_length = if options.linear {
+        parent_line(&EMPTY_TREE_OID).len()
+    } else {
+        0
+    };
+    let delta_base_commit = match &options.base_commit_file {
+        Some(path) => GitObject::commit(fs::read(path)?),
+        // With `--object-type tree`, the delta base is a tree with a single entry, an empty subdirectory
+        // whose name the generated trees extend with their entropy. Its NUL and OID are the footer.
+        None if generates_trees => GitObject::tree(b"40000 shorthash-".to_vec()),
+        None => {
+            let mut data = format!(
+                "\
+                    tree {}\n\
+                    author {identity} 1616279625 -0400\n\
+                    committer {identity} 1616279625 -0400\n\
+                    \n",
+                oid_to_hex(&EMPTY_TREE_OID),
+                identity = identity
+            )
+            .into_bytes();
+            data.extend_from_slice(match options.commit_encoding {
+                None => b"Entropy value for this commit: ",
+                // 0xbb is a right-pointing guillemet in ISO-8859-1 and the charsets based on it, but
+                // isn't valid UTF-8 on its own.
+                Some(_) => b"Entropy value for this commit \xbb ",
+            });
+            // The fallback identity leaves room for the entropy in the final SHA1 block, but other
+            // identities might not, so the message gets spaces before the entropy until it fits. (The
+            // encoding header is added below, so it's left as is with `--commit-encoding`.)
+            if options.commit_encoding.is_none() {
+                let padding_length = (0..64)
+                    .find(|&length| {
+                        entropy_fits_in_final_block(
+                            "commit",
+                            data.len() + length + parent_line_length,
+                            entropy_length + footer.len(),
+                        )
+                    })
+                    .unwrap_or(0);
+                data.resize(data.len() + padding_length, b' ');
+            }
+            GitObject::commit(data)
+        }
+    };
+    let delta_base_commit = match &options.commit_encoding {
+        Some(charset) => with_encoding(&delta_base_commit, charset).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--commit-encoding needs a delta base commit with a blank line before its message",
+            )
+        })?,
+        None => delta_base_commit,
+    };
+    // With `--tree-from-dir`, the commits point to a snapshot of a directory instead. Tree OIDs all have
+    // the same length, so this doesn't change the length of the delta base commit, or how much of it ends
+    // up in the final SHA1 block.
+    let mut snapshot = match &options.tree_from_dir {
+        Some(dir) => {
+            let snapshot = snapshot::snapshot_dir(dir)?;
+            println!(
+                "Snapshotted {} as tree {} ({} objects)",
+                dir.display(),
+                oid_to_hex(&snapshot.root_tree_oid),
+                snapshot.objects.len()
+            );
+            Some(snapshot)
+        }
+        None => None,
+    };
+    let delta_base_commit = match &snapshot {
+        Some(snapshot) => {
+            with_tree(&delta_base_commit, &snapshot.root_tree_oid).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "--tree-from-dir needs a delta base commit that starts with a `tree` line",
+                )
+            })?
+        }
+        None => delta_base_commit,
+    };
+    // This comes after `--tree-from-dir`, since the tree is part of what gets hashed.
+    let delta_base_commit = match &options.seed_base_entropy {
+        Some(shorthash) => {
+            let target = OidPrefix::parse(shorthash)?;
+            // The nonce becomes part of every generated commit too, so pick a length that keeps the
+            // entropy in the final SHA1 block. At least 8 hex digits leaves room for far more attempts
+            // than a 7-character match needs, and one of the next 32 lengths always fits.
+            let nonce_length = (8..=40)
+                .find(|&length| {
+                    entropy_fits_in_final_block(
+                        delta_base_commit.object_type.type_name(),
+                        delta_base_commit.data.len() + length + parent_line_length,
+                        entropy_length + footer.len(),
+                    )
+                })
+                .unwrap_or(8);
+            println!(
+                "Searching for a delta base commit whose OID starts with {} (about {} attempts)",
+                shorthash,
+                1u64 << (4 * shorthash.len())
+            );
+            let (commit, attempts) =
+                with_vanity_shorthash(&delta_base_commit, &footer, target, nonce_length)
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::NotFound,
+                            format!(
+                            "no delta base commit starts with {} after trying every {}-digit nonce",
+                            shorthash, nonce_length
+                        ),
+                        )
+                    })?;
+            println!(
+                "Found delta base commit {} after {} attempts",
+                oid_to_hex(&commit.oid()),
+                attempts
+            );
+            commit
+        }
+        None if footer.is_empty() => delta_base_commit,
+        None => GitObject {
+            data: [&delta_base_commit.data[..], &footer].concat(),
+            object_type: delta_base_commit.object_type,
+        },
+    };
+    if delta_base_commit.data.len() > MAX_DELTA_BASE_COMMIT_LENGTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "delta base commit is {} bytes long, but deltas can only copy {} bytes from it",
+                delta_base_commit.data.len(),
+                MAX_DELTA_BASE_COMMIT_LENGTH
+            ),
+        ));
+    }
+    // With `--tree-count`, the generated commits cycle through several delta base commits, which are
+    // copies of the delta base commit that point to different trees.
+    let mut subdirectory_trees = subdirectory_trees(options.tree_count);
+    let delta_base_commits = iter::once(Some(delta_base_commit.clone()))
+        .chain(
+            subdirectory_trees
+                .iter()
+                .map(|tree| with_tree(&delta_base_commit, &tree.oid())),
+        )
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--tree-count needs a delta base commit that starts with a `tree` line",
+            )
+        })?;
+    // The delta base commits are written no matter what, so they can't be left out like the other
+    // commits with an avoided shorthash.
+    if let Some(commit) = delta_base_commits
+        .iter()
+        .find(|commit| is_avoided(&commit.oid()))
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "delta base commit {} starts with a prefix from --avoid-prefix. --seed-base-entropy can \
+                give it a different OID.",
+                oid_to_hex(&commit.oid())
+            ),
+        ));
+    }
+    // Objects from `--base-objects` that are already in the pack for some other reason are left out.
+    let mut base_objects = match &options.base_objects {
+        Some(path) => {
+            let mut existing_oids = iter::once(EMPTY_TREE_OID)
+                .chain(
+                    snapshot
+                        .iter()
+                        .flat_map(|snapshot| snapshot.objects.iter().map(GitObject::oid)),
+                )
+                .chain(subdirectory_trees.iter().map(GitObject::oid))
+                .chain(delta_base_commits.iter().map(GitObject::oid))
+                .collect::<HashSet<_>>();
+            let base_objects = base_objects::read_manifest(path, &mut existing_oids)?;
+            println!(
+                "Read {} base objects from {}",
+                base_objects.len(),
+                path.display()
+            );
+            base_objects
+        }
+        None => Vec::new(),
+    };
+    // With `--reuse-existing`, the trees, blobs and base objects that the object directory already has
+    // are left out. The delta base commits are written regardless (see `Options::reuse_existing`).
+    let mut empty_tree = vec![empty_tree];
+    if options.reuse_existing {
+        let index_paths = pack_reader::pack_index_paths(&objects_dir.join("pack"))?
+            .into_iter()
+            // A pack that's about to be replaced can't provide them.
+            .filter(|path| !replaces_existing_pack || *path != pack_path.with_extension("idx"))
+            .collect::<Vec<_>>();
+        let mut reused_count =
+            remove_existing_objects(&mut empty_tree, &objects_dir, &index_paths)?
+                + remove_existing_objects(&mut subdirectory_trees, &objects_dir, &index_paths)?
+                + remove_existing_objects(&mut base_objects, &objects_dir, &index_paths)?;
+        if let Some(snapshot) = &mut snapshot {
+            reused_count +=
+                remove_existing_objects(&mut snapshot.objects, &objects_dir, &index_paths)?;
+        }
+        println!(
+            "Reusing {} trees, blobs and base objects that {} already has",
+            reused_count,
+            objects_dir.display()
+        );
+    }
+    let type_name = delta_base_commit.object_type.type_name();
+    let hashed_base_length = delta_base_commit.data.len() - footer.len() + parent_line_length;
+    let last_block_length =
+        final_sha1_block_length(type_name, hashed_base_length, entropy_length + footer.len());
+    let entropy_fits =
+        entropy_fits_in_final_block(type_name, hashed_base_length, entropy_length + footer.len());
+    if options.entropy_prefix_len.is_some() && !entropy_fits {
+        let widest_fitting_length = (1..=16).rev().find(|&length| {
+            entropy_fits_in_final_block(type_name, hashed_base_length, length + footer.len())
+        });
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "the delta base commit leaves {} bytes in its final SHA1 block, so {} characters of \
+                entropy don't fit in it and hashing would be twice as slow. {}",
+                last_block_length,
+                entropy_length,
+                match widest_fitting_length {
+                    Some(length) => format!("At most {} characters fit.", length),
+                    None => "Adding or removing a few bytes from the base commit would fix this."
+                        .to_string(),
+                }
+            ),
+        ));
+    } else if options.base_commit_file.is_none()
+        && options.commit_encoding.is_none()
+        && options.message_footer.is_none()
+    {
+        assert!(
+            entropy_fits,
+            "suboptimal commit length {}; hashing would be twice as slow",
+            last_block_length
+        );
+    } else if !entropy_fits && !footer.is_empty() {
+        eprintln!(
+            "warning: the {}-byte message footer is hashed along with the entropy for every attempt, \
+            so hashing will be at least twice as slow.",
+            footer.len()
+        );
+    } else if !entropy_fits {
+        eprintln!(
+            "warning: the delta base commit leaves {} bytes in its final SHA1 block, so hashing \
+            will be twice as slow. Adding or removing a few bytes from the base commit would fix this.",
+            last_block_length
+        );
+    }
+
+    if oid_prefix_range == FirstByteRange::ALL {
+        // Only one in every 2**prefix_bits attempts has the right prefix.
+        let attempts_per_match = 2f64.powi(prefix.bit_count as i32);
+        println!(
+            "Expecting about {:.0} commit attempts in total ({:.0} to reach the endgame)",
+            estimates::expected_attempts(SHORTHASH_BITS) * attempts_per_match,
+            estimates::expected_attempts_for_coverage(
+                SHORTHASH_BITS,
+                ENDGAME_THRESHOLD as f64 / SHORTHASH_COUNT as f64
+            ) * attempts_per_match
+        );
+    } else {
+        let expected_attempts =
+            estimates::expected_attempts_for_subset(SHORTHASH_BITS, shorthash_range.len());
+        println!(
+            "Expecting about {:.0} commit attempts in total. Only {:.1}% of attempts start with \
+            {:02x}-{:02x}, so that's {:.0}% of the attempts for a run over every shorthash, and {:.1} \
+            times as many as covering {} shorthashes would take if every attempt counted.",
+            expected_attempts,
+            100.0 * shorthash_range.len() as f64 / SHORTHASH_COUNT as f64,
+            oid_prefix_range.first,
+            oid_prefix_range.last,
+            100.0 * expected_attempts / estimates::expected_attempts(SHORTHASH_BITS),
+            SHORTHASH_COUNT as f64 / shorthash_range.len() as f64,
+            shorthash_range.len()
+        );
+    }
+
+    let mut found_shorthashes = PackedBoolArray::default();
+    if let Some(path) = &options.resume_from_idx {
+        if !pack_reader::verify_idx_trailer(&mut io::BufReader::new(File::open(path)?))? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} doesn't match its checksum", path.display()),
+            ));
+        }
+        let object_count = pack_reader::for_each_index_oid(path, |oid| {
+            if prefix.matches(oid) {
+                found_shorthashes.set(prefix.shorthash_index(oid), true)
+            }
+        })?;
+        println!(
+            "Skipping {} shorthashes already used by the {} objects in {}",
+            found_shorthashes.count_set(),
+            object_count,
+            path.display()
+        );
+    }
+    let mut resumed_attempt_count = 0;
+    if let Some(path) = &options.resume_from_checkpoint {
+        let checkpoint = Checkpoint::read(path)?;
+        if checkpoint.prefix != prefix {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} was written by a run with a different --prefix",
+                    path.display()
+                ),
+            ));
+        }
+        found_shorthashes = checkpoint.found_shorthashes;
+        resumed_attempt_count = checkpoint.attempt_count;
+        println!(
+            "Skipping {} shorthashes and {} attempts recorded in {}",
+            found_shorthashes.count_set(),
+            resumed_attempt_count,
+            path.display()
+        );
+    }
+
+    // The shorthashes outside of the range are marked as found up front, so that the generator skips
+    // commits outside of the range just like it skips commits with duplicate shorthashes.
+    found_shorthashes.set_range(0..shorthash_range.start);
+    found_shorthashes.set_range(shorthash_range.end..SHORTHASH_COUNT);
+    // The same goes for the shorthashes reserved with `--avoid-prefix`, which never get a commit.
+    if !avoided_prefixes.is_empty() {
+        let found_count = found_shorthashes.count_set();
+        for avoided_prefix in &avoided_prefixes {
+            found_shorthashes.set_range(avoided_prefix.shorthash_indices());
+        }
+        println!(
+            "Leaving {} shorthashes without a commit for --avoid-prefix",
+            found_shorthashes.count_set() - found_count
+        );
+    }
+    // The empty tree is in the pack anyway, so with `--object-type tree` it covers its own shorthash.
+    if generates_trees
+        && prefix.matches(&EMPTY_TREE_OID)
+        && shorthash_range.contains(&prefix.shorthash_index(&EMPTY_TREE_OID))
+    {
+        found_shorthashes.set(prefix.shorthash_index(&EMPTY_TREE_OID), true);
+    }
+
+    // Every commit takes at least one attempt, so `--max-attempts` also limits the number of commits.
+    let max_commit_count = ((SHORTHASH_COUNT - found_shorthashes.count_set())
+        .min(options.commit_count_cap) as u64)
+        .min(options.max_attempts.unwrap_or(u64::MAX));
+    let leaf_compression = options.leaf_compression.unwrap_or(options.compression);
+    let merge_compression = options.merge_compression.unwrap_or(options.compression);
+    let (mut estimated_pack_size, estimated_index_size) = estimates::estimate_output_sizes(
+        max_commit_count,
+        MERGE_COMMIT_PARENT_COUNT as u64,
+        leaf_compression != Compression::none(),
+        merge_compression != Compression::none(),
+    );
+    // The snapshot is usually negligible, but it could be anything. (Its objects are compressed, so this
+    // overestimates a little.)
+    if let Some(snapshot) = &snapshot {
+        estimated_pack_size += snapshot
+            .objects
+            .iter()
+            .map(|object| object.data.len() as u64)
+            .sum::<u64>();
+    }
+    estimated_pack_size += base_objects
+        .iter()
+        .map(|object| object.data.len() as u64)
+        .sum::<u64>();
+    if !options.loose && !options.count_only && options.stream_to.is_none() {
+        println!(
+            "Expecting a packfile of about {} and an index file of {}",
+            format_size(estimated_pack_size),
+            format_size(estimated_index_size)
+        );
+        check_pack_fits(
+            &objects_dir,
+            &options,
+            estimated_pack_size,
+            estimated_index_size,
+        )?;
+    }
+
+    let replayed = match &options.replay {
+        Some(path) => Some(AcceptedAttemptsReader::open(path)?),
+        None => None,
+    };
+    let random_seed = match options.entropy_source {
+        EntropySourceKind::Counter => None,
+        // A replay picks up the seed of the recorded run, unless it's given.
+        EntropySourceKind::Random => Some(match (options.seed, &replayed) {
+            (Some(seed), _) => seed,
+            (None, Some((_, parameters))) => parameters.random_seed.unwrap_or(0),
+            // So does a run resumed from its journal.
+            (None, None) => resumed_journal
+                .as_ref()
+                .and_then(|(journal, _)| journal.parameters().replay.random_seed)
+                .unwrap_or_else(RandomEntropySource::random_seed),
+        }),
+    };
+    let mut sorted_avoided_prefixes = avoided_prefixes.clone();
+    sorted_avoided_prefixes
+        .sort_by_key(|avoided_prefix| (avoided_prefix.bit_count, avoided_prefix.value));
+    let replay_parameters = ReplayParameters {
+        prefix,
+        random_seed,
+        delta_base_digest: delta_base_commits
+            .iter()
+            .fold(Sha1::new(), |hasher, commit| hasher.chain(commit.oid()))
+            .finalize()
+            .into(),
+        initial_entropy_length: entropy_length,
+        first_byte_range: oid_prefix_range,
+        avoided_prefixes: sorted_avoided_prefixes,
+    };
+    if let (Some((_, recorded_parameters)), Some(path)) = (&replayed, &options.replay) {
+        if let Some(difference) = recorded_parameters.difference(&replay_parameters) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} was recorded by a run with {}",
+                    path.display(),
+                    difference
+                ),
+            ));
+        }
+    }
+    let journal_parameters = JournalParameters {
+        replay: replay_parameters.clone(),
+        batch_size: options.batch_size,
+        delta_chain_depth: options.delta_chain_depth,
+        merged: !options.leaves_only,
+    };
+    let (journal, journal_entry) = match resumed_journal {
+        Some((journal, entry)) => {
+            if let Some(difference) = journal.parameters().difference(&journal_parameters) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{} was written by a run with {}",
+                        journal.path().display(),
+                        difference
+                    ),
+                ));
+            }
+            (Some(journal), entry)
+        }
+        None => (None, None),
+    };
+    // The objects that the resumed pack already has, which the generator won't generate again
+    let journaled_objects = match &journal_entry {
+        Some(entry) => {
+            let objects =
+                read_journaled_objects(&pack_path, entry, prefix, &mut found_shorthashes)?;
+            println!(
+                "Finishing {} from its journal, after its first {} objects and {} attempts",
+                pack_path.display(),
+                objects.len(),
+                entry.attempt_count
+            );
+            Some(objects)
+        }
+        None => {
+            if journal.is_some() {
+                println!(
+                    "Starting {} over, since its journal doesn't have an entry yet",
+                    pack_path.display()
+                );
+            }
+            None
+        }
+    };
+    let mut entropy_source: Box<dyn EntropySource> = match random_seed {
+        None => Box::new(CounterEntropySource::default()),
+        Some(seed) => {
+            println!("Using random entropy with seed {}", seed);
+            Box::new(RandomEntropySource::new(seed))
+        }
+    };
+
+    // The generator draws the entropy for its first attempt when it's created. A run resumed from its
+    // journal carries on from the journal's attempt, although its attempt limit still counts from the
+    // start of the original run.
+    let first_attempt = journal_entry
+        .as_ref()
+        .map_or(resumed_attempt_count, |entry| entry.attempt_count);
+    entropy_source.skip(first_attempt);
+    let mut deltified_generator = DeltifiedCommitGenerator {
+        delta_chain_depth: options.delta_chain_depth,
+        attempt_count: first_attempt,
+        delta_base_index: (first_attempt % delta_base_commits.len() as u64) as usize,
+        deadline: options
+            .time_limit
+            .map(|time_limit| Instant::now() + time_limit),
+        attempt_limit: options.max_attempts.map_or(u64::MAX, |max_attempts| {
+            resumed_attempt_count.saturating_add(max_attempts)
+        }),
+        ..DeltifiedCommitGenerator::new(
+            delta_base_commits.clone(),
+            found_shorthashes,
+            prefix,
+            shorthash_range.clone(),
+            options.commit_count_cap,
+            entropy_source,
+            options.linear,
+        )
+    };
+    deltified_generator.set_initial_entropy_length(entropy_length);
+    deltified_generator.set_footer_length(footer.len());
+    // The thread that writes the pack also goes through every hashed attempt, in order, so it keeps a core
+    // of its own rather than competing with the hashing threads for one.
+    deltified_generator.hasher_thread_count = match options.threads {
+        Some(thread_count) => thread_count,
+        None if options.linear || options.replay.is_some() => 0,
+        None => thread::available_parallelism().map_or(1, NonZeroUsize::get) - 1,
+    };
+    deltified_generator.hasher_channel_depth = options.channel_depth;
+    if deltified_generator.hasher_thread_count > 0 && !options.count_only {
+        println!(
+            "Hashing on {} threads, and writing the pack on another (with at most {} of hashed attempts \
+            waiting for it)",
+            deltified_generator.hasher_thread_count,
+            format_size(
+                BufferUsage::limit(
+                    deltified_generator.hasher_thread_count,
+                    options.channel_depth
+                )
+                .bytes as u64
+            )
+        );
+    }
+    if let Some(snapshot) = &snapshot {
+        deltified_generator
+            .merge_tree
+            .set_tree(snapshot.root_tree_oid);
+    }
+    deltified_generator.merge_tree.set_identity(identity);
+    if let Some(entry) = &journal_entry {
+        if !deltified_generator.restore_journal_entry(entry) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} doesn't match the generator's delta bases or merge tree",
+                    journal_path.display()
+                ),
+            ));
+        }
+    }
+
+    // Knowing the number of objects before any of them are generated lets the pack be hashed as it's
+    // written (and a streamed pack's header needs it). Runs with a time limit might stop early, though.
+    let snapshot_tree_count = snapshot.as_ref().map_or(0, |snapshot| {
+        snapshot
+            .objects
+            .iter()
+            .filter(|object| matches!(object.object_type, GitObjectType::Tree))
+            .count()
+    });
+    let tree_count = empty_tree.len() + subdirectory_trees.len() + snapshot_tree_count;
+    let blob_count = snapshot
+        .as_ref()
+        .map_or(0, |snapshot| snapshot.objects.len() - snapshot_tree_count);
+    let delta_base_commit_count = delta_base_commits.len();
+    let generated_type_name = delta_base_commits[0].object_type.type_name();
+    let base_object_count = base_objects.len();
+    let object_counts = |generated| ObjectCounts {
+        generated,
+        generated_type_name,
+        delta_base_commits: delta_base_commit_count,
+        trees: tree_count,
+        blobs: blob_count,
+        base_objects: base_object_count,
+    };
+    let mut total_object_count = None;
+    // How many commits a sample keeps depends on which ones are delta bases of the others.
+    if options.count_only
+        || (!options.loose
+            && options.time_limit.is_none()
+            && options.max_attempts.is_none()
+            && options.sample.is_none()
+            && journal_entry.is_none())
+    {
+        let mut generated = deltified_generator.count_generated_objects(options.batch_size);
+        // Leaving out the merge commits doesn't change which commits are generated.
+        if options.leaves_only {
+            generated.first_level_merge_commits = 0;
+            generated.has_top_level_merge_commit = false;
+        }
+        let counts = object_counts(generated);
+        if options.count_only {
+            counts.print();
+            return Ok(());
+        }
+        total_object_count = Some(counts.total() as u32);
+    }
 
-impl PackedBoolArray {
-    const MASKS: [u8; 8] = [1, 2, 4, 8, 16, 32, 64, 128];
+    if let Some(path) = &options.emit_refs_map {
+        let mut refs_map = RefsMapWriter::create(path, options.refs_map_format)?;
+        // The delta base commits are found commits too, if they're in range (see
+        // `DeltifiedCommitGenerator::new`).
+        for commit in &delta_base_commits {
+            let oid = commit.oid();
+            if prefix.matches(&oid) && shorthash_range.contains(&prefix.shorthash_index(&oid)) {
+                refs_map.record(prefix.shorthash_index(&oid), &oid)?;
+            }
+        }
+        deltified_generator.refs_map = Some(refs_map);
+    }
+    if let Some(path) = &options.record_accepted {
+        deltified_generator.accepted_attempts =
+            Some(AcceptedAttemptsWriter::create(path, &replay_parameters)?);
+    }
+    deltified_generator.replay = replayed.map(|(replay, _)| replay);
+    let pack_sync = Rc::new(PackSync::default());
+    if options.journal {
+        deltified_generator.journal = Some(match journal {
+            Some(journal) => journal,
+            None => Journal::create(&journal_path, journal_parameters)?,
+        });
+        deltified_generator.pack_sync = Rc::clone(&pack_sync);
+        deltified_generator.journal_interval = options
+            .journal_interval
+            .unwrap_or(journal::DEFAULT_INTERVAL);
+    }
+
+    // A streamed pack can't be finished early, since its header already has the number of objects.
+    if options.stream_to.is_none() {
+        interrupt::install();
+    }
+    let generated_objects: Box<dyn Iterator<Item = PackFileObject>> =
+        match (options.sample, options.leaves_only) {
+            (Some(step), _) => Box::new(deltified_generator.sample(options.batch_size, step)),
+            (None, true) => Box::new(deltified_generator.leaves_only(options.batch_size)),
+            (None, false) => Box::new(deltified_generator.batches(options.batch_size)),
+        };
+    let objects = empty_tree
+        .into_iter()
+        .chain(snapshot.into_iter().flat_map(|snapshot| snapshot.objects))
+        .chain(subdirectory_trees)
+        .chain(base_objects)
+        .chain(delta_base_commits)
+        // A pack resumed from its journal already has these.
+        .filter(|_| journal_entry.is_none())
+        .map(PackFileObject::Raw)
+        .chain(generated_objects);
 
-    #[inline(always)]
-    fn offset(index: usize) -> usize {
-        index / 8
+    if options.loose {
+        let object_count = write_loose_objects(&objects_dir, objects)?;
+        println!(
+            "Wrote {} loose objects to {}",
+            object_count,
+            objects_dir.display()
+        );
+        object_counts(deltified_generator.yielded_object_counts).print();
+        save_progress(&options, prefix, &deltified_generator, false)?;
+        finish_refs_map(&options, &mut deltified_generator)?;
+        finish_accepted_attempts(&options, &mut deltified_generator)?;
+        return register_alternates(&options.alternate_for, &objects_dir);
     }
 
-    #[inline(always)]
-    fn mask(index: usize) -> u8 {
-        Self::MASKS[index % 8]
+    // Report the size of the packfile once per GiB, since writing it can be slow on some filesystems.
+    let write_start = Instant::now();
+    let setup_time = write_start - start;
+    let mut next_reported_size = 1 << 30;
+    let mut report_bytes_written = |bytes_written: u64| {
+        if bytes_written >= next_reported_size {
+            next_reported_size = ((bytes_written >> 30) + 1) << 30;
+            println!(
+                "packfile size so far: {} GiB ({:.1} MiB/s)",
+                bytes_written >> 30,
+                bytes_written as f64 / (1 << 20) as f64 / write_start.elapsed().as_secs_f64()
+            );
+        }
+    };
+    let mut write_profile = WriteProfile::default();
+    let write_options = PackWriteOptions {
+        on_bytes_written: Some(&mut report_bytes_written),
+        profile: if options.profile {
+            Some(&mut write_profile)
+        } else {
+            None
+        },
+        big_offset_threshold: options.big_offset_threshold,
+        object_count: total_object_count,
+        direct_io: options.direct_io,
+        compression: Some(merge_compression),
+        delta_compression: Some(leaf_compression),
+        keep_metadata: options.dump_metadata.is_some(),
+        sync: if options.journal {
+            Some(&pack_sync)
+        } else {
+            None
+        },
+        ..PackWriteOptions::default()
+    };
+
+    if let Some(path) = &options.stream_to {
+        // Opening a FIFO blocks until something opens it for reading.
+        println!("Waiting for a reader to open {}", path.display());
+        let output = fs::File::create(path)?;
+        // --stream-to can't be used with --time-limit or --max-attempts, so the count is always known.
+        let object_count = total_object_count.expect("the object count is known");
+        stream_pack(output, object_count, objects, write_options)?;
+        println!("Wrote {} objects to {}", object_count, path.display());
+        object_counts(deltified_generator.yielded_object_counts).print();
+        write_checkpoint(
+            options.write_checkpoint.as_deref(),
+            prefix,
+            &deltified_generator,
+        )?;
+        finish_refs_map(&options, &mut deltified_generator)?;
+        finish_accepted_attempts(&options, &mut deltified_generator)?;
+        if options.profile {
+            print_profile(
+                setup_time,
+                &write_profile,
+                write_start.elapsed(),
+                start.elapsed(),
+            );
+        }
+        return Ok(());
     }
 
-    fn get(&self, index: usize) -> bool {
-        self.data[Self::offset(index)] & Self::mask(index) != 0
+    // When replacing an existing pack, the new one is written under temporary names that git ignores,
+    // and only moved into place once it's complete.
+    let (written_pack_path, written_index_path) = if replaces_existing_pack {
+        (
+            pack_path.with_extension("pack.tmp"),
+            pack_path.with_extension("idx.tmp"),
+        )
+    } else {
+        (pack_path.clone(), pack_path.with_extension("idx"))
+    };
+    let preallocation_length =
+        options
+            .preallocate
+            .as_ref()
+            .map(|preallocation| match preallocation {
+                Preallocation::Auto => estimated_pack_size,
+                Preallocation::Bytes(length) => *length,
+            });
+    let written = match (&journal_entry, journaled_objects) {
+        (Some(entry), Some(journaled_objects)) => resume_pack(
+            &written_pack_path,
+            &written_index_path,
+            entry.position,
+            journaled_objects,
+            objects,
+            write_options,
+        ),
+        _ => write_pack(
+            &written_pack_path,
+            &written_index_path,
+            objects,
+            write_options,
+            preallocation_length,
+        ),
+    };
+    let pack_file = match written {
+        Ok(pack_file) => pack_file,
+        Err(err) => {
+            if options.cleanup_on_error && is_storage_full(&err) {
+                // Without the pack, there's nothing for the journal to resume.
+                let journal_path = Some(&journal_path).filter(|_| options.journal);
+                for path in [&written_pack_path, &written_index_path]
+                    .iter()
+                    .copied()
+                    .chain(journal_path)
+                {
+                    match fs::remove_file(path) {
+                        Ok(()) => println!("Removed the partial {}", path.display()),
+                        Err(remove_err) if remove_err.kind() == io::ErrorKind::NotFound => {}
+                        Err(remove_err) => eprintln!(
+                            "warning: couldn't remove the partial {}: {}",
+                            path.display(),
+                            remove_err
+                        ),
+                    }
+                }
+            }
+            return Err(err);
+        }
+    };
+    if replaces_existing_pack {
+        // Remove the old index first, so that git never sees the new packfile with the old index (or the
+        // other way around). Until the new index is in place, git just doesn't see the pack at all.
+        match fs::remove_file(pack_path.with_extension("idx")) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        fs::rename(&written_pack_path, &pack_path)?;
+        fs::rename(&written_index_path, pack_path.with_extension("idx"))?;
+        println!("Replaced the existing pack at {}", pack_path.display());
     }
+    finish_journal(&mut deltified_generator)?;
 
-    fn set(&mut self, index: usize, value: bool) {
-        if value {
-            self.data[Self::offset(index)] |= Self::mask(index);
+    object_counts(deltified_generator.yielded_object_counts).print();
+    save_progress(&options, prefix, &deltified_generator, true)?;
+    finish_refs_map(&options, &mut deltified_generator)?;
+    finish_accepted_attempts(&options, &mut deltified_generator)?;
+    if let Some(path) = &options.dump_metadata {
+        dump_metadata(path, &pack_file)?;
+    }
+    if let Some(path) = &options.fanout_out {
+        dump_fanout(path, pack_file.fanout())?;
+    }
+    if let Some(mode) = options.verify_after {
+        println!("Verifying {}", pack_path.display());
+        match verify::verify_pack(&pack_path, &pack_path.with_extension("idx"), mode) {
+            Ok(checked_count) => println!(
+                "Verification passed: read back {} of {} objects",
+                checked_count,
+                pack_file.fanout()[255]
+            ),
+            Err(err) => {
+                println!("Verification FAILED: {}", err);
+                return Err(err);
+            }
+        }
+    }
+    let pack_path = if options.checksum_name {
+        let named_pack_path =
+            pack_path.with_file_name(format!("pack-{}.pack", oid_to_hex(pack_file.checksum())));
+        // The packfile is renamed first, since git only looks for packs that have an index file.
+        fs::rename(&pack_path, &named_pack_path)?;
+        fs::rename(
+            pack_path.with_extension("idx"),
+            named_pack_path.with_extension("idx"),
+        )?;
+        println!("Renamed the pack to {}", named_pack_path.display());
+        named_pack_path
+    } else if let Some(template) = options
+        .pack_name_template
+        .as_ref()
+        .filter(|template| template.needs_finished_pack())
+    {
+        pack_name_values.object_count = Some(pack_file.fanout()[255]);
+        pack_name_values.checksum = Some(*pack_file.checksum());
+        let (named_pack_path, _) = choose_pack_path(
+            &objects_dir.join("pack"),
+            &template.expand(&pack_name_values),
+            options.existing_pack,
+        )
+        .map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("{} The finished pack is at {}.", err, pack_path.display()),
+            )
+        })?;
+        // As with `--checksum-name`, the packfile goes first.
+        fs::rename(&pack_path, &named_pack_path)?;
+        fs::rename(
+            pack_path.with_extension("idx"),
+            named_pack_path.with_extension("idx"),
+        )?;
+        println!("Renamed the pack to {}", named_pack_path.display());
+        named_pack_path
+    } else {
+        pack_path
+    };
+    if options.update_info_packs {
+        if add_info_pack(&objects_dir, &pack_path)? {
+            println!(
+                "Listed {} in {}",
+                pack_path.display(),
+                objects_dir.join("info").join("packs").display()
+            );
         } else {
-            self.data[Self::offset(index)] &= !Self::mask(index);
+            println!(
+                "{} is already listed in {}",
+                pack_path.display(),
+                objects_dir.join("info").join("packs").display()
+            );
         }
     }
 
-    #[inline(always)]
-    fn hash_to_shorthash_index(hash: &Sha1Oid) -> usize {
-        (u32::from_be_bytes(hash[0..4].try_into().unwrap()) >> 4) as usize
+    register_alternates(&options.alternate_for, &objects_dir)?;
+    if options.profile {
+        print_profile(
+            setup_time,
+            &write_profile,
+            write_start.elapsed(),
+            start.elapsed(),
+        );
     }
+
+    // With --dump-metadata, the metadata is kept until it's written out. Avoid running its destructor,
+    // since it takes a very long time to clean up and we're about to exit the process anyway.
+    forget(pack_file);
+
+    Ok(())
 }
 
-impl Default for PackedBoolArray {
-    fn default() -> Self {
-        Self { data: vec![0; 1 << 25] }
+/// Removes the objects that `objects_dir` already has (see `pack_reader::has_object`) from `objects`,
+/// returning how many were removed.
+fn remove_existing_objects(
+    objects: &mut Vec<GitObject>,
+    objects_dir: &Path,
+    index_paths: &[PathBuf],
+) -> io::Result<usize> {
+    let original_count = objects.len();
+    let mut new_objects = Vec::with_capacity(original_count);
+    for object in objects.drain(..) {
+        if !pack_reader::has_object(objects_dir, index_paths, &object.oid())? {
+            new_objects.push(object);
+        }
     }
+    *objects = new_objects;
+    Ok(original_count - objects.len())
 }
 
-fn main() -> std::io::Result<()> {
-    let empty_tree = GitObject {
-        object_type: GitObjectType::Tree,
-        data: vec![],
-    };
-    let delta_base_commit = GitObject {
-        object_type: GitObjectType::Commit,
-        data: b"\
-            tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
-            author Teddy Katz <teddy.katz@gmail.com> 1616279625 -0400\n\
-            committer Teddy Katz <teddy.katz@gmail.com> 1616279625 -0400\n\
-            \n\
-            Entropy value for this commit: "
-            .to_vec(),
-    };
-    let last_block_length = (delta_base_commit.data.len()
-        + format!("commit {}\0", delta_base_commit.data.len()).len())
-        % 64;
-    assert!(
-        (0..=47).contains(&last_block_length),
-        "suboptimal commit length {}; hashing would be twice as slow",
-        last_block_length
+/// Registers `objects_dir` as an alternate object directory in each of the given repositories.
+fn register_alternates(repo_paths: &[PathBuf], objects_dir: &Path) -> io::Result<()> {
+    for repo_path in repo_paths {
+        let repo_objects_dir = repo_objects_dir(repo_path)?;
+        if add_alternate(&repo_objects_dir, objects_dir)? {
+            println!(
+                "Registered {} as an alternate object directory of {}",
+                objects_dir.display(),
+                repo_path.display()
+            );
+        } else {
+            println!(
+                "{} is already an alternate object directory of {}",
+                objects_dir.display(),
+                repo_path.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Prints where an OID is in a pack index, for spot-checking a generated pack without `git cat-file`.
+fn print_index_entry(index_path: &Path, oid: &Sha1Oid) -> io::Result<()> {
+    let entry = pack_reader::lookup_index_entry(index_path, oid)?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} isn't in {}", oid_to_hex(oid), index_path.display()),
+        )
+    })?;
+    println!(
+        "{} is object {} in {}",
+        oid_to_hex(oid),
+        entry.position,
+        index_path.display()
     );
-    let mut found_shorthashes = PackedBoolArray::default();
-    let delta_base_commit_oid = delta_base_commit.oid();
-    found_shorthashes.set(
-        PackedBoolArray::hash_to_shorthash_index(&delta_base_commit_oid),
-        true,
+    match entry.big_offset_index {
+        Some(big_offset_index) => println!(
+            "offset: {} (entry {} of the 8-byte offset table)",
+            entry.metadata.offset, big_offset_index
+        ),
+        None => println!("offset: {}", entry.metadata.offset),
+    }
+    println!("CRC32: {:08x}", entry.metadata.crc32);
+    Ok(())
+}
+
+/// Prints the distribution of the abbreviation lengths needed to identify the objects in an index.
+fn print_abbreviation_lengths(index_path: &Path) -> io::Result<()> {
+    let counts = abbrev::abbreviation_lengths(index_path)?;
+    let object_count: usize = counts.iter().sum();
+    println!(
+        "Shortest unambiguous abbreviations of the {} objects in {}:",
+        object_count,
+        index_path.display()
     );
+    for (length, count) in counts.iter().enumerate().filter(|(_, &count)| count != 0) {
+        println!(
+            "{:>2} characters: {} objects ({:.4}%)",
+            length,
+            count,
+            100.0 * *count as f64 / object_count as f64
+        );
+    }
+    let lengths = || (0..counts.len()).filter(|&length| counts[length] != 0);
+    if let (Some(shortest), Some(longest)) = (lengths().next(), lengths().next_back()) {
+        println!(
+            "The shortest unique prefix in the index is {} characters long, and every object can be \
+            identified by {} characters.",
+            shortest, longest
+        );
+    }
+    Ok(())
+}
 
-    let deltified_generator = DeltifiedCommitGenerator {
-        delta_base_commit: delta_base_commit.clone(),
-        delta_base_commit_oid,
-        found_shorthashes,
-        root_commit_oid_buffer: vec![delta_base_commit_oid],
-        merge_commit_oid_buffer: vec![],
-        delta_base_commit_extension_length: 8,
-        delta_base_commit_intermediate_sha1_state: Sha1::new()
-            .chain(format!("commit {}\0", delta_base_commit.data.len() + 8).as_bytes())
-            .chain(&delta_base_commit.data),
-        entropy_specifier: 0,
-        commit_count_cap: usize::MAX,
-        is_finished: false,
-    };
-
-    let pack_file = stream_to_pack_file(
-        vec![
-            PackFileObject::Raw(empty_tree),
-            PackFileObject::Raw(delta_base_commit),
-        ]
-        .into_iter()
-        .chain(deltified_generator),
-    )?;
+// The name of the generated pack, without `--checksum-name` or `--pack-name-template`
+const DEFAULT_PACK_NAME: &str = "pack-every-shorthash";
 
-    // Avoid running the destructor for the metadata, since it takes a very long time to clean up and
-    // we're about to exit the process anyway.
-    forget(pack_file);
+/// Picks the path of the generated packfile in `pack_dir`, named `pack_name`, according to what should
+/// happen if a pack from a previous run is already there. Also returns whether the pack replaces an
+/// existing one.
+fn choose_pack_path(
+    pack_dir: &Path,
+    pack_name: &str,
+    existing_pack: ExistingPack,
+) -> io::Result<(PathBuf, bool)> {
+    let pack_path = pack_dir.join(format!("{}.pack", pack_name));
+    let exists = |path: &Path| path.exists() || path.with_extension("idx").exists();
+    if !exists(&pack_path) {
+        return Ok((pack_path, false));
+    }
+    match existing_pack {
+        ExistingPack::Refuse => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!(
+                "{} already exists. Pass --force to replace it once the new pack is complete, or \
+                --append to write the new pack alongside it.",
+                pack_path.display()
+            ),
+        )),
+        ExistingPack::Replace => Ok((pack_path, true)),
+        ExistingPack::Append => {
+            let pack_path = (2..)
+                .map(|n| pack_dir.join(format!("{}-{}.pack", pack_name, n)))
+                .find(|path| !exists(path))
+                .unwrap();
+            println!(
+                "A pack already exists, so writing to {}",
+                pack_path.display()
+            );
+            Ok((pack_path, false))
+        }
+    }
+}
+
+/// Returns the path of the unfinished pack that the `--journal` at `journal_path` is for, checking that
+/// the pack hasn't been finished already, and that it's still there if the journal has an entry.
+fn journaled_pack_path(journal_path: &Path, has_entry: bool) -> io::Result<PathBuf> {
+    let pack_path = journal_path.with_extension("pack");
+    // The index file is created empty when the pack is started, and only filled in once the packfile is
+    // finished.
+    if fs::metadata(pack_path.with_extension("idx")).is_ok_and(|metadata| metadata.len() > 0) {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!(
+                "{} was left behind by a run that finished {}. Remove it to start a new pack.",
+                journal_path.display(),
+                pack_path.display()
+            ),
+        ));
+    }
+    if has_entry && !pack_path.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "{} is the journal of {}, which doesn't exist anymore",
+                journal_path.display(),
+                pack_path.display()
+            ),
+        ));
+    }
+    Ok(pack_path)
+}
+
+/// Prints the time spent in each phase of the run, for `--profile`. `write_time` covers everything from
+/// the start of writing the pack to the end of the run, so the time not accounted for by `write_profile`
+/// is what it took to save the checkpoint, metadata and so on afterwards. Freeing the B-tree of object
+/// offsets isn't a phase, since it's leaked instead.
+fn print_profile(
+    setup_time: Duration,
+    write_profile: &WriteProfile,
+    write_time: Duration,
+    total_time: Duration,
+) {
+    let write_phases = [
+        ("generating commits (SHA1)", write_profile.generating),
+        ("compressing objects (zlib)", write_profile.compressing),
+        (
+            "recording offsets (B-tree)",
+            write_profile.recording_offsets,
+        ),
+        ("writing the packfile", write_profile.writing_pack),
+        ("checksumming the packfile", write_profile.finishing_pack),
+        ("writing the index", write_profile.writing_index),
+    ];
+    let profiled_write_time = write_phases.iter().map(|(_, time)| *time).sum();
+    println!("Time spent in each phase, in seconds:");
+    for (phase, time) in iter::once(("setup", setup_time))
+        .chain(write_phases.iter().copied())
+        .chain(iter::once((
+            "finishing up",
+            write_time.saturating_sub(profiled_write_time),
+        )))
+    {
+        println!(
+            "  {:<28}{:>10.3} ({:.1}%)",
+            phase,
+            time.as_secs_f64(),
+            100.0 * time.as_secs_f64() / total_time.as_secs_f64()
+        );
+    }
+    println!("  {:<28}{:>10.3}", "total", total_time.as_secs_f64());
+}
+
+/// Writes the objects to a packfile and index file at the given paths, first preallocating
+/// `preallocation_length` bytes for the packfile if given.
+fn write_pack<T: IntoIterator<Item = PackFileObject>>(
+    pack_path: &Path,
+    index_path: &Path,
+    objects: T,
+    write_options: PackWriteOptions,
+    preallocation_length: Option<u64>,
+) -> io::Result<PackFile> {
+    let pack = create_output_file(pack_path)?;
+    if let Some(length) = preallocation_length {
+        preallocate(&pack, length)?;
+    }
+    write_pack_and_index(
+        pack,
+        create_output_file(index_path)?,
+        objects,
+        write_options,
+    )
+}
 
+/// Continues the unfinished packfile at `pack_path` from `position`, where its journal says it was synced,
+/// given the metadata of the objects before that position (see `read_journaled_objects`).
+fn resume_pack<T: IntoIterator<Item = PackFileObject>>(
+    pack_path: &Path,
+    index_path: &Path,
+    position: PackPosition,
+    journaled_objects: Vec<PackObjectMetadata>,
+    objects: T,
+    write_options: PackWriteOptions,
+) -> io::Result<PackFile> {
+    resume_pack_and_index(
+        OpenOptions::new().read(true).write(true).open(pack_path)?,
+        create_output_file(index_path)?,
+        position,
+        journaled_objects,
+        objects,
+        write_options,
+    )
+}
+
+/// Reads the objects of the unfinished packfile at `pack_path` up to where `entry` says it was synced,
+/// and returns their metadata for the index. The shorthashes of the generated commits among them, which
+/// are the only deltified objects, are marked as found in `found_shorthashes`.
+fn read_journaled_objects(
+    pack_path: &Path,
+    entry: &JournalEntry,
+    prefix: OidPrefix,
+    found_shorthashes: &mut PackedBoolArray,
+) -> io::Result<Vec<PackObjectMetadata>> {
+    let mismatch = |reason: String| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} doesn't match its journal: {}",
+                pack_path.display(),
+                reason
+            ),
+        )
+    };
+    let mut reader = PackReader::open_unfinished(pack_path, entry.position.object_count)?;
+    let mut objects = Vec::with_capacity(entry.position.object_count as usize);
+    for packed in &mut reader {
+        let packed = packed.map_err(|err| mismatch(err.to_string()))?;
+        if packed.delta.is_some() && prefix.matches(&packed.oid) {
+            found_shorthashes.set(prefix.shorthash_index(&packed.oid), true);
+        }
+        objects.push(PackObjectMetadata {
+            oid: packed.oid,
+            offset: packed.offset,
+            crc32: packed.crc32,
+        });
+    }
+    if reader.end_offset() != entry.position.length {
+        return Err(mismatch(format!(
+            "its first {} objects end at offset {} rather than {}",
+            objects.len(),
+            reader.end_offset(),
+            entry.position.length
+        )));
+    }
+    Ok(objects)
+}
+
+/// Saves a checkpoint of the generator's progress to `path`, if `--write-checkpoint` was passed.
+fn write_checkpoint(
+    path: Option<&Path>,
+    prefix: OidPrefix,
+    generator: &DeltifiedCommitGenerator,
+) -> io::Result<()> {
+    if let Some(path) = path {
+        Checkpoint::write(
+            path,
+            prefix,
+            generator.attempt_count,
+            &generator.found_shorthashes,
+        )?;
+        println!(
+            "Wrote a checkpoint of {} found shorthashes to {}",
+            generator.found_shorthash_count,
+            path.display()
+        );
+    }
     Ok(())
 }
 
+/// Finishes the `--emit-refs-map` table, or returns the error that stopped it from being written. This
+/// comes after saving the checkpoint, so that the run can be resumed either way.
+fn finish_refs_map(options: &Options, generator: &mut DeltifiedCommitGenerator) -> io::Result<()> {
+    if let Some(err) = generator.refs_map_error.take() {
+        return Err(err);
+    }
+    if let (Some(refs_map), Some(path)) = (generator.refs_map.take(), &options.emit_refs_map) {
+        refs_map.finish()?;
+        println!("Wrote the table of found commits to {}", path.display());
+    }
+    Ok(())
+}
+
+/// Removes the `--journal` once the pack is finished, since there's nothing left to resume, and then
+/// returns the error that stopped the journal from being updated, if there was one.
+fn finish_journal(generator: &mut DeltifiedCommitGenerator) -> io::Result<()> {
+    if let Some(journal) = generator.journal.take() {
+        journal.remove()?;
+    }
+    match generator.journal_error.take() {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+/// Finishes the `--record-accepted` file, or returns the error that stopped it from being written or that
+/// stopped the `--replay`.
+fn finish_accepted_attempts(
+    options: &Options,
+    generator: &mut DeltifiedCommitGenerator,
+) -> io::Result<()> {
+    if let Some(err) = generator.accepted_attempts_error.take() {
+        return Err(err);
+    }
+    if let (Some(accepted_attempts), Some(path)) =
+        (generator.accepted_attempts.take(), &options.record_accepted)
+    {
+        accepted_attempts.finish()?;
+        println!("Wrote the accepted attempts to {}", path.display());
+    }
+    Ok(())
+}
+
+/// Where a run that stopped early saves its checkpoint if `--write-checkpoint` wasn't passed, relative to
+/// the current directory
+const DEFAULT_CHECKPOINT_PATH: &str = "every-shorthash.checkpoint";
+
+/// Saves a checkpoint if `--write-checkpoint` was passed, and, if the run stopped early because of the time
+/// limit or Ctrl-C, prints the command that continues it. A run that stopped early always saves a
+/// checkpoint, at `DEFAULT_CHECKPOINT_PATH` unless `--write-checkpoint` says otherwise. Pass `wrote_pack`
+/// if this run's pack has to be kept when resuming.
+fn save_progress(
+    options: &Options,
+    prefix: OidPrefix,
+    generator: &DeltifiedCommitGenerator,
+    wrote_pack: bool,
+) -> io::Result<()> {
+    if !generator.stopped_early {
+        return write_checkpoint(options.write_checkpoint.as_deref(), prefix, generator);
+    }
+    if options.linear {
+        write_checkpoint(options.write_checkpoint.as_deref(), prefix, generator)?;
+        println!(
+            "A linear run can't be resumed, since the chain would have to continue from its tip."
+        );
+        return Ok(());
+    }
+    let checkpoint_path = match &options.write_checkpoint {
+        Some(path) => path.clone(),
+        None => env::current_dir()?.join(DEFAULT_CHECKPOINT_PATH),
+    };
+    write_checkpoint(Some(&checkpoint_path), prefix, generator)?;
+    println!(
+        "To resume: {}",
+        options::resume_command(&checkpoint_path, wrote_pack)
+    );
+    Ok(())
+}
+
+/// Formats a number of bytes in GiB, or in MiB (or KiB) if it's less than 1 GiB (or 1 MiB).
+fn format_size(bytes: u64) -> String {
+    if bytes >= 1 << 30 {
+        format!("{:.1} GiB", bytes as f64 / (1 << 30) as f64)
+    } else if bytes >= 1 << 20 {
+        format!("{:.1} MiB", bytes as f64 / (1 << 20) as f64)
+    } else {
+        format!("{:.1} KiB", bytes as f64 / (1 << 10) as f64)
+    }
+}
+
+/// Fails if a pack and index of the estimated sizes are unlikely to fit in `objects_dir`, either because
+/// of `--max-file-size` or because of a limit detected on the filesystem, including the free space (plus
+/// `--free-space-margin`). Otherwise, a run on a constrained filesystem would only fail once the pack
+/// outgrew the limit, which could be days into the run.
+fn check_pack_fits(
+    objects_dir: &Path,
+    options: &Options,
+    estimated_pack_size: u64,
+    estimated_index_size: u64,
+) -> io::Result<()> {
+    let limits = filesystem_limits(objects_dir)?;
+    let suggestion =
+        "Use --commit-count-cap to generate a smaller pack, or --prefix to split the search \
+        into shards that can be combined with the merge subcommand elsewhere.";
+
+    let max_file_size = match (options.max_file_size, limits.max_file_size) {
+        (Some(bytes), Some(detected)) if detected.0 < bytes => Some(detected),
+        (Some(bytes), _) => Some((bytes, "--max-file-size")),
+        (None, detected) => detected,
+    };
+    if let Some((max_file_size, reason)) = max_file_size {
+        if estimated_pack_size > max_file_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "the packfile is expected to grow to about {}, but {} only allows files up to \
+                    {}. {}",
+                    format_size(estimated_pack_size),
+                    reason,
+                    format_size(max_file_size),
+                    suggestion
+                ),
+            ));
+        }
+    }
+    if let Some(available_space) = limits.available_space {
+        let estimated_size = estimated_pack_size + estimated_index_size;
+        let required_space = estimated_size.saturating_add(
+            (estimated_size as f64 * options.free_space_margin as f64 / 100.0) as u64,
+        );
+        if required_space > available_space {
+            let message = format!(
+                "the packfile and index are expected to take up about {} ({} with a {}% margin), but \
+                only {} is available in {}.",
+                format_size(estimated_size),
+                format_size(required_space),
+                options.free_space_margin,
+                format_size(available_space),
+                objects_dir.display()
+            );
+            if options.ignore_free_space {
+                eprintln!("warning: {}", message);
+            } else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{} {} Pass --ignore-free-space to start anyway.",
+                        message, suggestion
+                    ),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes the OID, offset, and CRC32 of every object in the pack to a JSON file, as an array of objects.
+fn dump_metadata(path: &Path, pack_file: &PackFile) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    out.write_all(b"[")?;
+    for (i, object) in pack_file.metadata().enumerate() {
+        write!(
+            out,
+            "{}\n  {{\"oid\": \"{}\", \"offset\": {}, \"crc32\": {}}}",
+            if i == 0 { "" } else { "," },
+            oid_to_hex(&object.oid),
+            object.offset,
+            object.crc32
+        )?;
+    }
+    out.write_all(b"\n]\n")?;
+    out.into_inner()?.sync_all()
+}
+
+/// Writes a fanout table as one `<first byte in hex> <cumulative object count>` line per entry. A path of
+/// `-` writes to stdout.
+fn dump_fanout(path: &Path, fanout: &[u32; 256]) -> io::Result<()> {
+    let table = fanout
+        .iter()
+        .enumerate()
+        .map(|(first_byte, count)| format!("{:02x} {}\n", first_byte, count))
+        .collect::<String>();
+    if path == Path::new("-") {
+        io::stdout().write_all(table.as_bytes())
+    } else {
+        fs::write(path, table)
+    }
+}
+
+/// One of the delta base commits, which only differ in their trees. Each attempted commit extends one of
+/// them, cycling through them with each attempt.
+struct DeltaBase {
+    commit: GitObject,
+    oid: Sha1Oid,
+    intermediate_sha1_state: Sha1,
+    // The OID, size, and delta chain depth of the previous generated commit with this tree
+    previous_chained_commit: Option<(Sha1Oid, ObjectSize, usize)>,
+}
+
 struct DeltifiedCommitGenerator {
-    delta_base_commit: GitObject,
-    delta_base_commit_oid: Sha1Oid,
+    delta_bases: Vec<DeltaBase>,
+    // The index in `delta_bases` of the base of the commit currently being attempted. This advances with
+    // every attempt, so it's always `attempt_count` modulo the number of bases.
+    delta_base_index: usize,
     found_shorthashes: PackedBoolArray,
-    root_commit_oid_buffer: Vec<Sha1Oid>,
-    merge_commit_oid_buffer: Vec<Sha1Oid>,
+    prefix: OidPrefix,
+    found_shorthash_count: usize,
+    lowest_missing_shorthash: usize,
+    merge_tree: MergeTreeBuilder,
+    // The number of root commits, including the delta base commits that match the prefix
+    root_commit_count: usize,
     delta_base_commit_extension_length: usize,
-    delta_base_commit_intermediate_sha1_state: Sha1,
 
-    // Due to https://en.wikipedia.org/wiki/Coupon_collector%27s_problem, we expect to need
-    // 2**28 * (ln(2**28) + 0.577) = 2**32.3 total commits to find all 2**28 unique shorthashes,
-    // which is over the threshold of 2**32 32-bit ints.
-    entropy_specifier: u64,
+    // The maximum length of a chain of deltas leading back to the delta base commit. With a depth of 1,
+    // every commit is a delta against the delta base commit; otherwise, commits are deltas against the
+    // previous generated commit with the same tree until the chain reaches this length.
+    delta_chain_depth: usize,
+    // With `--linear`, each generated commit has the previous one as its parent (starting with the delta
+    // base commit) instead of being merged into the merge commits.
+    linear: bool,
+    // Whether the root commits are merged into the merge commits, which is the case unless the run is
+    // linear or generates trees (which can't be parents of a commit)
+    merged: bool,
+    // In linear mode, the OID of the most recent commit in the chain, until it has been reported at the end
+    linear_chain_tip: Option<Sha1Oid>,
+    // The length of the delta base commit's `tree` line, and the `parent` line that gets inserted after
+    // it in linear mode (or nothing otherwise)
+    tree_line_length: usize,
+    parent_line: Vec<u8>,
+    // The length of the `--message-footer` at the end of each delta base commit, which the generated
+    // commits have after their entropy rather than before it
+    footer_length: usize,
+
+    // The OIDs of the commits yielded since the consumer last asked for more objects, and their
+    // shorthash indices. These are recorded as found once the consumer asks again. See
+    // `acknowledge_pending_commits`.
+    pending_commits: Vec<Sha1Oid>,
+    pending_shorthashes: HashSet<usize>,
+
+    // Due to https://en.wikipedia.org/wiki/Coupon_collector%27s_problem, we expect to need about
+    // 2**32.3 total commits to find all 2**28 unique shorthashes (see `estimates::expected_attempts`),
+    // which is over the threshold of 2**32 32-bit ints. So after 2**32 attempts, the generator switches
+    // from 8 to 16 characters of entropy. (With `--entropy-prefix-len`, it starts with a different
+    // number of characters, and switches once every value of that width has been tried.)
+    attempt_count: u64,
+    entropy_source: Box<dyn EntropySource>,
+    // The entropy value of the commit currently being attempted
+    entropy: u64,
     commit_count_cap: usize,
-    is_finished: bool,
+
+    // Set once every shorthash has been found, or when the time limit runs out or the run is interrupted.
+    // No new root commits are generated after this point.
+    is_stopping: bool,
+    // Set if the run stopped because of the time limit or an interrupt, so that it can be resumed
+    stopped_early: bool,
+
+    // If set, no new root commits are generated after this time. The merge commits are still created over
+    // whatever root commits have been found so far, so the result is a valid pack with partial coverage.
+    deadline: Option<Instant>,
+    // Likewise, no new root commits are generated once `attempt_count` reaches this, for `--max-attempts`.
+    attempt_limit: u64,
+
+    // The number of objects of each kind yielded so far, for progress reports
+    yielded_object_counts: GeneratedObjectCounts,
+
+    // With `--emit-refs-map`, where each found commit is recorded. If recording one fails, the error is
+    // kept until the end of the run, and nothing more is recorded.
+    refs_map: Option<RefsMapWriter>,
+    refs_map_error: Option<io::Error>,
+    // With `--record-accepted`, where the attempts that find a new shorthash are recorded, and with
+    // `--replay`, where the attempts to make are read from. An error from either stops the recording or
+    // the replay, and is reported at the end like `refs_map_error`.
+    accepted_attempts: Option<AcceptedAttemptsWriter>,
+    replay: Option<AcceptedAttemptsReader>,
+    accepted_attempts_error: Option<io::Error>,
+
+    // With `--threads`, the number of threads that hash the attempts ahead of the generator, how many
+    // chunks each of them can get ahead (`--channel-depth`), and the threads themselves once the search is
+    // underway. They're restarted whenever the SHA1 states of the delta bases change. With no threads, the
+    // generator hashes each attempt itself.
+    hasher_thread_count: usize,
+    hasher_channel_depth: usize,
+    hashers: Option<HasherPool>,
+
+    // With `--journal`, the journal, how far the writer has written and synced the packfile, and how
+    // often to update the journal. An entry waits in `pending_journal_entry` until the packfile has been
+    // synced up to its position (see `update_journal`). An error writing the journal stops its updates,
+    // and is reported at the end like `refs_map_error`.
+    journal: Option<Journal>,
+    pack_sync: Rc<PackSync>,
+    journal_interval: Duration,
+    last_journal_update: Instant,
+    pending_journal_entry: Option<JournalEntry>,
+    journal_error: Option<io::Error>,
 }
 
+// Deltified commits copy the whole delta base commit with a single copy instruction, which can encode
+// sizes of up to 3 bytes.
+const MAX_DELTA_BASE_COMMIT_LENGTH: usize = 0xff_ff_ff;
+
+/// Returns the trees besides the empty tree that commits point to with `--tree-count`, so `count - 1` of
+/// them. Each one contains a single empty subdirectory, which has a different name in each tree.
+fn subdirectory_trees(count: usize) -> Vec<GitObject> {
+    (1..count)
+        .map(|index| {
+            let mut data = format!("40000 {}\0", index).into_bytes();
+            data.extend_from_slice(&EMPTY_TREE_OID);
+            GitObject::tree(data)
+        })
+        .collect()
+}
+
+/// Returns a copy of a commit that points to a different tree, or `None` if the commit doesn't start
+/// with a `tree` line.
+fn with_tree(commit: &GitObject, tree_oid: &Sha1Oid) -> Option<GitObject> {
+    let tree_line_length = "tree \n".len() + 40;
+    if commit.data.len() < tree_line_length
+        || !commit.data.starts_with(b"tree ")
+        || commit.data[tree_line_length - 1] != b'\n'
+    {
+        return None;
+    }
+    let mut data = format!("tree {}\n", oid_to_hex(tree_oid)).into_bytes();
+    data.extend_from_slice(&commit.data[tree_line_length..]);
+    Some(GitObject::commit(data))
+}
+
+/// Returns a copy of a commit with an `encoding <charset>` header after its other headers, or `None` if
+/// the commit has no blank line to separate its headers from its message.
+fn with_encoding(commit: &GitObject, charset: &str) -> Option<GitObject> {
+    let headers_length = commit
+        .data
+        .windows(2)
+        .position(|window| window == b"\n\n")?
+        + 1;
+    let mut data = commit.data[..headers_length].to_vec();
+    data.extend_from_slice(format!("encoding {}\n", charset).as_bytes());
+    data.extend_from_slice(&commit.data[headers_length..]);
+    Some(GitObject::commit(data))
+}
+
+/// Returns a copy of a commit with `nonce_length` hex digits and then `footer` appended to it, choosing
+/// the digits so that the copy's OID starts with `target`, along with the number of nonces that were
+/// tried. Returns `None` if no nonce of that length works. Nonces are tried in order, so the result is
+/// always the same.
+fn with_vanity_shorthash(
+    commit: &GitObject,
+    footer: &[u8],
+    target: OidPrefix,
+    nonce_length: usize,
+) -> Option<(GitObject, u64)> {
+    // Only the nonce changes between attempts, so everything before it is hashed once.
+    let intermediate_sha1_state = Sha1::new()
+        .chain(
+            format!(
+                "{} {}\0",
+                commit.object_type.type_name(),
+                commit.data.len() + nonce_length + footer.len()
+            )
+            .as_bytes(),
+        )
+        .chain(&commit.data);
+    let nonce_count = 1u64
+        .checked_shl(4 * nonce_length as u32)
+        .unwrap_or(u64::MAX);
+    (0..nonce_count).find_map(|attempt| {
+        let nonce = format!("{:01$x}", attempt, nonce_length);
+        let oid: Sha1Oid = intermediate_sha1_state
+            .clone()
+            .chain(nonce.as_bytes())
+            .chain(footer)
+            .finalize()
+            .into();
+        if !target.matches(&oid) {
+            return None;
+        }
+        let mut data = commit.data.clone();
+        data.extend_from_slice(nonce.as_bytes());
+        data.extend_from_slice(footer);
+        let vanity_commit = GitObject {
+            data,
+            object_type: commit.object_type.clone(),
+        };
+        debug_assert_eq!(vanity_commit.oid(), oid);
+        Some((vanity_commit, attempt + 1))
+    })
+}
+
+/// Returns the `parent` header line for a commit with the given parent.
+fn parent_line(parent_oid: &Sha1Oid) -> Vec<u8> {
+    format!("parent {}\n", oid_to_hex(parent_oid)).into_bytes()
+}
+
+/// Returns the number of bytes of the delta base commit (including the object header, which starts with
+/// `type_name`) that end up in the final 64-byte SHA1 block, when a commit is formed by appending
+/// `extension_length` bytes of entropy to it. The intermediate SHA1 state can only be reused across
+/// commits if this is small enough for the entropy and the SHA1 padding to fit in the same block.
+fn final_sha1_block_length(
+    type_name: &str,
+    delta_base_commit_length: usize,
+    extension_length: usize,
+) -> usize {
+    (delta_base_commit_length
+        + format!(
+            "{} {}\0",
+            type_name,
+            delta_base_commit_length + extension_length
+        )
+        .len())
+        % 64
+}
+
+/// Returns whether `extension_length` bytes of entropy fit in the final SHA1 block after the delta base
+/// commit, along with the 9 bytes of padding that SHA1 needs at least, so that each attempt only
+/// hashes one block.
+fn entropy_fits_in_final_block(
+    type_name: &str,
+    delta_base_commit_length: usize,
+    extension_length: usize,
+) -> bool {
+    final_sha1_block_length(type_name, delta_base_commit_length, extension_length)
+        + extension_length
+        <= 64 - 9
+}
+
+// Once this many shorthashes have been found, progress reports switch to describing the remaining
+// shorthashes. Note that there's no faster way to find the remaining shorthashes than what the
+// generator is already doing: every attempt produces an effectively-random shorthash, so searching
+// for a specific missing shorthash takes 2**28 attempts on average, whereas accepting any of the `k`
+// missing shorthashes takes 2**28 / k attempts. The endgame is slow because of the coupon collector's
+// problem, not because of the search strategy.
+const ENDGAME_THRESHOLD: usize = SHORTHASH_COUNT / 20 * 19;
+
 impl DeltifiedCommitGenerator {
-    fn get_entropy(&self) -> String {
-        if self.delta_base_commit_extension_length == 8 {
-            format!("{:08x}", self.entropy_specifier)
+    /// Creates a generator that finds commits for all of the shorthashes that aren't already set in
+    /// `found_shorthashes`, using commits whose OIDs start with `prefix`. The shorthashes outside of
+    /// `shorthash_range` should already be set. The delta base commits must all have the same length and
+    /// only differ in their trees, and in linear mode, there can only be one.
+    fn new(
+        delta_base_commits: Vec<GitObject>,
+        mut found_shorthashes: PackedBoolArray,
+        prefix: OidPrefix,
+        shorthash_range: Range<usize>,
+        commit_count_cap: usize,
+        mut entropy_source: Box<dyn EntropySource>,
+        linear: bool,
+    ) -> Self {
+        // The delta base commits are also root commits, unless they're excluded by the prefix or the OID
+        // prefix range.
+        let merged = !linear && matches!(delta_base_commits[0].object_type, GitObjectType::Commit);
+        let mut merge_tree = MergeTreeBuilder::default();
+        let mut root_commit_count = 0;
+        for delta_base_commit in &delta_base_commits {
+            let oid = delta_base_commit.oid();
+            if prefix.matches(&oid) && shorthash_range.contains(&prefix.shorthash_index(&oid)) {
+                found_shorthashes.set(prefix.shorthash_index(&oid), true);
+                if merged {
+                    merge_tree.push_root_commit(oid);
+                }
+                root_commit_count += 1;
+            }
+        }
+        let found_shorthash_count = found_shorthashes.count_set();
+        let tree_line_length = delta_base_commits[0]
+            .data
+            .iter()
+            .position(|&byte| byte == b'\n')
+            .map_or(0, |index| index + 1);
+        let (linear_chain_tip, parent_line) = if linear {
+            let oid = delta_base_commits[0].oid();
+            (Some(oid), parent_line(&oid))
         } else {
-            format!("{:016x}", self.entropy_specifier)
+            (None, Vec::new())
+        };
+        let delta_bases = delta_base_commits
+            .into_iter()
+            .map(|commit| DeltaBase {
+                oid: commit.oid(),
+                intermediate_sha1_state: Self::intermediate_sha1_state(
+                    &commit,
+                    tree_line_length,
+                    &parent_line,
+                    8,
+                    0,
+                ),
+                commit,
+                previous_chained_commit: None,
+            })
+            .collect();
+        Self {
+            delta_bases,
+            delta_base_index: 0,
+            found_shorthashes,
+            prefix,
+            found_shorthash_count,
+            lowest_missing_shorthash: 0,
+            merge_tree,
+            root_commit_count,
+            delta_base_commit_extension_length: 8,
+            delta_chain_depth: 1,
+            linear,
+            merged,
+            linear_chain_tip,
+            tree_line_length,
+            parent_line,
+            footer_length: 0,
+            pending_commits: Vec::new(),
+            pending_shorthashes: HashSet::new(),
+            attempt_count: 0,
+            entropy: entropy_source.next_entropy(),
+            entropy_source,
+            commit_count_cap,
+            is_stopping: found_shorthash_count == SHORTHASH_COUNT,
+            stopped_early: false,
+            deadline: None,
+            attempt_limit: u64::MAX,
+            yielded_object_counts: GeneratedObjectCounts::default(),
+            refs_map: None,
+            refs_map_error: None,
+            accepted_attempts: None,
+            replay: None,
+            accepted_attempts_error: None,
+            hasher_thread_count: 0,
+            hasher_channel_depth: hashers::DEFAULT_CHANNEL_DEPTH,
+            hashers: None,
+            journal: None,
+            pack_sync: Rc::default(),
+            journal_interval: journal::DEFAULT_INTERVAL,
+            last_journal_update: Instant::now(),
+            pending_journal_entry: None,
+            journal_error: None,
         }
     }
-}
 
-fn create_merge_commit(parent_oids: &[Sha1Oid]) -> GitObject {
-    GitObject {
-        object_type: GitObjectType::Commit,
-        data: format!(
-            "\
-                tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
-                {}\
-                author Teddy Katz <teddy.katz@gmail.com> 1616279625 -0400\n\
-                committer Teddy Katz <teddy.katz@gmail.com> 1616279625 -0400\n\
-                \n\
-                Merge of {} commits\n",
-            parent_oids
+    /// Computes the SHA1 state after absorbing the object header and the delta base commit up to its
+    /// last `footer_length` bytes, with `parent_line` inserted after its first `tree_line_length` bytes,
+    /// for commits that extend it by `extension_length` bytes.
+    fn intermediate_sha1_state(
+        delta_base_commit: &GitObject,
+        tree_line_length: usize,
+        parent_line: &[u8],
+        extension_length: usize,
+        footer_length: usize,
+    ) -> Sha1 {
+        Sha1::new()
+            .chain(
+                format!(
+                    "{} {}\0",
+                    delta_base_commit.object_type.type_name(),
+                    delta_base_commit.data.len() + parent_line.len() + extension_length
+                )
+                .as_bytes(),
+            )
+            .chain(&delta_base_commit.data[..tree_line_length])
+            .chain(parent_line)
+            .chain(
+                &delta_base_commit.data
+                    [tree_line_length..delta_base_commit.data.len() - footer_length],
+            )
+    }
+
+    fn update_intermediate_sha1_state(&mut self) {
+        // The hashing threads have the old states.
+        self.hashers = None;
+        for delta_base in &mut self.delta_bases {
+            delta_base.intermediate_sha1_state = Self::intermediate_sha1_state(
+                &delta_base.commit,
+                self.tree_line_length,
+                &self.parent_line,
+                self.delta_base_commit_extension_length,
+                self.footer_length,
+            );
+        }
+    }
+
+    /// Puts the entropy before the last `length` bytes of the delta base commits, which are a
+    /// `--message-footer`, instead of at their end.
+    fn set_footer_length(&mut self, length: usize) {
+        if length != self.footer_length {
+            self.footer_length = length;
+            self.update_intermediate_sha1_state();
+        }
+    }
+
+    /// Sets the number of characters of entropy to start with, instead of 8.
+    fn set_initial_entropy_length(&mut self, length: usize) {
+        if length != self.delta_base_commit_extension_length {
+            self.delta_base_commit_extension_length = length;
+            self.update_intermediate_sha1_state();
+        }
+    }
+
+    fn get_entropy(&self) -> String {
+        entropy_hex(self.entropy, self.delta_base_commit_extension_length)
+    }
+
+    /// Describes the attempts from the current one on for the hashing threads, up to the attempt limit
+    /// or the attempt where the entropy gets longer, whichever comes first.
+    fn hash_job(&self) -> HashJob {
+        let entropy_length = self.delta_base_commit_extension_length;
+        HashJob {
+            delta_bases: self
+                .delta_bases
                 .iter()
-                .map(|oid| {
-                    format!(
-                        "parent {}\n",
-                        oid.iter()
-                            .map(|&byte| format!("{:02x}", byte))
-                            .collect::<String>()
+                .map(|delta_base| {
+                    let data = &delta_base.commit.data;
+                    (
+                        delta_base.intermediate_sha1_state.clone(),
+                        data[data.len() - self.footer_length..].to_vec(),
                     )
                 })
-                .collect::<String>(),
-            parent_oids.len()
-        )
-        .as_bytes()
-        .to_vec(),
+                .collect(),
+            entropy_length,
+            prefix: self.prefix,
+            first_attempt: self.attempt_count,
+            first_entropy: self.entropy,
+            entropy_source: self.entropy_source.boxed_clone(),
+            end_attempt: if entropy_length < 16 {
+                self.attempt_limit.min(1 << (4 * entropy_length))
+            } else {
+                self.attempt_limit
+            },
+        }
     }
-}
 
-impl Iterator for DeltifiedCommitGenerator {
-    type Item = PackFileObject;
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.is_finished
-            || self.merge_commit_oid_buffer.len() * (1 << 14) + self.root_commit_oid_buffer.len()
-                > self.commit_count_cap
+    fn oid_for_current_entropy(&self) -> Sha1Oid {
+        let delta_base = &self.delta_bases[self.delta_base_index];
+        delta_base
+            .intermediate_sha1_state
+            .clone()
+            .chain(self.get_entropy().as_bytes())
+            .chain(&delta_base.commit.data[delta_base.commit.data.len() - self.footer_length..])
+            .finalize()
+            .into()
+    }
+
+    /// Records the commits yielded by the previous call to `next` or `next_batch` as found. This only
+    /// happens once more objects are requested: `stream_to_pack_file` doesn't ask for another object until
+    /// its write of the previous one has succeeded, so if a write fails, the failed commit is never
+    /// recorded. Its shorthash stays unset in `found_shorthashes` and it isn't merged into the merge
+    /// commits. (Writes are buffered, so "succeeded" means the writer accepted the object; the packfile is
+    /// only durable once it has been synced at the end.) With batches, the writer only asks for the next
+    /// batch after writing all of the previous one, so a failed write leaves the whole batch unrecorded.
+    fn acknowledge_pending_commits(&mut self) {
+        for oid in mem::take(&mut self.pending_commits) {
+            let shorthash_index = self.prefix.shorthash_index(&oid);
+            self.found_shorthashes.set(shorthash_index, true);
+            self.found_shorthash_count += 1;
+            if let Some(refs_map) = &mut self.refs_map {
+                if let Err(err) = refs_map.record(shorthash_index, &oid) {
+                    self.refs_map_error = Some(err);
+                    self.refs_map = None;
+                }
+            }
+            if self.found_shorthash_count == ENDGAME_THRESHOLD {
+                println!(
+                    "{} of {} shorthashes found; entering the endgame",
+                    self.found_shorthash_count, SHORTHASH_COUNT
+                );
+            }
+            if self.merged {
+                self.merge_tree.push_root_commit(oid);
+            }
+            self.root_commit_count += 1;
+        }
+        self.pending_shorthashes.clear();
+    }
+
+    /// Returns up to `max` objects at once, which amortizes the per-call overhead for consumers that hand
+    /// objects off in bulk. Like `next`, this records the previously returned objects as written, so it
+    /// should only be called once all of them have been written. An empty batch means the generator is
+    /// done. Merge commits over the commits of a batch are only created in later batches, so the pack
+    /// differs from the one produced by calling `next` repeatedly, although it has the same coverage.
+    fn next_batch(&mut self, max: usize) -> Vec<PackFileObject> {
+        self.acknowledge_pending_commits();
+        self.update_journal();
+        let mut batch = Vec::with_capacity(max);
+        while batch.len() < max {
+            match self.next_unacknowledged() {
+                Some(object) => batch.push(object),
+                None => break,
+            }
+        }
+        batch
+    }
+
+    /// With `--journal`, writes the pending journal entry once the packfile has been synced up to its
+    /// position, and every `journal_interval`, records the generator's state in a new pending entry and
+    /// asks for the packfile to be synced. This is called between batches, when every object generated so
+    /// far has been written and acknowledged, so the state is exactly what it takes to continue from the
+    /// end of what's been written.
+    fn update_journal(&mut self) {
+        let journal = match &self.journal {
+            Some(journal) if self.journal_error.is_none() => journal,
+            _ => return,
+        };
+        if let Some(entry) = &self.pending_journal_entry {
+            if self.pack_sync.synced().length < entry.position.length {
+                return;
+            }
+            if let Err(err) = journal.write_entry(entry) {
+                self.journal_error = Some(err);
+                return;
+            }
+            self.pending_journal_entry = None;
+            self.last_journal_update = Instant::now();
+        }
+        // Once the top-level merge commit is written, all that's left is finishing the pack.
+        if self.merge_tree.is_finished()
+            || self.last_journal_update.elapsed() < self.journal_interval
         {
-            return None;
+            return;
         }
+        self.pending_journal_entry = Some(JournalEntry {
+            position: self.pack_sync.written(),
+            attempt_count: self.attempt_count,
+            root_commit_count: self.root_commit_count as u64,
+            commit_count: self.yielded_object_counts.commits as u64,
+            first_level_merge_commit_count: self.yielded_object_counts.first_level_merge_commits
+                as u64,
+            is_stopping: self.is_stopping,
+            stopped_early: self.stopped_early,
+            previous_chained_commits: self
+                .delta_bases
+                .iter()
+                .map(|delta_base| {
+                    delta_base
+                        .previous_chained_commit
+                        .map(|(oid, size, depth)| (oid, size.0 as u64, depth as u64))
+                })
+                .collect(),
+            merge_tree_levels: self.merge_tree.unmerged_levels().to_vec(),
+        });
+        self.pack_sync.request();
+    }
 
-        if self.merge_commit_oid_buffer.len() >= 1 << 14 {
-            self.is_finished = true;
-            let final_merge = create_merge_commit(&self.merge_commit_oid_buffer);
-            println!(
-                "Top-level merge commit: {}",
-                final_merge
-                    .oid()
-                    .iter()
-                    .map(|&byte| format!("{:02x}", byte))
-                    .collect::<String>()
-            );
-            println!("Your call is important to us.");
-            println!("Please hold while an index file is generated. This will take a while");
-            return Some(PackFileObject::Raw(final_merge));
+    /// Picks up where the run that wrote `entry` to its journal left off. The shorthashes of the commits in
+    /// the pack should already be set in `found_shorthashes`, and the attempt count and entropy set up to
+    /// continue from the entry's attempt count. Returns false, and changes nothing, if the entry is for
+    /// different delta bases or a different merge tree.
+    fn restore_journal_entry(&mut self, entry: &JournalEntry) -> bool {
+        if entry.previous_chained_commits.len() != self.delta_bases.len()
+            || !self
+                .merge_tree
+                .restore_unmerged_levels(entry.merge_tree_levels.clone())
+        {
+            return false;
+        }
+        for (delta_base, commit) in self
+            .delta_bases
+            .iter_mut()
+            .zip(&entry.previous_chained_commits)
+        {
+            delta_base.previous_chained_commit =
+                commit.map(|(oid, size, depth)| (oid, ObjectSize(size as usize), depth as usize));
         }
+        self.root_commit_count = entry.root_commit_count as usize;
+        self.yielded_object_counts.commits = entry.commit_count as usize;
+        self.yielded_object_counts.first_level_merge_commits =
+            entry.first_level_merge_commit_count as usize;
+        self.is_stopping |= entry.is_stopping;
+        self.stopped_early = entry.stopped_early;
+        true
+    }
 
-        if self.root_commit_oid_buffer.len() >= 1 << 14 {
-            let merge = create_merge_commit(&self.root_commit_oid_buffer);
-            self.root_commit_oid_buffer.clear();
-            self.merge_commit_oid_buffer.push(merge.oid());
-            println!(
-                "created first-level merge commit {}/{}",
-                self.merge_commit_oid_buffer.len(),
-                1 << 14
-            );
+    /// Returns an iterator that generates objects `batch_size` at a time. A batch size of 1 yields the
+    /// same objects as the generator itself.
+    fn batches(&mut self, batch_size: usize) -> impl Iterator<Item = PackFileObject> + '_ {
+        iter::from_fn(move || {
+            let batch = self.next_batch(batch_size);
+            if batch.is_empty() {
+                None
+            } else {
+                Some(batch)
+            }
+        })
+        .flatten()
+    }
+
+    /// Returns an iterator like `batches`, but without any merge commits. Nothing refers to the generated
+    /// commits then, so they're unreachable: tools that walk the history won't find them, and `git gc`
+    /// prunes them unless the pack has a `.keep` file. Filtering the merge commits out of `batches` would
+    /// be no better, since their parents would be the same commits, and the merge tree would still have to
+    /// hold every root commit. This has to be called before generating anything.
+    fn leaves_only(&mut self, batch_size: usize) -> impl Iterator<Item = PackFileObject> + '_ {
+        self.merged = false;
+        self.batches(batch_size)
+    }
+
+    /// Like `leaves_only`, but only keeps every `step`th generated commit, starting with the first. The
+    /// skipped commits are still recorded as found, so the run covers about one in `step` shorthashes,
+    /// spread evenly over all of them. With a delta chain depth of more than 1, a kept commit can be a
+    /// delta against a skipped one, which is then kept too (see `Sample`).
+    fn sample(&mut self, batch_size: usize, step: usize) -> Sample<'_> {
+        debug_assert!(step > 0);
+        self.merged = false;
+        Sample {
+            generator: self,
+            batch_size,
+            step,
+            seen_count: 0,
+            chain_tails: HashMap::new(),
+            ready: VecDeque::new(),
+        }
+    }
+
+    /// Generates the next object, without recording the previously yielded commits as found. Returns
+    /// `None` at the end of a batch that can't continue until its commits have been recorded.
+    fn next_unacknowledged(&mut self) -> Option<PackFileObject> {
+        if self.is_stopping {
+            self.hashers = None;
+        }
+        let is_capped = self.root_commit_count + self.pending_commits.len() > self.commit_count_cap;
+        if self.merge_tree.is_finished() || (is_capped && self.merged) {
+            return None;
+        }
+
+        // The final merge commits have to include every root commit.
+        if self.is_stopping && !self.pending_commits.is_empty() {
+            return None;
+        }
+        if !self.merged {
+            if self.is_stopping || is_capped {
+                if let Some(tip) = self.linear_chain_tip.take() {
+                    println!("Tip of the linear chain: {}", oid_to_hex(&tip));
+                }
+                return None;
+            }
+        } else if let Some(merge) = self.merge_tree.next_merge_commit(self.is_stopping) {
+            if self.merge_tree.is_finished() {
+                self.yielded_object_counts.has_top_level_merge_commit = true;
+            } else {
+                self.yielded_object_counts.first_level_merge_commits += 1;
+            }
             return Some(PackFileObject::Raw(merge));
         }
 
         let new_oid = loop {
-            if self.entropy_specifier == (u32::MAX as u64) + 1 {
+            if self.replay.is_some() && !self.skip_to_replayed_attempt() {
+                return self.next_unacknowledged();
+            }
+            // This also handles runs resumed from a checkpoint with more than 2**32 attempts.
+            if self.delta_base_commit_extension_length < 16
+                && self.attempt_count >> (4 * self.delta_base_commit_extension_length) != 0
+            {
                 self.delta_base_commit_extension_length = 16;
-                self.delta_base_commit_intermediate_sha1_state = Sha1::new()
-                    .chain(
-                        format!(
-                            "commit {}\0",
-                            self.delta_base_commit.data.len()
-                                + self.delta_base_commit_extension_length
-                        )
-                        .as_bytes(),
-                    )
-                    .chain(&self.delta_base_commit.data);
+                self.update_intermediate_sha1_state();
             }
 
-            let oid = self
-                .delta_base_commit_intermediate_sha1_state
-                .clone()
-                .chain(self.get_entropy().as_bytes())
-                .finalize()
-                .into();
+            if self.hasher_thread_count > 0 && self.hashers.is_none() {
+                self.hashers = Some(HasherPool::start(
+                    self.hash_job(),
+                    self.hasher_thread_count,
+                    self.hasher_channel_depth,
+                ));
+            }
+            let oid = match self.hashers.as_mut().map(|hashers| hashers.next_match()) {
+                None => self.oid_for_current_entropy(),
+                Some(hashed) => {
+                    // The attempts before the next match (or before the end of the hashers' job, which
+                    // then starts over with the next attempt) don't match the prefix.
+                    let (attempt, oid) = match hashed {
+                        Some((attempt, oid)) => (attempt, Some(oid)),
+                        None => (self.hashers.take().unwrap().end_attempt(), None),
+                    };
+                    while self.attempt_count < attempt {
+                        self.record_attempt();
+                        if self.is_stopping {
+                            return self.next_unacknowledged();
+                        }
+                    }
+                    match oid {
+                        Some(oid) => {
+                            debug_assert_eq!(oid, self.oid_for_current_entropy());
+                            oid
+                        }
+                        None => continue,
+                    }
+                }
+            };
 
-            if !self
-                .found_shorthashes
-                .get(PackedBoolArray::hash_to_shorthash_index(&oid))
-            {
-                break oid;
+            if self.prefix.matches(&oid) {
+                let shorthash_index = self.prefix.shorthash_index(&oid);
+                // SAFETY: shorthash indices are always less than `SHORTHASH_COUNT`.
+                if !unsafe { self.found_shorthashes.get_unchecked(shorthash_index) }
+                    && (self.pending_shorthashes.is_empty()
+                        || !self.pending_shorthashes.contains(&shorthash_index))
+                {
+                    break oid;
+                }
             }
 
-            self.entropy_specifier += 1;
-            if self.entropy_specifier & 0xfffff == 0 {
-                println!("number of commits attempted so far: {}", self.entropy_specifier);
+            if self.replay.take().is_some() {
+                self.accepted_attempts_error = Some(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "attempt {} of the replay didn't find a new shorthash, so the recorded run \
+                        must have had different options",
+                        self.attempt_count
+                    ),
+                ));
+                self.stop_early("Replay failed");
+                return self.next_unacknowledged();
+            }
+            self.record_attempt();
+            if self.is_stopping {
+                return self.next_unacknowledged();
             }
         };
 
-        let delta_instructions = vec![
-            PackFileDeltaInstruction::CopyFromBaseObject {
-                offset: 0,
-                size: self.delta_base_commit.data.len(),
-            },
-            PackFileDeltaInstruction::AddNewData(self.get_entropy().as_bytes().to_vec()),
-        ];
+        if let Some(accepted_attempts) = &mut self.accepted_attempts {
+            if let Err(err) = accepted_attempts.record(self.attempt_count) {
+                self.accepted_attempts_error = Some(err);
+                self.accepted_attempts = None;
+            }
+        }
 
-        self.found_shorthashes
-            .set(PackedBoolArray::hash_to_shorthash_index(&new_oid), true);
-        self.entropy_specifier += 1;
-        if self.entropy_specifier & 0xfffff == 0 {
-            println!("number of commits attempted so far: {}", self.entropy_specifier);
+        let delta_base_length = self.delta_bases[self.delta_base_index].commit.data.len();
+        let new_size = ObjectSize(
+            delta_base_length + self.parent_line.len() + self.delta_base_commit_extension_length,
+        );
+        let delta_base = &mut self.delta_bases[self.delta_base_index];
+        let (base_oid, base_size, depth) = match delta_base.previous_chained_commit {
+            Some((oid, size, depth)) if depth < self.delta_chain_depth => (oid, size, depth + 1),
+            _ => (delta_base.oid, ObjectSize(delta_base_length), 1),
+        };
+        // The writer writes objects in order, so a later commit can use this one as its delta base even
+        // before it has been recorded as found.
+        if self.delta_chain_depth > 1 {
+            delta_base.previous_chained_commit = Some((new_oid, new_size, depth));
         }
-        self.root_commit_oid_buffer.push(new_oid);
+
+        // Every generated commit starts with the contents of its delta base commit before the footer, so
+        // the same delta instructions work regardless of whether the delta is against the delta base
+        // commit itself or against the previous generated commit with the same tree. Both end with the
+        // footer, which is copied from wherever it is in the base.
+        let entropy_offset = delta_base_length - self.footer_length;
+        let mut delta_instructions = if self.linear {
+            vec![
+                PackFileDeltaInstruction::CopyFromBaseObject {
+                    offset: 0,
+                    size: self.tree_line_length,
+                },
+                PackFileDeltaInstruction::AddNewData(self.parent_line.clone()),
+                PackFileDeltaInstruction::CopyFromBaseObject {
+                    offset: self.tree_line_length,
+                    size: entropy_offset - self.tree_line_length,
+                },
+                PackFileDeltaInstruction::AddNewData(self.get_entropy().as_bytes().to_vec()),
+            ]
+        } else {
+            vec![
+                PackFileDeltaInstruction::CopyFromBaseObject {
+                    offset: 0,
+                    size: entropy_offset,
+                },
+                PackFileDeltaInstruction::AddNewData(self.get_entropy().as_bytes().to_vec()),
+            ]
+        };
+        if self.footer_length > 0 {
+            delta_instructions.push(PackFileDeltaInstruction::CopyFromBaseObject {
+                offset: base_size.0 - self.footer_length,
+                size: self.footer_length,
+            });
+        }
+
+        self.record_attempt();
+        // In linear mode, the next commit has this one as its parent, so the part of the commit before the
+        // entropy changes, and the SHA1 state has to be recomputed. This only happens once per found
+        // commit, so it's cheap compared to the attempts.
+        if self.linear {
+            self.linear_chain_tip = Some(new_oid);
+            self.parent_line = parent_line(&new_oid);
+            self.update_intermediate_sha1_state();
+        }
+        self.pending_shorthashes
+            .insert(self.prefix.shorthash_index(&new_oid));
+        self.pending_commits.push(new_oid);
+        if self.found_shorthash_count + self.pending_commits.len() == SHORTHASH_COUNT {
+            self.is_stopping = true;
+        }
+        self.yielded_object_counts.commits += 1;
 
         Some(PackFileObject::Deltified {
-            base_oid: self.delta_base_commit_oid,
-            base_size: self.delta_base_commit.data.len(),
+            base_oid,
+            base_size,
             delta: delta_instructions,
             new_oid,
-            new_size: self.delta_base_commit.data.len() + self.delta_base_commit_extension_length,
+            new_size,
         })
     }
+
+    /// For `--replay`, skips ahead to the next recorded attempt, as if every attempt in between had been
+    /// made. Returns false, and stops the run early, if the replay has no attempts left or can't be read.
+    fn skip_to_replayed_attempt(&mut self) -> bool {
+        let next_attempt = match self.replay.as_mut().map(|replay| replay.next_attempt()) {
+            Some(Ok(Some(attempt))) => attempt,
+            Some(Ok(None)) => {
+                self.replay = None;
+                self.stop_early("Replay finished");
+                return false;
+            }
+            Some(Err(err)) => {
+                self.replay = None;
+                self.accepted_attempts_error = Some(err);
+                self.stop_early("Replay failed");
+                return false;
+            }
+            None => return true,
+        };
+        // The attempts in between aren't made, so the periodic check in `record_attempt` rarely runs.
+        if interrupt::is_interrupted() {
+            self.replay = None;
+            self.stop_early("Interrupted");
+            return false;
+        }
+        // The recorded attempts are in increasing order, and each one is made before reading the next.
+        let skipped = next_attempt - self.attempt_count;
+        if skipped > 0 {
+            self.entropy_source.skip(skipped - 1);
+            self.entropy = self.entropy_source.next_entropy();
+            self.attempt_count = next_attempt;
+            self.delta_base_index = (next_attempt % self.delta_bases.len() as u64) as usize;
+        }
+        true
+    }
+
+    fn record_attempt(&mut self) {
+        self.attempt_count += 1;
+        self.entropy = self.entropy_source.next_entropy();
+        self.delta_base_index += 1;
+        if self.delta_base_index == self.delta_bases.len() {
+            self.delta_base_index = 0;
+        }
+        // Unlike the time limit, this is exact, and it's only a comparison.
+        if self.attempt_count == self.attempt_limit && !self.is_stopping {
+            self.stop_early("Attempt limit reached");
+        }
+        if self.attempt_count & 0xfffff == 0 {
+            let counts = &self.yielded_object_counts;
+            println!(
+                "number of commits attempted so far: {} (commits: {}, merges: {})",
+                self.attempt_count,
+                counts.commits,
+                counts.first_level_merge_commits + counts.has_top_level_merge_commit as usize
+            );
+            if let Some(hashers) = &self.hashers {
+                let usage = hashers.buffer_usage();
+                println!(
+                    "hashed chunks waiting for the writer: {} of at most {} ({})",
+                    usage.chunks,
+                    BufferUsage::limit(self.hasher_thread_count, self.hasher_channel_depth).chunks,
+                    format_size(usage.bytes as u64)
+                );
+            }
+            // Checking the time (and for interrupts) is only done periodically, to keep it out of the hot
+            // loop.
+            if !self.is_stopping {
+                let stop_reason = if interrupt::is_interrupted() {
+                    Some("Interrupted")
+                } else if self
+                    .deadline
+                    .is_some_and(|deadline| Instant::now() >= deadline)
+                {
+                    Some("Time limit reached")
+                } else {
+                    None
+                };
+                if let Some(stop_reason) = stop_reason {
+                    self.stop_early(stop_reason);
+                }
+            }
+            if self.found_shorthash_count >= ENDGAME_THRESHOLD {
+                // Shorthashes are only ever added, so the lowest missing shorthash never decreases.
+                if let Some(index) = self
+                    .found_shorthashes
+                    .first_unset_from(self.lowest_missing_shorthash)
+                {
+                    self.lowest_missing_shorthash = index;
+                }
+                println!(
+                    "shorthashes remaining: {} (lowest missing: {:07x})",
+                    SHORTHASH_COUNT - self.found_shorthash_count,
+                    self.lowest_missing_shorthash
+                );
+            }
+        }
+    }
+
+    /// Stops generating new root commits before every shorthash has been found, so that the run can be
+    /// resumed.
+    fn stop_early(&mut self, stop_reason: &str) {
+        println!(
+            "{} after finding {} shorthashes in {} attempts{}",
+            stop_reason,
+            self.found_shorthash_count,
+            self.attempt_count,
+            if self.merged {
+                "; creating merge commits"
+            } else {
+                ""
+            }
+        );
+        self.is_stopping = true;
+        self.stopped_early = true;
+    }
+
+    /// Counts the objects that `batches(batch_size)` will generate, without generating them. The counts
+    /// only depend on how many shorthashes are left to find, and not on which attempts find them, so this
+    /// follows the bookkeeping of `next_batch` and `next_unacknowledged` as if every attempt found a new
+    /// shorthash. This has to be called before generating anything, and assumes that the run isn't
+    /// stopped early by `--time-limit` or `--max-attempts`.
+    fn count_generated_objects(&self, batch_size: usize) -> GeneratedObjectCounts {
+        let remaining_shorthash_count = SHORTHASH_COUNT - self.found_shorthash_count;
+        let mut counts = GeneratedObjectCounts::default();
+        // The same quantities as `root_commit_count`, the length of `pending_commits`, and the number of
+        // root commits that the merge tree hasn't merged yet
+        let mut root_commit_count = self.root_commit_count;
+        let mut pending_commit_count = 0;
+        let mut unmerged_root_commit_count = if self.merged { root_commit_count } else { 0 };
+        let mut is_stopping = self.is_stopping;
+        let mut is_finished = false;
+        loop {
+            // Each batch starts by acknowledging the previous one.
+            root_commit_count += pending_commit_count;
+            if self.merged {
+                unmerged_root_commit_count += pending_commit_count;
+            }
+            pending_commit_count = 0;
+
+            // Most batches only contain commits, so those are skipped over all at once: as many batches as
+            // fit before the cap, before a merge commit is due, and before the last shorthash is found.
+            let mut skipped_batch_count = [
+                self.commit_count_cap
+                    .saturating_add(1)
+                    .saturating_sub(root_commit_count)
+                    / batch_size,
+                remaining_shorthash_count.saturating_sub(counts.commits + 1) / batch_size,
+            ]
+            .iter()
+            .copied()
+            .min()
+            .unwrap();
+            if self.merged {
+                skipped_batch_count = skipped_batch_count.min(
+                    MERGE_COMMIT_PARENT_COUNT
+                        .saturating_sub(unmerged_root_commit_count)
+                        .div_ceil(batch_size),
+                );
+            }
+            if is_stopping || is_finished {
+                skipped_batch_count = 0;
+            }
+            root_commit_count += skipped_batch_count * batch_size;
+            unmerged_root_commit_count += skipped_batch_count * batch_size;
+            counts.commits += skipped_batch_count * batch_size;
+
+            let mut batch_length = 0;
+            while batch_length < batch_size {
+                let is_capped = root_commit_count + pending_commit_count > self.commit_count_cap;
+                // The conditions under which `next_unacknowledged` returns `None`
+                if is_finished
+                    || is_capped
+                    || (is_stopping && (!self.merged || pending_commit_count > 0))
+                {
+                    break;
+                }
+                batch_length += 1;
+                if self.merged {
+                    // The same conditions as in `MergeTreeBuilder::next_merge_commit`
+                    if counts.first_level_merge_commits >= MERGE_COMMIT_PARENT_COUNT
+                        || (is_stopping && unmerged_root_commit_count == 0)
+                        || (is_stopping
+                            && counts.first_level_merge_commits == 0
+                            && unmerged_root_commit_count <= MERGE_COMMIT_PARENT_COUNT)
+                    {
+                        counts.has_top_level_merge_commit = true;
+                        is_finished = true;
+                        continue;
+                    }
+                    if unmerged_root_commit_count >= MERGE_COMMIT_PARENT_COUNT
+                        || (is_stopping && unmerged_root_commit_count > 0)
+                    {
+                        counts.first_level_merge_commits += 1;
+                        unmerged_root_commit_count = 0;
+                        continue;
+                    }
+                }
+                pending_commit_count += 1;
+                counts.commits += 1;
+                if counts.commits == remaining_shorthash_count {
+                    is_stopping = true;
+                }
+            }
+            if batch_length == 0 {
+                return counts;
+            }
+        }
+    }
+}
+
+/// The iterator returned by `DeltifiedCommitGenerator::sample`. Every generated commit is a delta against
+/// either a delta base commit or the previous commit in its chain, so a kept commit needs the skipped
+/// commits between it and the start of its chain. Each commit is the delta base of at most one later
+/// commit, so the skipped commits are held back per chain until a kept commit needs them or the chain
+/// reaches its maximum depth.
+struct Sample<'a> {
+    generator: &'a mut DeltifiedCommitGenerator,
+    batch_size: usize,
+    step: usize,
+    // The number of generated commits so far, kept or skipped
+    seen_count: usize,
+    // For the last commit of each delta chain that can still be extended: its depth, and the skipped
+    // commits of the chain since the last kept one, which end with it unless it was kept
+    chain_tails: HashMap<Sha1Oid, (usize, Vec<PackFileObject>)>,
+    // Kept objects that haven't been returned yet
+    ready: VecDeque<PackFileObject>,
+}
+
+impl Sample<'_> {
+    fn push(&mut self, object: PackFileObject) {
+        let (base_oid, new_oid) = match &object {
+            PackFileObject::Deltified {
+                base_oid, new_oid, ..
+            } => (*base_oid, *new_oid),
+            // Only merge commits are stored whole, and the generator doesn't create them for a sample.
+            PackFileObject::Raw(_) => return self.ready.push_back(object),
+        };
+        let (depth, mut skipped) = match self.chain_tails.remove(&base_oid) {
+            Some((depth, skipped)) => (depth + 1, skipped),
+            None => (1, Vec::new()),
+        };
+        let counts = &mut self.generator.yielded_object_counts;
+        if self.seen_count % self.step == 0 {
+            counts.commits += skipped.len();
+            self.ready.extend(skipped.drain(..));
+            self.ready.push_back(object);
+        } else {
+            counts.commits -= 1;
+            skipped.push(object);
+        }
+        self.seen_count += 1;
+        if depth < self.generator.delta_chain_depth {
+            self.chain_tails.insert(new_oid, (depth, skipped));
+        }
+    }
+}
+
+impl Iterator for Sample<'_> {
+    type Item = PackFileObject;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(object) = self.ready.pop_front() {
+                return Some(object);
+            }
+            let batch = self.generator.next_batch(self.batch_size);
+            if batch.is_empty() {
+                return None;
+            }
+            for object in batch {
+                self.push(object);
+            }
+        }
+    }
+}
+
+/// The number of objects of each kind that a run generates, as computed by `count_generated_objects` or
+/// tallied as the generator yields them
+#[derive(Clone, Copy, Debug, Default)]
+struct GeneratedObjectCounts {
+    commits: usize,
+    first_level_merge_commits: usize,
+    has_top_level_merge_commit: bool,
+}
+
+/// The number of objects of each kind in a run's output, including the ones that aren't generated
+struct ObjectCounts {
+    generated: GeneratedObjectCounts,
+    // "commit", or "tree" with `--object-type tree`, for the generated objects and the delta base
+    generated_type_name: &'static str,
+    delta_base_commits: usize,
+    trees: usize,
+    blobs: usize,
+    // The objects from `--base-objects`, which aren't counted as trees or blobs above
+    base_objects: usize,
+}
+
+impl ObjectCounts {
+    fn total(&self) -> usize {
+        self.generated.commits
+            + self.delta_base_commits
+            + self.generated.first_level_merge_commits
+            + self.generated.has_top_level_merge_commit as usize
+            + self.trees
+            + self.blobs
+            + self.base_objects
+    }
+
+    fn print(&self) {
+        println!(
+            "Generated {}s: {}",
+            self.generated_type_name, self.generated.commits
+        );
+        println!(
+            "Delta base {}s: {}",
+            self.generated_type_name, self.delta_base_commits
+        );
+        println!(
+            "First-level merge commits: {}",
+            self.generated.first_level_merge_commits
+        );
+        println!(
+            "Top-level merge commits: {}",
+            self.generated.has_top_level_merge_commit as usize
+        );
+        println!("Trees: {}", self.trees);
+        println!("Blobs: {}", self.blobs);
+        if self.base_objects > 0 {
+            println!("Base objects from the manifest: {}", self.base_objects);
+        }
+        println!("Total objects: {}", self.total());
+    }
+}
+
+impl Iterator for DeltifiedCommitGenerator {
+    type Item = PackFileObject;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.acknowledge_pending_commits();
+        self.next_unacknowledged()
+    }
 }
 
 impl FusedIterator for DeltifiedCommitGenerator {}
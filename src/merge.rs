@@ -0,0 +1,159 @@
+use crate::{
+    git_utils::{
+        stream_to_pack_file, GitObjectType, ObjectSize, PackFileObject, PackWriteOptions, Sha1Oid,
+    },
+    interrupt,
+    merge_tree::MergeTreeBuilder,
+    pack_reader::{PackReader, PackedObject},
+    shorthash::{OidPrefix, PackedBoolArray},
+};
+use std::{
+    collections::{HashSet, VecDeque},
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Combines packs generated by separate runs (e.g. shards of the search running on different machines)
+/// into a single pack at `output_path`, with one commit per shorthash and a new tree of merge commits
+/// above them.
+///
+/// Parentless commits from the input packs are kept if they start with `prefix` and no earlier commit
+/// had the same shorthash, and the merge commits of the input packs are dropped. Trees and blobs are
+/// copied once each. Kept commits remain deltified against their original delta base whenever that
+/// base is also in the output pack.
+pub fn merge_packs(
+    input_paths: &[PathBuf],
+    output_path: &Path,
+    prefix: OidPrefix,
+) -> io::Result<()> {
+    let mut readers = VecDeque::new();
+    for path in input_paths {
+        let reader = PackReader::open(path)?;
+        println!(
+            "Reading {} objects from {}",
+            reader.object_count(),
+            path.display()
+        );
+        readers.push_back(reader);
+    }
+
+    let mut merged_objects = MergedObjects {
+        readers,
+        prefix,
+        found_shorthashes: PackedBoolArray::default(),
+        emitted_oids: HashSet::new(),
+        merge_tree: MergeTreeBuilder::default(),
+        error: None,
+    };
+    // Ctrl-C stops the merge after the object that's being written, leaving a valid pack of the objects
+    // merged so far.
+    interrupt::install();
+    let pack_file = stream_to_pack_file(
+        output_path,
+        &mut merged_objects,
+        PackWriteOptions {
+            cancel_token: Some(interrupt::cancel_token()),
+            ..PackWriteOptions::default()
+        },
+    )?;
+
+    match merged_objects.error {
+        Some(err) => Err(err),
+        None if pack_file.was_cancelled() => Err(io::Error::new(
+            io::ErrorKind::Interrupted,
+            format!(
+                "the merge was interrupted, so {} only has the first {} objects, without merge commits",
+                output_path.display(),
+                pack_file.fanout()[255]
+            ),
+        )),
+        None => {
+            println!(
+                "Merged {} commits into {}",
+                merged_objects.found_shorthashes.count_set(),
+                output_path.display()
+            );
+            Ok(())
+        }
+    }
+}
+
+/// An iterator over the objects of the merged pack. Since `stream_to_pack_file` consumes an iterator of
+/// objects, an error reading an input pack ends the iteration and is stored for `merge_packs` to return.
+struct MergedObjects {
+    readers: VecDeque<PackReader>,
+    prefix: OidPrefix,
+    found_shorthashes: PackedBoolArray,
+    // The OIDs of every object in the output pack so far, to deduplicate objects and to check whether
+    // delta bases are available. This uses a lot of memory when merging full-size packs.
+    emitted_oids: HashSet<Sha1Oid>,
+    merge_tree: MergeTreeBuilder,
+    error: Option<io::Error>,
+}
+
+impl MergedObjects {
+    /// Decides whether an object from an input pack belongs in the output pack, returning it if so.
+    fn select(&mut self, packed: PackedObject) -> Option<PackFileObject> {
+        if let GitObjectType::Commit = packed.object.object_type {
+            if has_parents(&packed.object.data) || !self.prefix.matches(&packed.oid) {
+                return None;
+            }
+            let shorthash_index = self.prefix.shorthash_index(&packed.oid);
+            if self.found_shorthashes.get(shorthash_index) {
+                return None;
+            }
+            self.found_shorthashes.set(shorthash_index, true);
+            self.merge_tree.push_root_commit(packed.oid);
+        } else if self.emitted_oids.contains(&packed.oid) {
+            return None;
+        }
+
+        self.emitted_oids.insert(packed.oid);
+        Some(match packed.delta {
+            Some(delta) if self.emitted_oids.contains(&delta.base_oid) => {
+                PackFileObject::Deltified {
+                    base_oid: delta.base_oid,
+                    base_size: delta.base_size,
+                    delta: delta.instructions,
+                    new_oid: packed.oid,
+                    new_size: ObjectSize(packed.object.data.len()),
+                }
+            }
+            _ => PackFileObject::Raw(packed.object),
+        })
+    }
+}
+
+impl Iterator for MergedObjects {
+    type Item = PackFileObject;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.error.is_some() || self.merge_tree.is_finished() {
+                return None;
+            }
+            if let Some(merge) = self.merge_tree.next_merge_commit(self.readers.is_empty()) {
+                return Some(PackFileObject::Raw(merge));
+            }
+            match self.readers.front_mut()?.next() {
+                Some(Ok(packed)) => {
+                    if let Some(object) = self.select(packed) {
+                        return Some(object);
+                    }
+                }
+                Some(Err(err)) => self.error = Some(err),
+                None => {
+                    self.readers.pop_front();
+                }
+            }
+        }
+    }
+}
+
+/// Returns whether the given commit data has any `parent` headers.
+fn has_parents(commit_data: &[u8]) -> bool {
+    commit_data
+        .split(|&byte| byte == b'\n')
+        .take_while(|line| !line.is_empty())
+        .any(|line| line.starts_with(b"parent "))
+}
@@ -0,0 +1,185 @@
+use crate::{
+    git_config::{Identity, FALLBACK_IDENTITY},
+    git_utils::{oid_to_hex, GitObject, Sha1Oid, EMPTY_TREE_OID},
+    shorthash::SHORTHASH_COUNT,
+};
+use std::mem;
+
+// The number of parents of each merge commit. With two levels of merge commits, this allows for 2**28
+// root commits.
+pub const MERGE_COMMIT_PARENT_COUNT: usize = 1 << 14;
+
+/// Creates the merge commits that make a set of root commits reachable from a single commit. By default,
+/// every 2**14 root commits are merged into a first-level merge commit, and then all of the first-level
+/// merge commits are merged into a top-level merge commit. With another number of parents, there are as
+/// many levels of merge commits as it takes for the top-level merge commit to reach the maximum number of
+/// root commits. Runs with no more root commits than one merge commit can have only get the top-level
+/// merge commit.
+#[derive(Debug)]
+pub struct MergeTreeBuilder {
+    // The commits at each level of the tree that haven't been merged yet: the root commits, then the
+    // first-level merge commits, and so on. The top-level merge commit merges the last level.
+    levels: Vec<Vec<Sha1Oid>>,
+    parent_count: usize,
+    is_finished: bool,
+    // The tree of the merge commits
+    tree_oid: Sha1Oid,
+    // The author and committer of the merge commits
+    identity: String,
+}
+
+impl Default for MergeTreeBuilder {
+    fn default() -> Self {
+        Self::new(MERGE_COMMIT_PARENT_COUNT, SHORTHASH_COUNT)
+    }
+}
+
+impl MergeTreeBuilder {
+    /// Creates a builder whose merge commits have `parent_count` parents each (except for the last ones
+    /// on each level), with enough levels for up to `max_root_commit_count` root commits.
+    pub fn new(parent_count: usize, max_root_commit_count: usize) -> Self {
+        debug_assert!(parent_count >= 2);
+        let mut level_count = 1;
+        let mut capacity = parent_count;
+        while capacity < max_root_commit_count {
+            level_count += 1;
+            capacity = capacity.saturating_mul(parent_count);
+        }
+        Self {
+            levels: vec![Vec::new(); level_count],
+            parent_count,
+            is_finished: false,
+            tree_oid: EMPTY_TREE_OID,
+            identity: FALLBACK_IDENTITY.to_string(),
+        }
+    }
+
+    /// Makes the merge commits point to `tree_oid` rather than the empty tree. This has to be called
+    /// before the first merge commit is created.
+    pub fn set_tree(&mut self, tree_oid: Sha1Oid) {
+        self.tree_oid = tree_oid;
+    }
+
+    /// Makes `identity` the author and committer of the merge commits. Like `set_tree`, this has to be
+    /// called before the first merge commit is created.
+    pub fn set_identity(&mut self, identity: Identity) {
+        self.identity = identity.to_string();
+    }
+
+    /// Returns the commits at each level that haven't been merged yet, starting with the root commits.
+    pub fn unmerged_levels(&self) -> &[Vec<Sha1Oid>] {
+        &self.levels
+    }
+
+    /// Replaces the unmerged commits with `levels`, as returned by `unmerged_levels` of a builder that
+    /// hadn't created the top-level merge commit yet. Returns `false` (and changes nothing) if the
+    /// builders have different numbers of levels.
+    pub fn restore_unmerged_levels(&mut self, levels: Vec<Vec<Sha1Oid>>) -> bool {
+        if levels.len() != self.levels.len() {
+            return false;
+        }
+        self.levels = levels;
+        true
+    }
+
+    pub fn push_root_commit(&mut self, oid: Sha1Oid) {
+        self.levels[0].push(oid);
+    }
+
+    /// Returns whether the top-level merge commit has been created.
+    pub fn is_finished(&self) -> bool {
+        self.is_finished
+    }
+
+    /// Returns the next merge commit, if one is due. `is_stopping` indicates that no more root commits
+    /// will be added, in which case the remaining commits on each level get merged and then the top-level
+    /// merge commit is created, even if the merge commits have fewer parents than usual.
+    pub fn next_merge_commit(&mut self, is_stopping: bool) -> Option<GitObject> {
+        if self.is_finished {
+            return None;
+        }
+
+        // A run that stops with few enough root commits for a single merge commit (e.g. a small test run)
+        // gets just the top-level merge commit over them, rather than a top-level merge commit whose only
+        // parent is a lone first-level merge commit.
+        if is_stopping
+            && self.levels.len() > 1
+            && self.levels[1..].iter().all(Vec::is_empty)
+            && self.levels[0].len() <= self.parent_count
+        {
+            let root_commits = mem::take(&mut self.levels[0]);
+            *self.levels.last_mut().expect("there's always a level") = root_commits;
+        }
+
+        let (top_level, lower_levels) = self
+            .levels
+            .split_last_mut()
+            .expect("there's always a level");
+        if top_level.len() >= self.parent_count
+            || (is_stopping && lower_levels.iter().all(Vec::is_empty))
+        {
+            self.is_finished = true;
+            let final_merge = create_merge_commit(&self.tree_oid, &self.identity, top_level);
+            println!("Top-level merge commit: {}", oid_to_hex(&final_merge.oid()));
+            println!("Your call is important to us.");
+            println!("Please hold while an index file is generated. This will take a while");
+            return Some(final_merge);
+        }
+
+        for level in 0..self.levels.len() - 1 {
+            let commits = &self.levels[level];
+            let is_due = commits.len() >= self.parent_count || (is_stopping && !commits.is_empty());
+            if !is_due {
+                continue;
+            }
+            // When stopping, a lone commit above the root commits moves up a level as it is, rather than
+            // becoming the only parent of another merge commit.
+            if is_stopping && level > 0 && commits.len() == 1 {
+                let oid = self.levels[level].pop().expect("the level has a commit");
+                self.levels[level + 1].push(oid);
+                continue;
+            }
+            let merge = create_merge_commit(&self.tree_oid, &self.identity, commits);
+            self.levels[level].clear();
+            self.levels[level + 1].push(merge.oid());
+            // Only the merge commits right below the top-level merge commit are reported, since there are
+            // at most `parent_count` of them.
+            if level + 2 == self.levels.len() {
+                println!(
+                    "created {} merge commit {}/{}",
+                    match level {
+                        0 => "first-level".to_string(),
+                        _ => format!("level-{}", level + 1),
+                    },
+                    self.levels[level + 1].len(),
+                    self.parent_count
+                );
+            }
+            return Some(merge);
+        }
+
+        None
+    }
+}
+
+fn create_merge_commit(tree_oid: &Sha1Oid, identity: &str, parent_oids: &[Sha1Oid]) -> GitObject {
+    GitObject::commit(
+        format!(
+            "\
+                tree {}\n\
+                {}\
+                author {identity} 1616279625 -0400\n\
+                committer {identity} 1616279625 -0400\n\
+                \n\
+                Merge of {} commits\n",
+            oid_to_hex(tree_oid),
+            parent_oids
+                .iter()
+                .map(|oid| format!("parent {}\n", oid_to_hex(oid)))
+                .collect::<String>(),
+            parent_oids.len(),
+            identity = identity,
+        )
+        .into_bytes(),
+    )
+}
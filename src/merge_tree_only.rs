@@ -0,0 +1,158 @@
+//! Builds a new tree of merge commits over the root commits of an earlier run, for trying out another
+//! shape of merge tree without regenerating 2**28 commits. The root commits ("leaves") are read from an
+//! `--emit-refs-map` table of the earlier run, and the new pack only contains the merge commits, so it's
+//! only useful alongside the earlier pack, which has the leaves and the tree that the merge commits point
+//! to.
+
+use crate::{
+    git_utils::{oid_to_hex, stream_to_pack_file, PackFileObject, PackWriteOptions, Sha1Oid},
+    merge_tree::MergeTreeBuilder,
+    options::parse_oid,
+    shorthash::SHORTHASH_COUNT,
+};
+use std::{
+    fs::File,
+    io,
+    io::{BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+};
+
+/// Writes a pack to `output_path` with the merge commits over the leaves listed in `leaves_path`, each
+/// merging up to `parent_count` commits. The table can be in either of the formats of `refs_map`.
+pub fn rebuild_merge_tree(
+    leaves_path: &Path,
+    output_path: &Path,
+    parent_count: usize,
+) -> io::Result<()> {
+    let file = File::open(leaves_path)?;
+    // Binary tables always have a record for every shorthash, and text tables have 49-byte lines, so
+    // there's no way to mistake one for the other.
+    let is_binary = file.metadata()?.len() == SHORTHASH_COUNT as u64 * 20;
+    let mut merge_commits = MergeCommits {
+        leaves: LeafReader {
+            reader: BufReader::new(file),
+            path: leaves_path.to_path_buf(),
+            is_binary,
+            line_number: 0,
+        },
+        merge_tree: MergeTreeBuilder::new(parent_count, SHORTHASH_COUNT),
+        leaf_count: 0,
+        merge_commit_count: 0,
+        error: None,
+    };
+    stream_to_pack_file(output_path, &mut merge_commits, PackWriteOptions::default())?;
+
+    match merge_commits.error {
+        Some(err) => Err(err),
+        None => {
+            println!(
+                "Wrote {} merge commits over {} leaves to {}",
+                merge_commits.merge_commit_count,
+                merge_commits.leaf_count,
+                output_path.display()
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Reads the OIDs of the leaves from a table, one at a time.
+struct LeafReader {
+    reader: BufReader<File>,
+    path: PathBuf,
+    is_binary: bool,
+    // The number of lines read so far from a text table, for error messages
+    line_number: usize,
+}
+
+impl LeafReader {
+    fn next_leaf(&mut self) -> io::Result<Option<Sha1Oid>> {
+        if self.is_binary {
+            let mut oid = [0; 20];
+            loop {
+                match self.reader.read_exact(&mut oid) {
+                    // Shorthashes that weren't found are all zeros.
+                    Ok(()) if oid == [0; 20] => continue,
+                    Ok(()) => return Ok(Some(oid)),
+                    Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        self.line_number += 1;
+        // Lines are `<shorthash> <OID>`, but a plain list of OIDs works too.
+        let oid_hex = line.split_whitespace().last().unwrap_or_default();
+        parse_oid(oid_hex).map(Some).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "line {} of {} doesn't end with an OID",
+                    self.line_number,
+                    self.path.display()
+                ),
+            )
+        })
+    }
+}
+
+/// An iterator over the merge commits. As in `merge`, an error reading the leaves ends the iteration and
+/// is stored for `rebuild_merge_tree` to return.
+struct MergeCommits {
+    leaves: LeafReader,
+    merge_tree: MergeTreeBuilder,
+    leaf_count: usize,
+    merge_commit_count: usize,
+    error: Option<io::Error>,
+}
+
+impl Iterator for MergeCommits {
+    type Item = PackFileObject;
+
+    fn next(&mut self) -> Option<PackFileObject> {
+        loop {
+            if self.error.is_some() || self.merge_tree.is_finished() {
+                return None;
+            }
+            let is_stopping = match self.leaves.next_leaf() {
+                Ok(Some(oid)) => {
+                    if self.leaf_count == SHORTHASH_COUNT {
+                        self.error = Some(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!(
+                                "{} has more than {} leaves, starting with {}",
+                                self.leaves.path.display(),
+                                SHORTHASH_COUNT,
+                                oid_to_hex(&oid)
+                            ),
+                        ));
+                        return None;
+                    }
+                    self.merge_tree.push_root_commit(oid);
+                    self.leaf_count += 1;
+                    false
+                }
+                Ok(None) if self.leaf_count == 0 => {
+                    self.error = Some(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{} doesn't list any leaves", self.leaves.path.display()),
+                    ));
+                    return None;
+                }
+                Ok(None) => true,
+                Err(err) => {
+                    self.error = Some(err);
+                    return None;
+                }
+            };
+            if let Some(merge) = self.merge_tree.next_merge_commit(is_stopping) {
+                self.merge_commit_count += 1;
+                return Some(PackFileObject::Raw(merge));
+            }
+        }
+    }
+}
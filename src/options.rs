@@ -0,0 +1,1221 @@
+use crate::{
+    git_utils::{parse_hex_oid, GitObjectType, Sha1Oid},
+    hashers::DEFAULT_CHANNEL_DEPTH,
+    merge_tree::MERGE_COMMIT_PARENT_COUNT,
+    pack_name::PackNameTemplate,
+    refs_map::RefsMapFormat,
+    verify::VerifyMode,
+};
+use flate2::Compression;
+use std::{
+    env, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+/// What the tool should do, chosen by the first command-line argument.
+#[derive(Debug)]
+pub enum Command {
+    /// Generates a pack with a commit for every shorthash. This is the default.
+    Generate,
+
+    /// Combines packs from several runs into a single pack, given as `merge <pack>... -o <output>`.
+    Merge {
+        input_paths: Vec<PathBuf>,
+        output_path: PathBuf,
+    },
+
+    /// Regenerates the index file of an existing pack, given as `idx-only <pack>`.
+    IdxOnly { pack_path: PathBuf },
+
+    /// Prints how long abbreviations have to be to identify each object in a pack index, given as
+    /// `abbrev-lengths <idx>`.
+    AbbrevLengths { index_path: PathBuf },
+
+    /// Looks up an OID in a pack index file, given as `lookup <oid> --idx <idx>`.
+    Lookup { oid: Sha1Oid, index_path: PathBuf },
+
+    /// Writes the contents of an object in a pack to stdout, given as
+    /// `dump-object <oid> --pack <pack> [--idx <idx>] [--pretty]`. The index defaults to the one next to
+    /// the pack.
+    DumpObject {
+        oid: Sha1Oid,
+        pack_path: PathBuf,
+        index_path: PathBuf,
+        pretty: bool,
+    },
+
+    /// Writes a pack with only a new tree of merge commits over the commits listed in an
+    /// `--emit-refs-map` table, given as `merge-tree-only --leaves <table> --fanout <n> -o <output>`.
+    MergeTreeOnly {
+        leaves_path: PathBuf,
+        parent_count: usize,
+        output_path: PathBuf,
+    },
+
+    /// Generates a small pack with `2**bits` attempts in a new repository in a temporary directory, and
+    /// checks the result with git, given as `demo [--bits <n>]`.
+    Demo { bits: u32 },
+
+    /// Prints `USAGE`, for `--help`.
+    Help,
+}
+
+/// The text printed by `--help`. Keep this in sync with `Options::from_args`.
+pub const USAGE: &str = "\
+Generates a git pack with a commit for every seven-character shorthash.
+
+Usage:
+    every-commit-shorthash [generate] [options]
+    every-commit-shorthash merge <pack>... -o <output pack>
+    every-commit-shorthash idx-only <pack>
+    every-commit-shorthash abbrev-lengths <idx>
+    every-commit-shorthash lookup <oid> --idx <idx>
+    every-commit-shorthash dump-object <oid> --pack <pack> [--idx <idx>] [-p|--pretty]
+    every-commit-shorthash merge-tree-only --leaves <refs map> [--fanout <n>] -o <output pack>
+    every-commit-shorthash demo [--bits <n>]
+
+With no options, generate writes pack-every-shorthash.pack and .idx into the repository in the
+current directory.
+
+What to generate:
+    --commit-count-cap <n>          Stop after about n generated commits
+    --time-limit <duration>         Stop searching after this long (e.g. 90s, 30m, 6h, 2d)
+    --max-attempts <n>              Stop searching after n attempted commits (e.g. 1_000_000)
+    --base-commit-file <file>       Use this commit body as the delta base instead of the template
+    --message-footer <file>         Add the file's contents to the message after the entropy line
+    --prefix <hex>                  Only generate commits whose OIDs start with this prefix
+    --oid-prefix-range <xx-yy>      Only cover shorthashes whose first byte is in this range
+    --avoid-prefix <hex>            Leave the shorthashes starting with this uncovered (repeatable)
+    --linear                        Chain the commits instead of merging them
+    --leaves-only                   Leave out the merge commits, so nothing refers to the commits
+    --sample <n>                    Like --leaves-only, but only keep every nth commit
+    --delta-chain-depth <n>         Deltify each commit against the previous one, up to n (<= 4095) deep
+    --batch-size <n>                Generate n objects at a time (changes the pack)
+    --threads <n|auto>              Hash on n threads besides the writer (auto: one per core, less 1)
+    --channel-depth <n>             Let each hashing thread get n chunks ahead of the writer (default 2)
+    --tree-count <n>                Cycle the commits through n trees (1 to 256)
+    --object-type <type>            Cover the shorthashes with commits (the default) or trees
+    --tree-from-dir <dir>           Point the commits at a snapshot of this directory
+    --base-objects <manifest>       Write the objects listed in this manifest before the commits
+    --entropy-source <kind>         counter (the default) or random
+    --seed <n>                      The seed for --entropy-source random
+    --entropy-prefix-len <n>        Start with n hex characters of entropy (1 to 16)
+    --seed-base-entropy <shorthash> Adjust the delta base commit until its OID starts with this
+    --commit-encoding <charset>     Declare this encoding in the commits, with a non-UTF-8 message
+
+Resuming:
+    --resume-from-idx <idx>         Skip the shorthashes of the objects in a previous pack
+    --resume-from-checkpoint <file> Continue from a checkpoint of a previous run
+    --write-checkpoint <file>       Save a checkpoint at the end of the run
+    --journal                       Journal the pack so that a rerun can finish it after a crash
+    --journal-interval <duration>   How often to sync the pack and update the journal (default 1m)
+
+Output:
+    --objects-dir <dir>             Write to this object directory instead of the repository's
+    --alternate-for <repo>          Register the object directory as an alternate of this repository
+    --reuse-existing                Leave out trees, blobs and base objects that are already there
+    --loose                         Write loose objects instead of a pack
+    --stream-to <path>              Write only the packfile, to a FIFO or other stream
+    --force                         Replace an existing pack-every-shorthash pack
+    --append                        Write alongside an existing pack under a different name
+    --checksum-name                 Name the pack after its checksum, like git does
+    --pack-name-template <template> Name the pack after a template like pack-{bits}-{date}
+    --update-info-packs             List the pack in objects/info/packs for dumb HTTP clients
+    --preallocate <bytes|auto>      Allocate disk space for the packfile up front
+    --direct-io                     Write the pack and index without the page cache (Linux only)
+    --compression <level>           zlib level: best (the default), fast, none or 0-9
+    --leaf-compression <level>      zlib level for the generated commits, instead of --compression
+    --merge-compression <level>     zlib level for the merge commits and other whole objects
+    --max-file-size <bytes>         The largest file that the output filesystem can hold
+    --free-space-margin <percent>   Extra free space to require beyond the estimate (default 10)
+    --ignore-free-space             Only warn if there doesn't seem to be enough free space
+    --cleanup-on-error              Remove the partial pack if the disk fills up
+    --big-offset-threshold <bytes>  Use 8-byte index offsets from this offset on (more than 12)
+    --verify-after[=sample|=full]   Check the pack and index once they're written (sample by default)
+
+Reporting:
+    --count-only                    Print how many objects of each kind would be written, and exit
+    --profile                       Print the time spent in each phase at the end
+    --dump-metadata <file>          Write the OID, offset and CRC32 of every object as JSON
+    --fanout-out <file|->           Write the fanout table of the index
+    --emit-refs-map <file>          Write the OID of the commit for each shorthash as it's found
+    --refs-map-format <format>      text (the default) or binary
+    --record-accepted <file>        Record which attempts found a new shorthash, for --replay
+    --replay <file>                 Only make the attempts recorded by --record-accepted
+    -h, --help                      Print this help and exit
+";
+
+/// How much disk space to allocate for the packfile before writing it.
+#[derive(Debug)]
+pub enum Preallocation {
+    /// Estimates the size of the packfile from the number of commits that will be generated
+    Auto,
+    Bytes(u64),
+}
+
+/// What to do if the pack directory already has a pack from a previous run.
+#[derive(Debug, Clone, Copy)]
+pub enum ExistingPack {
+    /// Fails before generating anything. This is the default, since the existing pack could be the
+    /// result of a multi-day run.
+    Refuse,
+    /// Replaces the existing pack once the new one has been written completely.
+    Replace,
+    /// Writes the new pack alongside the existing one, under a different name.
+    Append,
+}
+
+/// Where the entropy for attempted commits comes from.
+#[derive(Debug)]
+pub enum EntropySourceKind {
+    Counter,
+    Random,
+}
+
+/// Command-line options. Running with no arguments generates the full repository.
+#[derive(Debug)]
+pub struct Options {
+    pub command: Command,
+
+    /// Stops generating commits after roughly this many, leaving a partial pack. Runs with the same
+    /// options produce byte-identical packfiles and index files, so a small cap is a quick way to check
+    /// the output of a change against a previous version.
+    pub commit_count_cap: usize,
+
+    /// A file containing the body of a commit object (without the `commit <length>\0` header) to use
+    /// as the delta base, instead of the hardcoded template. Generated commits consist of this body
+    /// followed by hex entropy, so it should usually end partway through the commit message.
+    pub base_commit_file: Option<PathBuf>,
+
+    /// A file whose contents go in every commit message after the entropy, on the line after it. The
+    /// delta base commit ends with the footer, and each generated commit's delta copies the part before
+    /// the footer, adds the entropy, and then copies the footer. The footer has to be hashed for every
+    /// attempt, so a long one makes the search slower.
+    pub message_footer: Option<PathBuf>,
+
+    /// Stops searching for new shorthashes after this much time has passed, then finishes the merge
+    /// commits and the index as usual. Accepts a number of seconds, optionally suffixed with `s`, `m`,
+    /// `h` or `d`.
+    pub time_limit: Option<Duration>,
+
+    /// Stops searching for new shorthashes after this many attempted commits in this run, whether or not
+    /// they found a new shorthash, then finishes the merge commits and the index like `--time-limit`.
+    /// Unlike `--commit-count-cap`, this bounds the work rather than the size of the pack.
+    pub max_attempts: Option<u64>,
+
+    /// A pack index file from a previous run. The shorthashes of all of its objects are treated as
+    /// already found, so the new pack only contains commits for the remaining shorthashes.
+    pub resume_from_idx: Option<PathBuf>,
+
+    /// A checkpoint written by `--write-checkpoint` in a previous run. Like `--resume-from-idx`, the
+    /// shorthashes recorded in it are treated as already found, and the entropy sequence also continues
+    /// from where the previous run stopped. The previous run's pack has to be kept alongside the new one.
+    pub resume_from_checkpoint: Option<PathBuf>,
+
+    /// A path to save a checkpoint of the found shorthashes to once the run has finished writing its
+    /// objects, for resuming with `--resume-from-checkpoint`.
+    pub write_checkpoint: Option<PathBuf>,
+
+    /// Keeps a journal next to the pack of how much of it has been synced to disk, so that if the run
+    /// crashes or is killed, running the same command again finishes the same pack rather than starting
+    /// a new one. See `journal`.
+    pub journal: bool,
+
+    /// How often to sync the packfile and update the journal with `--journal`, instead of every minute.
+    /// Each update stalls the writer until the sync is done, but a crash loses everything since the last
+    /// one. With 0, the journal is updated after every batch.
+    pub journal_interval: Option<Duration>,
+
+    /// The maximum length of the delta chains of generated commits. By default, every commit is a delta
+    /// against the same base commit; with a larger depth, each commit is a delta against the previous
+    /// one, forming chains of up to this many deltas.
+    pub delta_chain_depth: usize,
+
+    /// Gives every generated commit the previous one as its parent, so that the commits form a single
+    /// linear chain starting at the delta base commit, instead of merging them with merge commits.
+    pub linear: bool,
+
+    /// Leaves out the merge commits, so that nothing refers to the generated commits.
+    pub leaves_only: bool,
+
+    /// Like `leaves_only`, but only keeps every this-many-th generated commit (along with the skipped
+    /// commits that the kept ones are deltas against), for a smaller pack spread over every shorthash.
+    pub sample: Option<usize>,
+
+    /// The number of objects to generate at a time before handing them to the writer. Larger batches
+    /// amortize the per-object overhead, but delay the merge commits over each batch until the next one,
+    /// so the pack differs from the one generated with the default of 1.
+    pub batch_size: usize,
+
+    /// The number of threads that hash the attempted commits, besides the thread that writes the pack, or
+    /// `None` for `auto` (the default), which is one less than the number of cores. The writer gets a core
+    /// of its own since it also goes through every hashed attempt to decide which ones to keep, so
+    /// hashers competing with it for a core would slow down the whole run. With 0, the writer hashes the
+    /// attempts itself.
+    pub threads: Option<usize>,
+
+    /// How many hashed chunks of attempts each hashing thread can have waiting for the writer before it
+    /// waits too. A deeper channel smooths over the writer's pauses (like writing a checkpoint) at the
+    /// cost of up to 512 KiB per chunk, which is what a chunk takes when every attempt matches.
+    pub channel_depth: usize,
+
+    /// A path to write the OID, offset, and CRC32 of every object in the generated pack to, as JSON.
+    /// This is mostly useful for capped runs, since the output for a full run is tens of gigabytes.
+    pub dump_metadata: Option<PathBuf>,
+
+    /// A path to write the fanout table of the generated index to, or `-` for stdout. The table has one
+    /// line per first OID byte, with the cumulative number of objects up to and including that byte, so
+    /// the tables of two runs can be compared with `diff`.
+    pub fanout_out: Option<PathBuf>,
+
+    /// An object directory to write the objects to, instead of the object directory of the repository in
+    /// the current directory. The directory is created if it doesn't exist, so this can be a standalone
+    /// object store that isn't part of any repository.
+    pub objects_dir: Option<PathBuf>,
+
+    /// Repositories to register the object directory as an alternate in once the objects have been
+    /// written, so that they can all share one copy of the generated pack. Can be given more than once.
+    pub alternate_for: Vec<PathBuf>,
+
+    /// Leaves the trees, blobs and `--base-objects` that the object directory already has (as loose
+    /// objects or in its packs) out of the output, e.g. when a repository already has the snapshot from
+    /// `--tree-from-dir`. The delta base commits are always written, since the generated commits are
+    /// deltas against them and a pack can only have deltas against its own objects.
+    pub reuse_existing: bool,
+
+    /// A hex prefix that every generated commit OID must start with. With a prefix, the tool generates a
+    /// commit for every 7-character shorthash that follows the prefix, at a cost of `16**prefix_length`
+    /// times as many attempts.
+    pub prefix: Option<String>,
+
+    /// A range of first OID bytes, such as `00-3f`, that every generated commit OID must start with. The
+    /// run then covers only the shorthashes starting with those bytes, so shards with disjoint ranges
+    /// produce packs that `merge` can combine without any duplicate shorthashes.
+    pub oid_prefix_range: Option<String>,
+
+    /// Hex prefixes of up to 7 characters for shorthashes to leave without a commit, e.g. to reserve
+    /// every OID starting with `dead` for commits made by hand. No generated commit starts with one of
+    /// them (after `--prefix`, if there is one), but the merge commits still can. Can be given more than
+    /// once.
+    pub avoid_prefixes: Vec<String>,
+
+    /// Writes every object as a loose object instead of writing a packfile. This is far too slow for a
+    /// full run, but the objects can be inspected directly with `git cat-file`, so it's useful alongside
+    /// `--commit-count-cap` when testing.
+    pub loose: bool,
+
+    /// Allocates disk space for the packfile before writing it, either a given number of bytes or `auto`
+    /// to estimate the size. On some filesystems, this avoids heavy fragmentation when writing a huge
+    /// packfile incrementally. The file is truncated to its real size at the end.
+    pub preallocate: Option<Preallocation>,
+
+    /// Writes the packfile and index file with `O_DIRECT`, so that writing tens of GiB doesn't evict
+    /// everything else from the page cache. Only supported on Linux.
+    pub direct_io: bool,
+
+    /// The zlib compression level of the objects in the packfile, which is the best compression by
+    /// default. `none` is the fastest to write, but the packfile is about 50% larger.
+    pub compression: Compression,
+
+    /// Overrides `compression` for the generated commits, which are deltas of a few bytes of entropy
+    /// that zlib can barely shrink, so a fast level costs little space.
+    pub leaf_compression: Option<Compression>,
+
+    /// Overrides `compression` for everything stored whole, which is mostly the merge commits. These are
+    /// few but large, and compress well.
+    pub merge_compression: Option<Compression>,
+
+    /// Renames the finished packfile and index file to `pack-<checksum>.pack` and `pack-<checksum>.idx`,
+    /// following git's convention of naming packs after their trailing checksum, instead of leaving them
+    /// as `pack-every-shorthash`.
+    pub checksum_name: bool,
+
+    /// Names the pack after a template instead, such as `pack-shorthash-{bits}-{date}`, whose tokens
+    /// stand for parameters of the run (see `pack_name`). A template with `{count}` or `{checksum}` has
+    /// the pack renamed once it's finished, like `checksum_name`.
+    pub pack_name_template: Option<PackNameTemplate>,
+
+    /// Adds the finished pack to the object directory's `info/packs` file, which lists the packs for
+    /// clients of git's dumb HTTP protocol, so that they can fetch it from a plain web server.
+    pub update_info_packs: bool,
+
+    /// The largest packfile that the output filesystem can hold, in bytes. The generator checks the
+    /// estimated size of the pack against this before starting, in addition to any limits it can detect
+    /// on its own (such as FAT32's 4 GiB file size limit, or the free space on the disk).
+    pub max_file_size: Option<u64>,
+
+    /// How much more free space than the estimated size of the packfile and index file to require before
+    /// starting, as a percentage of the estimate. The estimate is usually within a few percent.
+    pub free_space_margin: u64,
+
+    /// Only warns, rather than failing, if there doesn't seem to be enough free space for the output. The
+    /// space could be freed up while the run is going, for example.
+    pub ignore_free_space: bool,
+
+    /// Removes the partial packfile and index file if the disk fills up while writing them, rather than
+    /// leaving them behind to take up space.
+    pub cleanup_on_error: bool,
+
+    /// What to do if `pack-every-shorthash.pack` already exists: refuse, or `--force` to replace it, or
+    /// `--append` to write the new pack under a different name.
+    pub existing_pack: ExistingPack,
+
+    /// Where the entropy values of attempted commits come from: `counter` (the default) counts up from
+    /// 0, and `random` uses a seeded pseudorandom sequence, so the generated OIDs aren't predictable.
+    pub entropy_source: EntropySourceKind,
+
+    /// The seed for `--entropy-source random`. Runs with the same seed produce identical packs; without
+    /// a seed, a random one is chosen and printed.
+    pub seed: Option<u64>,
+
+    /// The number of hex characters of entropy that each attempted commit starts out with, from 1 to 16
+    /// (8 by default). Once every value of that width has been tried, the generator switches to 16
+    /// characters. Mostly useful for measuring how the width affects the hashing speed.
+    pub entropy_prefix_len: Option<usize>,
+
+    /// Prints how many seconds each phase of the run took at the end: generating commits, compressing
+    /// them, writing the packfile and writing the index, among others.
+    pub profile: bool,
+
+    /// The number of different trees that the generated commits point to, from 1 (the default, where
+    /// every commit has the empty tree) to 256. With more than one, the commits cycle through the empty
+    /// tree and trees that each contain an empty subdirectory, for testing tools that group commits by
+    /// tree.
+    pub tree_count: usize,
+
+    /// The type of the objects that cover the shorthashes: commits (the default), or trees, for testing
+    /// tools that resolve abbreviated tree OIDs. Each generated tree has a single entry, an empty
+    /// subdirectory whose name ends with the entropy. Nothing refers to the trees, so there are no merge
+    /// commits, and the options that change the generated commits can't be used.
+    pub object_type: GitObjectType,
+
+    /// A directory to snapshot as the tree of the generated commits and the merge commits, instead of
+    /// the empty tree, so that checking out any of them gives the directory's files (e.g. a README
+    /// explaining the repository).
+    pub tree_from_dir: Option<PathBuf>,
+
+    /// A manifest listing objects to write ahead of the generated commits, such as the trees and blobs
+    /// that a `--base-commit-file` points to. See `base_objects` for the format.
+    pub base_objects: Option<PathBuf>,
+
+    /// Lists objects at or past this offset in the index's table of 8-byte offsets, even though their
+    /// offsets would fit in 4 bytes. Git only needs the table for packs over 2 GiB, so this is for
+    /// testing tools that read index files against small packs. It has to be past the first object, at
+    /// offset 12, since git rejects indexes with as many 8-byte offsets as objects.
+    pub big_offset_threshold: Option<usize>,
+
+    /// Prints the exact number of objects of each kind that the run would write, and exits without
+    /// generating anything.
+    pub count_only: bool,
+
+    /// Streams the packfile to this path instead of writing a pack and index into the repository. The
+    /// path is usually a FIFO that `git index-pack --stdin` (or a program sending the pack over the
+    /// network) is reading from, so that the pack never needs to be stored.
+    pub stream_to: Option<PathBuf>,
+
+    /// A shorthash (or a shorter hex prefix) for the delta base commit's OID to start with. Before
+    /// generating anything, hex digits are appended to the delta base commit's message until its OID
+    /// matches, and every generated commit then extends the adjusted commit. A full 7-character shorthash
+    /// takes about 2**28 attempts to find.
+    pub seed_base_entropy: Option<String>,
+
+    /// A charset for the generated commits to declare in an `encoding` header, such as `ISO-8859-1`. The
+    /// hardcoded template's message then also gets a character that isn't valid UTF-8, so that tools
+    /// which ignore the header show it wrongly. A `--base-commit-file` gets the header, but its message
+    /// is left as it is.
+    pub commit_encoding: Option<String>,
+
+    /// A path to write a table of the commit found for each shorthash to, as the commits are found. See
+    /// `refs_map` for the formats.
+    pub emit_refs_map: Option<PathBuf>,
+
+    pub refs_map_format: RefsMapFormat,
+
+    /// A path to record the attempts that found a new shorthash to, for `--replay`. See `replay` for the
+    /// format.
+    pub record_accepted: Option<PathBuf>,
+
+    /// A file written by `--record-accepted` in a previous run. Rather than searching, the run only makes
+    /// the recorded attempts, so given the same options, it generates the same pack much faster.
+    pub replay: Option<PathBuf>,
+
+    /// Reads the pack back once it has been written and checks it against its index, either for a random
+    /// sample of objects or for all of them. See `verify` for what's checked.
+    pub verify_after: Option<VerifyMode>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            command: Command::Generate,
+            commit_count_cap: usize::MAX,
+            base_commit_file: None,
+            message_footer: None,
+            time_limit: None,
+            max_attempts: None,
+            resume_from_idx: None,
+            resume_from_checkpoint: None,
+            write_checkpoint: None,
+            journal: false,
+            journal_interval: None,
+            delta_chain_depth: 1,
+            linear: false,
+            leaves_only: false,
+            sample: None,
+            batch_size: 1,
+            threads: None,
+            channel_depth: DEFAULT_CHANNEL_DEPTH,
+            dump_metadata: None,
+            fanout_out: None,
+            objects_dir: None,
+            alternate_for: Vec::new(),
+            reuse_existing: false,
+            prefix: None,
+            oid_prefix_range: None,
+            avoid_prefixes: Vec::new(),
+            loose: false,
+            preallocate: None,
+            direct_io: false,
+            compression: Compression::best(),
+            leaf_compression: None,
+            merge_compression: None,
+            checksum_name: false,
+            pack_name_template: None,
+            update_info_packs: false,
+            max_file_size: None,
+            free_space_margin: 10,
+            ignore_free_space: false,
+            cleanup_on_error: false,
+            existing_pack: ExistingPack::Refuse,
+            entropy_source: EntropySourceKind::Counter,
+            seed: None,
+            entropy_prefix_len: None,
+            profile: false,
+            tree_count: 1,
+            object_type: GitObjectType::Commit,
+            tree_from_dir: None,
+            base_objects: None,
+            big_offset_threshold: None,
+            count_only: false,
+            stream_to: None,
+            seed_base_entropy: None,
+            commit_encoding: None,
+            emit_refs_map: None,
+            refs_map_format: RefsMapFormat::Text,
+            record_accepted: None,
+            replay: None,
+            verify_after: None,
+        }
+    }
+}
+
+impl Options {
+    pub fn from_args() -> io::Result<Self> {
+        let mut options = Self::default();
+        let mut args = env::args().skip(1).peekable();
+        let subcommand = match args.peek().map(String::as_str) {
+            Some("merge")
+            | Some("idx-only")
+            | Some("abbrev-lengths")
+            | Some("lookup")
+            | Some("dump-object")
+            | Some("merge-tree-only")
+            | Some("demo") => args.next(),
+            Some("generate") => {
+                args.next();
+                None
+            }
+            _ => None,
+        };
+        let is_merge = subcommand.as_deref() == Some("merge");
+        let is_merge_tree_only = subcommand.as_deref() == Some("merge-tree-only");
+        let is_dump_object = subcommand.as_deref() == Some("dump-object");
+        let is_demo = subcommand.as_deref() == Some("demo");
+        let mut input_paths = Vec::new();
+        let mut output_path = None;
+        let mut lookup_index_path = None;
+        let mut dump_pack_path = None;
+        let mut pretty = false;
+        let mut leaves_path = None;
+        let mut merge_parent_count = MERGE_COMMIT_PARENT_COUNT;
+        let mut demo_bits = 16;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--commit-count-cap" => {
+                    options.commit_count_cap = parse_value(&arg, args.next())?;
+                }
+                "--base-commit-file" => {
+                    options.base_commit_file = Some(parse_value(&arg, args.next())?);
+                }
+                "--message-footer" => {
+                    options.message_footer = Some(parse_value(&arg, args.next())?);
+                }
+                "--time-limit" => {
+                    options.time_limit = Some(parse_duration(&arg, args.next())?);
+                }
+                "--max-attempts" => {
+                    // Underscores are allowed as digit separators, since the counts get large.
+                    let count: String = parse_value(&arg, args.next())?;
+                    let count = parse_value(&arg, Some(count.replace('_', "")))?;
+                    if count == 0 {
+                        return Err(invalid_input(format!("{} must be at least 1", arg)));
+                    }
+                    options.max_attempts = Some(count);
+                }
+                "--resume-from-idx" => {
+                    options.resume_from_idx = Some(parse_value(&arg, args.next())?);
+                }
+                "--resume-from-checkpoint" => {
+                    options.resume_from_checkpoint = Some(parse_value(&arg, args.next())?);
+                }
+                "--write-checkpoint" => {
+                    options.write_checkpoint = Some(parse_value(&arg, args.next())?);
+                }
+                "--journal" => options.journal = true,
+                "--journal-interval" => {
+                    options.journal_interval = Some(parse_duration(&arg, args.next())?);
+                }
+                "--delta-chain-depth" => {
+                    options.delta_chain_depth = parse_value(&arg, args.next())?;
+                    // Git's pack-objects doesn't write deeper chains either, so other tools may not expect
+                    // them.
+                    if !(1..=4095).contains(&options.delta_chain_depth) {
+                        return Err(invalid_input(format!(
+                            "{} must be between 1 and 4095, not {}",
+                            arg, options.delta_chain_depth
+                        )));
+                    }
+                }
+                "--batch-size" => {
+                    options.batch_size = parse_value(&arg, args.next())?;
+                    if options.batch_size == 0 {
+                        return Err(invalid_input(format!("{} must be at least 1", arg)));
+                    }
+                }
+                "--threads" => {
+                    let value: String = parse_value(&arg, args.next())?;
+                    options.threads = if value == "auto" {
+                        None
+                    } else {
+                        Some(parse_value(&arg, Some(value))?)
+                    };
+                }
+                "--channel-depth" => {
+                    options.channel_depth = parse_value(&arg, args.next())?;
+                    if options.channel_depth == 0 {
+                        return Err(invalid_input(format!("{} must be at least 1", arg)));
+                    }
+                }
+                "--dump-metadata" => {
+                    options.dump_metadata = Some(parse_value(&arg, args.next())?);
+                }
+                "--objects-dir" => {
+                    options.objects_dir = Some(parse_value(&arg, args.next())?);
+                }
+                "--alternate-for" => {
+                    options.alternate_for.push(parse_value(&arg, args.next())?);
+                }
+                "--fanout-out" => {
+                    options.fanout_out = Some(parse_value(&arg, args.next())?);
+                }
+                "--prefix" => {
+                    options.prefix = Some(parse_value(&arg, args.next())?);
+                }
+                "--oid-prefix-range" => {
+                    options.oid_prefix_range = Some(parse_value(&arg, args.next())?);
+                }
+                "--avoid-prefix" => {
+                    let hex: String = parse_value(&arg, args.next())?;
+                    if !(1..=7).contains(&hex.len())
+                        || !hex.bytes().all(|byte| byte.is_ascii_hexdigit())
+                    {
+                        return Err(invalid_input(format!(
+                            "{} must be 1 to 7 hex characters, not {}",
+                            arg, hex
+                        )));
+                    }
+                    options.avoid_prefixes.push(hex.to_ascii_lowercase());
+                }
+                "--loose" => options.loose = true,
+                "--reuse-existing" => options.reuse_existing = true,
+                "--checksum-name" => options.checksum_name = true,
+                "--pack-name-template" => {
+                    let template: String = parse_value(&arg, args.next())?;
+                    options.pack_name_template = Some(PackNameTemplate::parse(&template)?);
+                }
+                "--update-info-packs" => options.update_info_packs = true,
+                "--linear" => options.linear = true,
+                "--leaves-only" => options.leaves_only = true,
+                "--sample" => {
+                    let step = parse_value(&arg, args.next())?;
+                    if step == 0 {
+                        return Err(invalid_input(format!("{} must be at least 1", arg)));
+                    }
+                    options.sample = Some(step);
+                }
+                "--cleanup-on-error" => options.cleanup_on_error = true,
+                "--force" | "--append" => {
+                    if !matches!(options.existing_pack, ExistingPack::Refuse) {
+                        return Err(invalid_input(
+                            "--force and --append can't be used together".to_string(),
+                        ));
+                    }
+                    options.existing_pack = if arg == "--force" {
+                        ExistingPack::Replace
+                    } else {
+                        ExistingPack::Append
+                    };
+                }
+                "--max-file-size" => {
+                    options.max_file_size = Some(parse_value(&arg, args.next())?);
+                }
+                "--free-space-margin" => {
+                    options.free_space_margin = parse_value(&arg, args.next())?;
+                }
+                "--ignore-free-space" => options.ignore_free_space = true,
+                "--profile" => options.profile = true,
+                "--count-only" => options.count_only = true,
+                "-h" | "--help" => {
+                    options.command = Command::Help;
+                    return Ok(options);
+                }
+                "--seed-base-entropy" => {
+                    let shorthash: String = parse_value(&arg, args.next())?;
+                    if !(1..=7).contains(&shorthash.len())
+                        || !shorthash.bytes().all(|byte| byte.is_ascii_hexdigit())
+                    {
+                        return Err(invalid_input(format!(
+                            "{} must be 1 to 7 hex characters, not {}",
+                            arg, shorthash
+                        )));
+                    }
+                    options.seed_base_entropy = Some(shorthash.to_ascii_lowercase());
+                }
+                "--commit-encoding" => {
+                    let charset: String = parse_value(&arg, args.next())?;
+                    // The header is a single line, and git splits its value at the first space.
+                    if charset.is_empty() || !charset.bytes().all(|byte| byte.is_ascii_graphic()) {
+                        return Err(invalid_input(format!(
+                            "{} must be a charset name without spaces, not {:?}",
+                            arg, charset
+                        )));
+                    }
+                    options.commit_encoding = Some(charset);
+                }
+                "--stream-to" => {
+                    options.stream_to = Some(parse_value(&arg, args.next())?);
+                }
+                "--object-type" => {
+                    options.object_type = match args.next().as_deref() {
+                        Some("commit") => GitObjectType::Commit,
+                        Some("tree") => GitObjectType::Tree,
+                        value => {
+                            return Err(invalid_input(format!(
+                                "{} must be commit or tree, not {}",
+                                arg,
+                                value.unwrap_or("nothing")
+                            )))
+                        }
+                    };
+                }
+                "--entropy-source" => {
+                    options.entropy_source = match args.next().as_deref() {
+                        Some("counter") => EntropySourceKind::Counter,
+                        Some("random") => EntropySourceKind::Random,
+                        value => {
+                            return Err(invalid_input(format!(
+                                "{} must be counter or random, not {}",
+                                arg,
+                                value.unwrap_or("nothing")
+                            )))
+                        }
+                    };
+                }
+                "--emit-refs-map" => {
+                    options.emit_refs_map = Some(parse_value(&arg, args.next())?);
+                }
+                "--record-accepted" => {
+                    options.record_accepted = Some(parse_value(&arg, args.next())?);
+                }
+                "--replay" => options.replay = Some(parse_value(&arg, args.next())?),
+                "--refs-map-format" => {
+                    options.refs_map_format = match args.next().as_deref() {
+                        Some("text") => RefsMapFormat::Text,
+                        Some("binary") => RefsMapFormat::Binary,
+                        value => {
+                            return Err(invalid_input(format!(
+                                "{} must be text or binary, not {}",
+                                arg,
+                                value.unwrap_or("nothing")
+                            )))
+                        }
+                    };
+                }
+                "--seed" => {
+                    options.seed = Some(parse_value(&arg, args.next())?);
+                }
+                "--entropy-prefix-len" => {
+                    let length = parse_value(&arg, args.next())?;
+                    if !(1..=16).contains(&length) {
+                        return Err(invalid_input(format!(
+                            "{} must be between 1 and 16, not {}",
+                            arg, length
+                        )));
+                    }
+                    options.entropy_prefix_len = Some(length);
+                }
+                "--tree-count" => {
+                    let count = parse_value(&arg, args.next())?;
+                    if !(1..=256).contains(&count) {
+                        return Err(invalid_input(format!(
+                            "{} must be between 1 and 256, not {}",
+                            arg, count
+                        )));
+                    }
+                    options.tree_count = count;
+                }
+                "--tree-from-dir" => {
+                    options.tree_from_dir = Some(parse_value(&arg, args.next())?);
+                }
+                "--base-objects" => {
+                    options.base_objects = Some(parse_value(&arg, args.next())?);
+                }
+                "--big-offset-threshold" => {
+                    let threshold = parse_value(&arg, args.next())?;
+                    if threshold <= 12 {
+                        return Err(invalid_input(format!(
+                            "{} must be more than 12, since git rejects indexes where even the first \
+                            object (at offset 12) has an 8-byte offset",
+                            arg
+                        )));
+                    }
+                    options.big_offset_threshold = Some(threshold);
+                }
+                "--direct-io" => options.direct_io = true,
+                "--compression" => options.compression = parse_compression(&arg, args.next())?,
+                "--leaf-compression" => {
+                    options.leaf_compression = Some(parse_compression(&arg, args.next())?)
+                }
+                "--merge-compression" => {
+                    options.merge_compression = Some(parse_compression(&arg, args.next())?)
+                }
+                "--verify-after" | "--verify-after=sample" => {
+                    options.verify_after = Some(VerifyMode::Sample)
+                }
+                "--verify-after=full" => options.verify_after = Some(VerifyMode::Full),
+                "--preallocate" => {
+                    options.preallocate = Some(match args.next() {
+                        Some(value) if value == "auto" => Preallocation::Auto,
+                        value => Preallocation::Bytes(parse_value(&arg, value)?),
+                    });
+                }
+                "-o" | "--output" if is_merge || is_merge_tree_only => {
+                    output_path = Some(parse_value(&arg, args.next())?);
+                }
+                "--leaves" if is_merge_tree_only => {
+                    leaves_path = Some(parse_value(&arg, args.next())?);
+                }
+                "--fanout" if is_merge_tree_only => {
+                    merge_parent_count = parse_value(&arg, args.next())?;
+                    if merge_parent_count < 2 {
+                        return Err(invalid_input(format!("{} must be at least 2", arg)));
+                    }
+                }
+                "--idx" if subcommand.as_deref() == Some("lookup") || is_dump_object => {
+                    lookup_index_path = Some(parse_value(&arg, args.next())?);
+                }
+                "--pack" if is_dump_object => {
+                    dump_pack_path = Some(parse_value::<PathBuf>(&arg, args.next())?);
+                }
+                "-p" | "--pretty" if is_dump_object => pretty = true,
+                "--bits" if is_demo => {
+                    demo_bits = parse_value(&arg, args.next())?;
+                    if !(1..=24).contains(&demo_bits) {
+                        return Err(invalid_input(format!(
+                            "{} must be from 1 to 24, since the demo is meant to be quick",
+                            arg
+                        )));
+                    }
+                }
+                _ if subcommand.is_some() && !arg.starts_with('-') => {
+                    input_paths.push(PathBuf::from(arg))
+                }
+                _ => {
+                    return Err(invalid_input(format!(
+                        "unrecognized argument {} (see --help)",
+                        arg
+                    )))
+                }
+            }
+        }
+        if options.loose
+            && (options.dump_metadata.is_some()
+                || options.fanout_out.is_some()
+                || options.preallocate.is_some()
+                || options.direct_io
+                || options.compression != Compression::best()
+                || options.leaf_compression.is_some()
+                || options.merge_compression.is_some()
+                || options.checksum_name
+                || options.pack_name_template.is_some()
+                || options.update_info_packs
+                || options.max_file_size.is_some()
+                || options.cleanup_on_error
+                || options.ignore_free_space
+                || options.profile
+                || options.big_offset_threshold.is_some()
+                || options.verify_after.is_some()
+                || options.journal
+                || !matches!(options.existing_pack, ExistingPack::Refuse))
+        {
+            return Err(invalid_input(
+                "--dump-metadata, --fanout-out, --preallocate, --direct-io, --compression, \
+                --leaf-compression, --merge-compression, --checksum-name, --pack-name-template, \
+                --update-info-packs, --max-file-size, --cleanup-on-error, --ignore-free-space, \
+                --profile, --big-offset-threshold, --verify-after, --journal, --force and --append \
+                can't be used with --loose, since no pack is written"
+                    .to_string(),
+            ));
+        }
+        if options.stream_to.is_some()
+            && (options.loose
+                || options.count_only
+                || options.time_limit.is_some()
+                || options.max_attempts.is_some()
+                || options.objects_dir.is_some()
+                || !options.alternate_for.is_empty()
+                || options.reuse_existing
+                || options.dump_metadata.is_some()
+                || options.fanout_out.is_some()
+                || options.preallocate.is_some()
+                || options.direct_io
+                || options.checksum_name
+                || options.pack_name_template.is_some()
+                || options.update_info_packs
+                || options.max_file_size.is_some()
+                || options.cleanup_on_error
+                || options.ignore_free_space
+                || options.big_offset_threshold.is_some()
+                || options.verify_after.is_some()
+                || options.sample.is_some()
+                || options.journal
+                || !matches!(options.existing_pack, ExistingPack::Refuse))
+        {
+            return Err(invalid_input(
+                "--loose, --count-only, --time-limit, --max-attempts, --objects-dir, --alternate-for, \
+                --reuse-existing, --dump-metadata, --fanout-out, --preallocate, --direct-io, \
+                --checksum-name, --pack-name-template, --update-info-packs, --max-file-size, \
+                --cleanup-on-error, --ignore-free-space, --big-offset-threshold, --verify-after, \
+                --sample, --journal, --force and --append can't be used with --stream-to, since the \
+                packfile header needs the number of objects up front and no index is written"
+                    .to_string(),
+            ));
+        }
+        if options.checksum_name && options.pack_name_template.is_some() {
+            return Err(invalid_input(
+                "--checksum-name and --pack-name-template can't be used together (put {checksum} in \
+                the template instead)"
+                    .to_string(),
+            ));
+        }
+        if options.direct_io && !cfg!(target_os = "linux") {
+            return Err(invalid_input(
+                "--direct-io is only supported on Linux".to_string(),
+            ));
+        }
+        if options.prefix.is_some() && options.oid_prefix_range.is_some() {
+            return Err(invalid_input(
+                "--prefix and --oid-prefix-range can't be used together".to_string(),
+            ));
+        }
+        if options.resume_from_idx.is_some() && options.resume_from_checkpoint.is_some() {
+            return Err(invalid_input(
+                "--resume-from-idx and --resume-from-checkpoint can't be used together".to_string(),
+            ));
+        }
+        if (options.record_accepted.is_some() || options.replay.is_some())
+            && (options.resume_from_idx.is_some() || options.resume_from_checkpoint.is_some())
+        {
+            return Err(invalid_input(
+                "--resume-from-idx and --resume-from-checkpoint can't be used with --record-accepted \
+                or --replay, since the attempts of the previous run weren't recorded"
+                    .to_string(),
+            ));
+        }
+        if options.replay.is_some()
+            && (options.record_accepted.is_some()
+                || options.time_limit.is_some()
+                || options.max_attempts.is_some())
+        {
+            return Err(invalid_input(
+                "--record-accepted, --time-limit and --max-attempts can't be used with --replay, which \
+                stops where the recorded run stopped"
+                    .to_string(),
+            ));
+        }
+        if options.threads.is_some_and(|threads| threads > 0)
+            && (options.linear || options.replay.is_some())
+        {
+            return Err(invalid_input(
+                "--threads can't be used with --linear or --replay, which hash one attempt at a time: \
+                each commit of a linear run changes the commits after it, and a replay only makes the \
+                recorded attempts"
+                    .to_string(),
+            ));
+        }
+        if options.sample.is_some()
+            && (options.linear || options.count_only || options.emit_refs_map.is_some())
+        {
+            return Err(invalid_input(
+                "--linear, --count-only and --emit-refs-map can't be used with --sample, since the \
+                skipped commits would still be parents, be counted, or be in the table"
+                    .to_string(),
+            ));
+        }
+        if options.linear
+            && (options.resume_from_idx.is_some()
+                || options.resume_from_checkpoint.is_some()
+                || options.delta_chain_depth > 1)
+        {
+            return Err(invalid_input(
+                "--resume-from-idx, --resume-from-checkpoint and --delta-chain-depth can't be used \
+                with --linear, since every commit has to be a delta against the delta base commit with \
+                a new parent"
+                    .to_string(),
+            ));
+        }
+        if options.tree_from_dir.is_some() && options.tree_count > 1 {
+            return Err(invalid_input(
+                "--tree-from-dir and --tree-count can't be used together".to_string(),
+            ));
+        }
+        if matches!(options.object_type, GitObjectType::Tree)
+            && (options.linear
+                || options.tree_count > 1
+                || options.tree_from_dir.is_some()
+                || options.base_commit_file.is_some()
+                || options.message_footer.is_some()
+                || options.commit_encoding.is_some())
+        {
+            return Err(invalid_input(
+                "--linear, --tree-count, --tree-from-dir, --base-commit-file, --message-footer and \
+                --commit-encoding can't be used with --object-type tree, since no commits are generated"
+                    .to_string(),
+            ));
+        }
+        if options.linear && options.tree_count > 1 {
+            return Err(invalid_input(
+                "--tree-count can't be used with --linear, since the chain starts at a single delta \
+                base commit, which only has one tree"
+                    .to_string(),
+            ));
+        }
+        if options.journal
+            && (options.linear
+                || options.sample.is_some()
+                || options.emit_refs_map.is_some()
+                || options.record_accepted.is_some()
+                || options.replay.is_some()
+                || options.direct_io
+                || !matches!(options.existing_pack, ExistingPack::Refuse))
+        {
+            return Err(invalid_input(
+                "--linear, --sample, --emit-refs-map, --record-accepted, --replay, --direct-io, --force \
+                and --append can't be used with --journal, since a resumed run couldn't pick up where \
+                they left off, or find the pack under its usual name"
+                    .to_string(),
+            ));
+        }
+        if options.journal_interval.is_some() && !options.journal {
+            return Err(invalid_input(
+                "--journal-interval only applies to --journal".to_string(),
+            ));
+        }
+        if options.seed.is_some() && !matches!(options.entropy_source, EntropySourceKind::Random) {
+            return Err(invalid_input(
+                "--seed only applies to --entropy-source random".to_string(),
+            ));
+        }
+        match subcommand.as_deref() {
+            Some("merge") => {
+                if input_paths.is_empty() {
+                    return Err(invalid_input(
+                        "merge needs at least one input pack".to_string(),
+                    ));
+                }
+                options.command = Command::Merge {
+                    input_paths,
+                    output_path: output_path.ok_or_else(|| {
+                        invalid_input("merge needs an output path (-o)".to_string())
+                    })?,
+                };
+            }
+            Some("idx-only") => {
+                if input_paths.len() != 1 {
+                    return Err(invalid_input("idx-only needs exactly one pack".to_string()));
+                }
+                options.command = Command::IdxOnly {
+                    pack_path: input_paths.remove(0),
+                };
+            }
+            Some("abbrev-lengths") => {
+                if input_paths.len() != 1 {
+                    return Err(invalid_input(
+                        "abbrev-lengths needs exactly one index file".to_string(),
+                    ));
+                }
+                options.command = Command::AbbrevLengths {
+                    index_path: input_paths.remove(0),
+                };
+            }
+            Some("lookup") => {
+                let oid = match input_paths.as_slice() {
+                    [oid] => parse_oid(&oid.to_string_lossy())?,
+                    _ => return Err(invalid_input("lookup needs exactly one OID".to_string())),
+                };
+                options.command = Command::Lookup {
+                    oid,
+                    index_path: lookup_index_path.ok_or_else(|| {
+                        invalid_input("lookup needs an index file (--idx)".to_string())
+                    })?,
+                };
+            }
+            Some("dump-object") => {
+                let oid = match input_paths.as_slice() {
+                    [oid] => parse_oid(&oid.to_string_lossy())?,
+                    _ => {
+                        return Err(invalid_input(
+                            "dump-object needs exactly one OID".to_string(),
+                        ))
+                    }
+                };
+                let pack_path = dump_pack_path.ok_or_else(|| {
+                    invalid_input("dump-object needs a pack (--pack)".to_string())
+                })?;
+                options.command = Command::DumpObject {
+                    oid,
+                    index_path: lookup_index_path
+                        .unwrap_or_else(|| pack_path.with_extension("idx")),
+                    pack_path,
+                    pretty,
+                };
+            }
+            Some("merge-tree-only") => {
+                options.command = Command::MergeTreeOnly {
+                    leaves_path: leaves_path.ok_or_else(|| {
+                        invalid_input(
+                            "merge-tree-only needs a table of leaves (--leaves)".to_string(),
+                        )
+                    })?,
+                    parent_count: merge_parent_count,
+                    output_path: output_path.ok_or_else(|| {
+                        invalid_input("merge-tree-only needs an output path (-o)".to_string())
+                    })?,
+                };
+            }
+            Some("demo") => options.command = Command::Demo { bits: demo_bits },
+            _ => {}
+        }
+        Ok(options)
+    }
+}
+
+/// Parses a full 40-character hex OID, in either case.
+pub fn parse_oid(hex: &str) -> io::Result<Sha1Oid> {
+    if hex.len() != 40 || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(invalid_input(format!(
+            "{} isn't a 40-character hex OID",
+            hex
+        )));
+    }
+    Ok(parse_hex_oid(&hex.to_ascii_lowercase()))
+}
+
+fn parse_value<T: FromStr>(flag: &str, value: Option<String>) -> io::Result<T> {
+    let value = value.ok_or_else(|| invalid_input(format!("missing value for {}", flag)))?;
+    value
+        .parse()
+        .map_err(|_| invalid_input(format!("invalid value for {}: {}", flag, value)))
+}
+
+fn parse_compression(flag: &str, value: Option<String>) -> io::Result<Compression> {
+    Ok(match value {
+        Some(value) if value == "best" => Compression::best(),
+        Some(value) if value == "fast" => Compression::fast(),
+        Some(value) if value == "none" => Compression::none(),
+        value => match parse_value(flag, value)? {
+            level @ 0..=9 => Compression::new(level),
+            level => {
+                return Err(invalid_input(format!(
+                    "{} must be best, fast, none or a level from 0 to 9, not {}",
+                    flag, level
+                )))
+            }
+        },
+    })
+}
+
+fn parse_duration(flag: &str, value: Option<String>) -> io::Result<Duration> {
+    let value = value.ok_or_else(|| invalid_input(format!("missing value for {}", flag)))?;
+    let (number, unit_seconds) = match value.char_indices().last() {
+        Some((index, 's')) => (&value[..index], 1),
+        Some((index, 'm')) => (&value[..index], 60),
+        Some((index, 'h')) => (&value[..index], 60 * 60),
+        Some((index, 'd')) => (&value[..index], 24 * 60 * 60),
+        _ => (&value[..], 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(unit_seconds))
+        .map(Duration::from_secs)
+        .ok_or_else(|| invalid_input(format!("invalid value for {}: {}", flag, value)))
+}
+
+/// Returns a shell command that runs this program again with the same arguments, except that it resumes
+/// from the checkpoint at `checkpoint_path` instead of wherever this run resumed from. If `appends_pack`,
+/// it also writes its pack alongside this run's pack rather than refusing to (or, with `--force`, instead
+/// of) replacing it.
+pub fn resume_command(checkpoint_path: &Path, appends_pack: bool) -> String {
+    let mut args = env::args();
+    let mut command = vec![args.next().unwrap_or_default()];
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            // The resumed run searches as usual, which also means it can't record its attempts.
+            "--resume-from-idx" | "--resume-from-checkpoint" | "--replay" | "--record-accepted" => {
+                args.next();
+            }
+            "--force" | "--append" => {}
+            _ => command.push(arg),
+        }
+    }
+    command.push("--resume-from-checkpoint".to_string());
+    command.push(checkpoint_path.display().to_string());
+    if appends_pack {
+        command.push("--append".to_string());
+    }
+    command
+        .iter()
+        .map(|arg| shell_quote(arg))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Quotes an argument for a POSIX shell, unless it only has characters that don't need quoting.
+fn shell_quote(arg: &str) -> String {
+    if !arg.is_empty()
+        && arg
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"%+,-./:=@_".contains(&byte))
+    {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
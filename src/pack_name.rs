@@ -0,0 +1,170 @@
+//! Names the generated pack after `--pack-name-template`, for scripts that keep the packs of several runs
+//! side by side. The template is the base name that `.pack` and `.idx` are added to, with tokens in
+//! braces for parameters of the run:
+//!
+//! - `{bits}`: how many leading bits of the OIDs the pack covers every value of, which is 28 for the
+//!   7-character shorthashes plus 4 for each hex character of `--prefix`
+//! - `{fanout}`: the range of first bytes of the OIDs that the pack covers, as `xx-yy` (`00-ff` unless
+//!   `--prefix` or `--oid-prefix-range` narrows it), which is the part of the index's fanout table that
+//!   the generated commits fill
+//! - `{date}`: the UTC date that the run started on, as `YYYY-MM-DD`
+//! - `{count}`: the number of objects in the pack
+//! - `{checksum}`: the pack's trailing checksum, which git names its own packs after
+//!
+//! The last two aren't known until the pack is finished, so a template that uses them has the pack
+//! written under the usual `pack-every-shorthash` name first, and renamed at the end.
+
+use crate::{
+    git_utils::{oid_to_hex, Sha1Oid},
+    shorthash::FirstByteRange,
+};
+use std::{
+    io,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Token {
+    Bits,
+    Fanout,
+    Date,
+    Count,
+    Checksum,
+}
+
+#[derive(Clone, Debug)]
+enum Part {
+    Literal(String),
+    Token(Token),
+}
+
+#[derive(Clone, Debug)]
+pub struct PackNameTemplate {
+    parts: Vec<Part>,
+}
+
+/// What the tokens of a template stand for. `object_count` and `checksum` are `None` until the pack is
+/// finished.
+pub struct PackNameValues {
+    pub bits: u32,
+    pub fanout: FirstByteRange,
+    pub date: String,
+    pub object_count: Option<u32>,
+    pub checksum: Option<Sha1Oid>,
+}
+
+impl PackNameTemplate {
+    pub fn parse(template: &str) -> io::Result<Self> {
+        let invalid = |message: String| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid --pack-name-template {}: {}", template, message),
+            )
+        };
+        if template.ends_with(".pack") || template.ends_with(".idx") {
+            return Err(invalid(
+                "it's the name without an extension, since the .pack and .idx files both get it"
+                    .to_string(),
+            ));
+        }
+        if template.contains('/') || template.contains('\\') || template.starts_with('.') {
+            return Err(invalid(
+                "it has to be a file name in the pack directory".to_string(),
+            ));
+        }
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while !rest.is_empty() {
+            match rest.find(['{', '}']) {
+                Some(start) if rest[start..].starts_with('{') => {
+                    if start > 0 {
+                        parts.push(Part::Literal(rest[..start].to_string()));
+                    }
+                    let length = rest[start..]
+                        .find('}')
+                        .ok_or_else(|| invalid("a { isn't closed".to_string()))?;
+                    let token = match &rest[start + 1..start + length] {
+                        "bits" => Token::Bits,
+                        "fanout" => Token::Fanout,
+                        "date" => Token::Date,
+                        "count" => Token::Count,
+                        "checksum" => Token::Checksum,
+                        name => {
+                            return Err(invalid(format!(
+                            "unknown token {{{}}} (the tokens are {{bits}}, {{fanout}}, {{date}}, \
+                                {{count}} and {{checksum}})",
+                            name
+                        )))
+                        }
+                    };
+                    parts.push(Part::Token(token));
+                    rest = &rest[start + length + 1..];
+                }
+                Some(_) => return Err(invalid("a } isn't opened".to_string())),
+                None => {
+                    parts.push(Part::Literal(rest.to_string()));
+                    rest = "";
+                }
+            }
+        }
+        if parts.is_empty() {
+            return Err(invalid("it's empty".to_string()));
+        }
+        Ok(Self { parts })
+    }
+
+    /// Returns whether the template has a token that's only known once the pack is finished.
+    pub fn needs_finished_pack(&self) -> bool {
+        self.parts
+            .iter()
+            .any(|part| matches!(part, Part::Token(Token::Count | Token::Checksum)))
+    }
+
+    /// Returns the base name of the pack, without an extension.
+    ///
+    /// Panics if the template needs the finished pack and `values` doesn't have it.
+    pub fn expand(&self, values: &PackNameValues) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Literal(literal) => literal.clone(),
+                Part::Token(Token::Bits) => values.bits.to_string(),
+                Part::Token(Token::Fanout) => {
+                    format!("{:02x}-{:02x}", values.fanout.first, values.fanout.last)
+                }
+                Part::Token(Token::Date) => values.date.clone(),
+                Part::Token(Token::Count) => values
+                    .object_count
+                    .expect("the pack is finished")
+                    .to_string(),
+                Part::Token(Token::Checksum) => {
+                    oid_to_hex(&values.checksum.expect("the pack is finished"))
+                }
+            })
+            .collect()
+    }
+}
+
+/// Returns today's date in UTC as `YYYY-MM-DD`.
+pub fn utc_date_today() -> String {
+    let days = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs() / 86400) as i64;
+    // Howard Hinnant's `civil_from_days`, which counts in 400-year eras starting on March 1st, so that
+    // the leap day is at the end of each year.
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
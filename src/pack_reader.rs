@@ -0,0 +1,884 @@
+use crate::git_utils::{
+    apply_delta, oid_to_hex, GitObject, GitObjectType, ObjectSize, PackFileDeltaInstruction,
+    PackObjectMetadata, PackOffset, Sha1Oid, BIG_OFFSET_THRESHOLD,
+};
+use crc::{crc32, Hasher32};
+use flate2::{Decompress, FlushDecompress, Status};
+use sha1::{Digest, Sha1};
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
+    convert::TryInto,
+    error, fmt, fs,
+    fs::File,
+    io,
+    io::{BufRead, BufReader, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+/// Reads and validates the header and fanout table of a version-2 pack index file, as documented
+/// [here](https://git-scm.com/docs/pack-format#_version_2_pack_idx_files_support_packs_larger_than_4_gib_and).
+/// Returns the fanout table, whose last entry is the number of objects in the index.
+fn read_index_header<R: Read>(mut index: R) -> io::Result<[u32; 256]> {
+    let mut header = [0; 8];
+    index.read_exact(&mut header)?;
+    if &header[0..4] != b"\xfftOc" || header[4..8] != 2u32.to_be_bytes() {
+        return Err(invalid_data("not a version-2 pack index file".to_string()));
+    }
+
+    let mut fanout = [0u32; 256];
+    let mut entry = [0; 4];
+    for count in fanout.iter_mut() {
+        index.read_exact(&mut entry)?;
+        *count = u32::from_be_bytes(entry);
+    }
+    if fanout.windows(2).any(|pair| pair[0] > pair[1]) {
+        return Err(invalid_data(
+            "pack index fanout table is not monotonic".to_string(),
+        ));
+    }
+    Ok(fanout)
+}
+
+/// Calls `f` with each OID in a version-2 pack index file, in the order they appear in the index (i.e.
+/// sorted). The OIDs are streamed from the file rather than collected, since a full index for this tool
+/// has 2**28 of them. Returns the number of OIDs.
+pub fn for_each_index_oid<F: FnMut(&Sha1Oid)>(path: &Path, mut f: F) -> io::Result<usize> {
+    let mut index = BufReader::new(File::open(path)?);
+    let fanout = read_index_header(&mut index)?;
+    let object_count = fanout[255] as usize;
+    let mut oid = Sha1Oid::default();
+    for _ in 0..object_count {
+        index.read_exact(&mut oid)?;
+        f(&oid);
+    }
+    Ok(object_count)
+}
+
+/// Calls `f` with the metadata of each entry of a version-2 pack index file, in OID order. Like
+/// `for_each_index_oid`, this streams the entries instead of collecting them, reading the tables of OIDs,
+/// CRC32s and offsets side by side. Returns the number of entries.
+pub fn for_each_index_entry<F: FnMut(PackObjectMetadata)>(
+    path: &Path,
+    mut f: F,
+) -> io::Result<usize> {
+    let index_file = File::open(path)?;
+    let index_length = index_file.metadata()?.len() as usize;
+    let mut oids = BufReader::new(index_file);
+    let fanout = read_index_header(&mut oids)?;
+    let object_count = fanout[255] as usize;
+    let big_offsets_length = big_offsets_length(index_length, object_count)?;
+
+    let oids_start = 8 + 256 * 4;
+    let open_table = |table_start: usize| -> io::Result<BufReader<File>> {
+        let mut table = File::open(path)?;
+        table.seek(SeekFrom::Start(table_start as u64))?;
+        Ok(BufReader::new(table))
+    };
+    let mut crc32s = open_table(oids_start + object_count * 20)?;
+    let mut small_offsets = open_table(oids_start + object_count * 24)?;
+    let big_offsets_start = oids_start + object_count * 28;
+    let mut big_offsets = open_table(big_offsets_start)?;
+    let mut next_big_offset_index = 0;
+
+    let mut entry = [0; 4];
+    let mut big_offset = [0; 8];
+    for _ in 0..object_count {
+        let mut oid = Sha1Oid::default();
+        oids.read_exact(&mut oid)?;
+        crc32s.read_exact(&mut entry)?;
+        let crc32 = u32::from_be_bytes(entry);
+        small_offsets.read_exact(&mut entry)?;
+        let small_offset = u32::from_be_bytes(entry);
+        let offset = if small_offset & BIG_OFFSET_THRESHOLD as u32 == 0 {
+            PackOffset(small_offset as u64)
+        } else {
+            let big_offset_index = (small_offset & !(BIG_OFFSET_THRESHOLD as u32)) as usize;
+            if big_offset_index >= big_offsets_length / 8 {
+                return Err(invalid_data(format!(
+                    "pack index refers to 8-byte offset {}, but there are only {}",
+                    big_offset_index,
+                    big_offsets_length / 8
+                )));
+            }
+            // This tool and git both fill the table in OID order, so this only seeks if some other
+            // tool didn't.
+            if big_offset_index != next_big_offset_index {
+                big_offsets.seek(SeekFrom::Start(
+                    (big_offsets_start + big_offset_index * 8) as u64,
+                ))?;
+            }
+            big_offsets.read_exact(&mut big_offset)?;
+            next_big_offset_index = big_offset_index + 1;
+            PackOffset(u64::from_be_bytes(big_offset))
+        };
+        f(PackObjectMetadata { oid, offset, crc32 });
+    }
+    Ok(object_count)
+}
+
+/// Reads every entry of a version-2 pack index file, in OID order, resolving offsets that are stored in
+/// the table of 8-byte offsets. Unlike `for_each_index_oid`, this collects the whole index in memory, so
+/// it's meant for checking small packs.
+#[allow(dead_code)]
+pub fn read_index_entries(path: &Path) -> io::Result<Vec<PackObjectMetadata>> {
+    let index_file = File::open(path)?;
+    let index_length = index_file.metadata()?.len() as usize;
+    let mut index = BufReader::new(index_file);
+    let fanout = read_index_header(&mut index)?;
+    let object_count = fanout[255] as usize;
+    let big_offsets_length = big_offsets_length(index_length, object_count)?;
+
+    let mut entries = Vec::with_capacity(object_count);
+    for _ in 0..object_count {
+        let mut oid = Sha1Oid::default();
+        index.read_exact(&mut oid)?;
+        entries.push(PackObjectMetadata {
+            oid,
+            offset: PackOffset(0),
+            crc32: 0,
+        });
+    }
+    let mut entry = [0; 4];
+    for metadata in &mut entries {
+        index.read_exact(&mut entry)?;
+        metadata.crc32 = u32::from_be_bytes(entry);
+    }
+    let mut small_offsets = Vec::with_capacity(object_count);
+    for _ in 0..object_count {
+        index.read_exact(&mut entry)?;
+        small_offsets.push(u32::from_be_bytes(entry));
+    }
+    let mut big_offsets = Vec::with_capacity(big_offsets_length / 8);
+    let mut big_offset = [0; 8];
+    for _ in 0..big_offsets_length / 8 {
+        index.read_exact(&mut big_offset)?;
+        big_offsets.push(u64::from_be_bytes(big_offset));
+    }
+
+    for (metadata, small_offset) in entries.iter_mut().zip(small_offsets) {
+        // Offsets with the top bit set are indices into the table of 8-byte offsets instead.
+        metadata.offset = if small_offset & BIG_OFFSET_THRESHOLD as u32 == 0 {
+            PackOffset(small_offset as u64)
+        } else {
+            let big_offset_index = (small_offset & !(BIG_OFFSET_THRESHOLD as u32)) as usize;
+            PackOffset(*big_offsets.get(big_offset_index).ok_or_else(|| {
+                invalid_data(format!(
+                    "pack index refers to 8-byte offset {}, but there are only {}",
+                    big_offset_index,
+                    big_offsets.len()
+                ))
+            })?)
+        };
+    }
+    Ok(entries)
+}
+
+/// Looks up one OID in a version-2 pack index file, returning its offset and CRC32 if it's in the index.
+/// The fanout table narrows the search down to the OIDs with the same first byte, which are then binary
+/// searched, so this only reads a few dozen OIDs from the file rather than the whole index.
+pub fn lookup_index_entry(path: &Path, oid: &Sha1Oid) -> io::Result<Option<IndexEntry>> {
+    let mut index = File::open(path)?;
+    let index_length = index.metadata()?.len() as usize;
+    let fanout = read_index_header(&mut index)?;
+    let object_count = fanout[255] as usize;
+    let big_offsets_length = big_offsets_length(index_length, object_count)?;
+
+    let oids_start = 8 + 256 * 4;
+    let mut low = match oid[0] {
+        0 => 0,
+        first_byte => fanout[first_byte as usize - 1] as usize,
+    };
+    let mut high = fanout[oid[0] as usize] as usize;
+    let mut candidate = Sha1Oid::default();
+    let position = loop {
+        if low >= high {
+            return Ok(None);
+        }
+        let middle = low + (high - low) / 2;
+        index.seek(SeekFrom::Start((oids_start + middle * 20) as u64))?;
+        index.read_exact(&mut candidate)?;
+        match candidate.cmp(oid) {
+            Ordering::Less => low = middle + 1,
+            Ordering::Greater => high = middle,
+            Ordering::Equal => break middle,
+        }
+    };
+
+    let mut entry = [0; 4];
+    index.seek(SeekFrom::Start(
+        (oids_start + object_count * 20 + position * 4) as u64,
+    ))?;
+    index.read_exact(&mut entry)?;
+    let crc32 = u32::from_be_bytes(entry);
+    index.seek(SeekFrom::Start(
+        (oids_start + object_count * 24 + position * 4) as u64,
+    ))?;
+    index.read_exact(&mut entry)?;
+    let small_offset = u32::from_be_bytes(entry);
+    let big_offset_index = if small_offset & BIG_OFFSET_THRESHOLD as u32 == 0 {
+        None
+    } else {
+        Some((small_offset & !(BIG_OFFSET_THRESHOLD as u32)) as usize)
+    };
+    let offset = match big_offset_index {
+        None => PackOffset(small_offset as u64),
+        Some(big_offset_index) => {
+            if big_offset_index >= big_offsets_length / 8 {
+                return Err(invalid_data(format!(
+                    "pack index refers to 8-byte offset {}, but there are only {}",
+                    big_offset_index,
+                    big_offsets_length / 8
+                )));
+            }
+            let mut big_offset = [0; 8];
+            index.seek(SeekFrom::Start(
+                (oids_start + object_count * 28 + big_offset_index * 8) as u64,
+            ))?;
+            index.read_exact(&mut big_offset)?;
+            PackOffset(u64::from_be_bytes(big_offset))
+        }
+    };
+    Ok(Some(IndexEntry {
+        position,
+        metadata: PackObjectMetadata {
+            oid: *oid,
+            offset,
+            crc32,
+        },
+        big_offset_index,
+    }))
+}
+
+/// Where `lookup_index_entry` found an object in an index file.
+#[derive(Debug)]
+pub struct IndexEntry {
+    /// The object's position in the sorted list of OIDs
+    pub position: usize,
+    pub metadata: PackObjectMetadata,
+    /// The entry in the table of 8-byte offsets that holds the object's offset, if it's stored there
+    pub big_offset_index: Option<usize>,
+}
+
+/// Lists the index files of the packs in `pack_dir`, in no particular order. A missing directory has no
+/// packs.
+pub fn pack_index_paths(pack_dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(pack_dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let mut index_paths = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_some_and(|extension| extension == "idx") {
+            index_paths.push(path);
+        }
+    }
+    Ok(index_paths)
+}
+
+/// Returns whether the object directory `objects_dir` has the object `oid`, either as a loose object or
+/// in one of the packs with the index files at `index_paths`. Like `lookup_index_entry`, this only reads
+/// the indexes, so an object in a pack that's missing its packfile still counts.
+pub fn has_object(objects_dir: &Path, index_paths: &[PathBuf], oid: &Sha1Oid) -> io::Result<bool> {
+    let hex = oid_to_hex(oid);
+    if objects_dir.join(&hex[..2]).join(&hex[2..]).is_file() {
+        return Ok(true);
+    }
+    for index_path in index_paths {
+        if lookup_index_entry(index_path, oid)?.is_some() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Returns the length of the table of 8-byte offsets in an index file of `index_length` bytes. The length
+/// isn't recorded anywhere, so like git, this infers it from the length of the file: the header and
+/// fanout table, 28 bytes per object for the OIDs, CRC32s and 4-byte offsets, and the two trailing
+/// checksums take up the rest.
+fn big_offsets_length(index_length: usize, object_count: usize) -> io::Result<usize> {
+    index_length
+        .checked_sub(8 + 256 * 4 + object_count * 28 + 40)
+        .filter(|length| length % 8 == 0)
+        .ok_or_else(|| invalid_data("pack index has the wrong length".to_string()))
+}
+
+/// An object read from a packfile, with its delta (if any) already applied.
+#[derive(Debug)]
+pub struct PackedObject {
+    pub offset: PackOffset,
+    /// The CRC32 of the object's entry in the packfile, as recorded in index files
+    pub crc32: u32,
+    pub oid: Sha1Oid,
+    pub object: GitObject,
+    /// The delta base and instructions of the object, if it's deltified in the packfile
+    pub delta: Option<PackedDelta>,
+}
+
+#[derive(Debug)]
+pub struct PackedDelta {
+    pub base_oid: Sha1Oid,
+    pub base_size: ObjectSize,
+    pub instructions: Vec<PackFileDeltaInstruction>,
+}
+
+/// The error for a packfile entry whose header has an object type that git doesn't define: 0, which is
+/// invalid, or 5, which is reserved. This is the inner error of an `InvalidData` `io::Error`, so callers
+/// can get the details with `get_ref` and `downcast_ref`.
+#[derive(Debug)]
+pub struct InvalidObjectType {
+    /// The offset of the entry in the packfile
+    pub offset: u64,
+    /// The 3-bit type from the entry's header
+    pub type_byte: u8,
+}
+
+impl fmt::Display for InvalidObjectType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "the object at offset {} has {} type {}",
+            self.offset,
+            if self.type_byte == 5 {
+                "the reserved"
+            } else {
+                "the invalid"
+            },
+            self.type_byte
+        )
+    }
+}
+
+impl error::Error for InvalidObjectType {}
+
+/// Where a packfile entry's data comes from
+enum EntryKind {
+    Object(GitObjectType),
+    OffsetDelta { base_offset: u64 },
+    RefDelta { base_oid: Sha1Oid },
+}
+
+/// A packfile entry before any delta has been applied
+struct RawEntry {
+    kind: EntryKind,
+    // The decompressed entry data: the object itself for non-deltified objects, or the encoded delta
+    data: Vec<u8>,
+    // The offset of the next entry
+    end_offset: u64,
+    // The CRC32 of the raw bytes of the entry
+    crc32: u32,
+}
+
+/// Reads the objects in a version-2 packfile, using the format that `stream_to_pack_file` writes.
+/// Iterating over the reader yields each object in the order it appears in the packfile.
+///
+/// The reader doesn't use an index file, so "ref delta" objects can only be resolved if their delta
+/// base is a non-deltified object that appears earlier in the same packfile. This is always the case
+/// for packs generated by this tool, which only contain "offset delta" objects anyway.
+pub struct PackReader {
+    pack: BufReader<File>,
+    // The offset in the packfile that `pack` is currently positioned at. This is tracked to avoid
+    // seeking (which discards the read buffer) when reading objects sequentially.
+    position: u64,
+    next_entry_offset: u64,
+    object_count: u32,
+    objects_read: u32,
+    // Recently read objects that may be delta bases. With delta chains, the next object's base is usually
+    // one of the last few objects.
+    base_cache: BaseCache,
+    // The offsets of non-deltified objects by OID, for resolving "ref delta" objects
+    offsets_by_oid: HashMap<Sha1Oid, u64>,
+    // The CRC32 of the bytes of the current entry that have been read so far
+    entry_crc32: crc32::Digest,
+}
+
+impl PackReader {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut pack = BufReader::new(File::open(path)?);
+        let mut header = [0; 12];
+        pack.read_exact(&mut header)?;
+        if &header[0..4] != b"PACK" || header[4..8] != 2u32.to_be_bytes() {
+            return Err(invalid_data(format!(
+                "{} is not a version-2 packfile",
+                path.display()
+            )));
+        }
+        Ok(Self {
+            pack,
+            position: 12,
+            next_entry_offset: 12,
+            object_count: u32::from_be_bytes(header[8..12].try_into().unwrap()),
+            objects_read: 0,
+            base_cache: BaseCache::default(),
+            offsets_by_oid: HashMap::new(),
+            entry_crc32: crc32::Digest::new(crc32::IEEE),
+        })
+    }
+
+    /// Opens a packfile that hasn't been finished, whose header may not have its object count yet, to read
+    /// its first `object_count` objects.
+    pub fn open_unfinished(path: &Path, object_count: u32) -> io::Result<Self> {
+        let mut reader = Self::open(path)?;
+        reader.object_count = object_count;
+        Ok(reader)
+    }
+
+    pub fn object_count(&self) -> u32 {
+        self.object_count
+    }
+
+    /// The offset right after the last object that has been read, where the next one starts
+    pub fn end_offset(&self) -> PackOffset {
+        PackOffset(self.next_entry_offset)
+    }
+
+    fn seek_to(&mut self, offset: u64) -> io::Result<()> {
+        if self.position != offset {
+            self.pack.seek(SeekFrom::Start(offset))?;
+            self.position = offset;
+        }
+        Ok(())
+    }
+
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let mut byte = [0];
+        self.pack.read_exact(&mut byte)?;
+        self.position += 1;
+        self.entry_crc32.write(&byte);
+        Ok(byte[0])
+    }
+
+    /// Reads the entry at the given offset, without resolving its delta base.
+    fn read_entry(&mut self, offset: u64) -> io::Result<RawEntry> {
+        self.seek_to(offset)?;
+        self.entry_crc32.reset();
+
+        // See `append_object_header` for the layout of the object header.
+        let mut byte = self.read_byte()?;
+        let type_byte = (byte >> 4) & 0b111;
+        let mut size = (byte & 0xf) as usize;
+        let mut shift = 4;
+        while byte & 0x80 != 0 {
+            if shift >= usize::BITS {
+                return Err(invalid_data(format!(
+                    "object size at offset {} is too large",
+                    offset
+                )));
+            }
+            byte = self.read_byte()?;
+            size |= ((byte & 0x7f) as usize) << shift;
+            shift += 7;
+        }
+
+        let kind = match type_byte {
+            1 => EntryKind::Object(GitObjectType::Commit),
+            2 => EntryKind::Object(GitObjectType::Tree),
+            3 => EntryKind::Object(GitObjectType::Blob),
+            4 => EntryKind::Object(GitObjectType::Tag),
+            6 => {
+                // This is the inverse of `append_variable_length_size_with_continuation_increment`.
+                let mut byte = self.read_byte()?;
+                let mut distance = (byte & 0x7f) as u64;
+                while byte & 0x80 != 0 {
+                    byte = self.read_byte()?;
+                    distance = distance
+                        .checked_add(1)
+                        .and_then(|distance| distance.checked_mul(1 << 7))
+                        .ok_or_else(|| {
+                            invalid_data(format!("delta base offset at {} is too large", offset))
+                        })?
+                        | (byte & 0x7f) as u64;
+                }
+                EntryKind::OffsetDelta {
+                    base_offset: offset
+                        .checked_sub(distance)
+                        .filter(|_| distance > 0)
+                        .ok_or_else(|| {
+                            invalid_data(format!(
+                                "delta base of the object at offset {} is outside the packfile",
+                                offset
+                            ))
+                        })?,
+                }
+            }
+            7 => {
+                let mut base_oid = Sha1Oid::default();
+                self.pack.read_exact(&mut base_oid)?;
+                self.position += base_oid.len() as u64;
+                self.entry_crc32.write(&base_oid);
+                EntryKind::RefDelta { base_oid }
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    InvalidObjectType { offset, type_byte },
+                ))
+            }
+        };
+
+        let data = self.read_compressed(size)?;
+        Ok(RawEntry {
+            kind,
+            data,
+            end_offset: self.position,
+            crc32: self.entry_crc32.sum32(),
+        })
+    }
+
+    /// Reads zlib-compressed data that decompresses to `size` bytes, consuming only the compressed bytes.
+    fn read_compressed(&mut self, size: usize) -> io::Result<Vec<u8>> {
+        let mut decompress = Decompress::new(true);
+        let mut data = Vec::with_capacity(size);
+        loop {
+            if data.len() == data.capacity() {
+                data.reserve(64);
+            }
+            let input = self.pack.fill_buf()?;
+            if input.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "packfile ends in the middle of an object",
+                ));
+            }
+            let total_in_before = decompress.total_in();
+            let status = decompress
+                .decompress_vec(input, &mut data, FlushDecompress::None)
+                .map_err(|err| invalid_data(format!("invalid compressed object data: {}", err)))?;
+            let consumed = (decompress.total_in() - total_in_before) as usize;
+            self.entry_crc32.write(&input[..consumed]);
+            self.pack.consume(consumed);
+            self.position += consumed as u64;
+            if status == Status::StreamEnd {
+                break;
+            }
+        }
+        if data.len() != size {
+            return Err(invalid_data(format!(
+                "object decompressed to {} bytes, but its header says {}",
+                data.len(),
+                size
+            )));
+        }
+        Ok(data)
+    }
+
+    /// Returns the offset of the delta base of an entry, or `None` if it isn't deltified.
+    fn base_offset(&self, kind: &EntryKind) -> io::Result<Option<u64>> {
+        match kind {
+            EntryKind::Object(_) => Ok(None),
+            EntryKind::OffsetDelta { base_offset } => Ok(Some(*base_offset)),
+            EntryKind::RefDelta { base_oid } => match self.offsets_by_oid.get(base_oid) {
+                Some(&base_offset) => Ok(Some(base_offset)),
+                None => Err(invalid_data(format!(
+                    "delta base {} is not a non-deltified object earlier in the packfile",
+                    crate::git_utils::oid_to_hex(base_oid)
+                ))),
+            },
+        }
+    }
+
+    /// Applies the delta of an entry (if any), returning the resulting object.
+    fn resolve_entry(
+        &mut self,
+        entry: RawEntry,
+    ) -> io::Result<(Sha1Oid, GitObject, Option<PackedDelta>)> {
+        match self.base_offset(&entry.kind)? {
+            None => {
+                let (oid, object) = undeltified_object(entry);
+                Ok((oid, object, None))
+            }
+            Some(base_offset) => {
+                let (base_oid, base) = self.read_base_at(base_offset)?;
+                let (oid, object, delta) = apply_entry_delta(&entry.data, base_oid, &base)?;
+                Ok((oid, object, Some(delta)))
+            }
+        }
+    }
+
+    /// Reads the fully-resolved object at the given offset, for use as a delta base. The delta chain is
+    /// followed back to an object that's cached or isn't deltified, and then applied one delta at a time,
+    /// caching each object along the way, so that long chains are neither recursed into nor resolved
+    /// again for the next delta against them.
+    fn read_base_at(&mut self, offset: u64) -> io::Result<(Sha1Oid, GitObject)> {
+        let mut chain = Vec::new();
+        let mut next_offset = offset;
+        let (mut oid, mut object) = loop {
+            if let Some(base) = self.base_cache.get(next_offset) {
+                break base;
+            }
+            let entry = self.read_entry(next_offset)?;
+            match self.base_offset(&entry.kind)? {
+                None => {
+                    let (oid, object) = undeltified_object(entry);
+                    self.base_cache.insert(next_offset, oid, object.clone());
+                    break (oid, object);
+                }
+                Some(base_offset) => {
+                    chain.push((next_offset, entry.data));
+                    next_offset = base_offset;
+                }
+            }
+        };
+        while let Some((entry_offset, delta)) = chain.pop() {
+            let (new_oid, new_object, _) = apply_entry_delta(&delta, oid, &object)?;
+            self.base_cache
+                .insert(entry_offset, new_oid, new_object.clone());
+            oid = new_oid;
+            object = new_object;
+        }
+        Ok((oid, object))
+    }
+
+    /// Reads the object whose entry starts at `offset` (as given by an index file), with its delta
+    /// applied. This doesn't affect which object iterating over the reader yields next. As when
+    /// iterating, a "ref delta" object can only be resolved if its delta base has already been read.
+    pub fn read_object_at(&mut self, offset: PackOffset) -> io::Result<PackedObject> {
+        let entry = self.read_entry(offset.0)?;
+        let crc32 = entry.crc32;
+        let (oid, object, delta) = self.resolve_entry(entry)?;
+        Ok(PackedObject {
+            offset,
+            crc32,
+            oid,
+            object,
+            delta,
+        })
+    }
+
+    fn read_next_object(&mut self) -> io::Result<PackedObject> {
+        let offset = self.next_entry_offset;
+        let entry = self.read_entry(offset)?;
+        self.next_entry_offset = entry.end_offset;
+        let crc32 = entry.crc32;
+        let is_deltified = !matches!(entry.kind, EntryKind::Object(_));
+        let (oid, object, delta) = self.resolve_entry(entry)?;
+        if !is_deltified {
+            self.offsets_by_oid.insert(oid, offset);
+        }
+        // Merge commits are by far the largest objects in generated packs, and nothing is deltified
+        // against them.
+        if !is_merge_commit(&object) {
+            self.base_cache.insert(offset, oid, object.clone());
+        }
+        Ok(PackedObject {
+            offset: PackOffset(offset),
+            crc32,
+            oid,
+            object,
+            delta,
+        })
+    }
+}
+
+/// Returns the object of an entry that isn't deltified.
+fn undeltified_object(entry: RawEntry) -> (Sha1Oid, GitObject) {
+    let object_type = match entry.kind {
+        EntryKind::Object(object_type) => object_type,
+        _ => unreachable!("the entry is deltified"),
+    };
+    let object = GitObject {
+        object_type,
+        data: entry.data,
+    };
+    (object.oid(), object)
+}
+
+/// Applies an encoded delta to its base, returning the resulting object and the decoded delta.
+fn apply_entry_delta(
+    delta: &[u8],
+    base_oid: Sha1Oid,
+    base: &GitObject,
+) -> io::Result<(Sha1Oid, GitObject, PackedDelta)> {
+    let (base_size, _, instructions) = decode_delta(delta)?;
+    if base_size.0 != base.data.len() {
+        return Err(invalid_data(format!(
+            "delta expects a {}-byte base, but its base is {} bytes",
+            base_size,
+            base.data.len()
+        )));
+    }
+    let object = GitObject {
+        object_type: base.object_type.clone(),
+        data: apply_delta(&base.data, &instructions)?,
+    };
+    Ok((
+        object.oid(),
+        object,
+        PackedDelta {
+            base_oid,
+            base_size,
+            instructions,
+        },
+    ))
+}
+
+/// Returns whether an object is a commit with more than one parent.
+fn is_merge_commit(object: &GitObject) -> bool {
+    matches!(object.object_type, GitObjectType::Commit)
+        && object
+            .data
+            .split(|&byte| byte == b'\n')
+            .take_while(|line| !line.is_empty())
+            .filter(|line| line.starts_with(b"parent "))
+            .count()
+            > 1
+}
+
+/// Resolved objects by their offsets in the packfile, for use as delta bases. Once it's full, the object
+/// that was used least recently is evicted to make room for the next one.
+#[derive(Default)]
+struct BaseCache {
+    // Each object, and when it was last used
+    objects: HashMap<u64, (Sha1Oid, GitObject, u64)>,
+    // The offsets of the cached objects, by when they were last used
+    offsets_by_use: BTreeMap<u64, u64>,
+    next_use: u64,
+}
+
+impl BaseCache {
+    const CAPACITY: usize = 1024;
+
+    fn get(&mut self, offset: u64) -> Option<(Sha1Oid, GitObject)> {
+        let (oid, object, last_use) = self.objects.get_mut(&offset)?;
+        self.offsets_by_use.remove(last_use);
+        *last_use = self.next_use;
+        self.offsets_by_use.insert(self.next_use, offset);
+        self.next_use += 1;
+        Some((*oid, object.clone()))
+    }
+
+    fn insert(&mut self, offset: u64, oid: Sha1Oid, object: GitObject) {
+        if let Some((_, _, last_use)) = self.objects.remove(&offset) {
+            self.offsets_by_use.remove(&last_use);
+        } else if self.objects.len() >= Self::CAPACITY {
+            let (_, least_recent_offset) = self.offsets_by_use.pop_first().unwrap();
+            self.objects.remove(&least_recent_offset);
+        }
+        self.objects.insert(offset, (oid, object, self.next_use));
+        self.offsets_by_use.insert(self.next_use, offset);
+        self.next_use += 1;
+    }
+}
+
+impl Iterator for PackReader {
+    type Item = io::Result<PackedObject>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.objects_read == self.object_count {
+            return None;
+        }
+        self.objects_read += 1;
+        let result = self.read_next_object();
+        if result.is_err() {
+            // Once an entry can't be read, it's impossible to find the start of the next one.
+            self.objects_read = self.object_count;
+        }
+        Some(result)
+    }
+}
+
+/// Decodes a delta in the format documented [here](https://git-scm.com/docs/pack-format#_deltified_representation),
+/// returning the base size, the result size, and the delta instructions.
+fn decode_delta(
+    delta: &[u8],
+) -> io::Result<(ObjectSize, ObjectSize, Vec<PackFileDeltaInstruction>)> {
+    let truncated = || invalid_data("delta is truncated".to_string());
+    let mut bytes = delta.iter().copied();
+    let read_size = |bytes: &mut dyn Iterator<Item = u8>| -> io::Result<usize> {
+        let mut size = 0;
+        let mut shift = 0;
+        loop {
+            let byte = bytes.next().ok_or_else(truncated)?;
+            if shift >= usize::BITS {
+                return Err(invalid_data("delta size is too large".to_string()));
+            }
+            size |= ((byte & 0x7f) as usize) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Ok(size);
+            }
+        }
+    };
+    let base_size = read_size(&mut bytes)?;
+    let new_size = read_size(&mut bytes)?;
+
+    let mut instructions = Vec::new();
+    while let Some(opcode) = bytes.next() {
+        if opcode & 0x80 != 0 {
+            let mut offset = 0;
+            let mut size = 0;
+            for i in 0..4 {
+                if opcode & (1 << i) != 0 {
+                    offset |= (bytes.next().ok_or_else(truncated)? as usize) << (8 * i);
+                }
+            }
+            for i in 0..3 {
+                if opcode & (0x10 << i) != 0 {
+                    size |= (bytes.next().ok_or_else(truncated)? as usize) << (8 * i);
+                }
+            }
+            // A copy size of zero is used to encode the maximum size.
+            if size == 0 {
+                size = 0x10000;
+            }
+            instructions.push(PackFileDeltaInstruction::CopyFromBaseObject { offset, size });
+        } else if opcode != 0 {
+            let new_data = bytes.by_ref().take(opcode as usize).collect::<Vec<_>>();
+            if new_data.len() != opcode as usize {
+                return Err(truncated());
+            }
+            instructions.push(PackFileDeltaInstruction::AddNewData(new_data));
+        } else {
+            return Err(invalid_data(
+                "delta contains a reserved instruction".to_string(),
+            ));
+        }
+    }
+
+    let result_size = instructions
+        .iter()
+        .map(|instruction| match instruction {
+            PackFileDeltaInstruction::CopyFromBaseObject { size, .. } => *size,
+            PackFileDeltaInstruction::AddNewData(new_data) => new_data.len(),
+        })
+        .sum::<usize>();
+    if result_size != new_size {
+        return Err(invalid_data(format!(
+            "delta produces {} bytes, but its header says {}",
+            result_size, new_size
+        )));
+    }
+    Ok((ObjectSize(base_size), ObjectSize(new_size), instructions))
+}
+
+/// Returns whether the trailing 20-byte SHA1 checksum of a packfile matches the rest of its contents.
+pub fn verify_pack_trailer<R: Read + Seek>(pack: &mut R) -> io::Result<bool> {
+    verify_trailing_checksum(pack)
+}
+
+/// Returns whether the trailing 20-byte SHA1 checksum of an index file matches the rest of its
+/// contents. This doesn't check the copy of the packfile checksum that precedes it.
+pub fn verify_idx_trailer<R: Read + Seek>(index: &mut R) -> io::Result<bool> {
+    verify_trailing_checksum(index)
+}
+
+/// Packfiles and index files both end with the SHA1 of everything before the checksum.
+fn verify_trailing_checksum<R: Read + Seek>(file: &mut R) -> io::Result<bool> {
+    let mut expected_checksum = Sha1Oid::default();
+    let length = file.seek(SeekFrom::End(0))?;
+    let checksum_offset = match length.checked_sub(expected_checksum.len() as u64) {
+        Some(checksum_offset) => checksum_offset,
+        None => return Ok(false),
+    };
+    file.seek(SeekFrom::Start(0))?;
+    let mut hasher = Sha1::new();
+    io::copy(&mut file.take(checksum_offset), &mut hasher)?;
+    file.read_exact(&mut expected_checksum)?;
+    Ok(hasher.finalize()[..] == expected_checksum)
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
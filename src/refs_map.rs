@@ -0,0 +1,80 @@
+//! Writes the table of `--emit-refs-map`, which maps each shorthash to the OID of the commit that covers
+//! it, as the commits are found. Nothing is kept in memory, so the table can cover all 2**28
+//! shorthashes. There are two formats:
+//!
+//! - text: one `<shorthash> <OID>` line per commit, in hex, in the order the commits were found. For a
+//!   full run, this is about 12 GiB.
+//! - binary: a 20-byte OID for each shorthash, in shorthash order, so the OID for a shorthash is at 20
+//!   times the shorthash. Shorthashes that weren't found have an OID of all zeros. This is always 5 GiB,
+//!   but it's written as a sparse file, so a partial run takes up less space on disk.
+//!
+//! With `--prefix`, the shorthash is the part of the OID after the prefix, as in `shorthash_index`.
+
+use crate::{
+    git_utils::{oid_to_hex, Sha1Oid},
+    shorthash::SHORTHASH_COUNT,
+};
+use std::{
+    fs::File,
+    io,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+#[derive(Clone, Copy, Debug)]
+pub enum RefsMapFormat {
+    Text,
+    Binary,
+}
+
+pub enum RefsMapWriter {
+    Text(BufWriter<File>),
+    Binary(File),
+}
+
+impl RefsMapWriter {
+    /// Creates (or truncates) the table at `path`.
+    pub fn create(path: &Path, format: RefsMapFormat) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Ok(match format {
+            RefsMapFormat::Text => Self::Text(BufWriter::new(file)),
+            RefsMapFormat::Binary => Self::Binary(file),
+        })
+    }
+
+    /// Records that the commit `oid` covers the shorthash with index `shorthash_index`.
+    pub fn record(&mut self, shorthash_index: usize, oid: &Sha1Oid) -> io::Result<()> {
+        match self {
+            Self::Text(out) => writeln!(out, "{:07x} {}", shorthash_index, oid_to_hex(oid)),
+            // Shorthashes are found in a random order, so each OID is written in place. That's one
+            // system call per commit, which is small next to the hashing and compression of each commit.
+            Self::Binary(file) => write_all_at(file, oid, shorthash_index as u64 * 20),
+        }
+    }
+
+    /// Finishes the table and syncs it to disk.
+    pub fn finish(self) -> io::Result<()> {
+        match self {
+            Self::Text(out) => out.into_inner()?.sync_all(),
+            Self::Binary(file) => {
+                // Extend the table to its full size, so that the shorthashes after the last one found
+                // also read as zeros.
+                file.set_len(SHORTHASH_COUNT as u64 * 20)?;
+                file.sync_all()
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.write_all_at(buf, offset)
+}
+
+#[cfg(not(unix))]
+fn write_all_at(mut file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    use std::io::{Seek, SeekFrom};
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(buf)
+}
@@ -0,0 +1,254 @@
+//! Records which attempts of a run found a new shorthash, for `--record-accepted`, so that `--replay` can
+//! generate the same pack again without searching. Whether an attempt is accepted only depends on the
+//! attempts before it, so a replay that jumps straight from one accepted attempt to the next yields the
+//! same commits in the same order, along with the same merge commits. A full run accepts 2**28 of its
+//! roughly 2**32.3 attempts, and replaying it only hashes those.
+//!
+//! The format is, with integers in network byte order:
+//!
+//! - the 4-byte magic `ESAA`
+//! - a 4-byte version number, currently 2
+//! - the number of bits in the OID prefix, as 1 byte, followed by the prefix itself as 8 bytes
+//! - the entropy source, as 1 byte (0 for the counter and 1 for random), followed by the seed of the
+//!   random entropy source (or 0) as 8 bytes
+//! - the SHA1 of the OIDs of the delta base commits, one after another
+//! - the initial number of characters of entropy, as 1 byte
+//! - the first and last byte of `--oid-prefix-range`, as 1 byte each
+//! - the number of prefixes from `--avoid-prefix`, as 4 bytes, followed by each of them in the same
+//!   format as the OID prefix
+//! - for each accepted attempt, in order, the number of attempts skipped since the previous accepted
+//!   attempt (or since the start of the run), as a LEB128 number
+//!
+//! Early in a run, almost every attempt is accepted, so most of the numbers take one byte. A full run
+//! takes a few hundred MiB. The attempts are written as they're accepted, so a file that ends in the
+//! middle of a number was cut off.
+
+use crate::{
+    git_utils::Sha1Oid,
+    shorthash::{FirstByteRange, OidPrefix},
+};
+use std::{
+    convert::TryInto,
+    fs::File,
+    io,
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+const MAGIC: &[u8; 4] = b"ESAA";
+const VERSION: u32 = 2;
+// The length of the header up to the avoided prefixes, which take `AVOIDED_PREFIX_LENGTH` bytes each
+const HEADER_LENGTH: usize = 53;
+const AVOIDED_PREFIX_LENGTH: usize = 9;
+
+/// What decides which attempts of a run are accepted, other than the shorthashes that were already
+/// found. A replay has to match the run that was recorded in all of these.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReplayParameters {
+    pub prefix: OidPrefix,
+    /// The seed of `--entropy-source random`, or `None` for the counter
+    pub random_seed: Option<u64>,
+    /// The SHA1 of the OIDs of the delta base commits, which covers every option that changes the
+    /// generated commits
+    pub delta_base_digest: Sha1Oid,
+    /// The number of characters of entropy in the first attempts, which changes what every attempt hashes
+    pub initial_entropy_length: usize,
+    /// The first bytes of the OIDs that can be accepted
+    pub first_byte_range: FirstByteRange,
+    /// The prefixes whose shorthashes are never accepted, sorted so that the order of the options doesn't
+    /// matter
+    pub avoided_prefixes: Vec<OidPrefix>,
+}
+
+impl ReplayParameters {
+    /// Describes the first difference between the parameters of two runs, if there is one.
+    pub fn difference(&self, other: &Self) -> Option<&'static str> {
+        if self.prefix != other.prefix {
+            Some("a different --prefix")
+        } else if self.random_seed != other.random_seed {
+            Some("a different --entropy-source or --seed")
+        } else if self.delta_base_digest != other.delta_base_digest {
+            Some("different delta base commits (which options like --tree-count change)")
+        } else if self.initial_entropy_length != other.initial_entropy_length {
+            Some("a different --entropy-prefix-len")
+        } else if self.first_byte_range != other.first_byte_range {
+            Some("a different --oid-prefix-range")
+        } else if self.avoided_prefixes != other.avoided_prefixes {
+            Some("different --avoid-prefix prefixes")
+        } else {
+            None
+        }
+    }
+}
+
+pub struct AcceptedAttemptsWriter {
+    out: BufWriter<File>,
+    // The attempt after the most recently recorded one
+    next_attempt: u64,
+}
+
+impl AcceptedAttemptsWriter {
+    /// Creates (or truncates) the file at `path`, and writes its header.
+    pub fn create(path: &Path, parameters: &ReplayParameters) -> io::Result<Self> {
+        let mut header = Vec::with_capacity(
+            HEADER_LENGTH + parameters.avoided_prefixes.len() * AVOIDED_PREFIX_LENGTH,
+        );
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&VERSION.to_be_bytes());
+        header.push(parameters.prefix.bit_count as u8);
+        header.extend_from_slice(&parameters.prefix.value.to_be_bytes());
+        header.push(parameters.random_seed.is_some() as u8);
+        header.extend_from_slice(&parameters.random_seed.unwrap_or(0).to_be_bytes());
+        header.extend_from_slice(&parameters.delta_base_digest);
+        header.push(parameters.initial_entropy_length as u8);
+        header.push(parameters.first_byte_range.first);
+        header.push(parameters.first_byte_range.last);
+        header.extend_from_slice(&(parameters.avoided_prefixes.len() as u32).to_be_bytes());
+        debug_assert_eq!(header.len(), HEADER_LENGTH);
+        for avoided_prefix in &parameters.avoided_prefixes {
+            header.push(avoided_prefix.bit_count as u8);
+            header.extend_from_slice(&avoided_prefix.value.to_be_bytes());
+        }
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(&header)?;
+        Ok(Self {
+            out,
+            next_attempt: 0,
+        })
+    }
+
+    /// Records that `attempt` (counting from 0) found a new shorthash. Attempts have to be recorded in
+    /// increasing order.
+    pub fn record(&mut self, attempt: u64) -> io::Result<()> {
+        debug_assert!(attempt >= self.next_attempt);
+        let mut skipped = attempt - self.next_attempt;
+        self.next_attempt = attempt + 1;
+        loop {
+            let byte = (skipped & 0x7f) as u8;
+            skipped >>= 7;
+            if skipped == 0 {
+                return self.out.write_all(&[byte]);
+            }
+            self.out.write_all(&[byte | 0x80])?;
+        }
+    }
+
+    /// Finishes the file and syncs it to disk.
+    pub fn finish(self) -> io::Result<()> {
+        self.out.into_inner()?.sync_all()
+    }
+}
+
+pub struct AcceptedAttemptsReader {
+    input: BufReader<File>,
+    path: PathBuf,
+    next_attempt: u64,
+}
+
+impl AcceptedAttemptsReader {
+    /// Opens a file written by `AcceptedAttemptsWriter`, and returns it along with the parameters of the
+    /// run that wrote it.
+    pub fn open(path: &Path) -> io::Result<(Self, ReplayParameters)> {
+        let mut input = BufReader::new(File::open(path)?);
+        let too_short = |err: io::Error| {
+            if err.kind() == io::ErrorKind::UnexpectedEof {
+                invalid(path, "it's too short for a header")
+            } else {
+                err
+            }
+        };
+        let mut header = [0; HEADER_LENGTH];
+        input.read_exact(&mut header).map_err(too_short)?;
+        if &header[..4] != MAGIC {
+            return Err(invalid(path, "it doesn't start with the right magic"));
+        }
+        let version = u32::from_be_bytes(header[4..8].try_into().unwrap());
+        if version != VERSION {
+            return Err(invalid(
+                path,
+                &format!(
+                    "it has version {}, but only version {} is supported",
+                    version, VERSION
+                ),
+            ));
+        }
+        let parameters = ReplayParameters {
+            prefix: OidPrefix {
+                bit_count: u32::from(header[8]),
+                value: u64::from_be_bytes(header[9..17].try_into().unwrap()),
+            },
+            random_seed: match header[17] {
+                0 => None,
+                1 => Some(u64::from_be_bytes(header[18..26].try_into().unwrap())),
+                _ => return Err(invalid(path, "it has an unknown entropy source")),
+            },
+            delta_base_digest: header[26..46].try_into().unwrap(),
+            initial_entropy_length: usize::from(header[46]),
+            first_byte_range: FirstByteRange {
+                first: header[47],
+                last: header[48],
+            },
+            avoided_prefixes: {
+                let count = u32::from_be_bytes(header[49..53].try_into().unwrap());
+                let mut avoided_prefixes = Vec::new();
+                for _ in 0..count {
+                    let mut avoided_prefix = [0; AVOIDED_PREFIX_LENGTH];
+                    input.read_exact(&mut avoided_prefix).map_err(too_short)?;
+                    avoided_prefixes.push(OidPrefix {
+                        bit_count: u32::from(avoided_prefix[0]),
+                        value: u64::from_be_bytes(avoided_prefix[1..].try_into().unwrap()),
+                    });
+                }
+                avoided_prefixes
+            },
+        };
+        Ok((
+            Self {
+                input,
+                path: path.to_path_buf(),
+                next_attempt: 0,
+            },
+            parameters,
+        ))
+    }
+
+    /// Returns the next accepted attempt, or `None` at the end of the file.
+    pub fn next_attempt(&mut self) -> io::Result<Option<u64>> {
+        let mut skipped = 0u64;
+        let mut shift = 0;
+        loop {
+            let mut byte = [0];
+            if self.input.read(&mut byte)? == 0 {
+                if shift == 0 {
+                    return Ok(None);
+                }
+                return Err(invalid(&self.path, "it ends in the middle of an attempt"));
+            }
+            if shift > 63 {
+                return Err(invalid(&self.path, "it skips too many attempts"));
+            }
+            skipped |= u64::from(byte[0] & 0x7f) << shift;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+        }
+        let attempt = self
+            .next_attempt
+            .checked_add(skipped)
+            .ok_or_else(|| invalid(&self.path, "it skips too many attempts"))?;
+        self.next_attempt = attempt + 1;
+        Ok(Some(attempt))
+    }
+}
+
+fn invalid(path: &Path, message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "{} is not a valid file of accepted attempts: {}",
+            path.display(),
+            message
+        ),
+    )
+}
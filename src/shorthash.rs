@@ -0,0 +1,248 @@
+use crate::git_utils::Sha1Oid;
+use std::{convert::TryInto, io, ops::Range};
+
+pub const SHORTHASH_BITS: u32 = 28;
+pub const SHORTHASH_COUNT: usize = 1 << SHORTHASH_BITS;
+
+/// Extracts the top `bits` bits of an OID, from 1 to 64, as an index. With `SHORTHASH_BITS`, this is
+/// the index of the OID's shorthash in a `PackedBoolArray`, which is how the generator buckets commits,
+/// so tools that analyze coverage or merge shards should use this to get the same buckets.
+#[inline(always)]
+pub fn shorthash_index(oid: &Sha1Oid, bits: u32) -> usize {
+    debug_assert!((1..=64).contains(&bits));
+    (u64::from_be_bytes(oid[0..8].try_into().unwrap()) >> (64 - bits)) as usize
+}
+
+// Invariant: `data` always holds exactly `SHORTHASH_COUNT` bits, so every shorthash index is in bounds.
+#[repr(transparent)]
+pub struct PackedBoolArray {
+    data: Vec<u8>,
+}
+
+impl PackedBoolArray {
+    const MASKS: [u8; 8] = [1, 2, 4, 8, 16, 32, 64, 128];
+
+    #[inline(always)]
+    fn offset(index: usize) -> usize {
+        index / 8
+    }
+
+    #[inline(always)]
+    fn mask(index: usize) -> u8 {
+        Self::MASKS[index % 8]
+    }
+
+    pub fn get(&self, index: usize) -> bool {
+        self.data[Self::offset(index)] & Self::mask(index) != 0
+    }
+
+    /// Like `get`, but without a bounds check, for the generator's hot loop.
+    ///
+    /// # Safety
+    ///
+    /// `index` must be less than `SHORTHASH_COUNT`. This always holds for indices returned by
+    /// `shorthash_index` with `SHORTHASH_BITS` bits and by `OidPrefix::shorthash_index`.
+    #[inline(always)]
+    pub unsafe fn get_unchecked(&self, index: usize) -> bool {
+        debug_assert!(index < SHORTHASH_COUNT);
+        self.data.get_unchecked(Self::offset(index)) & Self::mask(index) != 0
+    }
+
+    pub fn set(&mut self, index: usize, value: bool) {
+        if value {
+            self.data[Self::offset(index)] |= Self::mask(index);
+        } else {
+            self.data[Self::offset(index)] &= !Self::mask(index);
+        }
+    }
+
+    /// Sets every bit in `range`. Whole bytes are filled at once, so this is fast for large ranges.
+    pub fn set_range(&mut self, range: Range<usize>) {
+        let mut index = range.start;
+        while index < range.end && index % 8 != 0 {
+            self.set(index, true);
+            index += 1;
+        }
+        let whole_bytes_end = index.max(range.end / 8 * 8);
+        self.data[index / 8..whole_bytes_end / 8].fill(0xff);
+        for index in whole_bytes_end..range.end {
+            self.set(index, true);
+        }
+    }
+
+    /// Finds the first unset bit at or after `start`, if any. Fully-set regions are skipped 64 bits
+    /// at a time, which makes this fast even when almost every bit is set.
+    pub fn first_unset_from(&self, start: usize) -> Option<usize> {
+        let bit_count = self.data.len() * 8;
+        let mut index = start;
+        while index < bit_count && index % 64 != 0 {
+            if !self.get(index) {
+                return Some(index);
+            }
+            index += 1;
+        }
+        if index >= bit_count {
+            return None;
+        }
+        for (word_index, chunk) in self.data[index / 8..].chunks(8).enumerate() {
+            // Pad a trailing partial word with set bits so that it can't produce out-of-range results.
+            let mut bytes = [0xff; 8];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            let word = u64::from_le_bytes(bytes);
+            if !word != 0 {
+                return Some(index + word_index * 64 + word.trailing_ones() as usize);
+            }
+        }
+        None
+    }
+
+    /// Returns the bits packed into bytes, in the order used by `from_bytes`.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Unpacks an array from bytes returned by `as_bytes`, or returns `None` if there are the wrong
+    /// number of them.
+    pub fn from_bytes(data: Vec<u8>) -> Option<Self> {
+        if data.len() == SHORTHASH_COUNT / 8 {
+            Some(Self { data })
+        } else {
+            None
+        }
+    }
+
+    pub fn count_set(&self) -> usize {
+        self.data
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum()
+    }
+}
+
+/// A fixed hex prefix that every generated commit OID must start with. When a prefix is used, the
+/// shorthashes that get covered are the 7 hex characters that come after the prefix, so e.g. with a
+/// prefix of `dead` there's a commit starting with `deadXXXXXXX` for every `XXXXXXX`. Only one in
+/// `16**prefix_length` attempts has the right prefix, so this makes generation that much slower.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OidPrefix {
+    pub bit_count: u32,
+    pub value: u64,
+}
+
+impl OidPrefix {
+    // Shorthashes are extracted from the first 64 bits of an OID, so the prefix and the shorthash
+    // have to fit in those bits together.
+    const MAX_LENGTH: usize = (64 - SHORTHASH_BITS as usize) / 4;
+
+    pub fn parse(hex: &str) -> io::Result<Self> {
+        if hex.len() > Self::MAX_LENGTH || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "OID prefixes must be at most {} hex characters, but got {}",
+                    Self::MAX_LENGTH,
+                    hex
+                ),
+            ));
+        }
+        Ok(Self {
+            bit_count: 4 * hex.len() as u32,
+            value: if hex.is_empty() {
+                0
+            } else {
+                u64::from_str_radix(hex, 16).unwrap()
+            },
+        })
+    }
+
+    #[inline(always)]
+    pub fn matches(&self, oid: &Sha1Oid) -> bool {
+        self.bit_count == 0
+            || u64::from_be_bytes(oid[0..8].try_into().unwrap()) >> (64 - self.bit_count)
+                == self.value
+    }
+
+    /// Returns the index of the shorthash that comes after the prefix in an OID, which is the same as
+    /// `shorthash_index(oid, SHORTHASH_BITS)` without a prefix.
+    #[inline(always)]
+    pub fn shorthash_index(&self, oid: &Sha1Oid) -> usize {
+        shorthash_index(oid, self.bit_count + SHORTHASH_BITS) & (SHORTHASH_COUNT - 1)
+    }
+
+    /// Returns the indices of the shorthashes that start with this prefix, as if it were a prefix of the
+    /// shorthash rather than of the whole OID. The prefix can be at most 7 hex characters long.
+    pub fn shorthash_indices(&self) -> Range<usize> {
+        debug_assert!(self.bit_count <= SHORTHASH_BITS);
+        let shift = SHORTHASH_BITS - self.bit_count;
+        ((self.value as usize) << shift)..((self.value as usize + 1) << shift)
+    }
+
+    /// Returns the range of first bytes that OIDs with this prefix can start with.
+    pub fn first_byte_range(&self) -> FirstByteRange {
+        if self.bit_count >= 8 {
+            let first = (self.value >> (self.bit_count - 8)) as u8;
+            FirstByteRange { first, last: first }
+        } else {
+            let free_bits = 8 - self.bit_count;
+            let first = (self.value << free_bits) as u8;
+            FirstByteRange {
+                first,
+                last: first | ((1u16 << free_bits) - 1) as u8,
+            }
+        }
+    }
+}
+
+/// A range of first OID bytes, such as `00-3f`, for splitting the search into shards that cover disjoint
+/// sets of shorthashes. A shard only keeps commits whose OIDs start with a byte in its range, so it
+/// covers exactly the shorthashes that start with those bytes, and packs from shards with
+/// non-overlapping ranges never have a shorthash in common. Unlike with a prefix, the attempts that
+/// fall outside of the range are wasted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FirstByteRange {
+    pub first: u8,
+    pub last: u8,
+}
+
+impl FirstByteRange {
+    pub const ALL: Self = Self {
+        first: 0,
+        last: 0xff,
+    };
+
+    pub fn parse(range: &str) -> io::Result<Self> {
+        let parse_byte = |hex: &str| {
+            if hex.len() == 2 {
+                u8::from_str_radix(hex, 16).ok()
+            } else {
+                None
+            }
+        };
+        match range
+            .split_once('-')
+            .and_then(|(first, last)| Some((parse_byte(first)?, parse_byte(last)?)))
+        {
+            Some((first, last)) if first <= last => Ok(Self { first, last }),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "OID prefix ranges must look like 00-3f, with the first byte no greater than the \
+                    last, but got {}",
+                    range
+                ),
+            )),
+        }
+    }
+
+    /// Returns the indices of the shorthashes that start with a byte in the range.
+    pub fn shorthash_indices(&self) -> Range<usize> {
+        let shift = SHORTHASH_BITS - 8;
+        (usize::from(self.first) << shift)..((usize::from(self.last) + 1) << shift)
+    }
+}
+
+impl Default for PackedBoolArray {
+    fn default() -> Self {
+        Self { data: vec![0; 1 << 25] }
+    }
+}
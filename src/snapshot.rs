@@ -0,0 +1,135 @@
+//! Snapshots a directory as git blob and tree objects, for `--tree-from-dir`. The result is the same as
+//! what `git add --all && git write-tree` would produce in a repository containing the directory, so
+//! checking out a commit with the root tree recreates the directory's files.
+
+use crate::git_utils::{GitObject, Sha1Oid, EMPTY_TREE_OID};
+use std::{collections::HashSet, ffi::OsStr, fs, io, path::Path};
+
+/// The blobs and trees for the contents of a directory.
+pub struct Snapshot {
+    pub root_tree_oid: Sha1Oid,
+    /// Every blob and tree in the snapshot, without duplicates
+    pub objects: Vec<GitObject>,
+}
+
+/// Snapshots the files, symlinks and subdirectories in `dir`, recursively. Like git, this skips `.git`
+/// and empty directories, and only records whether files are executable rather than their full
+/// permissions. An empty `dir` becomes the empty tree, which isn't included in `objects`.
+pub fn snapshot_dir(dir: &Path) -> io::Result<Snapshot> {
+    let mut snapshot = Snapshot {
+        root_tree_oid: EMPTY_TREE_OID,
+        objects: Vec::new(),
+    };
+    let mut oids = HashSet::new();
+    if let Some(oid) = add_dir(dir, &mut snapshot.objects, &mut oids)? {
+        snapshot.root_tree_oid = oid;
+    }
+    Ok(snapshot)
+}
+
+/// Adds the objects for a directory and returns its tree's OID, or `None` if the directory has nothing
+/// in it that git would track.
+fn add_dir(
+    dir: &Path,
+    objects: &mut Vec<GitObject>,
+    oids: &mut HashSet<Sha1Oid>,
+) -> io::Result<Option<Sha1Oid>> {
+    // Each entry is (sort key, mode, name, OID). Git sorts tree entries by name, but compares the names
+    // of subdirectories as if they ended with a slash.
+    let mut entries = Vec::new();
+    for dir_entry in fs::read_dir(dir)? {
+        let dir_entry = dir_entry?;
+        let name = name_bytes(&dir_entry.file_name())?.to_vec();
+        if name == b".git" {
+            continue;
+        }
+        let path = dir_entry.path();
+        let file_type = dir_entry.file_type()?;
+        let (mode, object) = if file_type.is_dir() {
+            if let Some(oid) = add_dir(&path, objects, oids)? {
+                let mut sort_key = name.clone();
+                sort_key.push(b'/');
+                entries.push((sort_key, "40000", name, oid));
+            }
+            continue;
+        } else if file_type.is_symlink() {
+            let target = fs::read_link(&path)?;
+            (
+                "120000",
+                GitObject::blob(name_bytes(target.as_os_str())?.to_vec()),
+            )
+        } else if file_type.is_file() {
+            let mode = if is_executable(&dir_entry.metadata()?) {
+                "100755"
+            } else {
+                "100644"
+            };
+            (mode, GitObject::blob(fs::read(&path)?))
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} isn't a file, directory or symlink, so it can't be put in a tree",
+                    path.display()
+                ),
+            ));
+        };
+        entries.push((name.clone(), mode, name, add_object(object, objects, oids)));
+    }
+    if entries.is_empty() {
+        return Ok(None);
+    }
+
+    entries.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+    let mut data = Vec::new();
+    for (_, mode, name, oid) in entries {
+        data.extend_from_slice(mode.as_bytes());
+        data.push(b' ');
+        data.extend_from_slice(&name);
+        data.push(0);
+        data.extend_from_slice(&oid);
+    }
+    Ok(Some(add_object(GitObject::tree(data), objects, oids)))
+}
+
+/// Adds an object unless an identical one has already been added, e.g. for two files with the same
+/// contents, since a pack can only contain each object once. Returns the object's OID.
+fn add_object(
+    object: GitObject,
+    objects: &mut Vec<GitObject>,
+    oids: &mut HashSet<Sha1Oid>,
+) -> Sha1Oid {
+    let oid = object.oid();
+    if oids.insert(oid) {
+        objects.push(object);
+    }
+    oid
+}
+
+#[cfg(unix)]
+fn name_bytes(name: &OsStr) -> io::Result<&[u8]> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(name.as_bytes())
+}
+
+/// Elsewhere, names are stored as UTF-8, which is what git for Windows does.
+#[cfg(not(unix))]
+fn name_bytes(name: &OsStr) -> io::Result<&[u8]> {
+    name.to_str().map(str::as_bytes).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{:?} isn't valid UTF-8", name),
+        )
+    })
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_metadata: &fs::Metadata) -> bool {
+    false
+}
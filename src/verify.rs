@@ -0,0 +1,169 @@
+//! Checks a pack and its index once they've been written, for `--verify-after`. Both modes check the
+//! trailing checksums of the two files, that the index has the packfile's checksum, and that they have
+//! the same number of objects. Then:
+//!
+//! - sample (the default): reads `SAMPLE_SIZE` randomly chosen objects from the packfile at the offsets
+//!   that the index gives for them, and checks that they hash to the OIDs and CRC32s in the index. This
+//!   takes a few seconds even for a full pack.
+//! - full: reads every object in the packfile and hashes it, and checks that the index lists exactly
+//!   those objects, in order, at the right offsets and with the right CRC32s. This reads both files from
+//!   start to finish, which takes about as long as `git verify-pack` for a full pack.
+
+use crate::{
+    entropy::{EntropySource, RandomEntropySource},
+    git_utils::{oid_to_hex, PackObjectMetadata, Sha1Oid},
+    pack_reader::{
+        for_each_index_entry, lookup_index_entry, verify_idx_trailer, verify_pack_trailer,
+        PackReader,
+    },
+};
+use sha1::{Digest, Sha1};
+use std::{
+    convert::TryInto,
+    fs::File,
+    io,
+    io::{BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+#[derive(Clone, Copy, Debug)]
+pub enum VerifyMode {
+    Sample,
+    Full,
+}
+
+// The number of objects that the sample mode checks. Finding a corrupt object this way is a matter of
+// luck, but a pack that was written with the wrong offsets (or not at all) fails every sample.
+const SAMPLE_SIZE: usize = 10_000;
+
+/// Verifies the pack at `pack_path` against the index at `index_path`, returning the number of objects
+/// that were read back, or an `InvalidData` error describing the first problem.
+pub fn verify_pack(pack_path: &Path, index_path: &Path, mode: VerifyMode) -> io::Result<usize> {
+    let mut pack = BufReader::new(File::open(pack_path)?);
+    let mut index = BufReader::new(File::open(index_path)?);
+    if !verify_pack_trailer(&mut pack)? {
+        return Err(invalid_data(format!(
+            "{} doesn't match its checksum",
+            pack_path.display()
+        )));
+    }
+    if !verify_idx_trailer(&mut index)? {
+        return Err(invalid_data(format!(
+            "{} doesn't match its checksum",
+            index_path.display()
+        )));
+    }
+    // The index file has a copy of the packfile's checksum right before its own.
+    if trailing_bytes(&mut pack, 20)? != trailing_bytes(&mut index, 40)?[..20] {
+        return Err(invalid_data(format!(
+            "{} is for a different packfile than {}",
+            index_path.display(),
+            pack_path.display()
+        )));
+    }
+
+    let mut reader = PackReader::open(pack_path)?;
+    index.seek(SeekFrom::Start(8 + 255 * 4))?;
+    let mut entry = [0; 4];
+    index.read_exact(&mut entry)?;
+    let object_count = u32::from_be_bytes(entry) as usize;
+    if reader.object_count() as usize != object_count {
+        return Err(invalid_data(format!(
+            "the packfile has {} objects, but the index has {}",
+            reader.object_count(),
+            object_count
+        )));
+    }
+
+    match mode {
+        VerifyMode::Sample => {
+            let mut random = RandomEntropySource::new(RandomEntropySource::random_seed());
+            let mut oid = Sha1Oid::default();
+            for _ in 0..SAMPLE_SIZE.min(object_count) {
+                let position = (random.next_entropy() % object_count as u64) as usize;
+                index.seek(SeekFrom::Start((8 + 256 * 4 + position * 20) as u64))?;
+                index.read_exact(&mut oid)?;
+                // Looking the OID up also checks that the index is sorted around it.
+                let metadata = match lookup_index_entry(index_path, &oid)? {
+                    Some(entry) if entry.position == position => entry.metadata,
+                    _ => {
+                        return Err(invalid_data(format!(
+                            "the OIDs in the index aren't sorted around {}",
+                            oid_to_hex(&oid)
+                        )))
+                    }
+                };
+                let packed = reader.read_object_at(metadata.offset)?;
+                if packed.oid != metadata.oid || packed.crc32 != metadata.crc32 {
+                    return Err(invalid_data(format!(
+                        "the index lists {} at offset {}, but the object there is {}",
+                        oid_to_hex(&metadata.oid),
+                        metadata.offset,
+                        oid_to_hex(&packed.oid)
+                    )));
+                }
+            }
+            Ok(SAMPLE_SIZE.min(object_count))
+        }
+        VerifyMode::Full => {
+            // The packfile and the index list the objects in different orders, and a full index doesn't fit
+            // in memory, so each side is summarized as a sum of hashes of its entries, which doesn't
+            // depend on the order.
+            let mut pack_fingerprint = 0u64;
+            for (i, packed) in reader.enumerate() {
+                let packed = packed?;
+                pack_fingerprint =
+                    pack_fingerprint.wrapping_add(fingerprint(&PackObjectMetadata {
+                        oid: packed.oid,
+                        offset: packed.offset,
+                        crc32: packed.crc32,
+                    }));
+                if (i + 1) & 0xfffff == 0 {
+                    println!("objects verified so far: {}", i + 1);
+                }
+            }
+            let mut index_fingerprint = 0u64;
+            let mut previous_oid = None;
+            let mut is_sorted = true;
+            for_each_index_entry(index_path, |metadata| {
+                is_sorted &= previous_oid < Some(metadata.oid);
+                previous_oid = Some(metadata.oid);
+                index_fingerprint = index_fingerprint.wrapping_add(fingerprint(&metadata));
+            })?;
+            if !is_sorted {
+                return Err(invalid_data(
+                    "the OIDs in the index aren't sorted".to_string(),
+                ));
+            }
+            if pack_fingerprint != index_fingerprint {
+                return Err(invalid_data(
+                    "the index doesn't list the objects in the packfile at their offsets and CRC32s"
+                        .to_string(),
+                ));
+            }
+            Ok(object_count)
+        }
+    }
+}
+
+/// Hashes an object's OID, offset and CRC32 into a value for summing.
+fn fingerprint(metadata: &PackObjectMetadata) -> u64 {
+    let hash = Sha1::new()
+        .chain(metadata.oid)
+        .chain(metadata.offset.0.to_be_bytes())
+        .chain(metadata.crc32.to_be_bytes())
+        .finalize();
+    u64::from_be_bytes(hash[..8].try_into().unwrap())
+}
+
+/// Reads the last `length` bytes of a file.
+fn trailing_bytes<R: Read + Seek>(file: &mut R, length: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![0; length];
+    file.seek(SeekFrom::End(-(length as i64)))?;
+    file.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
@@ -0,0 +1,73 @@
+//! Helpers shared by the integration tests. Each test includes this with `mod common;`, and most of them
+//! only use some of it.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// Returns an empty directory for the test `name` in the system's temporary directory, removing whatever
+/// an earlier run of the test left there. The directory's name has the process ID in it, so that
+/// concurrent runs of the tests don't share it.
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Returns a command that runs the tool in `dir`. The commits are authored by the identity in git's
+/// config, so the tool gets `dir` as its home directory, without `XDG_CONFIG_HOME`, to keep the
+/// developer's config out of the output. It also finds the repository from `dir` rather than `GIT_DIR`.
+pub fn tool(dir: &Path) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_every-commit-shorthash"));
+    command
+        .current_dir(dir)
+        .env("HOME", dir)
+        .env_remove("XDG_CONFIG_HOME")
+        .env_remove("GIT_DIR");
+    command
+}
+
+/// Returns whether git is installed, and otherwise prints that `name` is skipped, for tests that need git
+/// to check the tool's output.
+pub fn git_is_installed(name: &str) -> bool {
+    let is_installed = Command::new("git")
+        .arg("--version")
+        .output()
+        .is_ok_and(|output| output.status.success());
+    if !is_installed {
+        eprintln!("skipping {}, since git isn't installed", name);
+    }
+    is_installed
+}
+
+/// Runs git in `repo` with `args` and returns its stdout, failing the test if git fails. Git finds the
+/// repository from `repo` rather than `GIT_DIR`. With a `home`, it also gets that as its home directory,
+/// without `XDG_CONFIG_HOME`, like the tool does.
+pub fn git(repo: &Path, home: Option<&Path>, args: &[&str]) -> String {
+    let mut command = Command::new("git");
+    command.args(args).current_dir(repo).env_remove("GIT_DIR");
+    if let Some(home) = home {
+        command.env("HOME", home).env_remove("XDG_CONFIG_HOME");
+    }
+    let output = command.output().unwrap();
+    assert!(
+        output.status.success(),
+        "git {} failed: {}",
+        args[0],
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Runs the tool in `dir` with `args`, and returns whether it succeeded along with its stdout and stderr.
+pub fn run(dir: &Path, args: &[&str]) -> (bool, String, String) {
+    let output = tool(dir).args(args).output().unwrap();
+    (
+        output.status.success(),
+        String::from_utf8_lossy(&output.stdout).into_owned(),
+        String::from_utf8_lossy(&output.stderr).into_owned(),
+    )
+}
@@ -0,0 +1,181 @@
+//! Compares the zlib levels of `--leaf-compression` and `--merge-compression`, by generating the same pack
+//! with each combination and timing the compression of its objects with `--profile`. The generated
+//! commits are deltas of a few bytes of entropy, and the merge commits are hundreds of KB of hex OIDs,
+//! so the two kinds of objects respond very differently to the level.
+//!
+//! A quick test checks which level each kind of object gets, by looking at the zlib streams in the
+//! packfile: a stream written at level 0 starts with a stored block, and the merge commits are
+//! compressible enough that any other level starts with a compressed one.
+//!
+//! The benchmark is ignored by default, since timing is only meaningful in a release build. Run it with
+//! `cargo test --release --test compression_levels -- --ignored --nocapture`. It stops after `2**21`
+//! attempts (so that the pack has merge commits), which takes a few seconds per combination.
+
+use std::fs;
+
+#[allow(dead_code)]
+mod common;
+#[allow(dead_code)]
+#[path = "../src/direct_io.rs"]
+mod direct_io;
+#[allow(dead_code)]
+#[path = "../src/git_utils.rs"]
+mod git_utils;
+#[allow(dead_code)]
+#[path = "../src/pack_reader.rs"]
+mod pack_reader;
+
+use common::{run, test_dir, tool};
+use pack_reader::read_index_entries;
+
+/// A packfile entry's type, uncompressed size, and whether its zlib stream starts with a stored block
+struct Entry {
+    type_id: u8,
+    size: usize,
+    stored: bool,
+}
+
+/// Generates a pack with `args`, and reads the start of each of its entries.
+fn generate_entries(name: &str, args: &[&str]) -> Vec<Entry> {
+    let dir = test_dir(&format!("compression_levels-{}", name));
+    let mut all_args = vec!["--objects-dir", "objects", "--commit-count-cap", "20000"];
+    all_args.extend_from_slice(args);
+    let (success, _, stderr) = run(&dir, &all_args);
+    assert!(success, "generating the pack failed: {}", stderr);
+    let pack_path = dir
+        .join("objects")
+        .join("pack")
+        .join("pack-every-shorthash.pack");
+    let pack = fs::read(&pack_path).unwrap();
+    let entries = read_index_entries(&pack_path.with_extension("idx"))
+        .unwrap()
+        .into_iter()
+        .map(|entry| {
+            let mut position = entry.offset.0 as usize;
+            let type_id = pack[position] >> 4 & 7;
+            let mut size = usize::from(pack[position] & 0xf);
+            let mut shift = 4;
+            while pack[position] & 0x80 != 0 {
+                position += 1;
+                size |= usize::from(pack[position] & 0x7f) << shift;
+                shift += 7;
+            }
+            position += 1;
+            match type_id {
+                // An offset delta's base offset is another variable-length number, and a ref delta's
+                // base is a whole OID.
+                6 => {
+                    while pack[position] & 0x80 != 0 {
+                        position += 1;
+                    }
+                    position += 1;
+                }
+                7 => position += 20,
+                _ => {}
+            }
+            // The first deflate block header comes after the 2-byte zlib header, and its BTYPE bits are 00
+            // for a stored block.
+            let stored = pack[position + 2] >> 1 & 3 == 0;
+            Entry {
+                type_id,
+                size,
+                stored,
+            }
+        })
+        .collect();
+    fs::remove_dir_all(&dir).unwrap();
+    entries
+}
+
+#[test]
+fn compression_of_each_kind_of_object() {
+    // The generated commits are the deltas, and the merge commits are the large whole objects.
+    let is_delta = |entry: &Entry| entry.type_id >= 6;
+    let is_merge = |entry: &Entry| entry.type_id == 1 && entry.size > 10_000;
+
+    let entries = generate_entries("stored_leaves", &["--leaf-compression", "none"]);
+    assert!(entries.iter().any(is_merge));
+    assert!(entries
+        .iter()
+        .filter(|entry| is_delta(entry))
+        .all(|entry| entry.stored));
+    assert!(entries
+        .iter()
+        .filter(|entry| is_merge(entry))
+        .all(|entry| !entry.stored));
+
+    let entries = generate_entries("stored_merges", &["--merge-compression", "none"]);
+    assert!(entries.iter().any(is_merge));
+    assert!(entries
+        .iter()
+        .filter(|entry| !is_delta(entry))
+        .all(|entry| entry.stored));
+}
+
+/// Generates a pack with `args`, and returns the seconds spent compressing objects and the size of the
+/// packfile.
+fn generate(name: &str, args: &[&str]) -> (f64, u64) {
+    let dir = test_dir(&format!("compression_levels-{}", name));
+    let output = tool(&dir)
+        .arg("--objects-dir")
+        .arg(dir.join("objects"))
+        .arg("--write-checkpoint")
+        .arg(dir.join("checkpoint"))
+        .args(["--max-attempts", &(1 << 21).to_string(), "--profile"])
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "generating the pack failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let compressing_seconds = stdout
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("compressing objects (zlib)"))
+        .and_then(|rest| rest.split_whitespace().next())
+        .expect("the run didn't print the time spent compressing")
+        .parse()
+        .unwrap();
+    let pack_size = fs::metadata(
+        dir.join("objects")
+            .join("pack")
+            .join("pack-every-shorthash.pack"),
+    )
+    .unwrap()
+    .len();
+    fs::remove_dir_all(&dir).unwrap();
+    (compressing_seconds, pack_size)
+}
+
+#[test]
+#[ignore]
+fn benchmark_compression_levels() {
+    let combinations: [(&str, &[&str]); 5] = [
+        ("best", &[]),
+        ("fast_leaves", &["--leaf-compression", "1"]),
+        ("fast_merges", &["--merge-compression", "1"]),
+        (
+            "stored_leaves_fast_merges",
+            &["--leaf-compression", "none", "--merge-compression", "1"],
+        ),
+        ("none", &["--compression", "none"]),
+    ];
+    let results: Vec<(f64, u64)> = combinations
+        .iter()
+        .map(|(name, args)| generate(name, args))
+        .collect();
+    for ((name, _), (seconds, size)) in combinations.iter().zip(&results) {
+        println!(
+            "{:<26} {:>7.3} s compressing, {:>10} bytes",
+            name, seconds, size
+        );
+    }
+    // The leaves are mostly incompressible entropy, so a fast level shouldn't cost more than 1% of the
+    // pack.
+    assert!(
+        results[1].1 * 100 <= results[0].1 * 101,
+        "compressing the leaves at level 1 made the pack more than 1% larger"
+    );
+}
@@ -0,0 +1,108 @@
+//! Checks that the commits are authored by the identity from git's config: the repository's `user.name`
+//! and `user.email` take precedence over the global config's, and the commits fall back to the built-in
+//! identity without either. Every commit in the pack, including the merge commits, should have the
+//! identity, and git should accept them.
+//!
+//! The test is skipped if `git` isn't installed.
+
+use std::{fs, path::Path};
+
+#[allow(dead_code)]
+mod common;
+
+use common::{git, git_is_installed, test_dir, tool};
+
+/// Generates a pack in `repo` and returns the distinct `author` and `committer` lines of its commits,
+/// without the timestamps.
+fn generate_identities(repo: &Path, home: &Path) -> Vec<String> {
+    let output = tool(repo)
+        .args(["--max-attempts", "1000", "--tree-count", "2"])
+        .args(["--write-checkpoint", ".git/every-shorthash.checkpoint"])
+        .env("HOME", home)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "generating the pack failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    git(repo, Some(home), &["fsck", "--strict", "--no-dangling"]);
+
+    let objects = git(
+        repo,
+        Some(home),
+        &["cat-file", "--batch-all-objects", "--batch-check"],
+    );
+    let mut identities = Vec::new();
+    let mut merge_count = 0;
+    for line in objects.lines() {
+        let fields: Vec<&str> = line.split(' ').collect();
+        if fields[1] != "commit" {
+            continue;
+        }
+        let commit = git(repo, Some(home), &["cat-file", "commit", fields[0]]);
+        merge_count += commit.contains("\nparent ") as usize;
+        for line in commit.lines() {
+            if line.starts_with("author ") || line.starts_with("committer ") {
+                let identity = line.rsplitn(3, ' ').nth(2).unwrap().to_string();
+                if !identities.contains(&identity) {
+                    identities.push(identity);
+                }
+            }
+        }
+    }
+    assert!(merge_count > 0, "the pack doesn't have a merge commit");
+    identities.sort();
+    identities
+}
+
+#[test]
+fn commits_are_authored_by_the_configured_user() {
+    if !git_is_installed("commits_are_authored_by_the_configured_user") {
+        return;
+    }
+    let dir = test_dir("config_identity");
+    let (home, repo) = (dir.join("home"), dir.join("repo"));
+    fs::create_dir_all(&home).unwrap();
+    fs::create_dir_all(&repo).unwrap();
+    git(&repo, Some(&home), &["init", "--quiet"]);
+
+    assert_eq!(
+        generate_identities(&repo, &home),
+        [
+            "author Teddy Katz <teddy.katz@gmail.com>",
+            "committer Teddy Katz <teddy.katz@gmail.com>"
+        ]
+    );
+
+    // A quoted value with escapes and a comment, in a global config that the repository's overrides. Git
+    // drops the quote at the end of the name.
+    fs::write(
+        home.join(".gitconfig"),
+        "[user]\n\tname = \"Global \\\"User\\\"\" ; a comment\n\temail = global@example.com\n",
+    )
+    .unwrap();
+    git(
+        &repo,
+        Some(&home),
+        &["config", "user.email", "repo.user@example.com"],
+    );
+    fs::remove_dir_all(repo.join(".git").join("objects").join("pack")).unwrap();
+    fs::create_dir(repo.join(".git").join("objects").join("pack")).unwrap();
+    assert_eq!(
+        generate_identities(&repo, &home),
+        [
+            "author Global \"User <repo.user@example.com>",
+            "committer Global \"User <repo.user@example.com>"
+        ]
+    );
+    // Git agrees on the identity.
+    assert_eq!(
+        git(&repo, Some(&home), &["var", "GIT_AUTHOR_IDENT"])
+            .rsplitn(3, ' ')
+            .nth(2)
+            .unwrap(),
+        "Global \"User <repo.user@example.com>"
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
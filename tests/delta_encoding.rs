@@ -0,0 +1,109 @@
+//! Checks the bytes that `encode_delta` produces against deltas encoded by hand from the [pack format
+//! documentation](https://git-scm.com/docs/pack-format#_deltified_representation), and that it rejects
+//! the instructions that the format can't represent.
+
+use std::io;
+
+#[allow(dead_code)]
+#[path = "../src/direct_io.rs"]
+mod direct_io;
+#[allow(dead_code)]
+#[path = "../src/git_utils.rs"]
+mod git_utils;
+
+use git_utils::{encode_delta, ObjectSize, PackFileDeltaInstruction};
+
+fn copy(offset: usize, size: usize) -> PackFileDeltaInstruction {
+    PackFileDeltaInstruction::CopyFromBaseObject { offset, size }
+}
+
+fn add(data: &[u8]) -> PackFileDeltaInstruction {
+    PackFileDeltaInstruction::AddNewData(data.to_vec())
+}
+
+#[test]
+fn sizes() {
+    // Each size is 7 bits per byte, least significant first, with the top bit set on all but the last.
+    for (base_size, new_size, expected) in [
+        (0, 0, &[0x00, 0x00][..]),
+        (0x7f, 0x80, &[0x7f, 0x80, 0x01]),
+        (0x3fff, 0x4000, &[0xff, 0x7f, 0x80, 0x80, 0x01]),
+        (
+            0x12_05_34_00,
+            0x5_00_00,
+            &[0x80, 0xe8, 0x94, 0x90, 0x01, 0x80, 0x80, 0x14],
+        ),
+    ] {
+        assert_eq!(
+            encode_delta(ObjectSize(base_size), ObjectSize(new_size), &[]).unwrap(),
+            expected,
+            "base size {:#x}, new size {:#x}",
+            base_size,
+            new_size
+        );
+    }
+}
+
+#[test]
+fn copy_instructions() {
+    // The opcode has a flag for each of the 4 offset bytes and 3 size bytes, and only the nonzero bytes
+    // follow it.
+    assert_eq!(
+        encode_delta(ObjectSize(0x300), ObjectSize(0x200), &[copy(0x100, 0x200)]).unwrap(),
+        [0x80, 0x06, 0x80, 0x04, 0xa2, 0x01, 0x02]
+    );
+    assert_eq!(
+        encode_delta(
+            ObjectSize(0x12_05_34_00),
+            ObjectSize(0x5_00_00),
+            &[copy(0x12_00_34_00, 0x5_00_00)]
+        )
+        .unwrap(),
+        [0x80, 0xe8, 0x94, 0x90, 0x01, 0x80, 0x80, 0x14, 0xca, 0x34, 0x12, 0x05]
+    );
+    // Every byte present, and a copy from the start of the base with no offset bytes at all
+    assert_eq!(
+        encode_delta(
+            ObjectSize(0xff_ff_ff_ff),
+            ObjectSize(0x1_05_06_06),
+            &[copy(0x1_02_03_04, 0x5_06_07), copy(0, 0xff_ff_ff)]
+        )
+        .unwrap()[9..],
+        [0xff, 0x04, 0x03, 0x02, 0x01, 0x07, 0x06, 0x05, 0xf0, 0xff, 0xff, 0xff]
+    );
+}
+
+#[test]
+fn add_instructions() {
+    // The opcode is the number of bytes that follow it.
+    assert_eq!(
+        encode_delta(ObjectSize(0), ObjectSize(1), &[add(b"a")]).unwrap(),
+        [0x00, 0x01, 0x01, b'a']
+    );
+    let data = (0..127).collect::<Vec<u8>>();
+    assert_eq!(
+        encode_delta(ObjectSize(0), ObjectSize(127), &[add(&data)]).unwrap(),
+        [&[0x00, 0x7f, 0x7f][..], &data].concat()
+    );
+}
+
+#[test]
+fn unencodable_instructions() {
+    // An opcode of 0 is reserved, one with the top bit set is a copy, and a copy's size has to fit in
+    // its 3 bytes without all of them being 0 (which git reads as 0x10000).
+    for (instruction, message) in [
+        (add(b""), "can't add 0 bytes of new data"),
+        (add(&[0; 128]), "can't add 128 bytes of new data"),
+        (copy(0, 0), "copies 0 bytes"),
+        (copy(0, 0x1_00_00_00), "copies 16777216 bytes"),
+    ] {
+        let err =
+            encode_delta(ObjectSize(0x1_00_00_00), ObjectSize(0), &[instruction]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(
+            err.to_string().contains(message),
+            "unexpected error: {}",
+            err
+        );
+    }
+}
@@ -0,0 +1,152 @@
+//! Checks that the deltified commits in a generated pack are what the generator claims they are. The
+//! generator computes each attempt's OID from a SHA1 state and the entropy, and builds the delta that
+//! goes in the pack (a copy of the delta base commit, and the entropy as new data) separately, so a wrong
+//! copy size or entropy string would give a pack whose index lists OIDs that none of its objects have.
+//! Each delta is read back from the packfile and applied to its base with `apply_delta`, and the result
+//! has to hash to the OID that was written to the index for it.
+
+use std::{collections::HashMap, fs, path::Path};
+
+#[allow(dead_code)]
+mod common;
+#[allow(dead_code)]
+#[path = "../src/direct_io.rs"]
+mod direct_io;
+#[allow(dead_code)]
+#[path = "../src/git_utils.rs"]
+mod git_utils;
+#[allow(dead_code)]
+#[path = "../src/pack_reader.rs"]
+mod pack_reader;
+
+use common::{test_dir, tool};
+use git_utils::{apply_delta, oid_to_hex, GitObject, Sha1Oid};
+use pack_reader::PackReader;
+
+/// Returns the OID that `--dump-metadata` lists for each offset in the pack.
+fn claimed_oids(metadata_path: &Path) -> HashMap<u64, String> {
+    fs::read_to_string(metadata_path)
+        .unwrap()
+        .lines()
+        .filter_map(|line| {
+            let (_, rest) = line.split_once("{\"oid\": \"")?;
+            let (oid, rest) = rest.split_once("\", \"offset\": ")?;
+            let (offset, _) = rest.split_once(',')?;
+            Some((offset.parse().unwrap(), oid.to_string()))
+        })
+        .collect()
+}
+
+/// Generates a pack with `args`, and checks every deltified object in it. `files` are written to the
+/// run's directory first. Returns the number of deltified objects.
+fn check_deltas(name: &str, files: &[(&str, &str)], args: &[&str]) -> usize {
+    let dir = test_dir(&format!("delta_oids-{}", name));
+    for (path, contents) in files {
+        fs::write(dir.join(path), contents).unwrap();
+    }
+    let output = tool(&dir)
+        .args(["--objects-dir", "objects", "--max-attempts", "3000"])
+        .args(["--dump-metadata", "metadata.json"])
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "the run failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let claimed = claimed_oids(&dir.join("metadata.json"));
+    let pack_path = dir
+        .join("objects")
+        .join("pack")
+        .join("pack-every-shorthash.pack");
+
+    let mut objects: HashMap<Sha1Oid, GitObject> = HashMap::new();
+    let mut deltified_count = 0;
+    for packed in PackReader::open(&pack_path).unwrap() {
+        let packed = packed.unwrap();
+        let claimed_oid = &claimed[&packed.offset.0];
+        if let Some(delta) = &packed.delta {
+            let base = &objects[&delta.base_oid];
+            assert_eq!(delta.base_size.0, base.data.len());
+            let reconstructed = GitObject {
+                object_type: base.object_type.clone(),
+                data: apply_delta(&base.data, &delta.instructions).unwrap(),
+            };
+            assert_eq!(
+                &oid_to_hex(&reconstructed.oid()),
+                claimed_oid,
+                "the delta at offset {} doesn't reconstruct the object that was claimed for it: {}",
+                packed.offset,
+                String::from_utf8_lossy(&reconstructed.data)
+            );
+            deltified_count += 1;
+        }
+        assert_eq!(&oid_to_hex(&packed.oid), claimed_oid);
+        objects.insert(packed.oid, packed.object);
+    }
+    assert_eq!(objects.len(), claimed.len());
+    fs::remove_dir_all(&dir).unwrap();
+    deltified_count
+}
+
+#[test]
+fn default_deltas() {
+    assert!(check_deltas("default", &[], &[]) > 1000);
+}
+
+#[test]
+fn delta_chains_with_random_entropy() {
+    // Each commit in a chain is a delta against the one before it rather than the delta base commit, and
+    // random entropy isn't a run of consecutive values that a wrong entropy string could still match.
+    let count = check_deltas(
+        "chains",
+        &[],
+        &[
+            "--delta-chain-depth",
+            "3",
+            "--tree-count",
+            "3",
+            "--entropy-source",
+            "random",
+            "--seed",
+            "5",
+        ],
+    );
+    assert!(count > 1000);
+}
+
+#[test]
+fn deltas_from_hashing_threads() {
+    // Short entropy, so that the threads' job ends and they start over with longer entropy.
+    let count = check_deltas(
+        "threads",
+        &[],
+        &["--threads", "2", "--entropy-prefix-len", "3"],
+    );
+    assert!(count > 1000);
+}
+
+#[test]
+fn deltas_with_a_commit_encoding() {
+    // The message is padded differently with a declared encoding, which changes the length that the
+    // delta copies from the base.
+    assert!(check_deltas("encoding", &[], &["--commit-encoding", "ISO-8859-1"]) > 1000);
+}
+
+#[test]
+fn deltas_with_a_message_footer() {
+    // The footer comes after the entropy, so it's copied from the end of the base.
+    let count = check_deltas(
+        "footer",
+        &[("footer", "\nSigned-off-by: Someone <someone@example.com>\n")],
+        &["--message-footer", "footer", "--delta-chain-depth", "2"],
+    );
+    assert!(count > 1000);
+}
+
+#[test]
+fn linear_deltas() {
+    // Each linear commit adds the parent line between two copies from the base.
+    assert!(check_deltas("linear", &[], &["--linear"]) > 1000);
+}
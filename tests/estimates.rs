@@ -0,0 +1,51 @@
+//! Checks the coupon collector estimates of how many attempts a run takes against values worked out by
+//! hand, and that they grow the way they should with the coverage and the shorthash size.
+
+#[allow(dead_code)]
+#[path = "../src/estimates.rs"]
+mod estimates;
+
+use estimates::{expected_attempts, expected_attempts_for_coverage};
+
+#[test]
+fn expected_attempts_for_every_shorthash() {
+    // 2**28 * (ln(2**28) + 0.5772) = 2**28 * 19.985, which is about 2**32.32
+    let attempts = expected_attempts(28);
+    assert!(
+        (attempts.log2() - 32.32).abs() < 0.01,
+        "2**{} attempts",
+        attempts.log2()
+    );
+    // With 2 shorthashes, the first attempt finds one, and each attempt after it has a 1/2 chance of
+    // finding the other, so it takes 1 + 2 = 3 attempts on average.
+    assert!(
+        (expected_attempts(1) - 3.0).abs() < 0.01,
+        "{}",
+        expected_attempts(1)
+    );
+    for bits in 1..32 {
+        assert!(expected_attempts(bits) < expected_attempts(bits + 1));
+    }
+}
+
+#[test]
+fn expected_attempts_for_part_of_the_shorthashes() {
+    for bits in [1, 8, 28] {
+        assert_eq!(
+            expected_attempts_for_coverage(bits, 1.0),
+            expected_attempts(bits)
+        );
+        assert_eq!(expected_attempts_for_coverage(bits, 0.0), 0.0);
+    }
+    // Finding the first half of n shorthashes takes about n * ln(2) attempts.
+    let half = expected_attempts_for_coverage(28, 0.5) / 2f64.powi(28);
+    assert!((half - 2f64.ln()).abs() < 0.001, "{} * 2**28", half);
+
+    let fractions = (0..=100).map(|percent| percent as f64 / 100.0);
+    let attempts = fractions
+        .map(|fraction| expected_attempts_for_coverage(28, fraction))
+        .collect::<Vec<_>>();
+    assert!(attempts.windows(2).all(|pair| pair[0] < pair[1]));
+    // The last 1% takes more attempts than the first 99%.
+    assert!(attempts[100] - attempts[99] > attempts[99]);
+}
@@ -0,0 +1,92 @@
+//! Checks `--leaves-only` and `--sample`, which leave out the merge commits (and, for a sample, most of
+//! the generated commits). The pack should still be complete: every commit that's a delta against a
+//! skipped commit needs that commit in the pack too, and git should accept every object in it. The counts
+//! that the run prints should match what's in the pack.
+//!
+//! The tests are skipped if `git` isn't installed.
+
+use std::{collections::HashMap, fs};
+
+#[allow(dead_code)]
+mod common;
+
+use common::{git, git_is_installed, test_dir, tool};
+
+/// Generates a pack with `args` and returns the number of generated commits that the run printed.
+fn check_filtered_run(name: &str, args: &[&str]) -> usize {
+    let repo = test_dir(&format!("filtered_generation-{}", name));
+    git(&repo, None, &["init", "--quiet"]);
+    let output = tool(&repo)
+        .args(["--max-attempts", "5000", "--tree-count", "2"])
+        .args(["--write-checkpoint", ".git/every-shorthash.checkpoint"])
+        .args(args)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "generating the pack failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let printed_count = |prefix: &str| -> usize {
+        stdout
+            .lines()
+            .find_map(|line| line.strip_prefix(prefix))
+            .unwrap_or_else(|| panic!("the run didn't print {:?}", prefix))
+            .parse()
+            .unwrap()
+    };
+    assert_eq!(printed_count("First-level merge commits: "), 0);
+    assert_eq!(printed_count("Top-level merge commits: "), 0);
+    let generated_count = printed_count("Generated commits: ");
+
+    let mut counts_by_type = HashMap::new();
+    for line in git(
+        &repo,
+        None,
+        &["cat-file", "--batch-all-objects", "--batch-check"],
+    )
+    .lines()
+    {
+        let object_type = line.split(' ').nth(1).unwrap().to_string();
+        *counts_by_type.entry(object_type).or_insert(0) += 1;
+    }
+    assert_eq!(
+        counts_by_type["commit"],
+        generated_count + printed_count("Delta base commits: ")
+    );
+    assert_eq!(
+        counts_by_type.values().sum::<usize>(),
+        printed_count("Total objects: ")
+    );
+    // Nothing refers to the commits, so they're all dangling, but they have to be valid.
+    git(&repo, None, &["fsck", "--strict", "--no-dangling"]);
+    fs::remove_dir_all(&repo).unwrap();
+    generated_count
+}
+
+#[test]
+fn leaves_only() {
+    if git_is_installed("leaves_only") {
+        // Early on, almost every attempt finds a new shorthash.
+        let generated_count = check_filtered_run("leaves_only", &["--leaves-only"]);
+        assert!(generated_count > 4900, "{} commits", generated_count);
+    }
+}
+
+#[test]
+fn sample_with_delta_chains() {
+    if git_is_installed("sample_with_delta_chains") {
+        let all_count =
+            check_filtered_run("sample_all", &["--leaves-only", "--delta-chain-depth", "3"]);
+        let sampled_count =
+            check_filtered_run("sample", &["--sample", "10", "--delta-chain-depth", "3"]);
+        // Every tenth commit, and at most the two before it in its chain
+        assert!(
+            sampled_count >= all_count / 10 && sampled_count <= all_count * 3 / 10 + 1,
+            "{} of {} commits",
+            sampled_count,
+            all_count
+        );
+    }
+}
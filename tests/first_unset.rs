@@ -0,0 +1,68 @@
+//! Checks that `PackedBoolArray::first_unset_from` finds the same bit as checking one bit at a time,
+//! around the 64-bit words that it skips over.
+
+#[allow(dead_code)]
+#[path = "../src/direct_io.rs"]
+mod direct_io;
+#[allow(dead_code)]
+#[path = "../src/git_utils.rs"]
+mod git_utils;
+#[allow(dead_code)]
+#[path = "../src/shorthash.rs"]
+mod shorthash;
+
+use shorthash::{PackedBoolArray, SHORTHASH_COUNT};
+
+/// Returns an array with every bit set except the ones in `unset`.
+fn all_set_except(unset: &[usize]) -> PackedBoolArray {
+    let mut array = PackedBoolArray::from_bytes(vec![0xff; SHORTHASH_COUNT / 8]).unwrap();
+    for &index in unset {
+        array.set(index, false);
+    }
+    array
+}
+
+#[test]
+fn start_inside_a_word() {
+    let array = all_set_except(&[3, 70, 200]);
+    assert_eq!(array.first_unset_from(0), Some(3));
+    assert_eq!(array.first_unset_from(3), Some(3));
+    // The rest of the first word is checked bit by bit before whole words are skipped.
+    assert_eq!(array.first_unset_from(4), Some(70));
+    assert_eq!(array.first_unset_from(65), Some(70));
+    assert_eq!(array.first_unset_from(71), Some(200));
+    assert_eq!(array.first_unset_from(201), None);
+}
+
+#[test]
+fn gap_on_a_word_boundary() {
+    let array = all_set_except(&[64, 128 + 63, SHORTHASH_COUNT - 1]);
+    assert_eq!(array.first_unset_from(0), Some(64));
+    assert_eq!(array.first_unset_from(1), Some(64));
+    assert_eq!(array.first_unset_from(64), Some(64));
+    // The last bit of a word, and the last bit of the array
+    assert_eq!(array.first_unset_from(65), Some(128 + 63));
+    assert_eq!(array.first_unset_from(128), Some(128 + 63));
+    assert_eq!(array.first_unset_from(192), Some(SHORTHASH_COUNT - 1));
+    assert_eq!(
+        array.first_unset_from(SHORTHASH_COUNT - 1),
+        Some(SHORTHASH_COUNT - 1)
+    );
+}
+
+#[test]
+fn every_bit_set() {
+    let array = all_set_except(&[]);
+    for start in [0, 1, 63, 64, SHORTHASH_COUNT - 65, SHORTHASH_COUNT - 1] {
+        assert_eq!(array.first_unset_from(start), None, "{}", start);
+    }
+}
+
+#[test]
+fn start_past_the_end() {
+    let array = PackedBoolArray::default();
+    assert_eq!(array.first_unset_from(0), Some(0));
+    for start in [SHORTHASH_COUNT, SHORTHASH_COUNT + 1, SHORTHASH_COUNT + 64] {
+        assert_eq!(array.first_unset_from(start), None, "{}", start);
+    }
+}
@@ -0,0 +1,361 @@
+//! Checks that the index file the tool writes is byte-for-byte the same as the one `git index-pack`
+//! writes for the same packfile. Both list the objects in OID order and only use the table of 8-byte
+//! offsets past the first 2 GiB of the packfile, so nothing about the index is left up to the writer,
+//! and any difference (in the CRC32s, the offsets or the trailer) is a bug. This isn't true with
+//! `--big-offset-threshold`, which deliberately writes indexes that git wouldn't. Those are checked
+//! against git's index for the same packfile instead, with both tables of offsets resolved.
+//!
+//! The fanout table is also checked on its own, against the OIDs that follow it and the object count in
+//! the packfile's header, since git relies on it for every lookup.
+//!
+//! The tests that compare with git are skipped if `git` isn't installed.
+
+use std::{convert::TryInto, fs, iter, path::Path, process::Command};
+
+#[allow(dead_code)]
+mod common;
+#[allow(dead_code)]
+#[path = "../src/direct_io.rs"]
+mod direct_io;
+#[allow(dead_code)]
+#[path = "../src/git_utils.rs"]
+mod git_utils;
+#[allow(dead_code)]
+#[path = "../src/pack_reader.rs"]
+mod pack_reader;
+
+use common::{git_is_installed, run, test_dir, tool};
+use git_utils::{
+    stream_to_pack_file, GitObject, ObjectSize, PackFileDeltaInstruction, PackFileObject,
+    PackWriteOptions, Sha1Oid,
+};
+use pack_reader::read_index_entries;
+
+/// Runs the tool with `args` in a new object directory and compares its index to git's.
+fn check_index_matches_git(name: &str, args: &[&str]) {
+    if !git_is_installed(name) {
+        return;
+    }
+    let dir = test_dir(&format!("idx_matches_git-{}", name));
+    let objects_dir = dir.join("objects");
+    let output = tool(&dir)
+        .arg("--objects-dir")
+        .arg(&objects_dir)
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "generating the pack failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let pack_path = objects_dir.join("pack").join("pack-every-shorthash.pack");
+    let git_pack_path = dir.join("git.pack");
+    fs::copy(&pack_path, &git_pack_path).unwrap();
+    let output = Command::new("git")
+        .arg("index-pack")
+        .arg(&git_pack_path)
+        .current_dir(&dir)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "git index-pack failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let index = fs::read(pack_path.with_extension("idx")).unwrap();
+    let git_index = fs::read(git_pack_path.with_extension("idx")).unwrap();
+    if let Some(offset) = index
+        .iter()
+        .zip(&git_index)
+        .position(|(byte, git_byte)| byte != git_byte)
+    {
+        panic!(
+            "the index differs from git's at byte {} (in the {})",
+            offset,
+            index_section(&git_index, offset)
+        );
+    }
+    assert_eq!(index.len(), git_index.len());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Names the part of an index file that `offset` is in, to make differences easier to track down.
+fn index_section(index: &[u8], offset: usize) -> &'static str {
+    let object_count =
+        u32::from_be_bytes([index[1028], index[1029], index[1030], index[1031]]) as usize;
+    let sections = [
+        (8, "header"),
+        (8 + 256 * 4, "fanout table"),
+        (8 + 256 * 4 + object_count * 20, "OIDs"),
+        (8 + 256 * 4 + object_count * 24, "CRC32s"),
+        (8 + 256 * 4 + object_count * 28, "4-byte offsets"),
+        (index.len() - 40, "8-byte offsets"),
+        (index.len() - 20, "packfile checksum"),
+    ];
+    sections
+        .iter()
+        .find(|(end, _)| offset < *end)
+        .map_or("index checksum", |(_, section)| section)
+}
+
+#[test]
+fn default_pack() {
+    // This is enough commits for a merge commit, which has a much larger packfile entry.
+    check_index_matches_git("default", &["--commit-count-cap", "20000"]);
+}
+
+#[test]
+fn delta_chains_and_trees() {
+    check_index_matches_git(
+        "delta_chains_and_trees",
+        &[
+            "--commit-count-cap",
+            "5000",
+            "--delta-chain-depth",
+            "3",
+            "--tree-count",
+            "4",
+        ],
+    );
+}
+
+#[test]
+fn uncompressed() {
+    // zlib streams of stored blocks, which git has to accept just like compressed ones
+    check_index_matches_git(
+        "uncompressed",
+        &["--commit-count-cap", "20000", "--compression", "none"],
+    );
+}
+
+#[test]
+fn mixed_compression() {
+    // Stored leaves next to a compressed merge commit
+    check_index_matches_git(
+        "mixed_compression",
+        &[
+            "--commit-count-cap",
+            "20000",
+            "--leaf-compression",
+            "none",
+            "--merge-compression",
+            "best",
+        ],
+    );
+}
+
+#[test]
+fn message_footer() {
+    // The deltas copy the footer from after the entropy of the previous commit in the chain.
+    let dir = test_dir("idx_matches_git-footer");
+    let footer_path = dir.join("footer");
+    fs::write(&footer_path, "Signed-off-by: Nobody <nobody@example.com>\n").unwrap();
+    check_index_matches_git(
+        "message_footer",
+        &[
+            "--commit-count-cap",
+            "5000",
+            "--delta-chain-depth",
+            "3",
+            "--message-footer",
+            footer_path.to_str().unwrap(),
+        ],
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn fanout_table() {
+    let dir = test_dir("idx_matches_git-fanout");
+    // With a prefix, the generated commits all land in one step of the table, and the few objects without
+    // it (the delta base commits and the merge commits) are spread over the rest.
+    let (success, _, stderr) = run(
+        &dir,
+        &[
+            "--objects-dir",
+            "objects",
+            "--commit-count-cap",
+            "2000",
+            "--prefix",
+            "8",
+            "--fanout-out",
+            "fanout.txt",
+        ],
+    );
+    assert!(success, "generating the pack failed: {}", stderr);
+    let pack_path = dir
+        .join("objects")
+        .join("pack")
+        .join("pack-every-shorthash.pack");
+    let pack = fs::read(&pack_path).unwrap();
+    let index = fs::read(pack_path.with_extension("idx")).unwrap();
+
+    let fanout = index[8..8 + 256 * 4]
+        .chunks(4)
+        .map(|count| u32::from_be_bytes(count.try_into().unwrap()))
+        .collect::<Vec<_>>();
+    assert!(
+        fanout.windows(2).all(|pair| pair[0] <= pair[1]),
+        "the fanout table decreases: {:?}",
+        fanout
+    );
+    let object_count = u32::from_be_bytes(pack[8..12].try_into().unwrap());
+    assert_eq!(fanout[255], object_count);
+    // Each entry counts the OIDs in the table after it whose first byte is at most its position.
+    let first_bytes = index[8 + 256 * 4..8 + 256 * 4 + object_count as usize * 20]
+        .chunks(20)
+        .map(|oid| oid[0])
+        .collect::<Vec<_>>();
+    for (position, &count) in fanout.iter().enumerate() {
+        assert_eq!(
+            count as usize,
+            first_bytes
+                .iter()
+                .filter(|&&first_byte| usize::from(first_byte) <= position)
+                .count(),
+            "wrong fanout entry for {:02x}",
+            position
+        );
+    }
+    assert!(fanout[0x7f] < fanout[0x8f] && fanout[0x8f] < fanout[255]);
+
+    // The table that the run dumps is the one in the index.
+    let dumped = fs::read_to_string(dir.join("fanout.txt")).unwrap();
+    assert!(dumped
+        .lines()
+        .map(|line| line.split_once(' ').unwrap().1.parse::<u32>().unwrap())
+        .eq(fanout.iter().copied()));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Reads the entries of an index file as (OID, offset, CRC32), resolving the offsets that are in the
+/// table of 8-byte offsets.
+fn index_entries(index_path: &Path) -> Vec<(Sha1Oid, u64, u32)> {
+    read_index_entries(index_path)
+        .unwrap()
+        .into_iter()
+        .map(|entry| (entry.oid, entry.offset.0, entry.crc32))
+        .collect()
+}
+
+/// Checks that the index of a packfile written with `big_offset_threshold` lists the objects at or past
+/// the threshold in the table of 8-byte offsets, and that its entries are the ones in git's index for the
+/// same packfile, which only has 4-byte offsets.
+fn check_big_offsets(pack_path: &Path, big_offset_threshold: u64) {
+    let index_path = pack_path.with_extension("idx");
+    let entries = index_entries(&index_path);
+    let big_offset_count = entries
+        .iter()
+        .filter(|(_, offset, _)| *offset >= big_offset_threshold)
+        .count();
+    assert!(big_offset_count > 0);
+    assert_eq!(
+        fs::metadata(&index_path).unwrap().len() as usize,
+        8 + 256 * 4 + entries.len() * 28 + big_offset_count * 8 + 40
+    );
+
+    if !git_is_installed("the comparison with git's index") {
+        return;
+    }
+    let git_pack_path = pack_path.with_file_name("git.pack");
+    fs::copy(pack_path, &git_pack_path).unwrap();
+    let output = Command::new("git")
+        .arg("index-pack")
+        .arg(&git_pack_path)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "git index-pack failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(entries == index_entries(&git_pack_path.with_extension("idx")));
+}
+
+#[test]
+fn smallest_big_offset_threshold() {
+    // Every object except the first one, at offset 12, gets an 8-byte offset.
+    let dir = test_dir("idx_matches_git-big_offsets");
+    let (success, _, stderr) = run(
+        &dir,
+        &[
+            "--objects-dir",
+            "objects",
+            "--commit-count-cap",
+            "5000",
+            "--big-offset-threshold",
+            "13",
+        ],
+    );
+    assert!(success, "generating the pack failed: {}", stderr);
+    let pack_path = dir
+        .join("objects")
+        .join("pack")
+        .join("pack-every-shorthash.pack");
+    check_big_offsets(&pack_path, 13);
+
+    // Git reads the objects at every offset back through the index.
+    if git_is_installed("git verify-pack") {
+        let output = Command::new("git")
+            .arg("verify-pack")
+            .arg(pack_path.with_extension("idx"))
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "git verify-pack failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn zero_big_offset_threshold() {
+    // Even the first object gets an 8-byte offset. `--big-offset-threshold` doesn't allow this, since git
+    // refuses to use such an index, but the table is still read back the same way.
+    let dir = test_dir("idx_matches_git-zero_big_offsets");
+    let pack_path = dir.join("pack.pack");
+    let base = GitObject::blob(b"base\n".to_vec());
+    let objects = (0..100).map(|i| {
+        let result = GitObject::blob(format!("base\n{}\n", i).into_bytes());
+        PackFileObject::Deltified {
+            base_oid: base.oid(),
+            base_size: ObjectSize(base.data.len()),
+            delta: vec![
+                PackFileDeltaInstruction::CopyFromBaseObject { offset: 0, size: 5 },
+                PackFileDeltaInstruction::AddNewData(result.data[5..].to_vec()),
+            ],
+            new_oid: result.oid(),
+            new_size: ObjectSize(result.data.len()),
+        }
+    });
+    stream_to_pack_file(
+        &pack_path,
+        iter::once(PackFileObject::Raw(base.clone())).chain(objects),
+        PackWriteOptions {
+            big_offset_threshold: Some(0),
+            ..PackWriteOptions::default()
+        },
+    )
+    .unwrap();
+    check_big_offsets(&pack_path, 0);
+
+    if git_is_installed("git verify-pack") {
+        let output = Command::new("git")
+            .arg("verify-pack")
+            .arg(pack_path.with_extension("idx"))
+            .output()
+            .unwrap();
+        // Git expects at most one 8-byte offset fewer than there are objects.
+        assert!(
+            String::from_utf8_lossy(&output.stderr).contains("wrong index v2 file size"),
+            "git verify-pack didn't reject the index: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
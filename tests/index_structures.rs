@@ -0,0 +1,158 @@
+//! Compares the two ways of collecting what the index file needs about each object: the `BTreeMap` from
+//! OID to offset and CRC32 that `PackEntryWriter` fills in as the pack is written, and a `Vec` of the
+//! same entries that's sorted once at the end. `write_index` goes over the entries in OID order four
+//! times (for the OIDs, the CRC32s, the 4-byte offsets and the 8-byte offsets), which the comment there
+//! suspects is slow for a B-tree because of cache thrashing.
+//!
+//! The benchmark is ignored by default, since timing is only meaningful in a release build. Run it with
+//! `cargo test --release --test index_structures -- --ignored --nocapture`. It uses `2**20` entries to
+//! finish in a few seconds; a full pack has a few hundred times as many, which only makes the B-tree's
+//! cache misses worse.
+
+use sha1::{Digest, Sha1};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    collections::BTreeMap,
+    hint::black_box,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+type Sha1Oid = [u8; 20];
+
+/// Wraps the system allocator to keep track of the most memory that was allocated at once.
+struct PeakAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK_ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for PeakAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+        PEAK_ALLOCATED.fetch_max(allocated, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: PeakAllocator = PeakAllocator;
+
+/// Returns `count` entries with random-looking OIDs, in the order that a pack would have them. The OIDs
+/// are hashes of the entry numbers, so every run uses the same entries.
+fn synthetic_entries(count: usize) -> Vec<(Sha1Oid, usize, u32)> {
+    (0..count)
+        .map(|i| {
+            let oid: Sha1Oid = Sha1::digest(&(i as u64).to_be_bytes()).into();
+            // Deltified commits take up about 20 bytes of the packfile each.
+            (
+                oid,
+                12 + i * 20,
+                u32::from_be_bytes([oid[4], oid[5], oid[6], oid[7]]),
+            )
+        })
+        .collect()
+}
+
+/// Goes over the entries in OID order four times, like `write_index`, and returns something that depends
+/// on every entry so the passes can't be skipped.
+fn four_passes<'a, I: Iterator<Item = (&'a Sha1Oid, usize, u32)>>(entries: impl Fn() -> I) -> u64 {
+    let oids = entries().fold(0u64, |sum, (oid, _, _)| sum.wrapping_add(oid[19] as u64));
+    let crc32s = entries().fold(0u64, |sum, (_, _, crc32)| sum.wrapping_add(crc32 as u64));
+    let small_offsets = entries().fold(0u64, |sum, (_, offset, _)| {
+        sum.wrapping_add((offset as u32) as u64)
+    });
+    let big_offsets = entries()
+        .filter(|(_, offset, _)| *offset >= 1 << 31)
+        .count() as u64;
+    oids ^ crc32s ^ small_offsets ^ big_offsets
+}
+
+fn with_b_tree(entries: &[(Sha1Oid, usize, u32)]) -> u64 {
+    let mut object_positions = BTreeMap::new();
+    for &(oid, offset, crc32) in entries {
+        object_positions.insert(oid, (offset, crc32));
+    }
+    four_passes(|| {
+        object_positions
+            .iter()
+            .map(|(oid, &(offset, crc32))| (oid, offset, crc32))
+    })
+}
+
+fn with_sorted_vec(entries: &[(Sha1Oid, usize, u32)]) -> u64 {
+    // The number of objects is known before a pack is written, so the `Vec` never has to grow.
+    let mut object_positions = Vec::with_capacity(entries.len());
+    for &entry in entries {
+        object_positions.push(entry);
+    }
+    object_positions.sort_unstable_by_key(|&(oid, _, _)| oid);
+    four_passes(|| {
+        object_positions
+            .iter()
+            .map(|(oid, offset, crc32)| (oid, *offset, *crc32))
+    })
+}
+
+/// Runs `build` and returns its result, how long it took, and the most memory it had allocated at once
+/// beyond what was already allocated.
+fn measure(build: impl FnOnce() -> u64) -> (u64, Duration, usize) {
+    let baseline = ALLOCATED.load(Ordering::Relaxed);
+    PEAK_ALLOCATED.store(baseline, Ordering::Relaxed);
+    let start = Instant::now();
+    let result = black_box(build());
+    let elapsed = start.elapsed();
+    (
+        result,
+        elapsed,
+        PEAK_ALLOCATED.load(Ordering::Relaxed) - baseline,
+    )
+}
+
+#[test]
+fn both_structures_iterate_in_oid_order() {
+    let entries = synthetic_entries(10_000);
+    assert_eq!(with_b_tree(&entries), with_sorted_vec(&entries));
+
+    let mut object_positions = entries.clone();
+    object_positions.sort_unstable_by_key(|&(oid, _, _)| oid);
+    let b_tree = entries
+        .iter()
+        .map(|&(oid, offset, crc32)| (oid, (offset, crc32)))
+        .collect::<BTreeMap<_, _>>();
+    assert!(object_positions
+        .iter()
+        .map(|&(oid, offset, crc32)| (oid, (offset, crc32)))
+        .eq(b_tree.into_iter()));
+}
+
+#[test]
+#[ignore]
+fn benchmark_index_structures() {
+    const ENTRY_COUNT: usize = 1 << 20;
+    let entries = synthetic_entries(ENTRY_COUNT);
+    let (b_tree_result, b_tree_time, b_tree_peak) = measure(|| with_b_tree(&entries));
+    let (vec_result, vec_time, vec_peak) = measure(|| with_sorted_vec(&entries));
+    assert_eq!(b_tree_result, vec_result);
+    println!(
+        "{} entries: B-tree {:?} and {} bytes per entry at peak, sorted Vec {:?} and {} bytes per \
+        entry at peak",
+        ENTRY_COUNT,
+        b_tree_time,
+        b_tree_peak / ENTRY_COUNT,
+        vec_time,
+        vec_peak / ENTRY_COUNT
+    );
+    assert!(
+        vec_time < b_tree_time,
+        "sorting a Vec was slower than filling a B-tree"
+    );
+    assert!(
+        vec_peak < b_tree_peak,
+        "sorting a Vec took more memory than filling a B-tree"
+    );
+}
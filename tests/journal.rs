@@ -0,0 +1,179 @@
+//! Checks that a `--journal` run that's killed partway through can be finished by running it again: the
+//! rerun has to truncate the packfile back to the last synced entry of the journal and carry on from the
+//! generator's state there, so the pack and index come out byte-for-byte the same as a run that was never
+//! interrupted. The killed run uses random entropy without a seed, so the rerun also has to pick up the
+//! seed from the journal.
+
+use std::{
+    fs,
+    fs::File,
+    path::Path,
+    process::Stdio,
+    thread,
+    time::{Duration, Instant},
+};
+
+#[allow(dead_code)]
+mod common;
+
+use common::{run, test_dir, tool};
+
+const PACK_NAME: &str = "pack-every-shorthash";
+
+// Large batches, so that syncing the packfile for every batch doesn't make the run crawl, and a delta
+// chain depth and tree count that give the journal's entries some chained commits to record.
+const OPTIONS: [&str; 10] = [
+    "--entropy-source",
+    "random",
+    "--batch-size",
+    "500",
+    "--commit-count-cap",
+    "20000",
+    "--delta-chain-depth",
+    "3",
+    "--tree-count",
+    "2",
+];
+
+#[test]
+fn killed_run_finishes_the_same_pack() {
+    let dir = test_dir("journal");
+    let pack_dir = dir.join("journaled").join("pack");
+    let journal_path = pack_dir.join(format!("{}.journal", PACK_NAME));
+    let journaled_args = [
+        &[
+            "--objects-dir",
+            "journaled",
+            "--journal",
+            "--journal-interval",
+            "0",
+        ][..],
+        &OPTIONS[..],
+    ]
+    .concat();
+
+    // Kill the run once the journal has had a few entries, since the first one is from before anything
+    // was generated. Without an entry, the journal is 85 bytes long.
+    let mut child = tool(&dir)
+        .args(&journaled_args)
+        .stdout(File::create(dir.join("killed.log")).unwrap())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let start = Instant::now();
+    let mut entries = Vec::new();
+    while entries.len() < 3 {
+        match fs::read(&journal_path) {
+            Ok(journal) if journal.len() > 85 && !entries.contains(&journal) => {
+                entries.push(journal)
+            }
+            _ => {}
+        }
+        assert!(
+            start.elapsed() < Duration::from_secs(60),
+            "the journal only got {} entries",
+            entries.len()
+        );
+        assert!(
+            child.try_wait().unwrap().is_none(),
+            "the run finished before it could be killed"
+        );
+        thread::sleep(Duration::from_millis(1));
+    }
+    child.kill().unwrap();
+    child.wait().unwrap();
+    let seed = fs::read_to_string(dir.join("killed.log"))
+        .unwrap()
+        .lines()
+        .find_map(|line| line.strip_prefix("Using random entropy with seed "))
+        .expect("the killed run didn't print its seed")
+        .to_string();
+    assert_eq!(
+        fs::metadata(pack_dir.join(format!("{}.idx", PACK_NAME)))
+            .unwrap()
+            .len(),
+        0,
+        "the index was written before the run was killed"
+    );
+
+    // A rerun with different options can't finish the pack, and leaves it alone.
+    let pack_length = fs::metadata(pack_dir.join(format!("{}.pack", PACK_NAME)))
+        .unwrap()
+        .len();
+    let (success, _, stderr) = run(
+        &dir,
+        &[&journaled_args[..], &["--delta-chain-depth", "2"]].concat(),
+    );
+    assert!(!success);
+    assert!(
+        stderr.contains("was written by a run with a different --delta-chain-depth"),
+        "unexpected error: {}",
+        stderr
+    );
+    assert_eq!(
+        fs::metadata(pack_dir.join(format!("{}.pack", PACK_NAME)))
+            .unwrap()
+            .len(),
+        pack_length
+    );
+
+    let (success, stdout, stderr) = run(&dir, &journaled_args);
+    assert!(success, "the resumed run failed: {}", stderr);
+    assert!(
+        stdout.contains(&format!("Using random entropy with seed {}", seed)),
+        "the resumed run didn't use the seed from the journal: {}",
+        stdout
+    );
+    assert!(
+        stdout.contains("from its journal, after its first"),
+        "the run started over: {}",
+        stdout
+    );
+    assert!(!journal_path.exists(), "the journal wasn't removed");
+
+    let (success, _, stderr) = run(
+        &dir,
+        &[
+            &["--objects-dir", "uninterrupted", "--seed", &seed][..],
+            &OPTIONS[..],
+        ]
+        .concat(),
+    );
+    assert!(success, "the uninterrupted run failed: {}", stderr);
+    for extension in ["pack", "idx"] {
+        let path = Path::new("pack").join(format!("{}.{}", PACK_NAME, extension));
+        assert!(
+            fs::read(dir.join("journaled").join(&path)).unwrap()
+                == fs::read(dir.join("uninterrupted").join(&path)).unwrap(),
+            "the resumed {} differs from the uninterrupted one",
+            path.display()
+        );
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn journal_conflicts() {
+    let dir = test_dir("journal-conflicts");
+    for (args, expected_error) in [
+        (
+            &["--journal", "--direct-io"][..],
+            "can't be used with --journal",
+        ),
+        (&["--journal", "--linear"], "can't be used with --journal"),
+        (
+            &["--journal-interval", "5m"],
+            "--journal-interval only applies to --journal",
+        ),
+    ] {
+        let (success, _, stderr) = run(&dir, &[&["--count-only"][..], args].concat());
+        assert!(!success, "{:?} succeeded", args);
+        assert!(
+            stderr.contains(expected_error),
+            "unexpected error for {:?}: {}",
+            args,
+            stderr
+        );
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,29 @@
+//! Checks that `shorthash_hex` formats the leading hex characters of an OID the same way as formatting
+//! the whole OID and keeping its start.
+
+#[allow(dead_code)]
+#[path = "../src/direct_io.rs"]
+mod direct_io;
+#[allow(dead_code)]
+#[path = "../src/git_utils.rs"]
+mod git_utils;
+
+use git_utils::{oid_to_hex, parse_hex_oid, shorthash_hex, EMPTY_TREE_OID};
+
+#[test]
+fn shorthash_hex_is_the_start_of_the_full_hex() {
+    for hex in [
+        "4b825dc642cb6eb9a060e54bf8d69288fbee4904",
+        "0000000000000000000000000000000000000000",
+        "ffffffffffffffffffffffffffffffffffffffff",
+        "0123456789abcdeffedcba987654321000f00f0f",
+    ] {
+        let oid = parse_hex_oid(hex);
+        assert_eq!(oid_to_hex(&oid), hex);
+        // No characters, an odd count that ends halfway through a byte, an even count, and all of them
+        for nibbles in [0, 7, 8, 40] {
+            assert_eq!(shorthash_hex(&oid, nibbles), hex[..nibbles], "{}", nibbles);
+        }
+    }
+    assert_eq!(shorthash_hex(&EMPTY_TREE_OID, 7), "4b825dc");
+}
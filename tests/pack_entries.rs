@@ -0,0 +1,269 @@
+//! Checks the entries that the pack writer writes for individual objects, and the objects that
+//! `write_to_sink` hands to a sink. Git is also asked to read the packfiles whose headers are checked,
+//! unless it isn't installed.
+
+use std::{fs, io, iter, path::Path, process::Command, sync::mpsc, thread};
+
+#[allow(dead_code)]
+mod common;
+#[allow(dead_code)]
+#[path = "../src/direct_io.rs"]
+mod direct_io;
+#[allow(dead_code)]
+#[path = "../src/git_utils.rs"]
+mod git_utils;
+#[allow(dead_code)]
+#[path = "../src/pack_reader.rs"]
+mod pack_reader;
+
+use common::test_dir;
+use git_utils::{
+    create_output_file, resume_pack_and_index, stream_to_pack_file, write_to_sink, CancelToken,
+    GitObject, GitObjectType, InMemorySink, ObjectSize, PackFileDeltaInstruction, PackFileObject,
+    PackObjectMetadata, PackOffset, PackPosition, PackWriteOptions, Sha1Oid,
+};
+use pack_reader::{read_index_entries, verify_idx_trailer, verify_pack_trailer, PackReader};
+
+/// Returns a blob, and a delta against it that appends a line.
+fn blob_and_delta() -> (GitObject, PackFileObject) {
+    let base = GitObject::blob(b"base\n".to_vec());
+    let result = GitObject::blob(b"base\nmore\n".to_vec());
+    let delta = PackFileObject::Deltified {
+        base_oid: base.oid(),
+        base_size: ObjectSize(base.data.len()),
+        delta: vec![
+            PackFileDeltaInstruction::CopyFromBaseObject { offset: 0, size: 5 },
+            PackFileDeltaInstruction::AddNewData(b"more\n".to_vec()),
+        ],
+        new_oid: result.oid(),
+        new_size: ObjectSize(result.data.len()),
+    };
+    (base, delta)
+}
+
+#[test]
+fn offset_delta_base_has_to_be_earlier() {
+    let dir = test_dir("pack_entries-delta_base");
+    let (base, delta) = blob_and_delta();
+    stream_to_pack_file(
+        &dir.join("earlier.pack"),
+        vec![PackFileObject::Raw(base.clone()), delta.clone()],
+        PackWriteOptions::default(),
+    )
+    .unwrap();
+
+    // Every object that `stream_to_pack_file` writes is before the next one, so a base at or after the
+    // delta can only come from resuming a packfile whose objects are listed at the wrong offsets. The
+    // resumed packfile ends at offset 12, where the delta goes.
+    for base_offset in [12, 100] {
+        let pack_path = dir.join(format!("base-at-{}.pack", base_offset));
+        let err = resume_pack_and_index(
+            create_output_file(&pack_path).unwrap(),
+            create_output_file(&pack_path.with_extension("idx")).unwrap(),
+            PackPosition {
+                length: PackOffset(12),
+                object_count: 1,
+            },
+            vec![PackObjectMetadata {
+                oid: base.oid(),
+                offset: PackOffset(base_offset),
+                crc32: 0,
+            }],
+            iter::once(delta.clone()),
+            PackWriteOptions::default(),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{}", err);
+        assert!(
+            err.to_string().contains(&format!(
+                "is at offset {}, which isn't before the delta's offset 12",
+                base_offset
+            )),
+            "unexpected error: {}",
+            err
+        );
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn reported_entry_lengths() {
+    let dir = test_dir("pack_entries-lengths");
+    let pack_path = dir.join("pack.pack");
+    let (base, delta) = blob_and_delta();
+    let mut written = Vec::new();
+    let mut record_written = |_: &Sha1Oid, offset: PackOffset, entry_length| {
+        written.push((offset.0, entry_length as u64))
+    };
+    stream_to_pack_file(
+        &pack_path,
+        vec![PackFileObject::Raw(base), delta],
+        PackWriteOptions {
+            on_object_written: Some(&mut record_written),
+            ..PackWriteOptions::default()
+        },
+    )
+    .unwrap();
+    // The entries fill the packfile between its 12-byte header and its 20-byte checksum.
+    let pack_length = fs::metadata(&pack_path).unwrap().len();
+    assert_eq!(written.len(), 2);
+    assert_eq!(written[0].0, 12);
+    assert_eq!(written[0].0 + written[0].1, written[1].0);
+    assert_eq!(written[1].0 + written[1].1, pack_length - 20);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn object_headers() {
+    let dir = test_dir("pack_entries-headers");
+    // The first byte has the type and the low 4 bits of the size, and always continues into the 7-bit
+    // groups of the rest of the size, even when they're all 0. Sizes that need another group of 7 bits
+    // take another byte.
+    let blob_type = 0x80 | 3 << 4;
+    for (size, expected) in [
+        (15, &[blob_type | 0xf, 0x00][..]),
+        (16, &[blob_type, 0x01]),
+        (2047, &[blob_type | 0xf, 0x7f]),
+        (2048, &[blob_type, 0x80, 0x01]),
+        (262143, &[blob_type | 0xf, 0xff, 0x7f]),
+        (262144, &[blob_type, 0x80, 0x80, 0x01]),
+    ] {
+        let pack_path = dir.join(format!("{}.pack", size));
+        stream_to_pack_file(
+            &pack_path,
+            iter::once(PackFileObject::Raw(GitObject::blob(vec![b'x'; size]))),
+            PackWriteOptions::default(),
+        )
+        .unwrap();
+        // The only entry starts right after the 12-byte header of the packfile.
+        let pack = fs::read(&pack_path).unwrap();
+        assert_eq!(&pack[12..12 + expected.len()], expected, "size {}", size);
+        check_git_accepts(&pack_path);
+    }
+
+    for (object_type, type_id) in [
+        (GitObjectType::Commit, 1),
+        (GitObjectType::Tree, 2),
+        (GitObjectType::Blob, 3),
+        (GitObjectType::Tag, 4),
+    ] {
+        let pack_path = dir.join(format!("{}.pack", object_type.type_name()));
+        stream_to_pack_file(
+            &pack_path,
+            iter::once(PackFileObject::Raw(GitObject {
+                data: vec![0; 5],
+                object_type,
+            })),
+            PackWriteOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read(&pack_path).unwrap()[12..14],
+            [0x85 | type_id << 4, 0x00]
+        );
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn cancelled_write() {
+    let dir = test_dir("pack_entries-cancelled");
+    let pack_path = dir.join("pack.pack");
+    let blobs = (0..100)
+        .map(|i| GitObject::blob(format!("blob {}\n", i).into_bytes()))
+        .collect::<Vec<_>>();
+    let cancel_token = CancelToken::default();
+    // Another thread cancels the write once it hears that the tenth object was written, and the writer
+    // waits to hear back, so that the write stops at the same object every time.
+    let (written_sender, written_receiver) = mpsc::channel();
+    let (cancelled_sender, cancelled_receiver) = mpsc::channel();
+    let pack = thread::scope(|scope| {
+        let cancel_token = &cancel_token;
+        scope.spawn(move || {
+            written_receiver.recv().unwrap();
+            cancel_token.cancel();
+            cancelled_sender.send(()).unwrap();
+        });
+        let mut written_count = 0;
+        let mut record_written = |_: &Sha1Oid, _: PackOffset, _: usize| {
+            written_count += 1;
+            if written_count == 10 {
+                written_sender.send(()).unwrap();
+                cancelled_receiver.recv().unwrap();
+            }
+        };
+        stream_to_pack_file(
+            &pack_path,
+            blobs.iter().cloned().map(PackFileObject::Raw),
+            PackWriteOptions {
+                cancel_token: Some(cancel_token),
+                on_object_written: Some(&mut record_written),
+                ..PackWriteOptions::default()
+            },
+        )
+        .unwrap()
+    });
+    assert!(pack.was_cancelled());
+
+    // The partial pack is finished like any other, with the objects written before the cancellation.
+    assert!(verify_pack_trailer(&mut fs::File::open(&pack_path).unwrap()).unwrap());
+    assert!(
+        verify_idx_trailer(&mut fs::File::open(pack_path.with_extension("idx")).unwrap()).unwrap()
+    );
+    let reader = PackReader::open(&pack_path).unwrap();
+    assert_eq!(reader.object_count(), 10);
+    let read_oids = reader.map(|object| object.unwrap().oid).collect::<Vec<_>>();
+    assert!(read_oids == blobs[..10].iter().map(GitObject::oid).collect::<Vec<_>>());
+    let mut indexed_oids = read_index_entries(&pack_path.with_extension("idx"))
+        .unwrap()
+        .iter()
+        .map(|entry| entry.oid)
+        .collect::<Vec<_>>();
+    indexed_oids.sort();
+    let mut written_oids = read_oids;
+    written_oids.sort();
+    assert!(indexed_oids == written_oids);
+    check_git_accepts(&pack_path);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn in_memory_sink() {
+    let (base, delta) = blob_and_delta();
+    let other = GitObject::blob(b"other\n".to_vec());
+    let objects = write_to_sink(
+        InMemorySink::default(),
+        vec![
+            PackFileObject::Raw(base.clone()),
+            delta.clone(),
+            PackFileObject::Raw(other.clone()),
+        ],
+    )
+    .unwrap();
+    assert!(
+        objects.iter().map(PackFileObject::oid).collect::<Vec<_>>()
+            == [base.oid(), delta.oid(), other.oid()]
+    );
+    // Deltas are kept as they were written, rather than resolved.
+    assert!(matches!(objects[1], PackFileObject::Deltified { .. }));
+}
+
+/// Checks that `git index-pack` reads the packfile at `pack_path`, unless git isn't installed.
+fn check_git_accepts(pack_path: &Path) {
+    let output = match Command::new("git")
+        .arg("index-pack")
+        .arg("--strict")
+        .arg("-o")
+        .arg(pack_path.with_extension("git.idx"))
+        .arg(pack_path)
+        .output()
+    {
+        Ok(output) => output,
+        Err(_) => return,
+    };
+    assert!(
+        output.status.success(),
+        "git index-pack failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
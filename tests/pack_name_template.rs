@@ -0,0 +1,110 @@
+//! Checks `--pack-name-template`: the tokens known up front name the pack as it's written, and a template
+//! with `{count}` or `{checksum}` has the finished pack renamed, with its index next to it. Templates that
+//! can't name both files are rejected before anything is generated.
+
+use std::{fs, path::Path};
+
+#[allow(dead_code)]
+mod common;
+
+use common::test_dir;
+
+/// Runs the tool with `args` and an object directory in `dir`, and returns whether it succeeded along
+/// with its stderr.
+fn run(dir: &Path, args: &[&str]) -> (bool, String) {
+    let (success, _, stderr) = common::run(
+        dir,
+        &[
+            &["--objects-dir", "objects", "--max-attempts", "2000"][..],
+            &["--write-checkpoint", "checkpoint"],
+            args,
+        ]
+        .concat(),
+    );
+    (success, stderr)
+}
+
+/// Returns the names of the files in the pack directory, sorted.
+fn pack_files(dir: &Path) -> Vec<String> {
+    let mut names = fs::read_dir(dir.join("objects").join("pack"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+#[test]
+fn tokens_are_substituted() {
+    let dir = test_dir("pack_name_template");
+
+    let args = [
+        "--prefix",
+        "a",
+        "--pack-name-template",
+        "pack-{bits}-{fanout}",
+    ];
+    let (success, stderr) = run(&dir, &args);
+    assert!(success, "the run failed: {}", stderr);
+    assert_eq!(
+        pack_files(&dir),
+        ["pack-32-a0-af.idx", "pack-32-a0-af.pack"]
+    );
+    // The same name again is refused, and `--append` picks another one.
+    let (success, stderr) = run(&dir, &args);
+    assert!(!success);
+    assert!(
+        stderr.contains("pack-32-a0-af.pack already exists"),
+        "unexpected error: {}",
+        stderr
+    );
+    let (success, stderr) = run(&dir, &[&args[..], &["--append"]].concat());
+    assert!(success, "the run failed: {}", stderr);
+    assert_eq!(
+        pack_files(&dir),
+        [
+            "pack-32-a0-af-2.idx",
+            "pack-32-a0-af-2.pack",
+            "pack-32-a0-af.idx",
+            "pack-32-a0-af.pack"
+        ]
+    );
+    fs::remove_dir_all(dir.join("objects")).unwrap();
+
+    let (success, stderr) = run(&dir, &["--pack-name-template", "pack-{count}-{checksum}"]);
+    assert!(success, "the run failed: {}", stderr);
+    let names = pack_files(&dir);
+    assert_eq!(names.len(), 2, "{:?}", names);
+    let pack = fs::read(dir.join("objects").join("pack").join(&names[1])).unwrap();
+    let checksum: String = pack[pack.len() - 20..]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    let object_count = u32::from_be_bytes([pack[8], pack[9], pack[10], pack[11]]);
+    let name = format!("pack-{}-{}", object_count, checksum);
+    assert_eq!(names, [format!("{}.idx", name), format!("{}.pack", name)]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn invalid_templates_are_rejected() {
+    let dir = test_dir("pack_name_template-invalid");
+    for (template, message) in [
+        ("pack-{bits}.pack", "without an extension"),
+        ("pack-{bits}.idx", "without an extension"),
+        ("../pack-{bits}", "a file name in the pack directory"),
+        ("pack-{seed}", "unknown token {seed}"),
+        ("pack-{bits", "a { isn't closed"),
+    ] {
+        let (success, stderr) = run(&dir, &["--pack-name-template", template]);
+        assert!(!success, "{} was accepted", template);
+        assert!(
+            stderr.contains(message),
+            "unexpected error for {}: {}",
+            template,
+            stderr
+        );
+    }
+    assert!(!dir.join("objects").exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,112 @@
+//! Checks that `--replay` reproduces the pack of the run that `--record-accepted` recorded, byte for
+//! byte, and that it refuses to replay a recording with options that change the generated commits.
+
+use std::{fs, path::Path};
+
+#[allow(dead_code)]
+mod common;
+
+use common::{run, test_dir};
+
+#[test]
+fn replay_reproduces_the_recorded_pack() {
+    let dir = test_dir("replay");
+    // A run that stops early, with random entropy and delta chains, so that the replay has to skip to
+    // the right entropy value and delta base for each attempt, and stop where the recorded run stopped.
+    let options = [
+        "--entropy-source",
+        "random",
+        "--seed",
+        "7",
+        "--tree-count",
+        "3",
+        "--delta-chain-depth",
+        "4",
+        "--write-checkpoint",
+        "checkpoint",
+    ];
+    let (success, _, stderr) = run(
+        &dir,
+        &[
+            &["--objects-dir", "recorded", "--max-attempts", "30000"],
+            &options[..],
+            &["--record-accepted", "accepted"],
+        ]
+        .concat(),
+    );
+    assert!(success, "the recorded run failed: {}", stderr);
+    let (success, _, stderr) = run(
+        &dir,
+        &[
+            &["--objects-dir", "replayed"],
+            &options[..],
+            &["--replay", "accepted"],
+        ]
+        .concat(),
+    );
+    assert!(success, "the replay failed: {}", stderr);
+    for extension in ["pack", "idx"] {
+        let path = Path::new("pack").join(format!("pack-every-shorthash.{}", extension));
+        assert!(
+            fs::read(dir.join("recorded").join(&path)).unwrap()
+                == fs::read(dir.join("replayed").join(&path)).unwrap(),
+            "the replayed {} differs from the recorded one",
+            extension
+        );
+    }
+
+    let (success, _, stderr) = run(
+        &dir,
+        &[
+            "--objects-dir",
+            "mismatched",
+            "--entropy-source",
+            "random",
+            "--seed",
+            "7",
+            "--replay",
+            "accepted",
+        ],
+    );
+    assert!(!success);
+    assert!(
+        stderr.contains("was recorded by a run with different delta base commits"),
+        "unexpected error: {}",
+        stderr
+    );
+
+    // These options change which attempts are accepted or what they hash, without changing the delta
+    // base commits.
+    for (changed_option, difference) in [
+        (
+            &["--entropy-prefix-len", "5"][..],
+            "a different --entropy-prefix-len",
+        ),
+        (
+            &["--oid-prefix-range", "00-7f"],
+            "a different --oid-prefix-range",
+        ),
+        (
+            &["--avoid-prefix", "abc"],
+            "different --avoid-prefix prefixes",
+        ),
+    ] {
+        let (success, _, stderr) = run(
+            &dir,
+            &[
+                &["--objects-dir", "mismatched"],
+                &options[..],
+                changed_option,
+                &["--replay", "accepted"],
+            ]
+            .concat(),
+        );
+        assert!(!success, "replayed with {:?}", changed_option);
+        assert!(
+            stderr.contains(&format!("was recorded by a run with {}", difference)),
+            "unexpected error: {}",
+            stderr
+        );
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
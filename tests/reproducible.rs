@@ -0,0 +1,46 @@
+//! Checks that the output is reproducible: two runs with the same options write byte-for-byte the same
+//! pack and index. The timestamps are fixed and the default entropy is a counter, so nothing should
+//! differ between runs, including when the attempts are hashed on several threads.
+
+use std::{fs, path::Path};
+
+#[allow(dead_code)]
+mod common;
+
+use common::{run, test_dir};
+
+/// Generates the same capped pack twice with `args`, and checks that both runs wrote the same files.
+fn check_reproducible(name: &str, args: &[&str]) {
+    let dir = test_dir(&format!("reproducible-{}", name));
+    for objects_dir in ["first", "second"] {
+        let (success, _, stderr) = run(
+            &dir,
+            &[
+                &["--objects-dir", objects_dir, "--commit-count-cap", "20000"][..],
+                args,
+            ]
+            .concat(),
+        );
+        assert!(success, "the {} run failed: {}", objects_dir, stderr);
+    }
+    for extension in ["pack", "idx"] {
+        let path = Path::new("pack").join(format!("pack-every-shorthash.{}", extension));
+        assert!(
+            fs::read(dir.join("first").join(&path)).unwrap()
+                == fs::read(dir.join("second").join(&path)).unwrap(),
+            "the two runs wrote different {} files",
+            extension
+        );
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn default_options() {
+    check_reproducible("default_options", &[]);
+}
+
+#[test]
+fn threads() {
+    check_reproducible("threads", &["--threads", "2"]);
+}
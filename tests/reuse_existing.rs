@@ -0,0 +1,89 @@
+//! Checks `--reuse-existing` against a repository that already has the snapshot from `--tree-from-dir`
+//! in a pack: the trees and blobs of the snapshot should be left out of the generated pack, and git
+//! should still find every object that the history refers to. A second run with `--append` should
+//! also reuse the empty tree from the first run's pack.
+//!
+//! The test is skipped if `git` isn't installed.
+
+use std::{fs, path::Path};
+
+#[allow(dead_code)]
+mod common;
+
+use common::{git, git_is_installed, test_dir, tool};
+
+/// Runs the tool in `repo` and returns the values it printed for the given line prefixes.
+fn generate(repo: &Path, extra_args: &[&str], prefixes: &[&str]) -> Vec<String> {
+    let output = tool(repo)
+        .args(["--tree-from-dir", "snapshot", "--reuse-existing"])
+        .args(["--max-attempts", "256"])
+        .args(["--write-checkpoint", ".git/every-shorthash.checkpoint"])
+        .args(extra_args)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "generating the pack failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    prefixes
+        .iter()
+        .map(|prefix| {
+            stdout
+                .lines()
+                .find_map(|line| line.strip_prefix(prefix))
+                .unwrap_or_else(|| panic!("the run didn't print {:?}", prefix))
+                .to_string()
+        })
+        .collect()
+}
+
+#[test]
+fn snapshot_in_existing_pack() {
+    if !git_is_installed("snapshot_in_existing_pack") {
+        return;
+    }
+    let repo = test_dir("reuse_existing");
+    fs::create_dir_all(repo.join("snapshot").join("dir")).unwrap();
+    fs::write(repo.join("snapshot").join("file"), "file\n").unwrap();
+    fs::write(repo.join("snapshot").join("dir").join("nested"), "nested\n").unwrap();
+    git(&repo, None, &["init", "--quiet"]);
+    git(&repo, None, &["add", "snapshot"]);
+    git(
+        &repo,
+        None,
+        &[
+            "-c",
+            "user.name=Test",
+            "-c",
+            "user.email=test@example.com",
+            "commit",
+            "--quiet",
+            "-m",
+            "Add the snapshot",
+        ],
+    );
+    git(&repo, None, &["gc", "--quiet"]);
+
+    // The snapshot's root tree is the `snapshot` subdirectory of the commit, which git has packed along
+    // with everything under it. Only the empty tree is new.
+    let values = generate(
+        &repo,
+        &[],
+        &["Trees: ", "Blobs: ", "Top-level merge commit: "],
+    );
+    assert_eq!(values[0], "1");
+    assert_eq!(values[1], "0");
+    git(
+        &repo,
+        None,
+        &["update-ref", "refs/heads/generated", &values[2]],
+    );
+
+    let values = generate(&repo, &["--append"], &["Trees: ", "Blobs: "]);
+    assert_eq!(values, ["0", "0"]);
+
+    git(&repo, None, &["fsck", "--strict", "--no-dangling"]);
+    fs::remove_dir_all(&repo).unwrap();
+}
@@ -0,0 +1,117 @@
+//! Checks the optimization that the whole search depends on: every attempted commit is the delta base
+//! commit followed by a few characters of entropy, so the SHA1 state after absorbing the delta base
+//! commit is computed once, and each attempt clones it and only hashes the entropy. If cloning a `Sha1`
+//! ever gave a different hash than hashing everything from scratch, every OID would be wrong, and if it
+//! ever got as expensive as hashing the whole commit, the search would be several times slower.
+//!
+//! The benchmark is ignored by default, since timing is only meaningful in a release build. Run it with
+//! `cargo test --release --test sha1_midstate -- --ignored --nocapture`.
+
+use sha1::{Digest, Sha1};
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+/// The default delta base commit, as built in `main`. With its header and 8 characters of entropy, it
+/// takes up four SHA1 blocks, only the last of which is hashed for each attempt.
+fn delta_base_commit() -> Vec<u8> {
+    b"\
+        tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+        author Teddy Katz <teddy.katz@gmail.com> 1616279625 -0400\n\
+        committer Teddy Katz <teddy.katz@gmail.com> 1616279625 -0400\n\
+        \n\
+        Entropy value for this commit: "
+        .to_vec()
+}
+
+fn intermediate_sha1_state(commit: &[u8], entropy_length: usize) -> Sha1 {
+    Sha1::new()
+        .chain(format!("commit {}\0", commit.len() + entropy_length).as_bytes())
+        .chain(commit)
+}
+
+fn hash_from_scratch(commit: &[u8], entropy: &[u8]) -> [u8; 20] {
+    Sha1::new()
+        .chain(format!("commit {}\0", commit.len() + entropy.len()).as_bytes())
+        .chain(commit)
+        .chain(entropy)
+        .finalize()
+        .into()
+}
+
+#[test]
+fn cloned_state_matches_hashing_from_scratch() {
+    let commit = delta_base_commit();
+    for entropy_length in [1, 8, 16] {
+        let state = intermediate_sha1_state(&commit, entropy_length);
+        for entropy in [0u64, 1, 0xdead_beef, u64::MAX] {
+            let entropy = format!("{:016x}", entropy)[16 - entropy_length..].to_string();
+            let cloned: [u8; 20] = state.clone().chain(entropy.as_bytes()).finalize().into();
+            assert_eq!(
+                cloned,
+                hash_from_scratch(&commit, entropy.as_bytes()),
+                "entropy {}",
+                entropy
+            );
+        }
+        // Finishing a clone must leave the original untouched for the next attempt.
+        let first: [u8; 20] = state.clone().chain(b"0").finalize().into();
+        let second: [u8; 20] = state.clone().chain(b"0").finalize().into();
+        assert_eq!(first, second);
+    }
+}
+
+#[test]
+fn cloned_state_matches_git() {
+    // The OID of the default delta base commit with the entropy `00000000`, as computed by
+    // `git hash-object -t commit`.
+    let commit = delta_base_commit();
+    let oid: [u8; 20] = intermediate_sha1_state(&commit, 8)
+        .clone()
+        .chain(b"00000000")
+        .finalize()
+        .into();
+    assert_eq!(
+        oid.iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>(),
+        "87fb1d25e91638c44f161b39bac2f424b803514c"
+    );
+}
+
+/// Returns the average time that `attempt` takes, over a few million attempts.
+fn time_per_attempt(mut attempt: impl FnMut(u64) -> [u8; 20]) -> Duration {
+    const ATTEMPTS: u32 = 2_000_000;
+    let start = Instant::now();
+    for entropy in 0..ATTEMPTS {
+        black_box(attempt(entropy as u64));
+    }
+    start.elapsed() / ATTEMPTS
+}
+
+#[test]
+#[ignore]
+fn benchmark_cloned_state() {
+    let commit = delta_base_commit();
+    let state = intermediate_sha1_state(&commit, 8);
+    let from_scratch =
+        time_per_attempt(|entropy| hash_from_scratch(&commit, &entropy.to_be_bytes()));
+    let cloned = time_per_attempt(|entropy| {
+        black_box(&state)
+            .clone()
+            .chain(entropy.to_be_bytes())
+            .finalize()
+            .into()
+    });
+    println!(
+        "hashing from scratch: {:?} per attempt, cloning the intermediate state: {:?} per attempt",
+        from_scratch, cloned
+    );
+    // Hashing from scratch absorbs four blocks where the clone only absorbs one, so the clone should be
+    // close to four times faster. Anything less than twice as fast means the clone got expensive.
+    assert!(
+        cloned * 2 < from_scratch,
+        "cloning the intermediate SHA1 state is too slow"
+    );
+}
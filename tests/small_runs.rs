@@ -0,0 +1,92 @@
+//! Checks the shape of the history for runs that are stopped after only a few attempts, like the
+//! shorthash spaces of 4 and 8 bits that the default 28 bits scale down to. Every commit that covers a
+//! shorthash, including the delta base commit, should be a parent of a single top-level merge commit,
+//! without a level of merge commits in between, and git should accept the result.
+//!
+//! The tests are skipped if `git` isn't installed.
+
+use std::{collections::HashSet, fs};
+
+#[allow(dead_code)]
+mod common;
+
+use common::{git, git_is_installed, test_dir, tool};
+
+fn check_small_run(name: &str, attempts: u64) {
+    if !git_is_installed(name) {
+        return;
+    }
+    let repo = test_dir(&format!("small_runs-{}", name));
+    git(&repo, None, &["init", "--quiet"]);
+    let output = tool(&repo)
+        .args(["--max-attempts", &attempts.to_string()])
+        .args(["--write-checkpoint", ".git/every-shorthash.checkpoint"])
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "generating the pack failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let printed_value = |prefix: &str| {
+        stdout
+            .lines()
+            .find_map(|line| line.strip_prefix(prefix))
+            .unwrap_or_else(|| panic!("the run didn't print {:?}", prefix))
+    };
+    assert_eq!(printed_value("First-level merge commits: "), "0");
+    let root_commit_count = printed_value("Generated commits: ")
+        .parse::<usize>()
+        .unwrap()
+        + printed_value("Delta base commits: ")
+            .parse::<usize>()
+            .unwrap();
+    let top_level_merge_commit = printed_value("Top-level merge commit: ");
+
+    // The first line is the top-level merge commit itself, followed by its parents.
+    let parents = git(
+        &repo,
+        None,
+        &["rev-list", "--parents", "-n", "1", top_level_merge_commit],
+    );
+    let parents = parents.split_whitespace().skip(1).collect::<Vec<_>>();
+    assert_eq!(parents.len(), root_commit_count);
+    let shorthashes = parents
+        .iter()
+        .map(|parent| &parent[..7])
+        .collect::<HashSet<_>>();
+    assert_eq!(shorthashes.len(), root_commit_count);
+    for parent in &parents {
+        assert_eq!(
+            git(&repo, None, &["rev-list", "--parents", "-n", "1", parent]).trim(),
+            *parent,
+            "{} isn't a root commit",
+            parent
+        );
+    }
+    let history = git(
+        &repo,
+        None,
+        &["rev-list", "--count", top_level_merge_commit],
+    );
+    assert_eq!(history.trim(), (root_commit_count + 1).to_string());
+
+    git(
+        &repo,
+        None,
+        &["update-ref", "refs/heads/master", top_level_merge_commit],
+    );
+    git(&repo, None, &["fsck", "--strict", "--no-dangling"]);
+    fs::remove_dir_all(&repo).unwrap();
+}
+
+#[test]
+fn sixteen_attempts() {
+    check_small_run("sixteen_attempts", 1 << 4);
+}
+
+#[test]
+fn two_hundred_fifty_six_attempts() {
+    check_small_run("two_hundred_fifty_six_attempts", 1 << 8);
+}
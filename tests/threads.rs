@@ -0,0 +1,119 @@
+//! Checks that hashing on several threads with `--threads` doesn't change the pack: the attempts are
+//! hashed out of order, but the generator still has to accept them in order, so the pack, index and
+//! checkpoint should be byte-for-byte the same as with a single thread. `--channel-depth` only changes
+//! how far the threads can get ahead of the writer, which the run reports as it goes.
+
+use std::{fs, path::Path};
+
+#[allow(dead_code)]
+mod common;
+
+use common::{run, test_dir};
+
+#[test]
+fn threads_generate_the_same_pack() {
+    let dir = test_dir("threads");
+    // A prefix, so that most attempts don't match, and 3 characters of entropy, so that the hashing
+    // threads have to start over with 16 characters after 4096 attempts. The delta chains and trees
+    // depend on the order that the commits are found in.
+    let options = [
+        "--prefix",
+        "a",
+        "--entropy-prefix-len",
+        "3",
+        "--entropy-source",
+        "random",
+        "--seed",
+        "11",
+        "--tree-count",
+        "3",
+        "--delta-chain-depth",
+        "2",
+        "--max-attempts",
+        "20000",
+    ];
+    for threads in ["0", "3"] {
+        let (success, _, stderr) = run(
+            &dir,
+            &[
+                &["--threads", threads, "--objects-dir", threads][..],
+                &["--write-checkpoint", &format!("checkpoint-{}", threads)],
+                &options[..],
+            ]
+            .concat(),
+        );
+        assert!(
+            success,
+            "the run with {} threads failed: {}",
+            threads, stderr
+        );
+    }
+    for path in [
+        Path::new("pack").join("pack-every-shorthash.pack"),
+        Path::new("pack").join("pack-every-shorthash.idx"),
+    ] {
+        assert!(
+            fs::read(dir.join("0").join(&path)).unwrap()
+                == fs::read(dir.join("3").join(&path)).unwrap(),
+            "{} differs with 3 threads",
+            path.display()
+        );
+    }
+    assert!(
+        fs::read(dir.join("checkpoint-0")).unwrap() == fs::read(dir.join("checkpoint-3")).unwrap(),
+        "the checkpoint differs with 3 threads"
+    );
+
+    let (success, _, stderr) = run(&dir, &["--threads", "2", "--linear", "--count-only"]);
+    assert!(!success);
+    assert!(
+        stderr.contains("--threads can't be used with --linear"),
+        "unexpected error: {}",
+        stderr
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn channel_depth_bounds_the_waiting_chunks() {
+    let dir = test_dir("threads-channel-depth");
+    // Enough attempts for a progress report, which comes every 2^20 attempts. 2 threads with a depth of
+    // 3 can have 2 * (3 + 1) chunks waiting, counting the one that each thread is waiting to send.
+    let (success, stdout, stderr) = run(
+        &dir,
+        &[
+            "--objects-dir",
+            "objects",
+            "--threads",
+            "2",
+            "--channel-depth",
+            "3",
+            "--prefix",
+            "ab",
+            "--max-attempts",
+            "1100000",
+        ],
+    );
+    assert!(success, "the run failed: {}", stderr);
+    assert!(
+        stdout.contains("(with at most 4.0 MiB of hashed attempts waiting for it)"),
+        "unexpected output: {}",
+        stdout
+    );
+    let report = stdout
+        .lines()
+        .find_map(|line| line.strip_prefix("hashed chunks waiting for the writer: "))
+        .unwrap_or_else(|| panic!("no buffer report in the output: {}", stdout));
+    let (chunks, rest) = report.split_once(" of at most ").unwrap();
+    assert!(chunks.parse::<usize>().unwrap() <= 8, "{}", report);
+    assert!(rest.starts_with("8 ("), "{}", report);
+
+    let (success, _, stderr) = run(&dir, &["--channel-depth", "0", "--count-only"]);
+    assert!(!success);
+    assert!(
+        stderr.contains("--channel-depth must be at least 1"),
+        "unexpected error: {}",
+        stderr
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
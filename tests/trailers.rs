@@ -0,0 +1,64 @@
+//! Checks `verify_pack_trailer` and `verify_idx_trailer`, which compare the SHA1 checksum at the end of a
+//! packfile or an index file with the rest of it.
+
+use std::{fs, io::Cursor};
+
+#[allow(dead_code)]
+mod common;
+#[allow(dead_code)]
+#[path = "../src/direct_io.rs"]
+mod direct_io;
+#[allow(dead_code)]
+#[path = "../src/git_utils.rs"]
+mod git_utils;
+#[allow(dead_code)]
+#[path = "../src/pack_reader.rs"]
+mod pack_reader;
+
+use common::test_dir;
+use git_utils::{parse_hex_oid, stream_to_pack_file, GitObject, PackFileObject, PackWriteOptions};
+use pack_reader::{verify_idx_trailer, verify_pack_trailer};
+
+#[test]
+fn written_files_verify_until_changed() {
+    let dir = test_dir("trailers");
+    let pack_path = dir.join("pack.pack");
+    stream_to_pack_file(
+        &pack_path,
+        vec![
+            PackFileObject::Raw(GitObject::blob(b"first\n".to_vec())),
+            PackFileObject::Raw(GitObject::blob(b"second\n".to_vec())),
+        ],
+        PackWriteOptions::default(),
+    )
+    .unwrap();
+    let mut pack = fs::read(&pack_path).unwrap();
+    let mut index = fs::read(pack_path.with_extension("idx")).unwrap();
+    assert!(verify_pack_trailer(&mut fs::File::open(&pack_path).unwrap()).unwrap());
+    assert!(
+        verify_idx_trailer(&mut fs::File::open(pack_path.with_extension("idx")).unwrap()).unwrap()
+    );
+
+    *pack.last_mut().unwrap() ^= 1;
+    *index.last_mut().unwrap() ^= 1;
+    assert!(!verify_pack_trailer(&mut Cursor::new(&pack)).unwrap());
+    assert!(!verify_idx_trailer(&mut Cursor::new(&index)).unwrap());
+    // A change before the checksum is caught too.
+    *pack.last_mut().unwrap() ^= 1;
+    pack[12] ^= 1;
+    assert!(!verify_pack_trailer(&mut Cursor::new(&pack)).unwrap());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn short_files() {
+    // Without 20 bytes, there's no checksum to match.
+    for length in [0, 1, 19] {
+        assert!(!verify_pack_trailer(&mut Cursor::new(vec![0; length])).unwrap());
+        assert!(!verify_idx_trailer(&mut Cursor::new(vec![0; length])).unwrap());
+    }
+    // Exactly 20 bytes is a checksum of nothing, which is the SHA1 of the empty string.
+    let empty_sha1 = parse_hex_oid("da39a3ee5e6b4b0d3255bfef95601890afd80709");
+    assert!(verify_pack_trailer(&mut Cursor::new(empty_sha1)).unwrap());
+    assert!(verify_idx_trailer(&mut Cursor::new(empty_sha1)).unwrap());
+}
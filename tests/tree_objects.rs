@@ -0,0 +1,58 @@
+//! Checks `--object-type tree`: every object in the generated pack should be a tree, no two trees should
+//! share a shorthash, and git should accept the trees as valid, even though nothing refers to them.
+//!
+//! The test is skipped if `git` isn't installed.
+
+use std::{collections::HashSet, fs};
+
+#[allow(dead_code)]
+mod common;
+
+use common::{git, git_is_installed, test_dir, tool};
+
+#[test]
+fn trees_cover_distinct_shorthashes() {
+    if !git_is_installed("trees_cover_distinct_shorthashes") {
+        return;
+    }
+    let repo = test_dir("tree_objects");
+    git(&repo, None, &["init", "--quiet"]);
+    let output = tool(&repo)
+        .args(["--object-type", "tree", "--max-attempts", "256"])
+        .args(["--write-checkpoint", ".git/every-shorthash.checkpoint"])
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "generating the pack failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let generated_count: usize = stdout
+        .lines()
+        .find_map(|line| line.strip_prefix("Generated trees: "))
+        .expect("the run didn't print the number of generated trees")
+        .parse()
+        .unwrap();
+
+    let objects = git(
+        &repo,
+        None,
+        &["cat-file", "--batch-all-objects", "--batch-check"],
+    );
+    let mut shorthashes = HashSet::new();
+    for line in objects.lines() {
+        let fields: Vec<&str> = line.split(' ').collect();
+        assert_eq!(fields[1], "tree", "{} isn't a tree", fields[0]);
+        assert!(
+            shorthashes.insert(&fields[0][..7]),
+            "two trees have the shorthash {}",
+            &fields[0][..7]
+        );
+    }
+    // The generated trees, the delta base tree and the empty tree
+    assert_eq!(shorthashes.len(), generated_count + 2);
+
+    git(&repo, None, &["fsck", "--strict"]);
+    fs::remove_dir_all(&repo).unwrap();
+}
@@ -0,0 +1,50 @@
+//! Checks that `--verify-after=full` reads back packs with long delta chains in a reasonable time. With
+//! several trees, the commits of the chains are interleaved, so each delta's base is a few objects back
+//! rather than the object right before it, and after every batch the chains continue past a merge commit.
+
+use std::fs;
+
+#[allow(dead_code)]
+mod common;
+
+use common::{run, test_dir};
+
+#[test]
+fn interleaved_delta_chains() {
+    let dir = test_dir("verify_after");
+    let (success, stdout, stderr) = run(
+        &dir,
+        &[
+            "--objects-dir",
+            "objects",
+            "--max-attempts",
+            "20000",
+            "--tree-count",
+            "3",
+            "--delta-chain-depth",
+            "4095",
+            "--verify-after=full",
+        ],
+    );
+    assert!(success, "generating the pack failed: {}", stderr);
+    assert!(
+        stdout.contains("Verification passed"),
+        "unexpected output: {}",
+        stdout
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn delta_chain_depth_limit() {
+    // Git's pack-objects doesn't write chains deeper than 4095 either.
+    let dir = test_dir("verify_after-depth_limit");
+    let (success, _, stderr) = run(&dir, &["--delta-chain-depth", "4096"]);
+    assert!(!success);
+    assert!(
+        stderr.contains("--delta-chain-depth must be between 1 and 4095, not 4096"),
+        "unexpected error: {}",
+        stderr
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}